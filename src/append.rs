@@ -0,0 +1,417 @@
+use std::marker::PhantomData;
+
+use anyhow::Result;
+use typenum::U2;
+
+use crate::hash::{Algorithm, Hashable};
+use crate::merkle::{get_merkle_tree_row_count_with_policy, Element, OddRowPolicy};
+use crate::proof::Proof;
+use crate::store::{Store, StoreConfig};
+
+/// Append-only binary Merkle Tree.
+///
+/// Leafs can be appended one at a time or in batches; only the nodes
+/// on the right edge of the tree that depend on the new leafs are
+/// rehashed.  The tree follows RFC 6962 (see `OddRowPolicy::Promote`),
+/// so at any size its root and proofs are identical to those of a
+/// `MerkleTree` built from the same leafs with that policy.
+///
+/// Each row of the tree is kept in its own store, so that rows can
+/// grow independently:
+///
+/// ```text
+///     [h1 h2 h3 h4 h5]
+///     [h12 h34 h5]
+///     [h1234 h5]
+///     [root]
+/// ```
+///
+/// Stores are allocated up front for at most `capacity` leafs, which
+/// is a hard limit: appending past it fails, keeping the tree as it
+/// was once full.  Trees created with `new_with_config` keep row `i`
+/// in the store `<id>-row-<i>` and can be reopened with `from_config`.
+#[derive(Debug)]
+pub struct AppendOnlyMerkleTree<E: Element, A: Algorithm<E>, S: Store<E>> {
+    rows: Vec<S>,
+    capacity: usize,
+    leafs: usize,
+    _a: PhantomData<A>,
+    _e: PhantomData<E>,
+}
+
+impl<E: Element, A: Algorithm<E>, S: Store<E>> AppendOnlyMerkleTree<E, A, S> {
+    /// Creates a new empty tree which can hold up to `capacity` leafs.
+    pub fn new(capacity: usize) -> Result<AppendOnlyMerkleTree<E, A, S>> {
+        OddRowPolicy::Promote.ensure_valid_shape(capacity, 2)?;
+
+        let rows = get_row_widths(capacity)
+            .into_iter()
+            .map(S::new)
+            .collect::<Result<Vec<S>>>()?;
+
+        Self::from_rows(rows, capacity, 0)
+    }
+
+    /// Creates a new empty tree which can hold up to `capacity` leafs,
+    /// backed by stores described by `config`.
+    pub fn new_with_config(
+        capacity: usize,
+        config: StoreConfig,
+    ) -> Result<AppendOnlyMerkleTree<E, A, S>> {
+        OddRowPolicy::Promote.ensure_valid_shape(capacity, 2)?;
+
+        let rows = get_row_widths(capacity)
+            .into_iter()
+            .enumerate()
+            .map(|(row, width)| S::new_with_config(width, 2, get_row_config(&config, row, width)))
+            .collect::<Result<Vec<S>>>()?;
+        ensure!(
+            rows.iter().all(|row| !row.loaded_from_disk()),
+            "Store already exists, use from_config to open it"
+        );
+
+        Self::from_rows(rows, capacity, 0)
+    }
+
+    /// Opens a tree of `leafs` leafs created with `new_with_config`
+    /// from the same `capacity` and `config`.
+    pub fn from_config(
+        capacity: usize,
+        config: StoreConfig,
+        leafs: usize,
+    ) -> Result<AppendOnlyMerkleTree<E, A, S>> {
+        OddRowPolicy::Promote.ensure_valid_shape(capacity, 2)?;
+
+        let rows = get_row_widths(capacity)
+            .into_iter()
+            .enumerate()
+            .map(|(row, width)| S::new_from_disk(width, 2, &get_row_config(&config, row, width)))
+            .collect::<Result<Vec<S>>>()?;
+
+        Self::from_rows(rows, capacity, leafs)
+    }
+
+    fn from_rows(
+        rows: Vec<S>,
+        capacity: usize,
+        leafs: usize,
+    ) -> Result<AppendOnlyMerkleTree<E, A, S>> {
+        ensure!(leafs <= capacity, "Leafs exceed capacity");
+        for (row, width) in rows.iter().zip(get_row_widths(leafs)) {
+            ensure!(
+                row.len() >= width,
+                "Store holds {} nodes, {} are required",
+                row.len(),
+                width
+            );
+        }
+
+        Ok(AppendOnlyMerkleTree {
+            rows,
+            capacity,
+            leafs,
+            _a: PhantomData,
+            _e: PhantomData,
+        })
+    }
+
+    /// Appends a single leaf, hashing it as `MerkleTree` does.
+    pub fn append(&mut self, item: E) -> Result<()> {
+        self.append_batch(vec![item])
+    }
+
+    /// Appends a hashable object as a single leaf.
+    pub fn append_data<O: Hashable<A>>(&mut self, data: O) -> Result<()> {
+        let mut a = A::default();
+        data.hash(&mut a);
+        self.append(a.hash())
+    }
+
+    /// Appends all leafs yielded by `items`, rehashing the affected
+    /// nodes once all of them have been added.  If the tree fills up,
+    /// the leafs appended so far are kept and an error is returned.
+    pub fn append_batch<I: IntoIterator<Item = E>>(&mut self, items: I) -> Result<()> {
+        let first = self.leafs;
+
+        let mut a = A::default();
+        let mut leafs = first;
+        let mut full = false;
+        for item in items {
+            if leafs == self.capacity {
+                full = true;
+                break;
+            }
+
+            a.reset();
+            self.rows[0].write_at(a.leaf(item), leafs)?;
+            leafs += 1;
+        }
+
+        if leafs > first {
+            self.rehash_from(first, leafs)?;
+            self.leafs = leafs;
+        }
+
+        ensure!(!full, "Tree is full (capacity: {})", self.capacity);
+
+        Ok(())
+    }
+
+    // Rehashes all nodes depending on leafs at index `from` and above.
+    fn rehash_from(&mut self, from: usize, leafs: usize) -> Result<()> {
+        let policy = OddRowPolicy::Promote;
+
+        let mut from = from;
+        let mut width = leafs;
+        let mut level = 0;
+        while width > 1 {
+            let parent_from = from / 2;
+            let parent_width = policy.next_row_width(width, 2);
+
+            // Promotion keeps every row a prefix of the next size's
+            // row, so only the nodes from `parent_from` change.
+            for i in parent_from..parent_width {
+                let start = i * 2;
+                let end = std::cmp::min(start + 2, width);
+                let nodes = self.rows[level].read_range(start..end)?;
+                let node = policy.hash_nodes::<E, A>(&nodes, 2, level);
+                self.rows[level + 1].write_at(node, i)?;
+            }
+
+            from = parent_from;
+            width = parent_width;
+            level += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Returns merkle root
+    pub fn root(&self) -> Result<E> {
+        ensure!(self.leafs > 0, "Empty tree has no root");
+        self.rows[self.row_count() - 1].read_at(0)
+    }
+
+    /// Returns number of elements in the tree.
+    pub fn len(&self) -> usize {
+        get_row_widths(self.leafs).iter().sum()
+    }
+
+    /// Returns `true` if no leafs were appended yet.
+    pub fn is_empty(&self) -> bool {
+        self.leafs == 0
+    }
+
+    /// Returns number of leafs appended so far.
+    pub fn leafs(&self) -> usize {
+        self.leafs
+    }
+
+    /// Returns the maximum number of leafs this tree can hold.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns row_count of the tree
+    pub fn row_count(&self) -> usize {
+        get_merkle_tree_row_count_with_policy(self.leafs, 2, OddRowPolicy::Promote)
+    }
+
+    /// Flushes the stores holding the rows.
+    pub fn sync(&self) -> Result<()> {
+        for row in &self.rows {
+            row.sync()?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns merkle leaf at index i
+    pub fn read_at(&self, i: usize) -> Result<E> {
+        ensure!(
+            i < self.leafs,
+            "{} is out of bounds (max: {})",
+            i,
+            self.leafs
+        );
+
+        self.rows[0].read_at(i)
+    }
+
+    /// Generate merkle tree inclusion proof for leaf `i`
+    pub fn gen_proof(&self, i: usize) -> Result<Proof<E, U2>> {
+        let item = self.read_at(i)?;
+
+        let row_count = self.row_count();
+        let widths = get_row_widths(self.leafs);
+        let mut lemma: Vec<E> = Vec::with_capacity(row_count + 1);
+        let mut path: Vec<usize> = Vec::with_capacity(row_count - 1);
+
+        let mut j = i;
+        lemma.push(item);
+        for (row, width) in self.rows[..row_count - 1].iter().zip(widths) {
+            // The sibling is missing if the node was promoted.
+            let sibling = j ^ 1;
+            if sibling < width {
+                lemma.push(row.read_at(sibling)?);
+            }
+
+            path.push(j % 2);
+            j /= 2;
+        }
+        lemma.push(self.root()?);
+
+        Proof::new_with_policy(lemma, path, OddRowPolicy::Promote, self.leafs)
+    }
+}
+
+// Returns the width of each row of a tree of `leafs` leafs, from the
+// leafs up to the root.
+fn get_row_widths(leafs: usize) -> Vec<usize> {
+    let policy = OddRowPolicy::Promote;
+    let row_count = get_merkle_tree_row_count_with_policy(leafs, 2, policy);
+    let mut widths = Vec::with_capacity(row_count);
+    let mut width = leafs;
+    for _ in 0..row_count {
+        widths.push(width);
+        width = policy.next_row_width(width, 2);
+    }
+
+    widths
+}
+
+// Returns the config of the store holding row `row` of a tree backed
+// by stores described by `config`.
+fn get_row_config(config: &StoreConfig, row: usize, width: usize) -> StoreConfig {
+    StoreConfig::from_config(config, format!("{}-row-{}", config.id, row), Some(width))
+}
+
+#[cfg(test)]
+use crate::merkle::MerkleTree;
+#[cfg(test)]
+use crate::options::TreeOptions;
+#[cfg(test)]
+use crate::store::{DiskStore, VecStore};
+#[cfg(test)]
+use crate::test_common::{Item, XOR128};
+
+#[cfg(test)]
+fn get_item(i: usize) -> Item {
+    let mut a = XOR128::new();
+    (i * 93).hash(&mut a);
+    a.hash()
+}
+
+#[cfg(test)]
+fn test_append<S: Store<Item>>(count: usize, batch: usize) {
+    let mut tree: AppendOnlyMerkleTree<Item, XOR128, S> =
+        AppendOnlyMerkleTree::new(count).expect("failed to create tree");
+    assert!(tree.is_empty());
+    assert!(tree.root().is_err());
+
+    let mut leafs = 0;
+    while leafs < count {
+        let end = std::cmp::min(leafs + batch, count);
+        tree.append_batch((leafs..end).map(get_item))
+            .expect("failed to append");
+        leafs = end;
+
+        let mt: MerkleTree<Item, XOR128, VecStore<_>> = MerkleTree::try_from_iter_with_options(
+            (0..leafs).map(|i| Ok(get_item(i))),
+            &TreeOptions::new().with_policy(OddRowPolicy::Promote),
+        )
+        .expect("failed to create tree");
+
+        assert_eq!(tree.leafs(), leafs);
+        assert_eq!(tree.len(), mt.len());
+        assert_eq!(tree.row_count(), mt.row_count());
+        assert_eq!(tree.root().unwrap(), mt.root());
+
+        for i in 0..leafs {
+            let p = tree.gen_proof(i).expect("failed to generate proof");
+            assert_eq!(p, mt.gen_proof(i).unwrap());
+            assert!(p.validate::<XOR128>().expect("failed to validate"));
+        }
+    }
+
+    assert!(tree.append(get_item(count)).is_err());
+    assert_eq!(tree.leafs(), count);
+}
+
+#[test]
+fn test_append_single() {
+    test_append::<VecStore<_>>(37, 1);
+    test_append::<DiskStore<_>>(37, 1);
+}
+
+#[test]
+fn test_append_batch() {
+    test_append::<VecStore<_>>(130, 7);
+    test_append::<DiskStore<_>>(130, 16);
+}
+
+#[test]
+fn test_append_batch_overflow() {
+    let mut tree: AppendOnlyMerkleTree<Item, XOR128, VecStore<_>> =
+        AppendOnlyMerkleTree::new(5).expect("failed to create tree");
+    assert!(tree.append_batch((0..7).map(get_item)).is_err());
+
+    // The leafs which fit are kept.
+    let mt: MerkleTree<Item, XOR128, VecStore<_>> = MerkleTree::from_data_with_options(
+        (0..5).map(|i| i * 93),
+        &TreeOptions::new().with_policy(OddRowPolicy::Promote),
+    )
+    .expect("failed to create tree");
+    assert_eq!(tree.leafs(), 5);
+    assert_eq!(tree.root().unwrap(), mt.root());
+
+    let mut tree: AppendOnlyMerkleTree<Item, XOR128, VecStore<_>> =
+        AppendOnlyMerkleTree::new(5).expect("failed to create tree");
+    for i in 0..5 {
+        tree.append_data(i * 93).expect("failed to append");
+    }
+    assert_eq!(tree.root().unwrap(), mt.root());
+}
+
+#[test]
+fn test_append_reopen() {
+    let temp_dir = tempdir::TempDir::new("test_append_reopen").unwrap();
+    let config = StoreConfig::new(temp_dir.path(), String::from("append"), 0);
+
+    let mut tree: AppendOnlyMerkleTree<Item, XOR128, DiskStore<_>> =
+        AppendOnlyMerkleTree::new_with_config(16, config.clone()).expect("failed to create tree");
+    tree.append_batch((0..10).map(get_item))
+        .expect("failed to append");
+    tree.sync().expect("failed to sync");
+    let root = tree.root().unwrap();
+    drop(tree);
+
+    assert!(
+        AppendOnlyMerkleTree::<Item, XOR128, DiskStore<_>>::new_with_config(16, config.clone())
+            .is_err()
+    );
+
+    let mut tree: AppendOnlyMerkleTree<Item, XOR128, DiskStore<_>> =
+        AppendOnlyMerkleTree::from_config(16, config.clone(), 10).expect("failed to open tree");
+    assert_eq!(tree.leafs(), 10);
+    assert_eq!(tree.root().unwrap(), root);
+    assert!(tree.read_at(10).is_err());
+
+    // The tree keeps growing from the leafs it was opened with, up to
+    // its capacity.
+    tree.append_batch((10..16).map(get_item))
+        .expect("failed to append");
+    assert!(tree.append(get_item(16)).is_err());
+
+    let mt: MerkleTree<Item, XOR128, VecStore<_>> = MerkleTree::try_from_iter_with_options(
+        (0..16).map(|i| Ok(get_item(i))),
+        &TreeOptions::new().with_policy(OddRowPolicy::Promote),
+    )
+    .expect("failed to create tree");
+    assert_eq!(tree.root().unwrap(), mt.root());
+    assert_eq!(tree.len(), mt.len());
+
+    assert!(
+        AppendOnlyMerkleTree::<Item, XOR128, DiskStore<_>>::from_config(16, config, 17).is_err()
+    );
+}
@@ -150,4 +150,50 @@ where
         }
         self.hash()
     }
+
+    /// Returns the node filling the short groups of siblings of the
+    /// trees built with `OddRowPolicy::Pad`.  By default, this is the
+    /// hash of the empty input.
+    #[inline]
+    fn pad(&mut self) -> T {
+        self.reset();
+        self.hash()
+    }
+
+    /// Returns the hash values for a batch of independent MT leafs,
+    /// in order.
+    ///
+    /// The tree builders hash the leafs a block at a time with this
+    /// method, so that backends able to compute several digests at
+    /// once (e.g. with SIMD lanes) can override it.  By default, each
+    /// leaf is hashed in turn with `leaf`.
+    #[inline]
+    fn leaf_batch(&mut self, leafs: Vec<T>) -> Vec<T> {
+        leafs
+            .into_iter()
+            .map(|leaf| {
+                self.reset();
+                self.leaf(leaf)
+            })
+            .collect()
+    }
+
+    /// Returns the hash values for a batch of independent MT interior
+    /// nodes of the same `height`, each given by its group of
+    /// children, in order.
+    ///
+    /// The tree builders hash the full groups of a row a chunk at a
+    /// time with this method, so that backends able to compute
+    /// several digests at once can override it.  By default, each
+    /// group is hashed in turn with `multi_node`.
+    #[inline]
+    fn multi_node_batch(&mut self, groups: &[&[T]], height: usize) -> Vec<T> {
+        groups
+            .iter()
+            .map(|nodes| {
+                self.reset();
+                self.multi_node(nodes, height)
+            })
+            .collect()
+    }
 }
@@ -19,6 +19,22 @@
 //! MTH(D[n]) = ALG(0x01 || MTH(D[0:k]) || MTH(D[k:n])),
 //! ```
 //!
+//! By default the number of leafs must be a power of the tree arity.
+//! Binary trees built with [`merkle::OddRowPolicy::Promote`] accept any number
+//! of leafs and match the RFC 6962 tree hash: a node without a sibling
+//! is promoted unchanged to the row above.  [`merkle::OddRowPolicy::Duplicate`]
+//! (Bitcoin) and [`merkle::OddRowPolicy::Pad`] instead fill the last group
+//! of a row with copies of its last node or with the padding node of the
+//! algorithm.
+//!
+//! The tree arity is part of the tree type (e.g. `U2`, `U3` or `U8`),
+//! unless [`merkle::DynMerkleTree`] is used, whose arity is only known at
+//! runtime.  Both build the same trees and proofs.  Runtime arity trees
+//! can also be built with an arity schedule giving the arity of each
+//! row (e.g. 8-ary at the leafs and binary above, see
+//! [`merkle::row_arity`]).  Any arity greater than 1 is supported,
+//! powers of two being the cheapest to lay out.
+//!
 //! Link: [](https://en.wikipedia.org/wiki/Merkle_tree)
 //!
 //! # Implementation choices
@@ -171,12 +187,31 @@ mod hash_impl;
 /// Store implementations.
 pub mod store;
 
-/// Merkle tree inclusion proof.
+/// Merkle tree inclusion, multi inclusion, range and consistency proofs.
 pub mod proof;
 
 /// Merkle tree abstractions, implementation and algorithms.
 pub mod merkle;
 
+/// Append-only merkle tree.
+pub mod append;
+
+/// Sparse merkle tree.
+pub mod sparse;
+
+/// Merkle mountain range.
+pub mod mmr;
+
+/// Sorted merkle tree.
+pub mod sorted;
+
+/// Streaming merkle tree builder, for inputs of unknown length.
+pub mod stream;
+
+/// Options of the merkle tree builds: thread pool, chunk sizes, progress and
+/// cancellation.
+pub mod options;
+
 /// Re-usable Testing primitives
 pub mod test_common;
 
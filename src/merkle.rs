@@ -1,21 +1,24 @@
 use std::marker::PhantomData;
-use std::path::PathBuf;
-use std::sync::{Arc, RwLock};
+use std::path::{Path, PathBuf};
 
 use anyhow::{Context, Result};
 use log::debug;
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use typenum::marker_traits::Unsigned;
 use typenum::{U0, U2};
 
 use crate::hash::{Algorithm, Hashable};
-use crate::proof::Proof;
+use crate::options::{BuildOptions, TreeOptions};
+use crate::proof::{ConsistencyProof, MultiProof, Proof, RangeProof};
 use crate::store::{
-    ExternalReader, LevelCacheStore, ReplicaConfig, Store, StoreConfig, VecStore, BUILD_CHUNK_NODES,
+    build_chunk_nodes, remove_cancelled, ExternalReader, LeafFileStore, LevelCacheStore,
+    ReplicaConfig, Store, StoreConfig, VecStore, BUILD_CHUNK_NODES,
 };
 
 // Number of batched nodes processed and stored together when
-// populating from the data leaves.
+// populating from the data leaves (default of
+// `BuildOptions::with_data_block_size`).
 pub const BUILD_DATA_BLOCK_SIZE: usize = 64 * BUILD_CHUNK_NODES;
 
 /// Merkle Tree.
@@ -41,7 +44,8 @@ pub const BUILD_DATA_BLOCK_SIZE: usize = 64 * BUILD_CHUNK_NODES;
 ///
 /// Merkle root is always the last element in the array.
 ///
-/// The number of inputs must always be a power of two.
+/// The number of inputs must always be a power of the arity, unless
+/// the tree is built with an `OddRowPolicy` other than `Reject`.
 ///
 /// This tree structure can consist of at most 3 layers of trees (of
 /// arity U, N and R, from bottom to top).
@@ -67,6 +71,16 @@ pub const BUILD_DATA_BLOCK_SIZE: usize = 64 * BUILD_CHUNK_NODES;
 /// At most, one more layer (top layer) can be constructed to group a
 /// number of the above sub-tree structures (not pictured).
 ///
+/// The trees of a layer may be of different heights, and there may be
+/// fewer of them than the layer arity (see `from_trees`), in which
+/// case leafs are addressed through the offsets of the trees (see
+/// `leaf_offsets`).
+///
+/// Trees with any number of layers can be constructed with
+/// `from_layer_trees` or `from_trees_with_arities`, in which case the
+/// arity of each layer is only known at runtime and SubTreeArity and
+/// TopTreeArity are left to 0.
+///
 /// BaseTreeArity is the arity of the base layer trees [bottom].
 /// SubTreeArity is the arity of the sub-tree layer of trees [middle].
 /// TopTreeArity is the arity of the top layer of trees [top].
@@ -82,7 +96,8 @@ enum Data<E: Element, A: Algorithm<E>, S: Store<E>, BaseTreeArity: Unsigned, Sub
     /// A BaseTree contains a single Store.
     BaseTree(S),
 
-    /// A SubTree contains a list of BaseTrees.
+    /// A SubTree contains a list of BaseTrees, or of trees which are
+    /// themselves SubTrees (see `MerkleTree::from_layer_trees`).
     SubTree(Vec<MerkleTree<E, A, S, BaseTreeArity>>),
 
     /// A TopTree contains a list of SubTrees.
@@ -148,6 +163,19 @@ where
     leafs: usize,
     len: usize,
 
+    // Arity of the base tree(s), which is BaseTreeArity unless it is
+    // U0 (see `DynMerkleTree`).  Base trees built with an arity
+    // schedule have one arity per row instead, from the leafs up,
+    // whose last one applies to all the rows above (see `row_arity`).
+    arities: Vec<usize>,
+
+    // Index of the first leaf of each slot of a compound layer,
+    // followed by the number of leafs (empty for a base tree).  The
+    // trees of a layer may hold different numbers of leafs, and the
+    // slots left over by fewer trees than the layer arity are empty
+    // (see `empty_root`).
+    offsets: Vec<usize>,
+
     // Note: The former 'upstream' merkle_light project uses 'height'
     // (with regards to the tree property) incorrectly, so we've
     // renamed it since it's actually a 'row_count'.  For example, a
@@ -157,6 +185,9 @@ where
     // Internally, this code considers only the row_count.
     row_count: usize,
 
+    // How odd rows of the base tree(s) are handled.
+    policy: OddRowPolicy,
+
     // Cache with the `root` of the tree built from `data`. This allows to
     // not access the `Store` (e.g., access to disks in `DiskStore`).
     root: E,
@@ -168,6 +199,16 @@ where
     _tta: PhantomData<TopTreeArity>,
 }
 
+/// Merkle tree whose base tree arity is only known at runtime.  It is
+/// built with the constructors taking `TreeOptions` with an arity
+/// (e.g. `try_from_iter_with_options`), and can be the base tree of compound trees like typed trees are.
+///
+/// The tree data, roots and proofs are bit-identical to the ones of
+/// the tree of the matching typed arity, so that both can be used
+/// interchangeably (see `Proof::into_arity`).
+pub type DynMerkleTree<E, A, S, SubTreeArity = U0, TopTreeArity = U0> =
+    MerkleTree<E, A, S, U0, SubTreeArity, TopTreeArity>;
+
 impl<
         E: Element,
         A: Algorithm<E>,
@@ -182,7 +223,10 @@ impl<
             .field("data", &self.data)
             .field("leafs", &self.leafs)
             .field("len", &self.len)
+            .field("arities", &self.arities)
+            .field("offsets", &self.offsets)
             .field("row_count", &self.row_count)
+            .field("policy", &self.policy)
             .field("root", &self.root)
             .finish()
     }
@@ -239,7 +283,7 @@ impl<
             TopTreeArity,
         >,
     > {
-        let branches = BaseTreeArity::to_usize();
+        let branches = Self::typed_arity()?;
         let mut trees = Vec::with_capacity(configs.len());
         ensure!(
             configs.len() == replica_config.offsets.len(),
@@ -328,6 +372,49 @@ impl<
         TopTreeArity: Unsigned,
     > MerkleTree<E, A, S, BaseTreeArity, SubTreeArity, TopTreeArity>
 {
+    // Returns the base tree arity of the tree type, for constructors
+    // which do not take it at runtime.
+    pub(crate) fn typed_arity() -> Result<usize> {
+        let branches = BaseTreeArity::to_usize();
+        ensure!(
+            branches > 0,
+            "Trees of runtime arity must be created with an explicit arity"
+        );
+
+        Ok(branches)
+    }
+
+    // Checks that the base trees of the tree type can be of arity
+    // `branches`.
+    fn check_arity(branches: usize) -> Result<()> {
+        ensure!(
+            branches > 0
+                && (BaseTreeArity::to_usize() == 0 || BaseTreeArity::to_usize() == branches),
+            "Invalid base tree arity {} (expected: {})",
+            branches,
+            BaseTreeArity::to_usize()
+        );
+
+        Ok(())
+    }
+
+    // Checks that the base trees of the tree type can have the arity
+    // schedule `arities`, returning it without the trailing arities
+    // which repeat the last one.
+    pub(crate) fn check_schedule(arities: &[usize]) -> Result<Vec<usize>> {
+        ensure!(!arities.is_empty(), "Arity schedules must not be empty");
+        for branches in arities {
+            Self::check_arity(*branches)?;
+        }
+
+        let mut arities = arities.to_vec();
+        while arities.len() > 1 && arities[arities.len() - 1] == arities[arities.len() - 2] {
+            arities.pop();
+        }
+
+        Ok(arities)
+    }
+
     /// Creates new merkle from a sequence of hashes.
     pub fn new<I: IntoIterator<Item = E>>(
         data: I,
@@ -343,31 +430,44 @@ impl<
         Self::try_from_iter_with_config(data.into_iter().map(Ok), config)
     }
 
+    // Returns the arity schedule of the base trees built with
+    // `options`, the base tree arity of the tree type by default.
+    pub(crate) fn options_schedule(options: &TreeOptions) -> Result<Vec<usize>> {
+        match options.arity_schedule() {
+            Some(arities) => Self::check_schedule(arities),
+            None => Ok(vec![Self::typed_arity()?]),
+        }
+    }
+
     /// Creates new merkle tree from a list of hashable objects.
     pub fn from_data<O: Hashable<A>, I: IntoIterator<Item = O>>(
         data: I,
     ) -> Result<MerkleTree<E, A, S, BaseTreeArity, SubTreeArity, TopTreeArity>> {
-        let mut a = A::default();
-        Self::try_from_iter(data.into_iter().map(|x| {
-            a.reset();
-            x.hash(&mut a);
-            Ok(a.hash())
-        }))
+        Self::from_data_with_options(data, &TreeOptions::new())
     }
 
     /// Creates new merkle tree from a list of hashable objects.
     pub fn from_data_with_config<O: Hashable<A>, I: IntoIterator<Item = O>>(
         data: I,
         config: StoreConfig,
+    ) -> Result<MerkleTree<E, A, S, BaseTreeArity, SubTreeArity, TopTreeArity>> {
+        Self::from_data_with_options(data, &TreeOptions::new().with_config(config))
+    }
+
+    /// Creates new merkle tree from a list of hashable objects, given
+    /// the tree options (see `TreeOptions`).
+    pub fn from_data_with_options<O: Hashable<A>, I: IntoIterator<Item = O>>(
+        data: I,
+        options: &TreeOptions,
     ) -> Result<MerkleTree<E, A, S, BaseTreeArity, SubTreeArity, TopTreeArity>> {
         let mut a = A::default();
-        Self::try_from_iter_with_config(
+        Self::try_from_iter_with_options(
             data.into_iter().map(|x| {
                 a.reset();
                 x.hash(&mut a);
                 Ok(a.hash())
             }),
-            config,
+            options,
         )
     }
 
@@ -378,6 +478,20 @@ impl<
         data: S,
         leafs: usize,
     ) -> Result<MerkleTree<E, A, S, BaseTreeArity, SubTreeArity, TopTreeArity>> {
+        Self::from_data_store_with_options(data, leafs, &TreeOptions::new())
+    }
+
+    /// Creates new merkle tree from an already allocated 'Store'
+    /// holding a tree built with the arity schedule and odd row policy
+    /// of `options` (whose config is unused).  The specified 'size' is
+    /// the number of base data leafs in the MT.
+    pub fn from_data_store_with_options(
+        data: S,
+        leafs: usize,
+        options: &TreeOptions,
+    ) -> Result<MerkleTree<E, A, S, BaseTreeArity, SubTreeArity, TopTreeArity>> {
+        let arities = Self::options_schedule(options)?;
+        let policy = options.policy();
         ensure!(
             SubTreeArity::to_usize() == 0,
             "Data stores must not have sub-tree layers"
@@ -387,22 +501,17 @@ impl<
             "Data stores must not have a top layer"
         );
 
-        let branches = BaseTreeArity::to_usize();
-        ensure!(next_pow2(leafs) == leafs, "leafs MUST be a power of 2");
-        ensure!(
-            next_pow2(branches) == branches,
-            "branches MUST be a power of 2"
-        );
+        policy.ensure_valid_schedule(leafs, &arities)?;
 
-        let tree_len = get_merkle_tree_len(leafs, branches)?;
+        let tree_len = get_merkle_tree_len_with_schedule(leafs, &arities, policy)?;
         ensure!(tree_len == data.len(), "Inconsistent tree data");
 
         ensure!(
-            is_merkle_tree_size_valid(leafs, branches),
+            is_merkle_tree_size_valid_with_schedule(leafs, &arities, policy),
             "MerkleTree size is invalid given the arity"
         );
 
-        let row_count = get_merkle_tree_row_count(leafs, branches);
+        let row_count = get_merkle_tree_row_count_with_schedule(leafs, &arities, policy);
         let root = data.read_at(data.len() - 1)?;
 
         Ok(MerkleTree {
@@ -410,6 +519,9 @@ impl<
             leafs,
             len: tree_len,
             row_count,
+            policy,
+            arities,
+            offsets: Vec::new(),
             root,
             _a: PhantomData,
             _e: PhantomData,
@@ -424,43 +536,7 @@ impl<
         data: &[u8],
         leafs: usize,
     ) -> Result<MerkleTree<E, A, S, BaseTreeArity, SubTreeArity, TopTreeArity>> {
-        ensure!(
-            SubTreeArity::to_usize() == 0,
-            "Data slice must not have sub-tree layers"
-        );
-        ensure!(
-            TopTreeArity::to_usize() == 0,
-            "Data slice must not have a top layer"
-        );
-
-        let branches = BaseTreeArity::to_usize();
-        let row_count = get_merkle_tree_row_count(leafs, branches);
-        let tree_len = get_merkle_tree_len(leafs, branches)?;
-        ensure!(
-            tree_len == data.len() / E::byte_len(),
-            "Inconsistent tree data"
-        );
-
-        ensure!(
-            is_merkle_tree_size_valid(leafs, branches),
-            "MerkleTree size is invalid given the arity"
-        );
-
-        let store = S::new_from_slice(tree_len, &data).context("failed to create data store")?;
-        let root = store.read_at(data.len() - 1)?;
-
-        Ok(MerkleTree {
-            data: Data::BaseTree(store),
-            leafs,
-            len: tree_len,
-            row_count,
-            root,
-            _a: PhantomData,
-            _e: PhantomData,
-            _bta: PhantomData,
-            _sta: PhantomData,
-            _tta: PhantomData,
-        })
+        Self::from_tree_slice_with_options(data, leafs, &TreeOptions::new())
     }
 
     /// Represent a fully constructed merkle tree from a provided slice.
@@ -469,6 +545,18 @@ impl<
         leafs: usize,
         config: StoreConfig,
     ) -> Result<MerkleTree<E, A, S, BaseTreeArity, SubTreeArity, TopTreeArity>> {
+        Self::from_tree_slice_with_options(data, leafs, &TreeOptions::new().with_config(config))
+    }
+
+    /// Represent a fully constructed merkle tree from a provided
+    /// slice, given the tree options (see `TreeOptions`).
+    pub fn from_tree_slice_with_options(
+        data: &[u8],
+        leafs: usize,
+        options: &TreeOptions,
+    ) -> Result<MerkleTree<E, A, S, BaseTreeArity, SubTreeArity, TopTreeArity>> {
+        let arities = Self::options_schedule(options)?;
+        let policy = options.policy();
         ensure!(
             SubTreeArity::to_usize() == 0,
             "Data slice must not have sub-tree layers"
@@ -478,28 +566,37 @@ impl<
             "Data slice must not have a top layer"
         );
 
-        let branches = BaseTreeArity::to_usize();
-        let row_count = get_merkle_tree_row_count(leafs, branches);
-        let tree_len = get_merkle_tree_len(leafs, branches)?;
+        policy.ensure_valid_schedule(leafs, &arities)?;
+
+        let row_count = get_merkle_tree_row_count_with_schedule(leafs, &arities, policy);
+        let tree_len = get_merkle_tree_len_with_schedule(leafs, &arities, policy)?;
         ensure!(
             tree_len == data.len() / E::byte_len(),
             "Inconsistent tree data"
         );
 
         ensure!(
-            is_merkle_tree_size_valid(leafs, branches),
+            is_merkle_tree_size_valid_with_schedule(leafs, &arities, policy),
             "MerkleTree size is invalid given the arity"
         );
 
-        let store = S::new_from_slice_with_config(tree_len, branches, &data, config)
-            .context("failed to create data store")?;
-        let root = store.read_at(data.len() - 1)?;
+        let store = match options.config() {
+            Some(config) => {
+                S::new_from_slice_with_config(tree_len, arities[0], &data, config.clone())
+            }
+            None => S::new_from_slice(tree_len, &data),
+        }
+        .context("failed to create data store")?;
+        let root = store.read_at(tree_len - 1)?;
 
         Ok(MerkleTree {
             data: Data::BaseTree(store),
             leafs,
             len: tree_len,
             row_count,
+            policy,
+            arities,
+            offsets: Vec::new(),
             root,
             _a: PhantomData,
             _e: PhantomData,
@@ -513,6 +610,10 @@ impl<
     /// trees.  The ordering of the trees is significant, as trees are
     /// leaf indexed / addressable in the same sequence that they are
     /// provided here.
+    ///
+    /// The trees may hold different numbers of leafs, and there may be
+    /// fewer of them than SubTreeArity, in which case the remaining
+    /// slots of the layer hold the empty root (see `empty_root`).
     pub fn from_trees(
         trees: Vec<MerkleTree<E, A, S, BaseTreeArity>>,
     ) -> Result<MerkleTree<E, A, S, BaseTreeArity, SubTreeArity, TopTreeArity>> {
@@ -520,64 +621,47 @@ impl<
             SubTreeArity::to_usize() > 0,
             "Cannot use from_trees if not constructing a structure with sub-trees"
         );
+        ensure!(!trees.is_empty(), "At least one tree is required");
         ensure!(
-            trees
-                .iter()
-                .all(|ref mt| mt.row_count() == trees[0].row_count()),
-            "All passed in trees must have the same row_count"
+            trees.iter().all(|mt| mt.policy() == trees[0].policy()),
+            "All passed in trees must have the same odd row policy"
         );
         ensure!(
-            trees.iter().all(|ref mt| mt.len() == trees[0].len()),
-            "All passed in trees must have the same length"
+            trees
+                .iter()
+                .all(|mt| mt.arity_schedule() == trees[0].arity_schedule()),
+            "All passed in trees must have the same arity"
         );
 
         let sub_tree_layer_nodes = SubTreeArity::to_usize();
         ensure!(
-            trees.len() == sub_tree_layer_nodes,
-            "Length of trees MUST equal the number of sub tree layer nodes"
+            trees.len() <= sub_tree_layer_nodes,
+            "Length of trees MUST not exceed the number of sub tree layer nodes"
         );
 
-        // If we are building a compound tree with no sub-trees,
-        // all properties revert to the single tree properties.
-        let (leafs, len, row_count, root) = if sub_tree_layer_nodes == 0 {
-            (
-                trees[0].leafs(),
-                trees[0].len(),
-                trees[0].row_count(),
-                trees[0].root(),
-            )
-        } else {
-            // Total number of leafs in the compound tree is the combined leafs total of all subtrees.
-            let leafs = trees.iter().fold(0, |leafs, mt| leafs + mt.leafs());
-            // Total length of the compound tree is the combined length of all subtrees plus the root.
-            let len = trees.iter().fold(0, |len, mt| len + mt.len()) + 1;
-            // Total row_count of the compound tree is the row_count of any of the sub-trees to top-layer plus root.
-            let row_count = trees[0].row_count() + 1;
-            // Calculate the compound root by hashing the top layer roots together.
-            let roots: Vec<E> = trees.iter().map(|x| x.root()).collect();
-            let root = A::default().multi_node(&roots, 1);
-
-            (leafs, len, row_count, root)
-        };
-
-        Ok(MerkleTree {
-            data: Data::SubTree(trees),
-            leafs,
-            len,
-            row_count,
-            root,
-            _a: PhantomData,
-            _e: PhantomData,
-            _bta: PhantomData,
-            _sta: PhantomData,
-            _tta: PhantomData,
-        })
+        let shapes = trees
+            .iter()
+            .map(|mt| (mt.leafs(), mt.len(), mt.row_count(), mt.root()))
+            .collect();
+        let policy = trees[0].policy();
+        let arities = trees[0].arity_schedule().to_vec();
+
+        Self::from_layer_data(
+            Data::SubTree(trees),
+            sub_tree_layer_nodes,
+            shapes,
+            policy,
+            arities,
+        )
     }
 
     /// Creates new top layer merkle tree from a vector of merkle
     /// trees with sub-trees.  The ordering of the trees is
     /// significant, as trees are leaf indexed / addressable in the
     /// same sequence that they are provided here.
+    ///
+    /// As with `from_trees`, the trees may hold different numbers of
+    /// leafs, and there may be fewer of them than TopTreeArity.
     pub fn from_sub_trees(
         trees: Vec<MerkleTree<E, A, S, BaseTreeArity, SubTreeArity>>,
     ) -> Result<MerkleTree<E, A, S, BaseTreeArity, SubTreeArity, TopTreeArity>> {
@@ -585,51 +669,38 @@ impl<
             TopTreeArity::to_usize() > 0,
             "Cannot use from_sub_trees if not constructing a structure with sub-trees"
         );
+        ensure!(!trees.is_empty(), "At least one tree is required");
         ensure!(
-            trees
-                .iter()
-                .all(|ref mt| mt.row_count() == trees[0].row_count()),
-            "All passed in trees must have the same row_count"
+            trees.iter().all(|mt| mt.policy() == trees[0].policy()),
+            "All passed in trees must have the same odd row policy"
         );
         ensure!(
-            trees.iter().all(|ref mt| mt.len() == trees[0].len()),
-            "All passed in trees must have the same length"
+            trees
+                .iter()
+                .all(|mt| mt.arity_schedule() == trees[0].arity_schedule()),
+            "All passed in trees must have the same arity"
         );
 
         let top_layer_nodes = TopTreeArity::to_usize();
         ensure!(
-            trees.len() == top_layer_nodes,
-            "Length of trees MUST equal the number of top layer nodes"
+            trees.len() <= top_layer_nodes,
+            "Length of trees MUST not exceed the number of top layer nodes"
         );
 
-        // If we are building a compound tree with no sub-trees,
-        // all properties revert to the single tree properties.
-        let (leafs, len, row_count, root) = {
-            // Total number of leafs in the compound tree is the combined leafs total of all subtrees.
-            let leafs = trees.iter().fold(0, |leafs, mt| leafs + mt.leafs());
-            // Total length of the compound tree is the combined length of all subtrees plus the root.
-            let len = trees.iter().fold(0, |len, mt| len + mt.len()) + 1;
-            // Total row_count of the compound tree is the row_count of any of the sub-trees to top-layer plus root.
-            let row_count = trees[0].row_count() + 1;
-            // Calculate the compound root by hashing the top layer roots together.
-            let roots: Vec<E> = trees.iter().map(|x| x.root()).collect();
-            let root = A::default().multi_node(&roots, 1);
-
-            (leafs, len, row_count, root)
-        };
-
-        Ok(MerkleTree {
-            data: Data::TopTree(trees),
-            leafs,
-            len,
-            row_count,
-            root,
-            _a: PhantomData,
-            _e: PhantomData,
-            _bta: PhantomData,
-            _sta: PhantomData,
-            _tta: PhantomData,
-        })
+        let shapes = trees
+            .iter()
+            .map(|mt| (mt.leafs(), mt.len(), mt.row_count(), mt.root()))
+            .collect();
+        let policy = trees[0].policy();
+        let arities = trees[0].arity_schedule().to_vec();
+
+        Self::from_layer_data(
+            Data::TopTree(trees),
+            top_layer_nodes,
+            shapes,
+            policy,
+            arities,
+        )
     }
 
     /// Creates new top layer merkle tree from a vector of merkle
@@ -637,63 +708,146 @@ impl<
     /// ordering of the trees is significant, as trees are leaf
     /// indexed / addressable in the same sequence that they are
     /// provided here.
+    ///
+    /// Trees are grouped in order by SubTreeArity, so that only the
+    /// last sub-tree may hold fewer trees.
     pub fn from_sub_trees_as_trees(
-        mut trees: Vec<MerkleTree<E, A, S, BaseTreeArity>>,
+        trees: Vec<MerkleTree<E, A, S, BaseTreeArity>>,
     ) -> Result<MerkleTree<E, A, S, BaseTreeArity, SubTreeArity, TopTreeArity>> {
         ensure!(
-            TopTreeArity::to_usize() > 0,
+            TopTreeArity::to_usize() > 0 && SubTreeArity::to_usize() > 0,
             "Cannot use from_sub_trees if not constructing a structure with sub-trees"
         );
-        ensure!(
-            trees
-                .iter()
-                .all(|ref mt| mt.row_count() == trees[0].row_count()),
-            "All passed in trees must have the same row_count"
-        );
-        ensure!(
-            trees.iter().all(|ref mt| mt.len() == trees[0].len()),
-            "All passed in trees must have the same length"
-        );
+        ensure!(!trees.is_empty(), "At least one tree is required");
 
         let sub_tree_count = TopTreeArity::to_usize();
         let top_layer_nodes = sub_tree_count * SubTreeArity::to_usize();
         ensure!(
-            trees.len() == top_layer_nodes,
-            "Length of trees MUST equal the number of top layer nodes"
+            trees.len() <= top_layer_nodes,
+            "Length of trees MUST not exceed the number of top layer nodes"
         );
 
         // Group the trees appropriately into sub-tree ready vectors.
-        let mut grouped_trees = Vec::with_capacity(sub_tree_count);
-        for _ in (0..sub_tree_count).step_by(trees.len() / sub_tree_count) {
-            grouped_trees.push(trees.split_off(trees.len() / sub_tree_count));
-        }
-        grouped_trees.insert(0, trees);
-
         let mut sub_trees: Vec<MerkleTree<E, A, S, BaseTreeArity, SubTreeArity>> =
             Vec::with_capacity(sub_tree_count);
-        for group in grouped_trees {
+        let mut iter = trees.into_iter().peekable();
+        while iter.peek().is_some() {
+            let group = iter.by_ref().take(SubTreeArity::to_usize()).collect();
             sub_trees.push(MerkleTree::from_trees(group)?);
         }
 
-        let (leafs, len, row_count, root) = {
-            // Total number of leafs in the compound tree is the combined leafs total of all subtrees.
-            let leafs = sub_trees.iter().fold(0, |leafs, mt| leafs + mt.leafs());
-            // Total length of the compound tree is the combined length of all subtrees plus the root.
-            let len = sub_trees.iter().fold(0, |len, mt| len + mt.len()) + 1;
-            // Total row_count of the compound tree is the row_count of any of the sub-trees to top-layer plus root.
-            let row_count = sub_trees[0].row_count() + 1;
-            // Calculate the compound root by hashing the top layer roots together.
-            let roots: Vec<E> = sub_trees.iter().map(|x| x.root()).collect();
-            let root = A::default().multi_node(&roots, 1);
-
-            (leafs, len, row_count, root)
-        };
+        Self::from_sub_trees(sub_trees)
+    }
+
+    /// Creates new compound merkle tree from a vector of merkle trees,
+    /// which may themselves be compound trees built by this method, so
+    /// that any number of layers can be stacked.  The arity of the new
+    /// layer is the number of trees, and all the trees must have the
+    /// same layers.  The ordering of the trees is significant, as trees
+    /// are leaf indexed / addressable in the same sequence that they
+    /// are provided here.
+    ///
+    /// The sub-tree and top layer arities of the tree type must be 0,
+    /// as the arity of each layer is only known at runtime (see
+    /// `layer_arities`).
+    pub fn from_layer_trees(
+        trees: Vec<MerkleTree<E, A, S, BaseTreeArity>>,
+    ) -> Result<MerkleTree<E, A, S, BaseTreeArity, SubTreeArity, TopTreeArity>> {
+        let arity = trees.len();
+        Self::from_layer_trees_with_arity(trees, arity)
+    }
+
+    /// Same as `from_layer_trees`, for a layer of `arity` slots, of
+    /// which the trees fill the first ones.  The remaining slots hold
+    /// the empty root (see `empty_root`).
+    pub fn from_layer_trees_with_arity(
+        trees: Vec<MerkleTree<E, A, S, BaseTreeArity>>,
+        arity: usize,
+    ) -> Result<MerkleTree<E, A, S, BaseTreeArity, SubTreeArity, TopTreeArity>> {
+        ensure!(
+            SubTreeArity::to_usize() == 0 && TopTreeArity::to_usize() == 0,
+            "Cannot use from_layer_trees if the layer arities are part of the tree type"
+        );
+        ensure!(!trees.is_empty(), "At least one tree is required");
+        ensure!(
+            trees.len() <= arity,
+            "Length of trees MUST not exceed the layer arity"
+        );
+        ensure!(
+            trees.iter().all(|mt| mt.policy() == trees[0].policy()),
+            "All passed in trees must have the same odd row policy"
+        );
+        ensure!(
+            trees
+                .iter()
+                .all(|mt| mt.arity_schedule() == trees[0].arity_schedule()),
+            "All passed in trees must have the same arity"
+        );
+        let layer_arities = trees[0].layer_arities();
+        ensure!(
+            trees.iter().all(|mt| mt.layer_arities() == layer_arities),
+            "All passed in trees must have the same layers"
+        );
+
+        let shapes = trees
+            .iter()
+            .map(|mt| (mt.leafs(), mt.len(), mt.row_count(), mt.root()))
+            .collect();
+        let policy = trees[0].policy();
+        let arities = trees[0].arity_schedule().to_vec();
+
+        Self::from_layer_data(Data::SubTree(trees), arity, shapes, policy, arities)
+    }
+
+    // Creates a compound tree whose layer of `arity` slots holds the
+    // trees of `data`, given the (leafs, len, row_count, root) of
+    // each of them in order.  Leafs are addressed through the offset
+    // table of the layer, as the trees may differ in size.
+    fn from_layer_data(
+        data: Data<E, A, S, BaseTreeArity, SubTreeArity>,
+        arity: usize,
+        shapes: Vec<(usize, usize, usize, E)>,
+        policy: OddRowPolicy,
+        arities: Vec<usize>,
+    ) -> Result<MerkleTree<E, A, S, BaseTreeArity, SubTreeArity, TopTreeArity>> {
+        ensure!(
+            !shapes.is_empty() && shapes.len() <= arity,
+            "Invalid number of trees for the layer arity"
+        );
+
+        // Total number of leafs in the compound tree is the combined
+        // leafs total of all subtrees, each starting at its offset.
+        let mut offsets = Vec::with_capacity(arity + 1);
+        let mut leafs = 0;
+        for (tree_leafs, _, _, _) in &shapes {
+            offsets.push(leafs);
+            leafs += tree_leafs;
+        }
+        offsets.resize(arity + 1, leafs);
+
+        // Total length of the compound tree is the combined length of all subtrees plus the root.
+        let len = shapes.iter().map(|(_, len, _, _)| len).sum::<usize>() + 1;
+        // Total row_count of the compound tree is the row_count of the tallest sub-tree plus root.
+        let row_count = shapes
+            .iter()
+            .map(|(_, _, row_count, _)| *row_count)
+            .max()
+            .unwrap()
+            + 1;
+        // Calculate the compound root by hashing the top layer roots
+        // together, the empty slots being hashed as empty roots.
+        let mut roots: Vec<E> = shapes.into_iter().map(|(_, _, _, root)| root).collect();
+        roots.resize(arity, Self::empty_root());
+        let root = A::default().multi_node(&roots, 1);
 
         Ok(MerkleTree {
-            data: Data::TopTree(sub_trees),
+            data,
             leafs,
             len,
             row_count,
+            policy,
+            arities,
+            offsets,
             root,
             _a: PhantomData,
             _e: PhantomData,
@@ -703,6 +857,41 @@ impl<
         })
     }
 
+    /// Returns the root standing for a missing tree in the slots of a
+    /// compound layer holding fewer trees than its arity, which is
+    /// the default (zero) element.
+    #[inline]
+    pub fn empty_root() -> E {
+        E::default()
+    }
+
+    /// Creates new compound merkle tree with a layer for each of the
+    /// `arities` (from the top layer down) over the base `trees`,
+    /// which are grouped in order, so that the number of trees must
+    /// be the product of the arities.
+    pub fn from_trees_with_arities(
+        trees: Vec<MerkleTree<E, A, S, BaseTreeArity>>,
+        arities: &[usize],
+    ) -> Result<MerkleTree<E, A, S, BaseTreeArity, SubTreeArity, TopTreeArity>> {
+        ensure!(!arities.is_empty(), "At least one layer is required");
+        ensure!(
+            arities.iter().all(|arity| *arity > 0)
+                && arities.iter().product::<usize>() == trees.len(),
+            "Number of trees MUST equal the product of the layer arities"
+        );
+
+        let mut trees = trees;
+        for arity in arities[1..].iter().rev() {
+            let count = trees.len() / arity;
+            let mut iter = trees.into_iter();
+            trees = (0..count)
+                .map(|_| MerkleTree::from_layer_trees(iter.by_ref().take(*arity).collect()))
+                .collect::<Result<Vec<_>>>()?;
+        }
+
+        Self::from_layer_trees(trees)
+    }
+
     /// Create a compound merkle tree given already constructed merkle
     /// trees contained as a slices. The ordering of the trees is
     /// significant, as trees are leaf indexed / addressable in the
@@ -773,7 +962,7 @@ impl<
         leafs: usize,
         configs: &[StoreConfig],
     ) -> Result<MerkleTree<E, A, S, BaseTreeArity, SubTreeArity, TopTreeArity>> {
-        let branches = BaseTreeArity::to_usize();
+        let branches = Self::typed_arity()?;
         let mut trees = Vec::with_capacity(configs.len());
         for config in configs {
             let data = S::new_with_config(
@@ -825,15 +1014,23 @@ impl<
         mut data: VecStore<E>,
         leafs: usize,
         row_count: usize,
+        arities: &[usize],
     ) -> Result<MerkleTree<E, A, VecStore<E>, BaseTreeArity>> {
-        let root = VecStore::build::<A, BaseTreeArity>(&mut data, leafs, row_count, None)?;
-        let branches = BaseTreeArity::to_usize();
+        let root = VecStore::build_with_schedule::<A>(
+            &mut data,
+            leafs,
+            row_count,
+            None,
+            arities,
+            OddRowPolicy::Reject,
+            &BuildOptions::default(),
+        )?;
 
-        let tree_len = get_merkle_tree_len(leafs, branches)?;
+        let tree_len = get_merkle_tree_len_with_schedule(leafs, arities, OddRowPolicy::Reject)?;
         ensure!(tree_len == Store::len(&data), "Inconsistent tree data");
 
         ensure!(
-            is_merkle_tree_size_valid(leafs, branches),
+            is_merkle_tree_size_valid_with_schedule(leafs, arities, OddRowPolicy::Reject),
             "MerkleTree size is invalid given the arity"
         );
 
@@ -842,6 +1039,9 @@ impl<
             leafs,
             len: tree_len,
             row_count,
+            policy: OddRowPolicy::Reject,
+            arities: arities.to_vec(),
+            offsets: Vec::new(),
             root,
             _a: PhantomData,
             _e: PhantomData,
@@ -851,6 +1051,61 @@ impl<
         })
     }
 
+    // Returns the index of the tree of the compound layer holding
+    // leaf `i`, and the index of the leaf in that tree.
+    fn locate_leaf(&self, i: usize) -> Result<(usize, usize)> {
+        ensure!(
+            i < self.leafs,
+            "{} is out of bounds (max: {})",
+            i,
+            self.leafs
+        ); // i in [0 .. self.leafs)
+        ensure!(!self.offsets.is_empty(), "sub trees required");
+
+        // Offsets of the trees are increasing, and the ones of the
+        // empty slots (if any) are all self.leafs.
+        let tree_index = match self.offsets.binary_search(&i) {
+            Ok(tree_index) => tree_index,
+            Err(next) => next - 1,
+        };
+
+        Ok((tree_index, i - self.offsets[tree_index]))
+    }
+
+    // Returns the roots of every slot of the compound layer, in order.
+    fn layer_roots(&self) -> Vec<E> {
+        let mut roots: Vec<E> = match &self.data {
+            Data::TopTree(sub_trees) => sub_trees.iter().map(|mt| mt.root()).collect(),
+            Data::SubTree(trees) => trees.iter().map(|mt| mt.root()).collect(),
+            Data::BaseTree(_) => Vec::new(),
+        };
+        roots.resize(self.layer_arity(), Self::empty_root());
+
+        roots
+    }
+
+    // Returns the number of slots of the compound layer (0 for a base
+    // tree).
+    fn layer_arity(&self) -> usize {
+        self.offsets.len().saturating_sub(1)
+    }
+
+    // Returns the lemma of the compound layer for the tree
+    // `tree_index`: the roots of the other slots followed by the
+    // compound root.
+    fn layer_lemma(&self, tree_index: usize) -> Vec<E> {
+        let mut lemma: Vec<E> = self
+            .layer_roots()
+            .into_iter()
+            .enumerate()
+            .filter(|(k, _)| *k != tree_index)
+            .map(|(_, root)| root)
+            .collect();
+        lemma.push(self.root());
+
+        lemma
+    }
+
     /// Generate merkle sub tree inclusion proof for leaf `i` for
     /// either the top layer or the sub-tree layer, specified by the
     /// top_layer flag
@@ -862,55 +1117,37 @@ impl<
         arity: usize,
     ) -> Result<Proof<E, BaseTreeArity>> {
         ensure!(arity != 0, "Invalid sub-tree arity");
+        ensure!(
+            arity == self.layer_arity(),
+            "Compound layer shape mis-match"
+        );
 
         // Locate the sub-tree the leaf is contained in.
-        let tree_index = i / (self.leafs / arity);
+        let (tree_index, leaf_index) = self.locate_leaf(i)?;
 
         // Generate the sub tree proof at this tree level.
         let sub_tree_proof = if top_layer {
             ensure!(self.data.sub_trees().is_some(), "sub trees required");
             let sub_trees = self.data.sub_trees().unwrap();
-            ensure!(arity == sub_trees.len(), "Top layer tree shape mis-match");
-
-            let tree = &sub_trees[tree_index];
-            let leaf_index = i % tree.leafs();
 
-            tree.gen_proof(leaf_index)
+            sub_trees[tree_index].gen_proof(leaf_index)
         } else {
             ensure!(self.data.base_trees().is_some(), "base trees required");
             let base_trees = self.data.base_trees().unwrap();
-            ensure!(arity == base_trees.len(), "Sub tree layer shape mis-match");
 
-            let tree = &base_trees[tree_index];
-            let leaf_index = i % tree.leafs();
-
-            tree.gen_proof(leaf_index)
+            base_trees[tree_index].gen_proof(leaf_index)
         }?;
 
         // Construct the top layer proof.  'lemma' length is
         // top_layer_nodes - 1 + root == top_layer_nodes
-        let mut path: Vec<usize> = Vec::with_capacity(1); // path - 1
-        let mut lemma: Vec<E> = Vec::with_capacity(arity);
-        for i in 0..arity {
-            if i != tree_index {
-                lemma.push(if top_layer {
-                    ensure!(self.data.sub_trees().is_some(), "sub trees required");
-                    let sub_trees = self.data.sub_trees().unwrap();
-
-                    sub_trees[i].root()
-                } else {
-                    ensure!(self.data.base_trees().is_some(), "base trees required");
-                    let base_trees = self.data.base_trees().unwrap();
+        let lemma = self.layer_lemma(tree_index);
+        let path = vec![tree_index]; // path - 1
 
-                    base_trees[i].root()
-                });
-            }
+        if top_layer {
+            Proof::new::<TopTreeArity, SubTreeArity>(Some(Box::new(sub_tree_proof)), lemma, path)
+        } else {
+            Proof::new_compound(sub_tree_proof, lemma, path, 0, arity)
         }
-
-        lemma.push(self.root());
-        path.push(tree_index);
-
-        Proof::new::<TopTreeArity, SubTreeArity>(Some(Box::new(sub_tree_proof)), lemma, path)
     }
 
     /// Generate merkle tree inclusion proof for leaf `i`
@@ -918,7 +1155,7 @@ impl<
     pub fn gen_proof(&self, i: usize) -> Result<Proof<E, BaseTreeArity>> {
         match &self.data {
             Data::TopTree(_) => self.gen_sub_tree_proof(i, true, TopTreeArity::to_usize()),
-            Data::SubTree(_) => self.gen_sub_tree_proof(i, false, SubTreeArity::to_usize()),
+            Data::SubTree(_) => self.gen_sub_tree_proof(i, false, self.layer_arity()),
             Data::BaseTree(_) => {
                 ensure!(
                     i < self.leafs,
@@ -932,16 +1169,12 @@ impl<
 
                 // level 1 width
                 let mut width = self.leafs;
-                let branches = BaseTreeArity::to_usize();
-                ensure!(width == next_pow2(width), "Must be a power of 2 tree");
-                ensure!(
-                    branches == next_pow2(branches),
-                    "branches must be a power of 2"
-                );
-                let shift = log2_pow2(branches);
+                let mut level = 0;
+                self.policy.ensure_valid_schedule(width, &self.arities)?;
 
-                let mut lemma: Vec<E> =
-                    Vec::with_capacity(get_merkle_proof_lemma_len(self.row_count, branches));
+                let lemma_len =
+                    get_merkle_proof_lemma_len_with_schedule(self.row_count, &self.arities);
+                let mut lemma: Vec<E> = Vec::with_capacity(lemma_len);
                 let mut path: Vec<usize> = Vec::with_capacity(self.row_count - 1); // path - 1
 
                 // item is first
@@ -954,10 +1187,20 @@ impl<
                     "Data slice must not have a top layer"
                 );
 
+                // Number of siblings missing from short groups, which
+                // are either promoted or filled according to the policy.
+                let mut missing_siblings = 0;
+
                 lemma.push(self.read_at(j)?);
                 while base + 1 < self.len() {
+                    let branches = row_arity(&self.arities, level);
+
+                    // The last group of a row may be short with
+                    // policies other than `Reject`.
                     let hash_index = (j / branches) * branches;
-                    for k in hash_index..hash_index + branches {
+                    let hash_end = std::cmp::min(hash_index + branches, width);
+                    missing_siblings += branches - (hash_end - hash_index);
+                    for k in hash_index..hash_end {
                         if k != j {
                             lemma.push(self.read_at(base + k)?)
                         }
@@ -966,8 +1209,9 @@ impl<
                     path.push(j % branches); // path_index
 
                     base += width;
-                    width >>= shift; // width /= branches;
-                    j >>= shift; // j /= branches;
+                    width = self.policy.next_row_width(width, branches);
+                    j /= branches;
+                    level += 1;
                 }
 
                 // root is final
@@ -976,7 +1220,7 @@ impl<
                 // Sanity check: if the `MerkleTree` lost its integrity and `data` doesn't match the
                 // expected values for `leafs` and `row_count` this can get ugly.
                 ensure!(
-                    lemma.len() == get_merkle_proof_lemma_len(self.row_count, branches),
+                    lemma.len() == lemma_len - missing_siblings,
                     "Invalid proof lemma length"
                 );
                 ensure!(
@@ -984,52 +1228,370 @@ impl<
                     "Invalid proof path length"
                 );
 
-                Proof::new::<U0, U0>(None, lemma, path)
+                Proof::new_with_schedule(lemma, path, &self.arities, self.policy, self.leafs)
             }
         }
     }
 
-    /// Generate merkle sub-tree inclusion proof for leaf `i` using
-    /// partial trees built from cached data if needed at that layer.
-    fn gen_cached_top_tree_proof<Arity: Unsigned>(
+    /// Generate merkle tree inclusion proof for all leafs in
+    /// `indices`, including each required node only once.
+    pub fn gen_multi_proof(&self, indices: &[usize]) -> Result<MultiProof<E, BaseTreeArity>> {
+        self.gen_multi_proof_from(indices, false, None)
+    }
+
+    /// Generate merkle tree inclusion proof for all leafs in
+    /// `indices` using partial trees built from cached data if
+    /// needed, building each partial tree only once.
+    pub fn gen_cached_multi_proof(
         &self,
-        i: usize,
+        indices: &[usize],
         rows_to_discard: Option<usize>,
-    ) -> Result<Proof<E, BaseTreeArity>> {
-        ensure!(Arity::to_usize() != 0, "Invalid top-tree arity");
+    ) -> Result<MultiProof<E, BaseTreeArity>> {
+        self.gen_multi_proof_from(indices, true, rows_to_discard)
+    }
+
+    fn gen_multi_proof_from(
+        &self,
+        indices: &[usize],
+        cached: bool,
+        rows_to_discard: Option<usize>,
+    ) -> Result<MultiProof<E, BaseTreeArity>> {
+        let mut indices = indices.to_vec();
+        indices.sort_unstable();
+        indices.dedup();
+        ensure!(!indices.is_empty(), "No leafs to prove");
         ensure!(
-            i < self.leafs,
+            indices[indices.len() - 1] < self.leafs,
             "{} is out of bounds (max: {})",
-            i,
+            indices[indices.len() - 1],
             self.leafs
-        ); // i in [0 .. self.leafs)
-
-        // Locate the sub-tree the leaf is contained in.
-        ensure!(self.data.sub_trees().is_some(), "sub trees required");
-        let trees = &self.data.sub_trees().unwrap();
-        let tree_index = i / (self.leafs / Arity::to_usize());
-        let tree = &trees[tree_index];
-        let tree_leafs = tree.leafs();
+        );
 
-        // Get the leaf index within the sub-tree.
-        let leaf_index = i % tree_leafs;
+        let nodes = self.read_proof_nodes(&indices, cached, rows_to_discard)?;
+        let items = self.read_nodes(0, &indices, cached, rows_to_discard)?;
 
-        // Generate the proof that will validate to the provided
-        // sub-tree root (note the branching factor of B).
-        let sub_tree_proof = tree.gen_cached_proof(leaf_index, rows_to_discard)?;
+        MultiProof::new_with_arity::<TopTreeArity, SubTreeArity>(
+            self.arities[0],
+            self.leafs,
+            self.policy,
+            indices,
+            items,
+            nodes,
+        )
+    }
 
-        // Construct the top layer proof.  'lemma' length is
-        // top_layer_nodes - 1 + root == top_layer_nodes
-        let mut path: Vec<usize> = Vec::with_capacity(1); // path - 1
-        let mut lemma: Vec<E> = Vec::with_capacity(Arity::to_usize());
-        for i in 0..Arity::to_usize() {
-            if i != tree_index {
-                lemma.push(trees[i].root())
-            }
-        }
+    /// Generate merkle tree inclusion proof for the contiguous leaf
+    /// range `start..end`, made of the leafs themselves and only the
+    /// nodes bordering the range.
+    pub fn gen_range_proof(
+        &self,
+        start: usize,
+        end: usize,
+    ) -> Result<RangeProof<E, BaseTreeArity>> {
+        self.gen_range_proof_from(start, end, false, None)
+    }
 
-        lemma.push(self.root());
-        path.push(tree_index);
+    /// Generate merkle tree inclusion proof for the contiguous leaf
+    /// range `start..end` using partial trees built from cached data
+    /// if needed, building each partial tree only once.
+    pub fn gen_cached_range_proof(
+        &self,
+        start: usize,
+        end: usize,
+        rows_to_discard: Option<usize>,
+    ) -> Result<RangeProof<E, BaseTreeArity>> {
+        self.gen_range_proof_from(start, end, true, rows_to_discard)
+    }
+
+    fn gen_range_proof_from(
+        &self,
+        start: usize,
+        end: usize,
+        cached: bool,
+        rows_to_discard: Option<usize>,
+    ) -> Result<RangeProof<E, BaseTreeArity>> {
+        ensure!(start < end, "Invalid empty range {}..{}", start, end);
+        ensure!(
+            end <= self.leafs,
+            "{} is out of bounds (max: {})",
+            end,
+            self.leafs
+        );
+
+        let indices: Vec<usize> = (start..end).collect();
+        let nodes = self.read_proof_nodes(&indices, cached, rows_to_discard)?;
+        let items = self.read_nodes(0, &indices, cached, rows_to_discard)?;
+
+        RangeProof::new_with_arity::<TopTreeArity, SubTreeArity>(
+            self.arities[0],
+            self.leafs,
+            self.policy,
+            start,
+            items,
+            nodes,
+        )
+    }
+
+    // Reads, row by row, the nodes needed (besides the leafs
+    // themselves) to rebuild the root from the sorted leaf `indices`.
+    fn read_proof_nodes(
+        &self,
+        indices: &[usize],
+        cached: bool,
+        rows_to_discard: Option<usize>,
+    ) -> Result<Vec<E>> {
+        // The compound rows are derived from the arities of the type.
+        let typed_arities: Vec<usize> = vec![TopTreeArity::to_usize(), SubTreeArity::to_usize()]
+            .into_iter()
+            .filter(|arity| *arity > 0)
+            .collect();
+        ensure!(
+            self.layer_arities() == typed_arities,
+            "Multi proofs require the layer arities to be part of the tree type"
+        );
+        ensure!(
+            self.has_equal_sub_trees(),
+            "Multi proofs require compound layers of equal sub-trees"
+        );
+        ensure!(
+            self.arities.len() == 1,
+            "Multi proofs require base trees of a single arity"
+        );
+
+        let rows = MultiProof::<E, BaseTreeArity>::get_rows(
+            self.leafs,
+            self.policy,
+            self.arities[0],
+            SubTreeArity::to_usize(),
+            TopTreeArity::to_usize(),
+        )?;
+        ensure!(rows.len() + 1 == self.row_count, "Inconsistent row_count");
+
+        let mut nodes = Vec::new();
+        let mut known = indices.to_vec();
+        for (level, row) in rows.iter().enumerate() {
+            let mut siblings = Vec::new();
+            let mut parents = Vec::new();
+            let mut known_index = 0;
+            for (group, parent) in row.groups(&known) {
+                for k in group {
+                    if known_index < known.len() && known[known_index] == k {
+                        known_index += 1;
+                    } else {
+                        siblings.push(k);
+                    }
+                }
+                parents.push(parent);
+            }
+
+            nodes.extend(self.read_nodes(level, &siblings, cached, rows_to_discard)?);
+            known = parents;
+        }
+
+        Ok(nodes)
+    }
+
+    // Returns the width of row `level` of this (possibly compound) tree.
+    fn row_width(&self, level: usize) -> usize {
+        match &self.data {
+            Data::TopTree(sub_trees) if level + 1 < self.row_count => {
+                sub_trees.len() * sub_trees[0].row_width(level)
+            }
+            Data::SubTree(trees) if level + 1 < self.row_count => {
+                trees.len() * trees[0].row_width(level)
+            }
+            Data::TopTree(_) | Data::SubTree(_) => 1,
+            Data::BaseTree(_) => (0..level).fold(self.leafs, |width, row| {
+                self.policy
+                    .next_row_width(width, row_arity(&self.arities, row))
+            }),
+        }
+    }
+
+    // Reads the (sorted) nodes `indices` of row `level` of this
+    // (possibly compound) tree.  If `cached` is set, nodes of
+    // discarded rows are read from partial trees built once per
+    // segment of the base data.
+    fn read_nodes(
+        &self,
+        level: usize,
+        indices: &[usize],
+        cached: bool,
+        rows_to_discard: Option<usize>,
+    ) -> Result<Vec<E>> {
+        if level + 1 == self.row_count {
+            ensure!(indices.iter().all(|j| *j == 0), "Invalid root index");
+            return Ok(vec![self.root(); indices.len()]);
+        }
+
+        let mut nodes = Vec::with_capacity(indices.len());
+        match &self.data {
+            Data::TopTree(sub_trees) => {
+                let width = sub_trees[0].row_width(level);
+                for (tree, group) in group_by_tree(indices, width) {
+                    nodes.extend(sub_trees[tree].read_nodes(
+                        level,
+                        &group,
+                        cached,
+                        rows_to_discard,
+                    )?);
+                }
+            }
+            Data::SubTree(trees) => {
+                let width = trees[0].row_width(level);
+                for (tree, group) in group_by_tree(indices, width) {
+                    nodes.extend(trees[tree].read_nodes(level, &group, cached, rows_to_discard)?);
+                }
+            }
+            Data::BaseTree(store) => {
+                let (cached_rows, cached_leafs, partial_row_count) = if cached {
+                    self.get_cached_tree_shape(rows_to_discard)?
+                } else {
+                    (0, self.leafs, 1)
+                };
+
+                if level + 1 >= partial_row_count {
+                    // Runs of adjacent nodes are read with a single read_range.
+                    let row_start: usize = (0..level).map(|l| self.row_width(l)).sum();
+                    let mut i = 0;
+                    while i < indices.len() {
+                        let mut end = i + 1;
+                        while end < indices.len() && indices[end] == indices[end - 1] + 1 {
+                            end += 1;
+                        }
+                        nodes.extend(store.read_range(
+                            row_start + indices[i]..row_start + indices[end - 1] + 1,
+                        )?);
+                        i = end;
+                    }
+                } else {
+                    debug!(
+                        "reading {} nodes of row {} from partial trees (rows_to_discard {})",
+                        indices.len(),
+                        level,
+                        cached_rows
+                    );
+
+                    let segment_width = self.leafs / cached_leafs;
+                    let width = self.row_width(level) / cached_leafs;
+                    for (segment, group) in group_by_tree(indices, width) {
+                        let partial_tree = self.build_segment_tree(
+                            segment * segment_width,
+                            segment_width,
+                            partial_row_count,
+                        )?;
+                        nodes.extend(partial_tree.read_nodes(level, &group, false, None)?);
+                    }
+                }
+            }
+        }
+
+        Ok(nodes)
+    }
+
+    /// Generate an RFC 6962 consistency proof showing that the tree
+    /// built over the first `old_size` leafs is a prefix of this tree.
+    pub fn gen_consistency_proof(&self, old_size: usize) -> Result<ConsistencyProof<E>> {
+        ensure!(
+            self.data.store().is_some(),
+            "Consistency proofs require a base tree"
+        );
+        ensure!(
+            self.arities == [2],
+            "Consistency proofs require a binary tree"
+        );
+        ensure!(
+            old_size > 0 && old_size <= self.leafs,
+            "{} is not a valid old tree size (max: {})",
+            old_size,
+            self.leafs
+        );
+
+        let mut nodes = Vec::new();
+        self.gen_consistency_sub_proof(old_size, 0, self.leafs, true, &mut nodes)?;
+
+        Ok(ConsistencyProof::new(old_size, self.leafs, nodes))
+    }
+
+    // RFC 6962 SUBPROOF(m, D[start:end], complete).
+    fn gen_consistency_sub_proof(
+        &self,
+        m: usize,
+        start: usize,
+        end: usize,
+        complete: bool,
+        nodes: &mut Vec<E>,
+    ) -> Result<()> {
+        let n = end - start;
+        if m == n {
+            if !complete {
+                nodes.push(self.read_range_root(start, end)?);
+            }
+
+            return Ok(());
+        }
+
+        // k is the largest power of two smaller than n.
+        let k = next_pow2(n) >> 1;
+        if m <= k {
+            self.gen_consistency_sub_proof(m, start, start + k, complete, nodes)?;
+            nodes.push(self.read_range_root(start + k, end)?);
+        } else {
+            self.gen_consistency_sub_proof(m - k, start + k, end, false, nodes)?;
+            nodes.push(self.read_range_root(start, start + k)?);
+        }
+
+        Ok(())
+    }
+
+    // Returns the stored root of the leafs in `start..end`, which
+    // must exactly cover a single node of the binary base tree.
+    fn read_range_root(&self, start: usize, end: usize) -> Result<E> {
+        let height = log2_pow2(next_pow2(end - start));
+        ensure!(
+            start % (1 << height) == 0 && (end - start == 1 << height || end == self.leafs),
+            "Range {}..{} is not covered by a single node",
+            start,
+            end
+        );
+
+        let mut base = 0;
+        let mut width = self.leafs;
+        for _ in 0..height {
+            base += width;
+            width = self.policy.next_row_width(width, 2);
+        }
+
+        self.read_at(base + (start >> height))
+    }
+
+    /// Generate merkle sub-tree inclusion proof for leaf `i` using
+    /// partial trees built from cached data if needed at that layer.
+    fn gen_cached_top_tree_proof<Arity: Unsigned>(
+        &self,
+        i: usize,
+        rows_to_discard: Option<usize>,
+    ) -> Result<Proof<E, BaseTreeArity>> {
+        ensure!(Arity::to_usize() != 0, "Invalid top-tree arity");
+        ensure!(
+            Arity::to_usize() == self.layer_arity(),
+            "Top layer tree shape mis-match"
+        );
+
+        // Locate the sub-tree the leaf is contained in, and the leaf
+        // index within the sub-tree.
+        ensure!(self.data.sub_trees().is_some(), "sub trees required");
+        let trees = &self.data.sub_trees().unwrap();
+        let (tree_index, leaf_index) = self.locate_leaf(i)?;
+
+        // Generate the proof that will validate to the provided
+        // sub-tree root (note the branching factor of B).
+        let sub_tree_proof = trees[tree_index].gen_cached_proof(leaf_index, rows_to_discard)?;
+
+        // Construct the top layer proof.  'lemma' length is
+        // top_layer_nodes - 1 + root == top_layer_nodes
+        let lemma = self.layer_lemma(tree_index);
+        let path = vec![tree_index]; // path - 1
 
         // Generate the final compound tree proof which is composed of
         // a sub-tree proof of branching factor B and a top-level
@@ -1039,50 +1601,38 @@ impl<
 
     /// Generate merkle sub-tree inclusion proof for leaf `i` using
     /// partial trees built from cached data if needed at that layer.
-    fn gen_cached_sub_tree_proof<Arity: Unsigned>(
+    fn gen_cached_sub_tree_proof(
         &self,
         i: usize,
+        arity: usize,
         rows_to_discard: Option<usize>,
     ) -> Result<Proof<E, BaseTreeArity>> {
-        ensure!(Arity::to_usize() != 0, "Invalid sub-tree arity");
+        ensure!(arity != 0, "Invalid sub-tree arity");
         ensure!(
-            i < self.leafs,
-            "{} is out of bounds (max: {})",
-            i,
-            self.leafs
-        ); // i in [0 .. self.leafs)
+            arity == self.layer_arity(),
+            "Sub tree layer shape mis-match"
+        );
 
-        // Locate the sub-tree the leaf is contained in.
+        // Locate the sub-tree the leaf is contained in, and the leaf
+        // index within the sub-tree.
         ensure!(self.data.base_trees().is_some(), "base trees required");
         let trees = &self.data.base_trees().unwrap();
-        let tree_index = i / (self.leafs / Arity::to_usize());
-        let tree = &trees[tree_index];
-        let tree_leafs = tree.leafs();
-
-        // Get the leaf index within the sub-tree.
-        let leaf_index = i % tree_leafs;
+        let (tree_index, leaf_index) = self.locate_leaf(i)?;
 
         // Generate the proof that will validate to the provided
         // sub-tree root (note the branching factor of B).
-        let sub_tree_proof = tree.gen_cached_proof(leaf_index, rows_to_discard)?;
+        let sub_tree_proof = trees[tree_index].gen_cached_proof(leaf_index, rows_to_discard)?;
 
         // Construct the top layer proof.  'lemma' length is
         // top_layer_nodes - 1 + root == top_layer_nodes
-        let mut path: Vec<usize> = Vec::with_capacity(1); // path - 1
-        let mut lemma: Vec<E> = Vec::with_capacity(Arity::to_usize());
-        for i in 0..Arity::to_usize() {
-            if i != tree_index {
-                lemma.push(trees[i].root())
-            }
-        }
-
-        lemma.push(self.root());
-        path.push(tree_index);
+        let lemma = self.layer_lemma(tree_index);
+        let path = vec![tree_index]; // path - 1
 
         // Generate the final compound tree proof which is composed of
-        // a sub-tree proof of branching factor B and a top-level
-        // proof with a branching factor of SubTreeArity.
-        Proof::new::<TopTreeArity, SubTreeArity>(Some(Box::new(sub_tree_proof)), lemma, path)
+        // a sub-tree proof of branching factor B (or a proof of the
+        // sub-tree layer below) and a proof with a branching factor of
+        // the number of trees.
+        Proof::new_compound(sub_tree_proof, lemma, path, 0, arity)
     }
 
     /// Generate merkle tree inclusion proof for leaf `i` by first
@@ -1101,7 +1651,9 @@ impl<
     ) -> Result<Proof<E, BaseTreeArity>> {
         match &self.data {
             Data::TopTree(_) => self.gen_cached_top_tree_proof::<TopTreeArity>(i, rows_to_discard),
-            Data::SubTree(_) => self.gen_cached_sub_tree_proof::<SubTreeArity>(i, rows_to_discard),
+            Data::SubTree(_) => {
+                self.gen_cached_sub_tree_proof(i, self.layer_arity(), rows_to_discard)
+            }
             Data::BaseTree(_) => {
                 ensure!(
                     i < self.leafs,
@@ -1110,41 +1662,8 @@ impl<
                     self.leafs
                 ); // i in [0 .. self.leafs]
 
-                // For partial tree building, the data layer width must be a
-                // power of 2.
-                ensure!(
-                    self.leafs == next_pow2(self.leafs),
-                    "The size of the data layer must be a power of 2"
-                );
-
-                let branches = BaseTreeArity::to_usize();
-                let total_size = get_merkle_tree_len(self.leafs, branches)?;
-                // If rows to discard is specified and we *know* it's a value that will cause an error
-                // (i.e. there are not enough rows to discard, we use a sane default instead).  This
-                // primarily affects tests because it only affects 'small' trees, entirely outside the
-                // scope of any 'production' tree width.
-                let rows_to_discard = if let Some(rows) = rows_to_discard {
-                    std::cmp::min(
-                        rows,
-                        StoreConfig::default_rows_to_discard(self.leafs, branches),
-                    )
-                } else {
-                    StoreConfig::default_rows_to_discard(self.leafs, branches)
-                };
-                let cache_size = get_merkle_tree_cache_size(self.leafs, branches, rows_to_discard)?;
-                ensure!(
-                    cache_size < total_size,
-                    "Generate a partial proof with all data available?"
-                );
-
-                let cached_leafs = get_merkle_tree_leafs(cache_size, branches)?;
-                ensure!(
-                    cached_leafs == next_pow2(cached_leafs),
-                    "The size of the cached leafs must be a power of 2"
-                );
-
-                let cache_row_count = get_merkle_tree_row_count(cached_leafs, branches);
-                let partial_row_count = self.row_count - cache_row_count + 1;
+                let (rows_to_discard, cached_leafs, partial_row_count) =
+                    self.get_cached_tree_shape(rows_to_discard)?;
 
                 // Calculate the subset of the base layer data width that we
                 // need in order to build the partial tree required to build
@@ -1154,51 +1673,24 @@ impl<
                 let segment_start = (i / segment_width) * segment_width;
                 let segment_end = segment_start + segment_width;
 
-                debug!("leafs {}, branches {}, total size {}, total row_count {}, cache_size {}, rows_to_discard {}, \
+                debug!("leafs {}, branches {:?}, total row_count {}, rows_to_discard {}, \
                         partial_row_count {}, cached_leafs {}, segment_width {}, segment range {}-{} for {}",
-                       self.leafs, branches, total_size, self.row_count, cache_size, rows_to_discard, partial_row_count,
+                       self.leafs, self.arities, self.row_count, rows_to_discard, partial_row_count,
                        cached_leafs, segment_width, segment_start, segment_end, i);
 
-                // Copy the proper segment of the base data into memory and
-                // initialize a VecStore to back a new, smaller MT.
-                let mut data_copy = vec![0; segment_width * E::byte_len()];
-                ensure!(self.data.store().is_some(), "store data required");
-
-                self.data.store().unwrap().read_range_into(
-                    segment_start,
-                    segment_end,
-                    &mut data_copy,
-                )?;
-                let partial_store = VecStore::new_from_slice(segment_width, &data_copy)?;
-                ensure!(
-                    Store::len(&partial_store) == segment_width,
-                    "Inconsistent store length"
-                );
-
-                // Before building the tree, resize the store where the tree
-                // will be built to allow space for the newly constructed layers.
-                data_copy.resize(
-                    get_merkle_tree_len(segment_width, branches)? * E::byte_len(),
-                    0,
-                );
-
                 // Build the optimally small tree.
-                let partial_tree: MerkleTree<E, A, VecStore<E>, BaseTreeArity> =
-                    Self::build_partial_tree(partial_store, segment_width, partial_row_count)?;
-                ensure!(
-                    partial_row_count == partial_tree.row_count(),
-                    "Inconsistent partial tree row_count"
-                );
+                let partial_tree =
+                    self.build_segment_tree(segment_start, segment_width, partial_row_count)?;
 
                 // Generate entire proof with access to the base data, the
                 // cached data, and the partial tree.
                 let proof = self.gen_proof_with_partial_tree(i, rows_to_discard, &partial_tree)?;
 
                 debug!(
-                    "generated partial_tree of row_count {} and len {} with {} branches for proof at {}",
+                    "generated partial_tree of row_count {} and len {} with {:?} branches for proof at {}",
                     partial_tree.row_count,
                     partial_tree.len(),
-                    branches,
+                    self.arities,
                     i
                 );
 
@@ -1207,6 +1699,88 @@ impl<
         }
     }
 
+    // Returns the rows to discard (adjusted to the tree size), the
+    // number of cached leafs and the partial tree row count for
+    // base trees stored with discarded rows.
+    fn get_cached_tree_shape(
+        &self,
+        rows_to_discard: Option<usize>,
+    ) -> Result<(usize, usize, usize)> {
+        // For partial tree building, the rows of the tree must be
+        // made of full groups.
+        let arities = &self.arities;
+        ensure!(
+            is_merkle_tree_size_valid_with_schedule(self.leafs, arities, OddRowPolicy::Reject),
+            "The size of the data layer must be a power of the arity"
+        );
+
+        let total_size =
+            get_merkle_tree_len_with_schedule(self.leafs, arities, OddRowPolicy::Reject)?;
+        // If rows to discard is specified and we *know* it's a value that will cause an error
+        // (i.e. there are not enough rows to discard), we discard as many rows as the tree
+        // allows instead.  This primarily affects tests because it only affects 'small' trees,
+        // entirely outside the scope of any 'production' tree width.  Note that this must not
+        // be the (per-arity) default, which can be less than the rows the store discarded.
+        let rows_to_discard = if let Some(rows) = rows_to_discard {
+            // The base and the root rows are never discarded.
+            std::cmp::min(rows, self.row_count.saturating_sub(2))
+        } else {
+            StoreConfig::default_rows_to_discard_with_schedule(self.leafs, arities)
+        };
+        let cache_size =
+            get_merkle_tree_cache_size_with_schedule(self.leafs, arities, rows_to_discard)?;
+        ensure!(
+            cache_size < total_size,
+            "Generate a partial proof with all data available?"
+        );
+
+        // The first cached row is the one above the discarded rows.
+        let cached_leafs =
+            (0..=rows_to_discard).fold(self.leafs, |width, row| width / row_arity(arities, row));
+
+        let partial_row_count = rows_to_discard + 2;
+
+        Ok((rows_to_discard, cached_leafs, partial_row_count))
+    }
+
+    // Builds the partial tree over the base data segment starting at
+    // `segment_start`, for rows that are otherwise unavailable.
+    fn build_segment_tree(
+        &self,
+        segment_start: usize,
+        segment_width: usize,
+        partial_row_count: usize,
+    ) -> Result<MerkleTree<E, A, VecStore<E>, BaseTreeArity>> {
+        // Copy the proper segment of the base data into memory and
+        // initialize a VecStore to back a new, smaller MT.
+        let mut data_copy = vec![0; segment_width * E::byte_len()];
+        ensure!(self.data.store().is_some(), "store data required");
+
+        self.data.store().unwrap().read_range_into(
+            segment_start,
+            segment_start + segment_width,
+            &mut data_copy,
+        )?;
+        let partial_store = VecStore::new_from_slice(segment_width, &data_copy)?;
+        ensure!(
+            Store::len(&partial_store) == segment_width,
+            "Inconsistent store length"
+        );
+
+        let partial_tree: MerkleTree<E, A, VecStore<E>, BaseTreeArity> = Self::build_partial_tree(
+            partial_store,
+            segment_width,
+            partial_row_count,
+            &self.arities,
+        )?;
+        ensure!(
+            partial_row_count == partial_tree.row_count(),
+            "Inconsistent partial tree row_count"
+        );
+
+        Ok(partial_tree)
+    }
+
     /// Generate merkle tree inclusion proof for leaf `i` given a
     /// partial tree for lookups where data is otherwise unavailable.
     fn gen_proof_with_partial_tree(
@@ -1222,25 +1796,23 @@ impl<
             self.leafs
         ); // i in [0 .. self.leafs)
 
-        // For partial tree building, the data layer width must be a
-        // power of 2.
+        // For partial tree building, the rows of the tree must be
+        // made of full groups.
         let mut width = self.leafs;
-        let branches = BaseTreeArity::to_usize();
-        ensure!(width == next_pow2(width), "Must be a power of 2 tree");
+        let arities = &self.arities;
         ensure!(
-            branches == next_pow2(branches),
-            "branches must be a power of 2"
+            is_merkle_tree_size_valid_with_schedule(width, arities, OddRowPolicy::Reject),
+            "Must be a power of the arity tree"
         );
 
         let data_width = width;
-        let total_size = get_merkle_tree_len(data_width, branches)?;
-        let cache_size = get_merkle_tree_cache_size(self.leafs, branches, rows_to_discard)?;
+        let total_size =
+            get_merkle_tree_len_with_schedule(data_width, arities, OddRowPolicy::Reject)?;
+        let cache_size =
+            get_merkle_tree_cache_size_with_schedule(self.leafs, arities, rows_to_discard)?;
         let cache_index_start = total_size - cache_size;
-        let cached_leafs = get_merkle_tree_leafs(cache_size, branches)?;
-        ensure!(
-            cached_leafs == next_pow2(cached_leafs),
-            "Cached leafs size must be a power of 2"
-        );
+        let cached_leafs =
+            (0..=rows_to_discard).fold(self.leafs, |width, row| width / row_arity(arities, row));
 
         // Calculate the subset of the data layer width that we need
         // in order to build the partial tree required to build the
@@ -1248,18 +1820,14 @@ impl<
         let mut segment_width = width / cached_leafs;
         let segment_start = (i / segment_width) * segment_width;
 
-        // shift is the amount that we need to decrease the width by
-        // the number of branches at each level up the main merkle
-        // tree.
-        let shift = log2_pow2(branches);
-
         // segment_shift is the amount that we need to offset the
         // partial tree offsets to keep them within the space of the
         // partial tree as we move up it.
         //
-        // segment_shift is conceptually (segment_start >>
-        // (current_row_count * shift)), which tracks an offset in the
-        // main merkle tree that we apply to the partial tree.
+        // segment_shift is conceptually (segment_start / (product of
+        // the arities of the rows below the current one)), which
+        // tracks an offset in the main merkle tree that we apply to
+        // the partial tree.
         let mut segment_shift = segment_start;
 
         // 'j' is used to track the challenged nodes required for the
@@ -1275,8 +1843,12 @@ impl<
         // that we're currently processing in the partial tree.
         let mut partial_base = 0;
 
-        let mut lemma: Vec<E> =
-            Vec::with_capacity(get_merkle_proof_lemma_len(self.row_count, branches));
+        // 'level' is the row of the main merkle tree that we're
+        // currently processing, which determines its arity.
+        let mut level = 0;
+
+        let lemma_len = get_merkle_proof_lemma_len_with_schedule(self.row_count, arities);
+        let mut lemma: Vec<E> = Vec::with_capacity(lemma_len);
         let mut path: Vec<usize> = Vec::with_capacity(self.row_count - 1); // path - 1
 
         ensure!(
@@ -1290,6 +1862,8 @@ impl<
 
         lemma.push(self.read_at(j)?);
         while base + 1 < self.len() {
+            let branches = row_arity(arities, level);
+
             let hash_index = (j / branches) * branches;
             for k in hash_index..hash_index + branches {
                 if k != j {
@@ -1308,14 +1882,16 @@ impl<
             path.push(j % branches); // path_index
 
             base += width;
-            width >>= shift; // width /= branches
+            width = div_arity(width, branches); // width /= branches
 
             partial_base += segment_width;
-            segment_width >>= shift; // segment_width /= branches
+            segment_width = div_arity(segment_width, branches); // segment_width /= branches
+
+            segment_shift = div_arity(segment_shift, branches); // segment_shift /= branches
 
-            segment_shift >>= shift; // segment_shift /= branches
+            j = div_arity(j, branches); // j /= branches;
 
-            j >>= shift; // j /= branches;
+            level += 1;
         }
 
         // root is final
@@ -1323,16 +1899,146 @@ impl<
 
         // Sanity check: if the `MerkleTree` lost its integrity and `data` doesn't match the
         // expected values for `leafs` and `row_count` this can get ugly.
-        ensure!(
-            lemma.len() == get_merkle_proof_lemma_len(self.row_count, branches),
-            "Invalid proof lemma length"
-        );
+        ensure!(lemma.len() == lemma_len, "Invalid proof lemma length");
         ensure!(
             path.len() == self.row_count - 1,
             "Invalid proof path length"
         );
 
-        Proof::new::<U0, U0>(None, lemma, path)
+        Proof::new_with_schedule(lemma, path, arities, OddRowPolicy::Reject, 0)
+    }
+
+    /// Replaces leaf `i` with `leaf` and recomputes its ancestors.
+    ///
+    /// Note that `leaf` is stored as is, so it must be hashed the
+    /// same way as the leafs returned by `read_at`.
+    pub fn update_leaf(&mut self, i: usize, leaf: E) -> Result<()> {
+        self.update_leaves(&[(i, leaf)])
+    }
+
+    /// Replaces the specified leafs and recomputes their ancestors,
+    /// hashing each ancestor only once (and each row in parallel).
+    pub fn update_leaves(&mut self, leaves: &[(usize, E)]) -> Result<()> {
+        ensure!(
+            self.data.store().is_some(),
+            "Leaf updates require a base tree"
+        );
+        ensure!(
+            leaves.iter().all(|(i, _)| *i < self.leafs),
+            "Leaf index out of bounds (max: {})",
+            self.leafs
+        );
+
+        let store = self.data.store_mut().unwrap();
+        for (i, leaf) in leaves {
+            store.write_at(leaf.clone(), *i)?;
+        }
+
+        let mut dirty: Vec<usize> = leaves.iter().map(|(i, _)| *i).collect();
+        dirty.sort_unstable();
+        dirty.dedup();
+
+        self.update_rows(dirty, 0, 0, self.leafs)
+    }
+
+    /// Recomputes the cached rows of a base tree stored with
+    /// discarded rows (i.e. a `LevelCacheStore`), after the base data
+    /// of the specified leafs was updated through its `ExternalReader`.
+    pub fn update_cached_leaves(
+        &mut self,
+        indices: &[usize],
+        rows_to_discard: Option<usize>,
+    ) -> Result<()> {
+        ensure!(
+            self.data.store().is_some(),
+            "Leaf updates require a base tree"
+        );
+        ensure!(
+            indices.iter().all(|i| *i < self.leafs),
+            "Leaf index out of bounds (max: {})",
+            self.leafs
+        );
+
+        let arities = &self.arities;
+        let (rows_to_discard, cached_leafs, partial_row_count) =
+            self.get_cached_tree_shape(rows_to_discard)?;
+        let cache_size =
+            get_merkle_tree_cache_size_with_schedule(self.leafs, arities, rows_to_discard)?;
+        let cache_start =
+            get_merkle_tree_len_with_schedule(self.leafs, arities, OddRowPolicy::Reject)?
+                - cache_size;
+
+        // Each segment of the base data is rebuilt once, providing
+        // the updated node of the first cached row.
+        let segment_width = self.leafs / cached_leafs;
+        let mut segments: Vec<usize> = indices.iter().map(|i| i / segment_width).collect();
+        segments.sort_unstable();
+        segments.dedup();
+
+        let roots = segments
+            .iter()
+            .map(|segment| {
+                self.build_segment_tree(segment * segment_width, segment_width, partial_row_count)
+                    .map(|tree| tree.root())
+            })
+            .collect::<Result<Vec<E>>>()?;
+
+        let store = self.data.store_mut().unwrap();
+        for (segment, root) in segments.iter().zip(roots) {
+            store.write_at(root, cache_start + segment)?;
+        }
+
+        self.update_rows(segments, partial_row_count - 1, cache_start, cached_leafs)
+    }
+
+    // Recomputes the parents of the `dirty` (sorted) nodes of the row
+    // at `level`, starting at `row_start`, up to the root.
+    fn update_rows(
+        &mut self,
+        dirty: Vec<usize>,
+        level: usize,
+        row_start: usize,
+        width: usize,
+    ) -> Result<()> {
+        let policy = self.policy;
+
+        let mut dirty = dirty;
+        let mut level = level;
+        let mut row_start = row_start;
+        let mut width = width;
+        while width > 1 {
+            let branches = row_arity(&self.arities, level);
+            let mut parents: Vec<usize> = dirty.iter().map(|j| j / branches).collect();
+            parents.dedup();
+
+            let store = self.data.store().unwrap();
+            let nodes = parents
+                .par_iter()
+                .map(|parent| {
+                    let start = row_start + parent * branches;
+                    let end = row_start + std::cmp::min((parent + 1) * branches, width);
+                    let nodes = store.read_range(start..end)?;
+
+                    Ok(policy.hash_nodes::<E, A>(&nodes, branches, level))
+                })
+                .collect::<Result<Vec<E>>>()?;
+
+            row_start += width;
+            let store = self.data.store_mut().unwrap();
+            for (parent, node) in parents.iter().zip(nodes) {
+                store.write_at(node, row_start + parent)?;
+            }
+
+            dirty = parents;
+            width = policy.next_row_width(width, branches);
+            level += 1;
+        }
+
+        let store = self.data.store().unwrap();
+        store.sync()?;
+        self.root = store.read_at(row_start)?;
+
+        Ok(())
     }
 
     /// Returns merkle root
@@ -1341,6 +2047,22 @@ impl<
         self.root.clone()
     }
 
+    /// Returns the arity of the base tree(s), which is the arity of
+    /// their leaf row if they were built with an arity schedule.
+    #[inline]
+    pub fn arity(&self) -> usize {
+        self.arities[0]
+    }
+
+    /// Returns the arity schedule of the base tree(s), from the leaf
+    /// row up, whose last arity applies to all the rows above it (see
+    /// `row_arity`).  This is the single arity of the tree unless it
+    /// was built with an arity schedule.
+    #[inline]
+    pub fn arity_schedule(&self) -> &[usize] {
+        &self.arities
+    }
+
     /// Returns number of elements in the tree.
     #[inline]
     pub fn len(&self) -> usize {
@@ -1355,13 +2077,13 @@ impl<
     /// interface.
     #[inline]
     pub fn compact(&mut self, config: StoreConfig, store_version: u32) -> Result<bool> {
-        let branches = BaseTreeArity::to_usize();
+        let arities = self.arities.clone();
         ensure!(self.data.store_mut().is_some(), "store data required");
 
         self.data
             .store_mut()
             .unwrap()
-            .compact(branches, config, store_version)
+            .compact_with_schedule(&arities, config, store_version)
     }
 
     #[inline]
@@ -1399,49 +2121,137 @@ impl<
         self.leafs
     }
 
-    /// Returns data reference
+    /// Returns the odd row policy the base tree(s) were built with.
     #[inline]
-    pub fn data(&self) -> Option<&S> {
+    pub fn policy(&self) -> OddRowPolicy {
+        self.policy
+    }
+
+    /// Returns the arity of each compound layer of this tree, from the
+    /// top layer down.  Empty for a base tree.
+    pub fn layer_arities(&self) -> Vec<usize> {
+        let mut arities = match &self.data {
+            Data::TopTree(sub_trees) => sub_trees[0].layer_arities(),
+            Data::SubTree(trees) => trees[0].layer_arities(),
+            Data::BaseTree(_) => return Vec::new(),
+        };
+        arities.insert(0, self.layer_arity());
+
+        arities
+    }
+
+    /// Returns the index of the first leaf of each slot of the top
+    /// compound layer, followed by the number of leafs.  Slots left
+    /// over by fewer trees than the layer arity are empty.  Empty for
+    /// a base tree.
+    pub fn leaf_offsets(&self) -> &[usize] {
+        &self.offsets
+    }
+
+    // Returns true if every compound layer of this tree is full, with
+    // trees holding the same number of leafs.
+    fn has_equal_sub_trees(&self) -> bool {
         match &self.data {
-            Data::TopTree(_) => None,
-            Data::SubTree(_) => None,
-            Data::BaseTree(store) => Some(store),
+            Data::TopTree(sub_trees) => {
+                sub_trees.len() == self.layer_arity()
+                    && sub_trees
+                        .iter()
+                        .all(|mt| mt.leafs() == sub_trees[0].leafs() && mt.has_equal_sub_trees())
+            }
+            Data::SubTree(trees) => {
+                trees.len() == self.layer_arity()
+                    && trees
+                        .iter()
+                        .all(|mt| mt.leafs() == trees[0].leafs() && mt.has_equal_sub_trees())
+            }
+            Data::BaseTree(_) => true,
         }
     }
 
-    /// Returns merkle leaf at index i
-    #[inline]
-    pub fn read_at(&self, i: usize) -> Result<E> {
+    /// Returns true if the base tree(s) were built with
+    /// `OddRowPolicy::Duplicate` and a full group of siblings in any
+    /// row ends with two equal nodes.  The root of such a tree is also
+    /// the root of a tree built over fewer leafs (CVE-2012-2459), so
+    /// it must not be trusted to commit to its leafs.
+    pub fn is_mutated(&self) -> Result<bool> {
         match &self.data {
             Data::TopTree(sub_trees) => {
-                // Locate the top-layer tree the sub-tree leaf is contained in.
-                ensure!(
-                    TopTreeArity::to_usize() == sub_trees.len(),
-                    "Top layer tree shape mis-match"
-                );
-                let tree_index = i / (self.leafs / TopTreeArity::to_usize());
-                let tree = &sub_trees[tree_index];
-                let tree_leafs = tree.leafs();
+                for tree in sub_trees {
+                    if tree.is_mutated()? {
+                        return Ok(true);
+                    }
+                }
 
-                // Get the leaf index within the sub-tree.
-                let leaf_index = i % tree_leafs;
+                Ok(false)
+            }
+            Data::SubTree(trees) => {
+                for tree in trees {
+                    if tree.is_mutated()? {
+                        return Ok(true);
+                    }
+                }
 
-                tree.read_at(leaf_index)
+                Ok(false)
             }
-            Data::SubTree(base_trees) => {
-                // Locate the sub-tree layer tree the base leaf is contained in.
-                ensure!(
-                    SubTreeArity::to_usize() == base_trees.len(),
-                    "Sub-tree shape mis-match"
-                );
-                let tree_index = i / (self.leafs / SubTreeArity::to_usize());
-                let tree = &base_trees[tree_index];
-                let tree_leafs = tree.leafs();
+            Data::BaseTree(store) => {
+                if self.policy != OddRowPolicy::Duplicate {
+                    return Ok(false);
+                }
+
+                let mut row_start = 0;
+                let mut width = self.leafs;
+                let mut level = 0;
+                while width > 1 {
+                    let branches = row_arity(&self.arities, level);
+                    let chunk_nodes = build_chunk_nodes(branches);
+                    for start in (0..width).step_by(chunk_nodes) {
+                        let end = std::cmp::min(start + chunk_nodes, width);
+                        let nodes = store.read_range(row_start + start..row_start + end)?;
+                        if nodes
+                            .chunks(branches)
+                            .any(|group| self.policy.is_mutated(group, branches))
+                        {
+                            return Ok(true);
+                        }
+                    }
 
-                // Get the leaf index within the sub-tree.
-                let leaf_index = i % tree_leafs;
+                    row_start += width;
+                    width = self.policy.next_row_width(width, branches);
+                    level += 1;
+                }
 
-                tree.read_at(leaf_index)
+                Ok(false)
+            }
+        }
+    }
+
+    /// Returns data reference
+    #[inline]
+    pub fn data(&self) -> Option<&S> {
+        match &self.data {
+            Data::TopTree(_) => None,
+            Data::SubTree(_) => None,
+            Data::BaseTree(store) => Some(store),
+        }
+    }
+
+    /// Returns merkle leaf at index i
+    #[inline]
+    pub fn read_at(&self, i: usize) -> Result<E> {
+        match &self.data {
+            Data::TopTree(sub_trees) => {
+                // Locate the top-layer tree the sub-tree leaf is
+                // contained in, and the leaf index within the sub-tree.
+                let (tree_index, leaf_index) = self.locate_leaf(i)?;
+
+                sub_trees[tree_index].read_at(leaf_index)
+            }
+            Data::SubTree(base_trees) => {
+                // Locate the sub-tree layer tree the base leaf is
+                // contained in, and the leaf index within the sub-tree.
+                let (tree_index, leaf_index) = self.locate_leaf(i)?;
+
+                base_trees[tree_index].read_at(leaf_index)
             }
             Data::BaseTree(data) => {
                 // Read from the base layer tree data.
@@ -1473,37 +2283,62 @@ impl<
 
     /// Build the tree given a slice of all leafs, in bytes form.
     pub fn from_byte_slice_with_config(leafs: &[u8], config: StoreConfig) -> Result<Self> {
+        Self::from_byte_slice_with_options(leafs, &TreeOptions::new().with_config(config))
+    }
+
+    /// Build the tree given a slice of all leafs, in bytes form.
+    pub fn from_byte_slice(leafs: &[u8]) -> Result<Self> {
+        Self::from_byte_slice_with_options(leafs, &TreeOptions::new())
+    }
+
+    /// Build the tree given a slice of all leafs, in bytes form, and
+    /// the tree options (see `TreeOptions`).
+    pub fn from_byte_slice_with_options(leafs: &[u8], options: &TreeOptions) -> Result<Self> {
+        let arities = Self::options_schedule(options)?;
+        let policy = options.policy();
         ensure!(
             leafs.len() % E::byte_len() == 0,
-            "{} ist not a multiple of {}",
+            "{} is not a multiple of {}",
             leafs.len(),
             E::byte_len()
         );
 
         let leafs_count = leafs.len() / E::byte_len();
-        let branches = BaseTreeArity::to_usize();
-        ensure!(leafs_count > 1, "not enough leaves");
-        ensure!(
-            next_pow2(leafs_count) == leafs_count,
-            "size MUST be a power of 2"
-        );
-        ensure!(
-            next_pow2(branches) == branches,
-            "branches MUST be a power of 2"
-        );
+        policy.ensure_valid_schedule(leafs_count, &arities)?;
 
-        let size = get_merkle_tree_len(leafs_count, branches)?;
-        let row_count = get_merkle_tree_row_count(leafs_count, branches);
+        let size = get_merkle_tree_len_with_schedule(leafs_count, &arities, policy)?;
+        let row_count = get_merkle_tree_row_count_with_schedule(leafs_count, &arities, policy);
 
-        let mut data = S::new_from_slice_with_config(size, branches, leafs, config.clone())
-            .context("failed to create data store")?;
-        let root = S::build::<A, BaseTreeArity>(&mut data, leafs_count, row_count, Some(config))?;
+        let mut data = match options.config() {
+            Some(config) => S::new_from_slice_with_config(size, arities[0], leafs, config.clone()),
+            None => S::new_from_slice(size, leafs),
+        }
+        .context("failed to create data store")?;
+        let build_options = options.build_options();
+        let root = remove_cancelled::<E, S, _>(
+            build_options.run(|| {
+                S::build_with_schedule::<A>(
+                    &mut data,
+                    leafs_count,
+                    row_count,
+                    options.config().cloned(),
+                    &arities,
+                    policy,
+                    build_options,
+                )
+            }),
+            options.config(),
+            build_options,
+        )?;
 
         Ok(MerkleTree {
             data: Data::BaseTree(data),
             leafs: leafs_count,
             len: size,
             row_count,
+            policy,
+            arities,
+            offsets: Vec::new(),
             root,
             _a: PhantomData,
             _e: PhantomData,
@@ -1512,40 +2347,94 @@ impl<
             _tta: PhantomData,
         })
     }
+}
 
-    /// Build the tree given a slice of all leafs, in bytes form.
-    pub fn from_byte_slice(leafs: &[u8]) -> Result<Self> {
+impl<
+        E: Element,
+        A: Algorithm<E>,
+        S: LeafFileStore<E>,
+        BaseTreeArity: Unsigned,
+        SubTreeArity: Unsigned,
+        TopTreeArity: Unsigned,
+    > MerkleTree<E, A, S, BaseTreeArity, SubTreeArity, TopTreeArity>
+{
+    /// Build the tree given the path of a file of all leafs, in bytes
+    /// form, without reading the whole file into memory.  The base
+    /// layer of a `LevelCacheStore` is not copied at all, but read
+    /// from the file through an `ExternalReader`.
+    pub fn from_leaf_file<P: AsRef<Path>>(path: P, config: StoreConfig) -> Result<Self> {
+        Self::from_leaf_file_with_options(path, &TreeOptions::new().with_config(config))
+    }
+
+    /// Build the tree given the path of a file of all leafs, in bytes
+    /// form, and the tree options (see `TreeOptions`), which must
+    /// include a config.
+    pub fn from_leaf_file_with_options<P: AsRef<Path>>(
+        path: P,
+        options: &TreeOptions,
+    ) -> Result<Self> {
+        let config = options
+            .config()
+            .cloned()
+            .ok_or_else(|| anyhow!("Trees built from a leaf file require a store config"))?;
+        let policy = options.policy();
+        let arities = Self::options_schedule(options)?;
+        let path = path.as_ref();
+
+        let file_len = std::fs::metadata(path)
+            .with_context(|| format!("failed to open leaf file {:?}", path))?
+            .len() as usize;
         ensure!(
-            leafs.len() % E::byte_len() == 0,
+            file_len % E::byte_len() == 0,
             "{} is not a multiple of {}",
-            leafs.len(),
+            file_len,
             E::byte_len()
         );
 
-        let leafs_count = leafs.len() / E::byte_len();
-        let branches = BaseTreeArity::to_usize();
-        ensure!(leafs_count > 1, "not enough leaves");
-        ensure!(
-            next_pow2(leafs_count) == leafs_count,
-            "size MUST be a power of 2"
-        );
-        ensure!(
-            next_pow2(branches) == branches,
-            "branches MUST be a power of 2"
-        );
+        let leafs = file_len / E::byte_len();
+        policy.ensure_valid_schedule(leafs, &arities)?;
 
-        let size = get_merkle_tree_len(leafs_count, branches)?;
-        let row_count = get_merkle_tree_row_count(leafs_count, branches);
+        let size = get_merkle_tree_len_with_schedule(leafs, &arities, policy)?;
+        let row_count = get_merkle_tree_row_count_with_schedule(leafs, &arities, policy);
 
-        let mut data = S::new_from_slice(size, leafs).context("failed to create data store")?;
+        let build_options = options.build_options();
+        let mut data = remove_cancelled::<E, S, _>(
+            S::new_from_leaf_file(size, leafs, &arities, path, config.clone(), build_options),
+            Some(&config),
+            build_options,
+        )
+        .context("failed to create data store")?;
 
-        let root = S::build::<A, BaseTreeArity>(&mut data, leafs_count, row_count, None)?;
+        // If the data store was loaded from disk, we know we have
+        // access to the full merkle tree.
+        let root = if data.loaded_from_disk() {
+            data.last().context("failed to read root")?
+        } else {
+            remove_cancelled::<E, S, _>(
+                build_options.run(|| {
+                    S::build_with_schedule::<A>(
+                        &mut data,
+                        leafs,
+                        row_count,
+                        Some(config.clone()),
+                        &arities,
+                        policy,
+                        build_options,
+                    )
+                }),
+                Some(&config),
+                build_options,
+            )?
+        };
 
         Ok(MerkleTree {
             data: Data::BaseTree(data),
-            leafs: leafs_count,
+            leafs,
             len: size,
             row_count,
+            policy,
+            arities,
+            offsets: Vec::new(),
             root,
             _a: PhantomData,
             _e: PhantomData,
@@ -1571,6 +2460,12 @@ where
         I: IntoParallelIterator<Item = E>,
         I::Iter: IndexedParallelIterator,
         BaseTreeArity: Unsigned;
+
+    fn from_par_iter_with_options<I>(par_iter: I, options: &TreeOptions) -> Result<Self>
+    where
+        I: IntoParallelIterator<Item = E>,
+        I::Iter: IndexedParallelIterator,
+        BaseTreeArity: Unsigned;
 }
 
 impl<
@@ -1589,37 +2484,7 @@ impl<
         I: IntoParallelIterator<Item = E>,
         I::Iter: IndexedParallelIterator,
     {
-        let iter = into.into_par_iter();
-
-        let leafs = iter.opt_len().expect("must be sized");
-        let branches = BaseTreeArity::to_usize();
-        ensure!(leafs > 1, "not enough leaves");
-        ensure!(next_pow2(leafs) == leafs, "size MUST be a power of 2");
-        ensure!(
-            next_pow2(branches) == branches,
-            "branches MUST be a power of 2"
-        );
-
-        let size = get_merkle_tree_len(leafs, branches)?;
-        let row_count = get_merkle_tree_row_count(leafs, branches);
-
-        let mut data = S::new(size).expect("failed to create data store");
-
-        populate_data_par::<E, A, S, BaseTreeArity, _>(&mut data, iter)?;
-        let root = S::build::<A, BaseTreeArity>(&mut data, leafs, row_count, None)?;
-
-        Ok(MerkleTree {
-            data: Data::BaseTree(data),
-            leafs,
-            len: size,
-            row_count,
-            root,
-            _a: PhantomData,
-            _e: PhantomData,
-            _bta: PhantomData,
-            _sta: PhantomData,
-            _tta: PhantomData,
-        })
+        Self::from_par_iter_with_options(into, &TreeOptions::new())
     }
 
     /// Creates new merkle tree from an iterator over hashable objects.
@@ -1629,50 +2494,65 @@ impl<
         I: IntoParallelIterator<Item = E>,
         I::Iter: IndexedParallelIterator,
     {
+        Self::from_par_iter_with_options(into, &TreeOptions::new().with_config(config))
+    }
+
+    /// Creates new merkle tree from an iterator over hashable objects,
+    /// given the tree options (see `TreeOptions`).
+    fn from_par_iter_with_options<I>(into: I, options: &TreeOptions) -> Result<Self>
+    where
+        BaseTreeArity: Unsigned,
+        I: IntoParallelIterator<Item = E>,
+        I::Iter: IndexedParallelIterator,
+    {
+        let arities = Self::options_schedule(options)?;
+        let policy = options.policy();
         let iter = into.into_par_iter();
 
         let leafs = iter.opt_len().expect("must be sized");
-        let branches = BaseTreeArity::to_usize();
-        ensure!(leafs > 1, "not enough leaves");
-        ensure!(next_pow2(leafs) == leafs, "size MUST be a power of 2");
-        ensure!(
-            next_pow2(branches) == branches,
-            "branches MUST be a power of 2"
-        );
+        policy.ensure_valid_schedule(leafs, &arities)?;
 
-        let size = get_merkle_tree_len(leafs, branches)?;
-        let row_count = get_merkle_tree_row_count(leafs, branches);
+        let size = get_merkle_tree_len_with_schedule(leafs, &arities, policy)?;
+        let row_count = get_merkle_tree_row_count_with_schedule(leafs, &arities, policy);
 
-        let mut data = S::new_with_config(size, branches, config.clone())
-            .context("failed to create data store")?;
+        let mut data = match options.config() {
+            Some(config) => S::new_with_config_and_schedule(size, &arities, config.clone()),
+            None => S::new(size),
+        }
+        .context("failed to create data store")?;
 
         // If the data store was loaded from disk, we know we have
         // access to the full merkle tree.
-        if data.loaded_from_disk() {
-            let root = data.last().context("failed to read root")?;
-
-            return Ok(MerkleTree {
-                data: Data::BaseTree(data),
-                leafs,
-                len: size,
-                row_count,
-                root,
-                _a: PhantomData,
-                _e: PhantomData,
-                _bta: PhantomData,
-                _sta: PhantomData,
-                _tta: PhantomData,
-            });
-        }
-
-        populate_data_par::<E, A, S, BaseTreeArity, _>(&mut data, iter)?;
-        let root = S::build::<A, BaseTreeArity>(&mut data, leafs, row_count, Some(config))?;
+        let build_options = options.build_options();
+        let root = if data.loaded_from_disk() {
+            data.last().context("failed to read root")?
+        } else {
+            remove_cancelled::<E, S, _>(
+                build_options.run(|| {
+                    S::build_pipelined::<A, _>(
+                        &mut data,
+                        iter,
+                        leafs,
+                        row_count,
+                        options.config().cloned(),
+                        &arities,
+                        policy,
+                        build_options,
+                    )
+                }),
+                options.config(),
+                build_options,
+            )?
+        };
 
         Ok(MerkleTree {
             data: Data::BaseTree(data),
             leafs,
             len: size,
             row_count,
+            policy,
+            arities,
+            offsets: Vec::new(),
             root,
             _a: PhantomData,
             _e: PhantomData,
@@ -1696,38 +2576,7 @@ impl<
     /// the provided iterator. This method returns the first error yielded by
     /// the iterator, if the iterator yielded an error.
     pub fn try_from_iter<I: IntoIterator<Item = Result<E>>>(into: I) -> Result<Self> {
-        let iter = into.into_iter();
-
-        let (_, n) = iter.size_hint();
-        let leafs = n.ok_or_else(|| anyhow!("could not get size hint from iterator"))?;
-        let branches = BaseTreeArity::to_usize();
-        ensure!(leafs > 1, "not enough leaves");
-        ensure!(next_pow2(leafs) == leafs, "size MUST be a power of 2");
-        ensure!(
-            next_pow2(branches) == branches,
-            "branches MUST be a power of 2"
-        );
-
-        let size = get_merkle_tree_len(leafs, branches)?;
-        let row_count = get_merkle_tree_row_count(leafs, branches);
-
-        let mut data = S::new(size).context("failed to create data store")?;
-        populate_data::<E, A, S, BaseTreeArity, I>(&mut data, iter)
-            .context("failed to populate data")?;
-        let root = S::build::<A, BaseTreeArity>(&mut data, leafs, row_count, None)?;
-
-        Ok(MerkleTree {
-            data: Data::BaseTree(data),
-            leafs,
-            len: size,
-            row_count,
-            root,
-            _a: PhantomData,
-            _e: PhantomData,
-            _bta: PhantomData,
-            _sta: PhantomData,
-            _tta: PhantomData,
-        })
+        Self::try_from_iter_with_options(into, &TreeOptions::new())
     }
 
     /// Attempts to create a new merkle tree using hashable objects yielded by
@@ -1737,52 +2586,75 @@ impl<
         into: I,
         config: StoreConfig,
     ) -> Result<Self> {
+        Self::try_from_iter_with_options(into, &TreeOptions::new().with_config(config))
+    }
+
+    /// Attempts to create a new merkle tree using hashable objects yielded by
+    /// the provided iterator and the tree options (see `TreeOptions`). This
+    /// method returns the first error yielded by the iterator, if the iterator
+    /// yielded an error.
+    ///
+    /// The iterator must have an upper size bound, see
+    /// `stream::StreamingBuilder` for inputs of unknown length.
+    pub fn try_from_iter_with_options<I: IntoIterator<Item = Result<E>>>(
+        into: I,
+        options: &TreeOptions,
+    ) -> Result<Self> {
+        let arities = Self::options_schedule(options)?;
+        let policy = options.policy();
         let iter = into.into_iter();
 
         let (_, n) = iter.size_hint();
         let leafs = n.ok_or_else(|| anyhow!("could not get size hint from iterator"))?;
-        let branches = BaseTreeArity::to_usize();
-        ensure!(leafs > 1, "not enough leaves");
-        ensure!(next_pow2(leafs) == leafs, "size MUST be a power of 2");
-        ensure!(
-            next_pow2(branches) == branches,
-            "branches MUST be a power of 2"
-        );
+        policy.ensure_valid_schedule(leafs, &arities)?;
 
-        let size = get_merkle_tree_len(leafs, branches)?;
-        let row_count = get_merkle_tree_row_count(leafs, branches);
+        let size = get_merkle_tree_len_with_schedule(leafs, &arities, policy)?;
+        let row_count = get_merkle_tree_row_count_with_schedule(leafs, &arities, policy);
 
-        let mut data = S::new_with_config(size, branches, config.clone())
-            .context("failed to create data store")?;
+        let mut data = match options.config() {
+            Some(config) => S::new_with_config_and_schedule(size, &arities, config.clone()),
+            None => S::new(size),
+        }
+        .context("failed to create data store")?;
 
         // If the data store was loaded from disk, we know we have
         // access to the full merkle tree.
-        if data.loaded_from_disk() {
-            let root = data.last().context("failed to read root")?;
-
-            return Ok(MerkleTree {
-                data: Data::BaseTree(data),
-                leafs,
-                len: size,
-                row_count,
-                root,
-                _a: PhantomData,
-                _e: PhantomData,
-                _bta: PhantomData,
-                _sta: PhantomData,
-                _tta: PhantomData,
-            });
-        }
-
-        populate_data::<E, A, S, BaseTreeArity, I>(&mut data, iter)
-            .expect("failed to populate data");
-        let root = S::build::<A, BaseTreeArity>(&mut data, leafs, row_count, Some(config))?;
+        // The leafs are read from the iterator on this thread, and
+        // only the rows above are built on the thread pool of the
+        // build options.
+        let build_options = options.build_options();
+        let root = if data.loaded_from_disk() {
+            data.last().context("failed to read root")?
+        } else {
+            remove_cancelled::<E, S, _>(
+                populate_data::<E, A, S, BaseTreeArity, I>(&mut data, iter, build_options)
+                    .context("failed to populate data")
+                    .and_then(|_| {
+                        build_options.run(|| {
+                            S::build_with_schedule::<A>(
+                                &mut data,
+                                leafs,
+                                row_count,
+                                options.config().cloned(),
+                                &arities,
+                                policy,
+                                build_options,
+                            )
+                        })
+                    }),
+                options.config(),
+                build_options,
+            )?
+        };
 
         Ok(MerkleTree {
             data: Data::BaseTree(data),
             leafs,
             len: size,
             row_count,
+            policy,
+            arities,
+            offsets: Vec::new(),
             root,
             _a: PhantomData,
             _e: PhantomData,
@@ -1810,29 +2682,198 @@ impl Element for [u8; 32] {
     }
 }
 
+/// Policy applied when a row of the base tree cannot be evenly
+/// grouped by the tree arity.
+///
+/// `Reject` is the default and only allows trees whose leaf count is
+/// a power of the arity, so that every row is full.
+///
+/// `Promote` follows the RFC 6962 `MTH(D[n])` definition, where the
+/// tree is split at the largest power of two smaller than `n`.  Built
+/// bottom-up, this is the same as carrying the lone last node of an
+/// odd row up to the next row unchanged.  It accepts any number of
+/// leafs (n >= 1) and is only defined for binary trees.  Each row of
+/// `width` nodes is followed by a row of `ceil(width / 2)` nodes, and
+/// proofs omit the sibling for the rows where the node was promoted.
+///
+/// `Duplicate` follows Bitcoin, where the last group of a row is
+/// filled up to the arity by repeating its last node, and `Pad` fills
+/// it with the padding node of the algorithm instead (see
+/// `Algorithm::pad`).  Both accept any number
+/// of leafs with any arity, and proofs only hold the actual siblings:
+/// the filler nodes are recomputed during validation.
+///
+/// Duplicating the last node makes the tree over `[a, b, c]` share its
+/// root with the tree over `[a, b, c, c]` (CVE-2012-2459).  Trees and
+/// proofs built with `Duplicate` can be checked for such a mutation,
+/// where a full group of siblings ends with two equal nodes (see
+/// `MerkleTree::is_mutated`).
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum OddRowPolicy {
+    Reject,
+    Promote,
+    Duplicate,
+    Pad,
+}
+
+impl Default for OddRowPolicy {
+    fn default() -> Self {
+        OddRowPolicy::Reject
+    }
+}
+
+impl OddRowPolicy {
+    /// Ensures that a base tree of `leafs` with `branches` can be built
+    /// with this policy.
+    pub fn ensure_valid_shape(&self, leafs: usize, branches: usize) -> Result<()> {
+        ensure!(branches > 1, "branches MUST be greater than 1");
+
+        match self {
+            OddRowPolicy::Reject => {
+                ensure!(leafs > 1, "not enough leaves");
+                ensure!(
+                    is_power_of(leafs, branches),
+                    "size MUST be a power of the arity"
+                );
+            }
+            OddRowPolicy::Promote => {
+                ensure!(leafs > 0, "not enough leaves");
+                ensure!(branches == 2, "RFC 6962 trees MUST be binary");
+            }
+            OddRowPolicy::Duplicate | OddRowPolicy::Pad => {
+                ensure!(leafs > 0, "not enough leaves");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Ensures that a base tree of `leafs` with the arity schedule
+    /// `arities` (see `row_arity`) can be built with this policy.
+    /// Without odd rows, every row must be made of full groups.
+    pub fn ensure_valid_schedule(&self, leafs: usize, arities: &[usize]) -> Result<()> {
+        ensure!(!arities.is_empty(), "arity schedule MUST not be empty");
+        if arities.len() == 1 {
+            return self.ensure_valid_shape(leafs, arities[0]);
+        }
+
+        if *self != OddRowPolicy::Reject {
+            for branches in arities {
+                self.ensure_valid_shape(leafs, *branches)?;
+            }
+        } else {
+            ensure!(
+                arities.iter().all(|branches| *branches > 1),
+                "branches MUST be greater than 1"
+            );
+            ensure!(leafs > 1, "not enough leaves");
+
+            let mut width = leafs;
+            let mut row = 0;
+            while width > 1 {
+                let branches = row_arity(arities, row);
+                ensure!(
+                    width % branches == 0,
+                    "size MUST be a multiple of the row arities"
+                );
+                width /= branches;
+                row += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the width of the row above a row of `width` nodes.
+    #[inline]
+    pub fn next_row_width(&self, width: usize, branches: usize) -> usize {
+        match self {
+            OddRowPolicy::Reject => div_arity(width, branches), // width / branches
+            _ => (width + branches - 1) / branches,
+        }
+    }
+
+    /// Hashes a group of sibling `nodes` into their parent.  Only the
+    /// last group of a row may hold less than `branches` nodes.
+    #[inline]
+    pub fn hash_nodes<T: Clone + AsRef<[u8]>, A: Algorithm<T>>(
+        &self,
+        nodes: &[T],
+        branches: usize,
+        height: usize,
+    ) -> T {
+        debug_assert!(nodes.len() <= branches);
+
+        match self {
+            OddRowPolicy::Promote if nodes.len() == 1 => nodes[0].clone(),
+            OddRowPolicy::Duplicate | OddRowPolicy::Pad if nodes.len() < branches => {
+                let filler = match self {
+                    OddRowPolicy::Duplicate => nodes[nodes.len() - 1].clone(),
+                    _ => A::default().pad(),
+                };
+                let mut group = nodes.to_vec();
+                group.resize(branches, filler);
+
+                A::default().multi_node(&group, height)
+            }
+            _ => A::default().multi_node(nodes, height),
+        }
+    }
+
+    /// Hashes each group of `branches` siblings of `nodes` into their
+    /// parent, in order.  The full groups are hashed as a batch (see
+    /// `Algorithm::multi_node_batch`); only the last group may hold
+    /// less than `branches` nodes.
+    pub fn hash_groups<T: Clone + AsRef<[u8]>, A: Algorithm<T>>(
+        &self,
+        nodes: &[T],
+        branches: usize,
+        height: usize,
+    ) -> Vec<T> {
+        let full = nodes.len() - nodes.len() % branches;
+        let groups: Vec<&[T]> = nodes[..full].chunks(branches).collect();
+
+        let mut parents = A::default().multi_node_batch(&groups, height);
+        if full < nodes.len() {
+            parents.push(self.hash_nodes::<T, A>(&nodes[full..], branches, height));
+        }
+
+        parents
+    }
+
+    /// Returns true if the group of sibling `nodes` hashes like a
+    /// short group would with `Duplicate`, i.e. if it is full and ends
+    /// with two equal nodes (CVE-2012-2459).  Always false with other
+    /// policies.
+    #[inline]
+    pub fn is_mutated<T: PartialEq>(&self, nodes: &[T], branches: usize) -> bool {
+        *self == OddRowPolicy::Duplicate
+            && nodes.len() == branches
+            && nodes[branches - 1] == nodes[branches - 2]
+    }
+}
+
 // Tree length calculation given the number of leafs in the tree and the branches.
 pub fn get_merkle_tree_len(leafs: usize, branches: usize) -> Result<usize> {
     ensure!(leafs >= branches, "leaf and branch mis-match");
+    ensure!(branches > 0, "branches must be greater than 0");
+    if branches == 1 {
+        return Ok(leafs);
+    }
     ensure!(
-        branches == next_pow2(branches),
-        "branches must be a power of 2"
+        is_power_of(leafs, branches),
+        "leafs must be a power of the arity"
     );
 
     // Optimization
     if branches == 2 {
-        ensure!(leafs == next_pow2(leafs), "leafs must be a power of 2");
         return Ok(2 * leafs - 1);
     }
 
     let mut len = leafs;
     let mut cur = leafs;
-    let shift = log2_pow2(branches);
-    if shift == 0 {
-        return Ok(len);
-    }
-
     while cur > 0 {
-        cur >>= shift; // cur /= branches
+        cur = div_arity(cur, branches); // cur /= branches
         ensure!(cur < leafs, "invalid input provided");
         len += cur;
     }
@@ -1840,6 +2881,29 @@ pub fn get_merkle_tree_len(leafs: usize, branches: usize) -> Result<usize> {
     Ok(len)
 }
 
+// Tree length calculation given the number of leafs in the tree, the
+// branches and the odd row policy.
+pub fn get_merkle_tree_len_with_policy(
+    leafs: usize,
+    branches: usize,
+    policy: OddRowPolicy,
+) -> Result<usize> {
+    if policy == OddRowPolicy::Reject {
+        return get_merkle_tree_len(leafs, branches);
+    }
+
+    policy.ensure_valid_shape(leafs, branches)?;
+
+    let mut len = leafs;
+    let mut width = leafs;
+    while width > 1 {
+        width = policy.next_row_width(width, branches);
+        len += width;
+    }
+
+    Ok(len)
+}
+
 // Tree length calculation given the number of leafs in the tree, the
 // rows_to_discard, and the branches.
 pub fn get_merkle_tree_cache_size(
@@ -1847,7 +2911,6 @@ pub fn get_merkle_tree_cache_size(
     branches: usize,
     rows_to_discard: usize,
 ) -> Result<usize> {
-    let shift = log2_pow2(branches);
     let len = get_merkle_tree_len(leafs, branches)?;
     let mut row_count = get_merkle_tree_row_count(leafs, branches);
 
@@ -1865,7 +2928,7 @@ pub fn get_merkle_tree_cache_size(
 
     while row_count > cache_base {
         cache_size -= cur_leafs;
-        cur_leafs >>= shift; // cur /= branches
+        cur_leafs = div_arity(cur_leafs, branches); // cur /= branches
         row_count -= 1;
     }
 
@@ -1873,20 +2936,18 @@ pub fn get_merkle_tree_cache_size(
 }
 
 pub fn is_merkle_tree_size_valid(leafs: usize, branches: usize) -> bool {
-    if branches == 0 || leafs != next_pow2(leafs) || branches != next_pow2(branches) {
-        return false;
-    }
+    branches > 1 && is_power_of(leafs, branches)
+}
 
-    let mut cur = leafs;
-    let shift = log2_pow2(branches);
-    while cur != 1 {
-        cur >>= shift; // cur /= branches
-        if cur > leafs || cur == 0 {
-            return false;
-        }
+pub fn is_merkle_tree_size_valid_with_policy(
+    leafs: usize,
+    branches: usize,
+    policy: OddRowPolicy,
+) -> bool {
+    match policy {
+        OddRowPolicy::Reject => is_merkle_tree_size_valid(leafs, branches),
+        _ => policy.ensure_valid_shape(leafs, branches).is_ok(),
     }
-
-    true
 }
 
 // Row_Count calculation given the number of leafs in the tree and the branches.
@@ -1895,10 +2956,38 @@ pub fn get_merkle_tree_row_count(leafs: usize, branches: usize) -> usize {
     if branches == 2 {
         (leafs * branches).trailing_zeros() as usize
     } else {
-        (branches as f64 * leafs as f64).log(branches as f64) as usize
+        let mut row_count = 1;
+        let mut cur = leafs;
+        while cur > 1 {
+            cur = div_arity(cur, branches); // cur /= branches
+            row_count += 1;
+        }
+
+        row_count
     }
 }
 
+// Row_Count calculation given the number of leafs in the tree, the
+// branches and the odd row policy.
+pub fn get_merkle_tree_row_count_with_policy(
+    leafs: usize,
+    branches: usize,
+    policy: OddRowPolicy,
+) -> usize {
+    if policy == OddRowPolicy::Reject {
+        return get_merkle_tree_row_count(leafs, branches);
+    }
+
+    let mut row_count = 1;
+    let mut width = leafs;
+    while width > 1 {
+        width = policy.next_row_width(width, branches);
+        row_count += 1;
+    }
+
+    row_count
+}
+
 // Given a tree of 'row_count' with the specified number of 'branches',
 // calculate the length of hashes required for the proof.
 pub fn get_merkle_proof_lemma_len(row_count: usize, branches: usize) -> usize {
@@ -1906,24 +2995,22 @@ pub fn get_merkle_proof_lemma_len(row_count: usize, branches: usize) -> usize {
 }
 
 // This method returns the number of 'leafs' given a merkle tree
-// length of 'len', where leafs must be a power of 2, respecting the
-// number of branches.
+// length of 'len', where leafs must be a power of the number of
+// branches.
 pub fn get_merkle_tree_leafs(len: usize, branches: usize) -> Result<usize> {
-    ensure!(
-        branches == next_pow2(branches),
-        "branches must be a power of 2"
-    );
+    ensure!(branches > 1, "branches must be greater than 1");
 
     let leafs = {
         // Optimization:
         if branches == 2 {
             (len >> 1) + 1
         } else {
-            let mut leafs = 1;
+            let mut leafs: usize = 1;
             let mut cur = len;
-            let shift = log2_pow2(branches);
             while cur != 1 {
-                leafs <<= shift; // leafs *= branches
+                leafs = leafs
+                    .checked_mul(branches)
+                    .context("Invalid tree length provided for the specified arity")?;
                 ensure!(
                     cur > leafs,
                     "Invalid tree length provided for the specified arity"
@@ -1940,13 +3027,145 @@ pub fn get_merkle_tree_leafs(len: usize, branches: usize) -> Result<usize> {
     };
 
     ensure!(
-        leafs == next_pow2(leafs),
+        is_power_of(leafs, branches),
         "Invalid tree length provided for the specified arity"
     );
 
     Ok(leafs)
 }
 
+/// Returns the arity of `row` (the leafs being row 0) of a base tree
+/// with the arity schedule `arities`, whose last arity applies to all
+/// the rows above it.  A single arity is the schedule of a tree whose
+/// rows all have the same arity.
+#[inline]
+pub fn row_arity(arities: &[usize], row: usize) -> usize {
+    arities[std::cmp::min(row, arities.len() - 1)]
+}
+
+// Tree length calculation given the number of leafs in the tree, the
+// arity schedule and the odd row policy.
+pub fn get_merkle_tree_len_with_schedule(
+    leafs: usize,
+    arities: &[usize],
+    policy: OddRowPolicy,
+) -> Result<usize> {
+    if arities.len() == 1 {
+        return get_merkle_tree_len_with_policy(leafs, arities[0], policy);
+    }
+    policy.ensure_valid_schedule(leafs, arities)?;
+
+    let mut len = leafs;
+    let mut width = leafs;
+    let mut row = 0;
+    while width > 1 {
+        width = policy.next_row_width(width, row_arity(arities, row));
+        len += width;
+        row += 1;
+    }
+
+    Ok(len)
+}
+
+// Tree length calculation given the number of leafs in the tree, the
+// rows_to_discard, and the arity schedule.
+pub fn get_merkle_tree_cache_size_with_schedule(
+    leafs: usize,
+    arities: &[usize],
+    rows_to_discard: usize,
+) -> Result<usize> {
+    if arities.len() == 1 {
+        return get_merkle_tree_cache_size(leafs, arities[0], rows_to_discard);
+    }
+
+    let len = get_merkle_tree_len_with_schedule(leafs, arities, OddRowPolicy::Reject)?;
+    let row_count = get_merkle_tree_row_count_with_schedule(leafs, arities, OddRowPolicy::Reject);
+
+    ensure!(
+        row_count - 1 > rows_to_discard,
+        "Cannot discard all rows except for the base"
+    );
+
+    // The base row and the 'rows_to_discard' rows above it are not
+    // part of the cache.
+    let mut cache_size = len;
+    let mut width = leafs;
+    for row in 0..=rows_to_discard {
+        cache_size -= width;
+        width /= row_arity(arities, row);
+    }
+
+    Ok(cache_size)
+}
+
+pub fn is_merkle_tree_size_valid_with_schedule(
+    leafs: usize,
+    arities: &[usize],
+    policy: OddRowPolicy,
+) -> bool {
+    match arities.len() {
+        0 => false,
+        1 => is_merkle_tree_size_valid_with_policy(leafs, arities[0], policy),
+        _ => policy.ensure_valid_schedule(leafs, arities).is_ok(),
+    }
+}
+
+// Row_Count calculation given the number of leafs in the tree, the
+// arity schedule and the odd row policy.
+pub fn get_merkle_tree_row_count_with_schedule(
+    leafs: usize,
+    arities: &[usize],
+    policy: OddRowPolicy,
+) -> usize {
+    if arities.len() == 1 {
+        return get_merkle_tree_row_count_with_policy(leafs, arities[0], policy);
+    }
+
+    let mut row_count = 1;
+    let mut width = leafs;
+    while width > 1 {
+        width = policy.next_row_width(width, row_arity(arities, row_count - 1));
+        row_count += 1;
+    }
+
+    row_count
+}
+
+// Given a tree of 'row_count' with the specified arity schedule,
+// calculate the length of hashes required for the proof.
+pub fn get_merkle_proof_lemma_len_with_schedule(row_count: usize, arities: &[usize]) -> usize {
+    2 + (0..row_count - 1)
+        .map(|row| row_arity(arities, row) - 1)
+        .sum::<usize>()
+}
+
+// This method returns the number of 'leafs' given a merkle tree
+// length of 'len' and the arity schedule, for trees of full rows.
+pub fn get_merkle_tree_leafs_with_schedule(len: usize, arities: &[usize]) -> Result<usize> {
+    if arities.len() == 1 {
+        return get_merkle_tree_leafs(len, arities[0]);
+    }
+
+    // Try the trees of increasing row count until one is long enough.
+    let mut leafs: usize = 1;
+    let mut row = 0;
+    loop {
+        leafs = leafs
+            .checked_mul(row_arity(arities, row))
+            .context("Invalid tree length provided for the specified arity")?;
+        row += 1;
+
+        let tree_len = get_merkle_tree_len_with_schedule(leafs, arities, OddRowPolicy::Reject)?;
+        ensure!(
+            tree_len <= len,
+            "Invalid tree length provided for the specified arity"
+        );
+        if tree_len == len {
+            return Ok(leafs);
+        }
+    }
+}
+
 /// returns next highest power of two from a given number if it is not
 /// already a power of two.
 pub fn next_pow2(n: usize) -> usize {
@@ -1958,6 +3177,49 @@ pub fn log2_pow2(n: usize) -> usize {
     n.trailing_zeros() as usize
 }
 
+/// Returns true if `n` is a power of `base` (including `base^0`).
+pub fn is_power_of(n: usize, base: usize) -> bool {
+    if base.is_power_of_two() && base > 1 {
+        return n.is_power_of_two() && log2_pow2(n) % log2_pow2(base) == 0;
+    }
+    if n == 0 || base < 2 {
+        return n == 1;
+    }
+
+    let mut n = n;
+    while n % base == 0 {
+        n /= base;
+    }
+
+    n == 1
+}
+
+/// Divides `n` by the arity `branches`, using a shift if it is a
+/// power of 2.
+#[inline]
+pub fn div_arity(n: usize, branches: usize) -> usize {
+    if branches.is_power_of_two() {
+        n >> log2_pow2(branches)
+    } else {
+        n / branches
+    }
+}
+
+// Splits the (sorted) row indices across trees with rows of
+// `width` nodes each, as local indices per tree.
+fn group_by_tree(indices: &[usize], width: usize) -> Vec<(usize, Vec<usize>)> {
+    let mut groups: Vec<(usize, Vec<usize>)> = Vec::new();
+    for j in indices {
+        let tree = j / width;
+        match groups.last_mut() {
+            Some((t, group)) if *t == tree => group.push(j % width),
+            _ => groups.push((tree, vec![j % width])),
+        }
+    }
+
+    groups
+}
+
 pub fn populate_data<
     E: Element,
     A: Algorithm<E>,
@@ -1967,22 +3229,33 @@ pub fn populate_data<
 >(
     data: &mut S,
     iter: <I as std::iter::IntoIterator>::IntoIter,
+    options: &BuildOptions,
 ) -> Result<()> {
     if !data.is_empty() {
         return Ok(());
     }
 
-    let mut buf = Vec::with_capacity(BUILD_DATA_BLOCK_SIZE * E::byte_len());
+    let block_size = options.data_block_size();
+    let mut buf = Vec::with_capacity(block_size * E::byte_len());
 
     let mut a = A::default();
+    let mut block = Vec::with_capacity(block_size);
     for item in iter {
         // short circuit the tree-populating routine if the iterator yields an
         // error
-        let item = item?;
+        block.push(item?);
+
+        if block.len() >= block_size {
+            options.check_cancelled()?;
+
+            // Hash the leafs of the block as a batch.
+            for leaf in a.leaf_batch(std::mem::replace(
+                &mut block,
+                Vec::with_capacity(block_size),
+            )) {
+                buf.extend(leaf.as_ref());
+            }
 
-        a.reset();
-        buf.extend(a.leaf(item).as_ref());
-        if buf.len() >= BUILD_DATA_BLOCK_SIZE * E::byte_len() {
             let data_len = data.len();
             // FIXME: Integrate into `len()` call into `copy_from_slice`
             // once we update to `stable` 1.36.
@@ -1990,6 +3263,9 @@ pub fn populate_data<
             buf.clear();
         }
     }
+    for leaf in a.leaf_batch(block) {
+        buf.extend(leaf.as_ref());
+    }
     let data_len = data.len();
     data.copy_from_slice(&buf, data_len)?;
     data.sync()?;
@@ -1997,40 +3273,6 @@ pub fn populate_data<
     Ok(())
 }
 
-fn populate_data_par<E, A, S, BaseTreeArity, I>(data: &mut S, iter: I) -> Result<()>
-where
-    E: Element,
-    A: Algorithm<E>,
-    S: Store<E>,
-    BaseTreeArity: Unsigned,
-    I: ParallelIterator<Item = E> + IndexedParallelIterator,
-{
-    if !data.is_empty() {
-        return Ok(());
-    }
-
-    let store = Arc::new(RwLock::new(data));
-
-    iter.chunks(BUILD_DATA_BLOCK_SIZE)
-        .enumerate()
-        .try_for_each(|(index, chunk)| {
-            let mut a = A::default();
-            let mut buf = Vec::with_capacity(BUILD_DATA_BLOCK_SIZE * E::byte_len());
-
-            for item in chunk {
-                a.reset();
-                buf.extend(a.leaf(item).as_ref());
-            }
-            store
-                .write()
-                .unwrap()
-                .copy_from_slice(&buf[..], BUILD_DATA_BLOCK_SIZE * index)
-        })?;
-
-    store.write().unwrap().sync()?;
-    Ok(())
-}
-
 #[test]
 fn test_get_merkle_tree_methods() {
     assert!(get_merkle_tree_len(16, 4).is_ok());
@@ -0,0 +1,628 @@
+use std::marker::PhantomData;
+
+use anyhow::{Context, Result};
+
+use crate::hash::{Algorithm, Hashable};
+use crate::merkle::Element;
+use crate::store::{Store, StoreConfig};
+
+/// Merkle Mountain Range.
+///
+/// The leafs are covered by a list of perfect binary trees (peaks) of
+/// strictly decreasing heights, one for each bit set in the number of
+/// leafs.  Appending a leaf adds a peak of height 0 and merges peaks
+/// of equal height, so existing nodes never change.
+///
+/// Nodes are kept in a single store in the order they are created,
+/// i.e. each tree is laid out in post-order:
+///
+/// ```text
+///           6
+///         /   \
+///       2       5       9
+///      / \     / \     / \
+///     0   1   3   4   7   8   10
+/// ```
+///
+/// The root is the hash of the peaks bagged from right to left:
+/// `Node(p0, Node(p1, ... Node(pk-1, pk)))`.
+///
+/// Appending does not sync the store, see `sync`.
+#[derive(Debug)]
+pub struct MerkleMountainRange<E: Element, A: Algorithm<E>, S: Store<E>> {
+    store: S,
+    capacity: usize,
+    leafs: usize,
+    _a: PhantomData<A>,
+    _e: PhantomData<E>,
+}
+
+/// Inclusion proof for a leaf of a `MerkleMountainRange`.
+///
+/// Siblings are ordered from the leaf row up to the peak containing
+/// the leaf, and peaks hold all other peaks, left to right.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MmrProof<E: Element> {
+    leafs: usize,
+    index: usize,
+    item: E,
+    siblings: Vec<E>,
+    peaks: Vec<E>,
+}
+
+/// Proof that a `MerkleMountainRange` of `old_leafs` leafs is a prefix
+/// of one of `new_leafs` leafs.
+///
+/// Holds the old peaks, and the nodes covering only new leafs which are
+/// needed to compute the new peaks from the old ones, left to right.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MmrConsistencyProof<E: Element> {
+    old_leafs: usize,
+    new_leafs: usize,
+    old_peaks: Vec<E>,
+    nodes: Vec<E>,
+}
+
+impl<E: Element, A: Algorithm<E>, S: Store<E>> MerkleMountainRange<E, A, S> {
+    /// Creates a new empty MMR which can hold up to `capacity` leafs.
+    pub fn new(capacity: usize) -> Result<MerkleMountainRange<E, A, S>> {
+        let store = S::new(get_mmr_size(capacity))?;
+        Self::from_store(store, capacity)
+    }
+
+    /// Creates a new empty MMR which can hold up to `capacity` leafs,
+    /// backed by the store described by `config`.
+    pub fn new_with_config(
+        capacity: usize,
+        config: StoreConfig,
+    ) -> Result<MerkleMountainRange<E, A, S>> {
+        let store = S::new_with_config(get_mmr_size(capacity), 2, config)?;
+        ensure!(
+            !store.loaded_from_disk(),
+            "Store already exists, use from_store to open it"
+        );
+
+        Self::from_store(store, capacity)
+    }
+
+    /// Opens the MMR whose nodes are held by `store` (e.g. a store
+    /// loaded from disk), which can grow up to `capacity` leafs.  The
+    /// number of leafs is derived from the length of the store, which
+    /// must hold exactly the nodes of the MMR.
+    pub fn from_store(store: S, capacity: usize) -> Result<MerkleMountainRange<E, A, S>> {
+        let leafs = get_mmr_leafs(store.len())?;
+        ensure!(leafs <= capacity, "Leafs exceed capacity");
+
+        Ok(MerkleMountainRange {
+            store,
+            capacity,
+            leafs,
+            _a: PhantomData,
+            _e: PhantomData,
+        })
+    }
+
+    /// Appends a single leaf, stored as `Leaf(item)`, merging the
+    /// peaks of equal height.
+    pub fn append(&mut self, item: E) -> Result<()> {
+        ensure!(
+            self.leafs < self.capacity,
+            "MMR is full (capacity: {})",
+            self.capacity
+        );
+
+        let mut a = A::default();
+        let mut pos = self.len();
+        let mut node = a.leaf(item);
+        self.store.write_at(node.clone(), pos)?;
+
+        // Leaf `i` closes one perfect tree per trailing one bit of `i`.
+        let mut start = self.leafs;
+        for height in 0..(self.leafs + 1).trailing_zeros() as usize {
+            start -= 1 << height;
+            let left = self.store.read_at(get_node_pos(start, height))?;
+
+            a.reset();
+            node = a.node(left, node, height);
+            pos += 1;
+            self.store.write_at(node.clone(), pos)?;
+        }
+
+        self.leafs += 1;
+
+        Ok(())
+    }
+
+    /// Appends all leafs yielded by `items`.  If the MMR fills up, the
+    /// leafs appended so far are kept and an error is returned.
+    pub fn extend<I: IntoIterator<Item = E>>(&mut self, items: I) -> Result<()> {
+        for item in items {
+            self.append(item)?;
+        }
+
+        Ok(())
+    }
+
+    /// Appends a hashable object as a single leaf.
+    pub fn append_data<O: Hashable<A>>(&mut self, data: O) -> Result<()> {
+        let mut a = A::default();
+        data.hash(&mut a);
+        self.append(a.hash())
+    }
+
+    /// Returns the peaks, left to right.
+    pub fn peaks(&self) -> Result<Vec<E>> {
+        get_peaks(self.leafs)
+            .iter()
+            .map(|(start, height)| self.store.read_at(get_node_pos(*start, *height)))
+            .collect()
+    }
+
+    /// Returns merkle root, i.e. the bagged peaks.
+    pub fn root(&self) -> Result<E> {
+        ensure!(self.leafs > 0, "Empty MMR has no root");
+        Ok(bag_peaks::<E, A>(self.leafs, &self.peaks()?))
+    }
+
+    /// Returns number of nodes in the MMR.
+    pub fn len(&self) -> usize {
+        get_mmr_size(self.leafs)
+    }
+
+    /// Returns `true` if no leafs were appended yet.
+    pub fn is_empty(&self) -> bool {
+        self.leafs == 0
+    }
+
+    /// Returns number of leafs appended so far.
+    pub fn leafs(&self) -> usize {
+        self.leafs
+    }
+
+    /// Returns the maximum number of leafs this MMR can hold.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the store holding the nodes.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Flushes the store holding the nodes.
+    pub fn sync(&self) -> Result<()> {
+        self.store.sync()
+    }
+
+    /// Returns merkle leaf at index i
+    pub fn read_at(&self, i: usize) -> Result<E> {
+        ensure!(
+            i < self.leafs,
+            "{} is out of bounds (max: {})",
+            i,
+            self.leafs
+        );
+
+        self.store.read_at(get_node_pos(i, 0))
+    }
+
+    /// Generate MMR inclusion proof for leaf `i`
+    pub fn gen_proof(&self, i: usize) -> Result<MmrProof<E>> {
+        let item = self.read_at(i)?;
+
+        let mut siblings = Vec::new();
+        let mut peaks = Vec::new();
+        for (start, height) in get_peaks(self.leafs) {
+            if i < start || i >= start + (1 << height) {
+                peaks.push(self.store.read_at(get_node_pos(start, height))?);
+                continue;
+            }
+
+            for h in 0..height {
+                let sibling = (i >> h << h) ^ (1 << h);
+                siblings.push(self.store.read_at(get_node_pos(sibling, h))?);
+            }
+        }
+
+        Ok(MmrProof {
+            leafs: self.leafs,
+            index: i,
+            item,
+            siblings,
+            peaks,
+        })
+    }
+
+    /// Generate a proof that the MMR of the first `old_leafs` leafs is
+    /// a prefix of this MMR.
+    pub fn gen_consistency_proof(&self, old_leafs: usize) -> Result<MmrConsistencyProof<E>> {
+        ensure!(
+            old_leafs > 0 && old_leafs <= self.leafs,
+            "{} is not a valid old MMR size (max: {})",
+            old_leafs,
+            self.leafs
+        );
+
+        let mut old_peaks = Vec::new();
+        let mut nodes = Vec::new();
+        for (start, height) in get_peaks(self.leafs) {
+            walk_range::<E, A, _, _>(
+                start,
+                height,
+                old_leafs,
+                &mut |start, height| {
+                    let node = self.store.read_at(get_node_pos(start, height))?;
+                    old_peaks.push(node.clone());
+                    Ok(node)
+                },
+                &mut |start, height| {
+                    let node = self.store.read_at(get_node_pos(start, height))?;
+                    nodes.push(node.clone());
+                    Ok(node)
+                },
+            )?;
+        }
+
+        Ok(MmrConsistencyProof {
+            old_leafs,
+            new_leafs: self.leafs,
+            old_peaks,
+            nodes,
+        })
+    }
+}
+
+impl<E: Element> MmrProof<E> {
+    /// Verifies MMR inclusion proof against `root`.
+    pub fn validate<A: Algorithm<E>>(&self, root: &E) -> bool {
+        if self.index >= self.leafs {
+            return false;
+        }
+
+        let mut a = A::default();
+        let mut siblings = self.siblings.iter();
+        let mut others = self.peaks.iter();
+        let mut peaks = Vec::new();
+        for (start, height) in get_peaks(self.leafs) {
+            if self.index < start || self.index >= start + (1 << height) {
+                match others.next() {
+                    Some(peak) => peaks.push(peak.clone()),
+                    None => return false,
+                }
+                continue;
+            }
+
+            let mut h = self.item.clone();
+            for level in 0..height {
+                let sibling = match siblings.next() {
+                    Some(sibling) => sibling.clone(),
+                    None => return false,
+                };
+
+                a.reset();
+                h = if self.index >> level & 1 == 1 {
+                    a.node(sibling, h, level)
+                } else {
+                    a.node(h, sibling, level)
+                };
+            }
+            peaks.push(h);
+        }
+
+        siblings.next().is_none()
+            && others.next().is_none()
+            && bag_peaks::<E, A>(self.leafs, &peaks) == *root
+    }
+
+    /// Returns the index of the proven leaf.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Returns the proven leaf.
+    pub fn item(&self) -> &E {
+        &self.item
+    }
+
+    /// Returns the number of leafs of the MMR this proof was generated from.
+    pub fn leafs(&self) -> usize {
+        self.leafs
+    }
+
+    /// Returns the siblings of the leaf, up to its peak.
+    pub fn siblings(&self) -> &Vec<E> {
+        &self.siblings
+    }
+
+    /// Returns the peaks not containing the leaf.
+    pub fn peaks(&self) -> &Vec<E> {
+        &self.peaks
+    }
+}
+
+impl<E: Element> MmrConsistencyProof<E> {
+    /// Verifies that `old_root` is the root of the first `old_leafs`
+    /// leafs of the MMR with root `new_root`.
+    pub fn validate<A: Algorithm<E>>(&self, old_root: &E, new_root: &E) -> Result<bool> {
+        ensure!(
+            self.old_leafs > 0 && self.old_leafs <= self.new_leafs,
+            "Invalid consistency proof sizes"
+        );
+
+        if self.old_peaks.len() != get_peaks(self.old_leafs).len()
+            || bag_peaks::<E, A>(self.old_leafs, &self.old_peaks) != *old_root
+        {
+            return Ok(false);
+        }
+
+        let mut old_peaks = self.old_peaks.iter();
+        let mut nodes = self.nodes.iter();
+        let mut peaks = Vec::new();
+        for (start, height) in get_peaks(self.new_leafs) {
+            let peak = walk_range::<E, A, _, _>(
+                start,
+                height,
+                self.old_leafs,
+                &mut |_, _| old_peaks.next().cloned().context("Missing old peak"),
+                &mut |_, _| nodes.next().cloned().context("Missing node"),
+            );
+
+            match peak {
+                Ok(peak) => peaks.push(peak),
+                Err(_) => return Ok(false),
+            }
+        }
+
+        Ok(old_peaks.next().is_none()
+            && nodes.next().is_none()
+            && bag_peaks::<E, A>(self.new_leafs, &peaks) == *new_root)
+    }
+
+    /// Returns the number of leafs in the old MMR.
+    pub fn old_leafs(&self) -> usize {
+        self.old_leafs
+    }
+
+    /// Returns the number of leafs in the new MMR.
+    pub fn new_leafs(&self) -> usize {
+        self.new_leafs
+    }
+
+    /// Returns the peaks of the old MMR.
+    pub fn old_peaks(&self) -> &Vec<E> {
+        &self.old_peaks
+    }
+
+    /// Returns the nodes covering only new leafs.
+    pub fn nodes(&self) -> &Vec<E> {
+        &self.nodes
+    }
+}
+
+/// Returns the number of nodes of an MMR of `leafs` leafs.
+pub fn get_mmr_size(leafs: usize) -> usize {
+    2 * leafs - leafs.count_ones() as usize
+}
+
+// Returns the number of leafs of an MMR of `size` nodes.
+fn get_mmr_leafs(size: usize) -> Result<usize> {
+    // Each leaf adds at least one and at most two nodes.
+    let mut leafs = (size + 1) / 2;
+    while get_mmr_size(leafs) < size {
+        leafs += 1;
+    }
+    ensure!(
+        get_mmr_size(leafs) == size,
+        "{} nodes do not form an MMR",
+        size
+    );
+
+    Ok(leafs)
+}
+
+// Returns the first leaf and height of each peak of an MMR of `leafs`
+// leafs, left to right.
+fn get_peaks(leafs: usize) -> Vec<(usize, usize)> {
+    let mut peaks = Vec::new();
+    let mut start = 0;
+    for height in (0..usize::MAX.count_ones() as usize).rev() {
+        if leafs >> height & 1 == 1 {
+            peaks.push((start, height));
+            start += 1 << height;
+        }
+    }
+
+    peaks
+}
+
+// Returns the position of the root of the perfect tree of `height`
+// whose first leaf is `start` (a multiple of 2^height).
+fn get_node_pos(start: usize, height: usize) -> usize {
+    get_mmr_size(start) + (1 << (height + 1)) - 2
+}
+
+// Bags the peaks of an MMR of `leafs` leafs from right to left.
+fn bag_peaks<E: Element, A: Algorithm<E>>(leafs: usize, peaks: &[E]) -> E {
+    let heights = get_peaks(leafs);
+    let mut a = A::default();
+    let mut root = peaks[peaks.len() - 1].clone();
+    for (peak, (_, height)) in peaks.iter().zip(heights.iter()).rev().skip(1) {
+        a.reset();
+        root = a.node(peak.clone(), root, *height);
+    }
+
+    root
+}
+
+// Returns the hash of the perfect tree of `height` whose first leaf
+// is `start`, in an MMR grown from one of `old_leafs` leafs.  Trees
+// made of old leafs only are peaks of the old MMR, read with
+// `old_peak`, and those made of new leafs only are read with
+// `new_node`; the others are hashed from their two halves.
+fn walk_range<E, A, F, G>(
+    start: usize,
+    height: usize,
+    old_leafs: usize,
+    old_peak: &mut F,
+    new_node: &mut G,
+) -> Result<E>
+where
+    E: Element,
+    A: Algorithm<E>,
+    F: FnMut(usize, usize) -> Result<E>,
+    G: FnMut(usize, usize) -> Result<E>,
+{
+    if start + (1 << height) <= old_leafs {
+        return old_peak(start, height);
+    }
+    if start >= old_leafs {
+        return new_node(start, height);
+    }
+
+    let half = 1 << (height - 1);
+    let left = walk_range::<E, A, F, G>(start, height - 1, old_leafs, old_peak, new_node)?;
+    let right = walk_range::<E, A, F, G>(start + half, height - 1, old_leafs, old_peak, new_node)?;
+
+    Ok(A::default().node(left, right, height - 1))
+}
+
+#[cfg(test)]
+use crate::store::{DiskStore, VecStore};
+#[cfg(test)]
+use crate::test_common::{Item, XOR128};
+
+#[cfg(test)]
+fn get_item(i: usize) -> Item {
+    let mut a = XOR128::new();
+    (i * 93).hash(&mut a);
+    a.hash()
+}
+
+#[cfg(test)]
+fn test_mmr<S: Store<Item>>(count: usize) {
+    let mut mmr: MerkleMountainRange<Item, XOR128, S> =
+        MerkleMountainRange::new(count).expect("failed to create MMR");
+    assert!(mmr.root().is_err());
+
+    let mut roots = Vec::new();
+    for i in 0..count {
+        mmr.append(get_item(i)).expect("failed to append");
+        assert_eq!(mmr.leafs(), i + 1);
+        assert_eq!(mmr.store().len(), mmr.len());
+        assert_eq!(mmr.peaks().unwrap().len(), (i + 1).count_ones() as usize);
+        roots.push(mmr.root().unwrap());
+    }
+    assert!(mmr.append(get_item(count)).is_err());
+
+    let root = mmr.root().unwrap();
+    for i in 0..count {
+        let p = mmr.gen_proof(i).expect("failed to generate proof");
+        assert_eq!(*p.item(), XOR128::new().leaf(get_item(i)));
+        assert!(p.validate::<XOR128>(&root));
+        assert!(!p.validate::<XOR128>(&roots[0]) || count == 1);
+
+        let mut broken = p.clone();
+        if !broken.siblings.is_empty() {
+            broken.siblings[0][0] ^= 0xff;
+            assert!(!broken.validate::<XOR128>(&root));
+        }
+    }
+
+    for old_leafs in 1..=count {
+        let p = mmr
+            .gen_consistency_proof(old_leafs)
+            .expect("failed to generate consistency proof");
+        assert!(p
+            .validate::<XOR128>(&roots[old_leafs - 1], &root)
+            .expect("failed to validate"));
+        if old_leafs > 1 {
+            assert!(!p
+                .validate::<XOR128>(&roots[old_leafs - 2], &root)
+                .expect("failed to validate"));
+        }
+        if !p.nodes().is_empty() {
+            let mut broken = p.clone();
+            broken.nodes.pop();
+            assert!(!broken
+                .validate::<XOR128>(&roots[old_leafs - 1], &root)
+                .expect("failed to validate"));
+        }
+    }
+    assert!(mmr.gen_consistency_proof(0).is_err());
+    assert!(mmr.gen_consistency_proof(count + 1).is_err());
+}
+
+#[test]
+fn test_mmr_vec() {
+    test_mmr::<VecStore<_>>(1);
+    test_mmr::<VecStore<_>>(37);
+}
+
+#[test]
+fn test_mmr_disk() {
+    test_mmr::<DiskStore<_>>(19);
+}
+
+#[test]
+fn test_mmr_layout() {
+    assert_eq!(get_mmr_size(7), 11);
+    assert_eq!(get_peaks(7), vec![(0, 2), (4, 1), (6, 0)]);
+    assert_eq!(get_node_pos(0, 2), 6);
+    assert_eq!(get_node_pos(4, 1), 9);
+    assert_eq!(get_node_pos(6, 0), 10);
+    assert_eq!(get_node_pos(3, 0), 4);
+
+    for leafs in 0..100 {
+        assert_eq!(get_mmr_leafs(get_mmr_size(leafs)).unwrap(), leafs);
+    }
+    assert!(get_mmr_leafs(2).is_err());
+    assert!(get_mmr_leafs(5).is_err());
+}
+
+#[test]
+fn test_mmr_reopen() {
+    let temp_dir = tempdir::TempDir::new("test_mmr_reopen").unwrap();
+    let config = StoreConfig::new(temp_dir.path(), String::from("mmr"), 0);
+
+    let mut mmr: MerkleMountainRange<Item, XOR128, DiskStore<_>> =
+        MerkleMountainRange::new_with_config(10, config.clone()).expect("failed to create MMR");
+    mmr.extend((0..10).map(get_item)).expect("failed to append");
+    assert!(mmr.extend((10..12).map(get_item)).is_err());
+    assert_eq!(mmr.leafs(), 10);
+    mmr.sync().expect("failed to sync");
+    let root = mmr.root().unwrap();
+    drop(mmr);
+
+    assert!(
+        MerkleMountainRange::<Item, XOR128, DiskStore<_>>::new_with_config(10, config.clone())
+            .is_err()
+    );
+
+    let store = DiskStore::new_from_disk(get_mmr_size(10), 2, &config).unwrap();
+    let mmr: MerkleMountainRange<Item, XOR128, DiskStore<_>> =
+        MerkleMountainRange::from_store(store, 10).expect("failed to open MMR");
+    assert_eq!(mmr.leafs(), 10);
+    assert_eq!(mmr.root().unwrap(), root);
+
+    // The leafs appended to a store in memory carry over, and the MMR
+    // keeps growing from them.
+    let mut store = VecStore::new(get_mmr_size(16)).unwrap();
+    for i in 0..mmr.len() {
+        store.push(mmr.store().read_at(i).unwrap()).unwrap();
+    }
+    let mut mmr: MerkleMountainRange<Item, XOR128, VecStore<_>> =
+        MerkleMountainRange::from_store(store, 16).expect("failed to open MMR");
+    assert_eq!(mmr.leafs(), 10);
+    assert_eq!(mmr.root().unwrap(), root);
+
+    mmr.append(get_item(10)).expect("failed to append");
+    let p = mmr.gen_consistency_proof(10).unwrap();
+    assert!(p
+        .validate::<XOR128>(&root, &mmr.root().unwrap())
+        .expect("failed to validate"));
+
+    let mut store = VecStore::new(2).unwrap();
+    store.push(get_item(0)).unwrap();
+    store.push(get_item(1)).unwrap();
+    assert!(MerkleMountainRange::<Item, XOR128, VecStore<_>>::from_store(store, 16).is_err());
+}
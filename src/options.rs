@@ -0,0 +1,270 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use anyhow::Result;
+use rayon::ThreadPool;
+
+use crate::merkle::{OddRowPolicy, BUILD_DATA_BLOCK_SIZE};
+use crate::store::{StoreConfig, BUILD_CHUNK_NODES, SMALL_TREE_BUILD};
+
+/// Token shared with a merkle tree build, to cancel it from another
+/// thread.  A cancelled build stops at the next row (or block of
+/// leafs) with an error, and the store files it created from a
+/// `StoreConfig` are removed.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels the builds using this token.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Callback called with the index of each row of a tree above the
+/// leafs as soon as it is built, and the number of rows of the tree.
+pub type ProgressFn = dyn Fn(usize, usize) + Send + Sync;
+
+/// Options of the merkle tree builds: the rayon thread pool they run
+/// on, the sizes of the chunks of work they are split into, a
+/// progress callback and a cancellation token.
+///
+/// The options are given to the `MerkleTree` constructors through
+/// `TreeOptions::with_build_options`, or to `Store::build_with_options`:
+///
+/// ```ignore
+/// let options = BuildOptions::new()
+///     .with_thread_pool(pool)
+///     .with_progress(|row, row_count| println!("row {}/{}", row, row_count))
+///     .with_cancel_token(token.clone());
+/// let tree = MerkleTree::from_par_iter_with_options(
+///     leafs,
+///     &TreeOptions::new().with_config(config).with_build_options(options),
+/// )?;
+/// ```
+///
+/// By default, builds use the global rayon pool and the default chunk
+/// sizes (`SMALL_TREE_BUILD`, `BUILD_CHUNK_NODES` and
+/// `BUILD_DATA_BLOCK_SIZE`).
+#[derive(Clone)]
+pub struct BuildOptions {
+    pool: Option<Arc<ThreadPool>>,
+    small_tree_build: usize,
+    chunk_nodes: usize,
+    data_block_size: usize,
+    progress: Option<Arc<ProgressFn>>,
+    cancel: Option<CancelToken>,
+}
+
+impl Default for BuildOptions {
+    fn default() -> Self {
+        BuildOptions {
+            pool: None,
+            small_tree_build: SMALL_TREE_BUILD,
+            chunk_nodes: BUILD_CHUNK_NODES,
+            data_block_size: BUILD_DATA_BLOCK_SIZE,
+            progress: None,
+            cancel: None,
+        }
+    }
+}
+
+impl fmt::Debug for BuildOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BuildOptions")
+            .field(
+                "pool",
+                &self.pool.as_ref().map(|pool| pool.current_num_threads()),
+            )
+            .field("small_tree_build", &self.small_tree_build)
+            .field("chunk_nodes", &self.chunk_nodes)
+            .field("data_block_size", &self.data_block_size)
+            .field("progress", &self.progress.is_some())
+            .field("cancel", &self.cancel)
+            .finish()
+    }
+}
+
+impl BuildOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs the builds on `pool` rather than on the global rayon pool.
+    pub fn with_thread_pool(mut self, pool: Arc<ThreadPool>) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+
+    /// Builds trees of up to `leafs` leafs in memory (see
+    /// `SMALL_TREE_BUILD`).
+    pub fn with_small_tree_build(mut self, leafs: usize) -> Self {
+        self.small_tree_build = leafs;
+        self
+    }
+
+    /// Hashes rows `nodes` at a time in each thread (see
+    /// `BUILD_CHUNK_NODES`).
+    pub fn with_chunk_nodes(mut self, nodes: usize) -> Self {
+        self.chunk_nodes = std::cmp::max(nodes, 1);
+        self
+    }
+
+    /// Hashes and writes the leafs `leafs` at a time (see
+    /// `BUILD_DATA_BLOCK_SIZE`).
+    pub fn with_data_block_size(mut self, leafs: usize) -> Self {
+        self.data_block_size = std::cmp::max(leafs, 1);
+        self
+    }
+
+    /// Calls `progress` each time a row of a tree is built (see
+    /// `ProgressFn`).
+    pub fn with_progress<F: Fn(usize, usize) + Send + Sync + 'static>(
+        mut self,
+        progress: F,
+    ) -> Self {
+        self.progress = Some(Arc::new(progress));
+        self
+    }
+
+    /// Cancels the builds when `cancel` is.
+    pub fn with_cancel_token(mut self, cancel: CancelToken) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    // Runs `op` in the thread pool of the options, if any.
+    pub(crate) fn run<R, F>(&self, op: F) -> R
+    where
+        R: Send,
+        F: FnOnce() -> R + Send,
+    {
+        match &self.pool {
+            Some(pool) => pool.install(op),
+            None => op(),
+        }
+    }
+
+    pub fn small_tree_build(&self) -> usize {
+        self.small_tree_build
+    }
+
+    pub fn data_block_size(&self) -> usize {
+        self.data_block_size
+    }
+
+    /// Number of nodes to hash in each thread at a time for a row
+    /// hashed with `branches` children per node (see
+    /// `store::build_chunk_nodes`).
+    pub fn build_chunk_nodes(&self, branches: usize) -> usize {
+        std::cmp::max(self.chunk_nodes / branches, 1) * branches
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel
+            .as_ref()
+            .map(|cancel| cancel.is_cancelled())
+            .unwrap_or(false)
+    }
+
+    /// Fails if the build was cancelled.
+    pub fn check_cancelled(&self) -> Result<()> {
+        ensure!(!self.is_cancelled(), "merkle tree build cancelled");
+        Ok(())
+    }
+
+    /// Reports that the row `row` of a tree of `row_count` rows is
+    /// built.
+    pub fn report_row(&self, row: usize, row_count: usize) {
+        if let Some(progress) = &self.progress {
+            progress(row, row_count);
+        }
+    }
+}
+
+/// Options of the construction of a base `MerkleTree`: the arity
+/// schedule of its rows, its odd row policy, the config of the store
+/// holding it and the options of its build (see `BuildOptions`).
+///
+/// ```ignore
+/// let options = TreeOptions::new()
+///     .with_arity(4)
+///     .with_policy(OddRowPolicy::Pad)
+///     .with_config(config);
+/// let tree = MerkleTree::try_from_iter_with_options(leafs, &options)?;
+/// ```
+///
+/// By default, trees have the base tree arity of their type, reject
+/// odd rows, are held by a store created without a config and are
+/// built with the default `BuildOptions`.
+#[derive(Clone, Debug, Default)]
+pub struct TreeOptions {
+    arities: Option<Vec<usize>>,
+    policy: OddRowPolicy,
+    config: Option<StoreConfig>,
+    build: BuildOptions,
+}
+
+impl TreeOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builds trees of arity `branches`, which must match the base
+    /// tree arity of the tree type unless it is 0.
+    pub fn with_arity(self, branches: usize) -> Self {
+        self.with_arity_schedule(&[branches])
+    }
+
+    /// Builds trees with the arity schedule `arities` (see
+    /// `merkle::row_arity`).
+    pub fn with_arity_schedule(mut self, arities: &[usize]) -> Self {
+        self.arities = Some(arities.to_vec());
+        self
+    }
+
+    /// Builds trees with the odd row policy `policy`.
+    pub fn with_policy(mut self, policy: OddRowPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Holds the trees in stores created from `config`.
+    pub fn with_config(mut self, config: StoreConfig) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Builds trees with the build options `build`.
+    pub fn with_build_options(mut self, build: BuildOptions) -> Self {
+        self.build = build;
+        self
+    }
+
+    /// Returns the arity schedule of the trees, if not the base tree
+    /// arity of the tree type.
+    pub fn arity_schedule(&self) -> Option<&[usize]> {
+        self.arities.as_deref()
+    }
+
+    pub fn policy(&self) -> OddRowPolicy {
+        self.policy
+    }
+
+    pub fn config(&self) -> Option<&StoreConfig> {
+        self.config.as_ref()
+    }
+
+    pub fn build_options(&self) -> &BuildOptions {
+        &self.build
+    }
+}
@@ -1,17 +1,26 @@
 use crate::hash::{Algorithm, Hashable};
-use crate::merkle::get_merkle_proof_lemma_len;
-
-use anyhow::Result;
+use crate::merkle::{
+    get_merkle_proof_lemma_len_with_schedule, get_merkle_tree_row_count_with_schedule, row_arity,
+    Element, OddRowPolicy,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
 use std::marker::PhantomData;
+use std::ops;
 use typenum::marker_traits::Unsigned;
-use typenum::U2;
+use typenum::{U0, U2};
 
 #[cfg(test)]
-use typenum::{U0, U1, U3, U4, U5, U8};
+use typenum::{U1, U3, U4, U5, U8};
 
 #[cfg(test)]
 use crate::merkle::MerkleTree;
 #[cfg(test)]
+use crate::options::TreeOptions;
+#[cfg(test)]
 use crate::store::VecStore;
 #[cfg(test)]
 use crate::test_common::{get_vec_tree_from_slice, Item, XOR128};
@@ -25,22 +34,44 @@ use crate::test_common::{get_vec_tree_from_slice, Item, XOR128};
 /// ```
 ///
 /// Proof validation is positioned hash against lemma path to match root hash.
-#[derive(Debug, Clone, Eq, PartialEq)]
+///
+/// Proofs can be serialized with serde, or to the compact binary
+/// format of `to_bytes`.  Both record the tree arities, and decoding
+/// fails if the base tree arity is not `BaseTreeArity`.
+///
+/// If `BaseTreeArity` is U0, the base tree arity is only known at
+/// runtime (see `DynMerkleTree`), and proofs of any base tree arity
+/// are accepted.  Such proofs are bit-identical to the ones of the
+/// matching typed arity, see `into_arity`.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(
+    into = "ProofData<T>",
+    try_from = "ProofData<T>",
+    bound(serialize = "T: Serialize", deserialize = "T: Deserialize<'de>")
+)]
 /// U is the default tree arity (U2 = binary)
 pub struct Proof<T: Eq + Clone + AsRef<[u8]>, BaseTreeArity: Unsigned = U2> {
     // Optional proofs at immediate lower level from current.  Should
     // be None at the base layer.
     pub sub_tree_proof: Option<Box<Proof<T, BaseTreeArity>>>,
 
-    top_layer_nodes: usize,      // arity of top layer
-    sub_tree_layer_nodes: usize, // arity of sub-tree layer
+    top_layer_nodes: usize,        // arity of top layer
+    sub_tree_layer_nodes: usize,   // arity of sub-tree layer
+    base_tree_arities: Vec<usize>, // arity schedule of base tree
 
     lemma: Vec<T>,
     path: Vec<usize>, // branch index
 
+    policy: OddRowPolicy, // odd row handling of the base tree
+    leafs: usize,         // base tree leafs (only tracked if not `Reject`)
+
     _u: PhantomData<BaseTreeArity>, // number of branches per node
 }
 
+/// Inclusion proof of a `DynMerkleTree`, whose base tree arity is
+/// only known at runtime.
+pub type DynProof<T> = Proof<T, U0>;
+
 impl<T: Eq + Clone + AsRef<[u8]>, BaseTreeArity: Unsigned> Proof<T, BaseTreeArity> {
     /// Creates new MT inclusion proof
     pub fn new<TopLayerArity: Unsigned, SubTreeArity: Unsigned>(
@@ -48,179 +79,1912 @@ impl<T: Eq + Clone + AsRef<[u8]>, BaseTreeArity: Unsigned> Proof<T, BaseTreeArit
         lemma: Vec<T>,
         path: Vec<usize>,
     ) -> Result<Proof<T, BaseTreeArity>> {
+        let base_tree_arities = match &sub_tree_proof {
+            Some(sub_tree_proof) => sub_tree_proof.base_tree_arities.clone(),
+            None => vec![BaseTreeArity::to_usize()],
+        };
         if TopLayerArity::to_usize() == 0 && SubTreeArity::to_usize() == 0 {
+            ensure!(
+                base_tree_arities[0] > 0,
+                "Runtime arity proofs must be created with new_with_arity"
+            );
             ensure!(lemma.len() > 2, "Invalid lemma length (short)");
             ensure!(
                 lemma.len()
-                    == get_merkle_proof_lemma_len(path.len() + 1, BaseTreeArity::to_usize()),
+                    == get_merkle_proof_lemma_len_with_schedule(path.len() + 1, &base_tree_arities),
+                "Invalid lemma length"
+            );
+        }
+
+        Ok(Proof {
+            sub_tree_proof,
+            top_layer_nodes: TopLayerArity::to_usize(),
+            sub_tree_layer_nodes: SubTreeArity::to_usize(),
+            base_tree_arities,
+            lemma,
+            path,
+
+            policy: OddRowPolicy::Reject,
+            leafs: 0,
+
+            _u: PhantomData,
+        })
+    }
+
+    /// Creates new base layer MT inclusion proof for a tree whose
+    /// odd rows are handled according to `policy`.
+    ///
+    /// Only the actual siblings of the proven node are part of the
+    /// lemma (promoted rows contribute none, and filler nodes are
+    /// omitted), so the number of base tree `leafs` is recorded to
+    /// allow the row widths to be recomputed during validation.
+    pub fn new_with_policy(
+        lemma: Vec<T>,
+        path: Vec<usize>,
+        policy: OddRowPolicy,
+        leafs: usize,
+    ) -> Result<Proof<T, BaseTreeArity>> {
+        Self::new_with_arity(lemma, path, BaseTreeArity::to_usize(), policy, leafs)
+    }
+
+    /// Creates new base layer MT inclusion proof for a tree of arity
+    /// `branches`, whose odd rows are handled according to `policy`.
+    ///
+    /// `branches` must be `BaseTreeArity`, unless it is U0.
+    pub fn new_with_arity(
+        lemma: Vec<T>,
+        path: Vec<usize>,
+        branches: usize,
+        policy: OddRowPolicy,
+        leafs: usize,
+    ) -> Result<Proof<T, BaseTreeArity>> {
+        Self::new_with_schedule(lemma, path, &[branches], policy, leafs)
+    }
+
+    /// Creates new base layer MT inclusion proof for a tree with the
+    /// arity schedule `arities` (see `merkle::row_arity`), whose odd
+    /// rows are handled according to `policy`.
+    ///
+    /// Every arity must be `BaseTreeArity`, unless it is U0.
+    pub fn new_with_schedule(
+        lemma: Vec<T>,
+        path: Vec<usize>,
+        arities: &[usize],
+        policy: OddRowPolicy,
+        leafs: usize,
+    ) -> Result<Proof<T, BaseTreeArity>> {
+        ensure!(!arities.is_empty(), "Arity schedules must not be empty");
+        for branches in arities {
+            ensure!(
+                *branches > 0
+                    && (BaseTreeArity::to_usize() == 0 || BaseTreeArity::to_usize() == *branches),
+                "Invalid base tree arity {} (expected: {})",
+                branches,
+                BaseTreeArity::to_usize()
+            );
+        }
+
+        if policy == OddRowPolicy::Reject {
+            ensure!(lemma.len() > 2, "Invalid lemma length (short)");
+            ensure!(
+                lemma.len() == get_merkle_proof_lemma_len_with_schedule(path.len() + 1, arities),
                 "Invalid lemma length"
             );
+
+            return Ok(Proof {
+                sub_tree_proof: None,
+                top_layer_nodes: 0,
+                sub_tree_layer_nodes: 0,
+                base_tree_arities: arities.to_vec(),
+                lemma,
+                path,
+
+                policy,
+                leafs: 0,
+
+                _u: PhantomData,
+            });
+        }
+
+        policy.ensure_valid_schedule(leafs, arities)?;
+        ensure!(lemma.len() >= 2, "Invalid lemma length (short)");
+        ensure!(
+            path.len() + 1 == get_merkle_tree_row_count_with_schedule(leafs, arities, policy),
+            "Invalid path length"
+        );
+
+        Ok(Proof {
+            sub_tree_proof: None,
+            top_layer_nodes: 0,
+            sub_tree_layer_nodes: 0,
+            base_tree_arities: arities.to_vec(),
+            lemma,
+            path,
+
+            policy,
+            leafs,
+
+            _u: PhantomData,
+        })
+    }
+
+    /// Creates new compound layer MT inclusion proof, with runtime
+    /// arities rather than the ones of a tree type.
+    ///
+    /// `top_layer_nodes` is only set for the top layer of a three
+    /// layer tree, in which case `sub_tree_layer_nodes` is the arity of
+    /// the layer below it.  Otherwise this is a sub-tree layer of
+    /// arity `sub_tree_layer_nodes`, over either a base layer proof or
+    /// another sub-tree layer proof.
+    pub fn new_compound(
+        sub_tree_proof: Proof<T, BaseTreeArity>,
+        lemma: Vec<T>,
+        path: Vec<usize>,
+        top_layer_nodes: usize,
+        sub_tree_layer_nodes: usize,
+    ) -> Result<Proof<T, BaseTreeArity>> {
+        let arity = if top_layer_nodes > 0 {
+            top_layer_nodes
+        } else {
+            sub_tree_layer_nodes
+        };
+        ensure!(arity > 0, "Invalid compound layer arity");
+        ensure!(lemma.len() == arity, "Invalid lemma length");
+        ensure!(path.len() == 1, "Invalid path length");
+
+        Ok(Proof {
+            base_tree_arities: sub_tree_proof.base_tree_arities.clone(),
+            sub_tree_proof: Some(Box::new(sub_tree_proof)),
+            top_layer_nodes,
+            sub_tree_layer_nodes,
+            lemma,
+            path,
+
+            policy: OddRowPolicy::Reject,
+            leafs: 0,
+
+            _u: PhantomData,
+        })
+    }
+
+    /// Return proof target leaf
+    pub fn item(&self) -> T {
+        self.lemma.first().unwrap().clone()
+    }
+
+    /// Return sub tree root
+    pub fn sub_tree_root(&self) -> T {
+        assert!(self.sub_tree_layer_nodes > 0 && self.sub_tree_proof.is_some());
+        self.sub_tree_proof.as_ref().unwrap().root()
+    }
+
+    /// Return tree root
+    pub fn root(&self) -> T {
+        self.lemma.last().unwrap().clone()
+    }
+
+    /// Validates sub-tree proofs with the specified arity.
+    fn validate_sub_tree_proof<A: Algorithm<T>>(&self, arity: usize) -> Result<bool> {
+        // Ensure that the sub_tree validates to the root of that
+        // sub_tree.
+        let valid = self.sub_tree_proof.as_ref().unwrap().validate::<A>()?;
+        if !valid {
+            return Ok(valid);
+        }
+
+        // Validate top-most/current layer
+        //
+        // Check that the remaining proof matches the tree root (note
+        // that Proof::validate at the base layer cannot handle a
+        // proof this small, so this is a version specific for what we
+        // know we have in this case).
+        let mut a = A::default();
+        a.reset();
+        let node_count = arity;
+        let h = {
+            let mut nodes: Vec<T> = Vec::with_capacity(node_count);
+            let mut cur_index = 0;
+            for j in 0..node_count {
+                if j == self.path()[0] {
+                    nodes.push(self.sub_tree_root().clone());
+                } else {
+                    nodes.push(self.lemma()[cur_index].clone());
+                    cur_index += 1;
+                }
+            }
+
+            if cur_index != node_count - 1 {
+                return Ok(false);
+            }
+
+            a.multi_node(&nodes, 0)
+        };
+
+        Ok(h == self.root())
+    }
+
+    /// Verifies MT inclusion proof
+    pub fn validate<A: Algorithm<T>>(&self) -> Result<bool> {
+        if self.top_layer_nodes > 0 {
+            // Special Top layer handling here.
+            ensure!(
+                self.sub_tree_proof.is_some(),
+                "Sub tree proof must be present for validation"
+            );
+
+            return self.validate_sub_tree_proof::<A>(self.top_layer_nodes);
+        }
+
+        if self.sub_tree_layer_nodes > 0 {
+            // Sub-tree layer handling here.
+            ensure!(
+                self.sub_tree_proof.is_some(),
+                "Sub tree proof must be present for validation"
+            );
+
+            return self.validate_sub_tree_proof::<A>(self.sub_tree_layer_nodes);
+        }
+
+        // Base layer handling here.
+        ensure!(
+            self.sub_tree_layer_nodes == 0,
+            "Base layer proof must have 0 as sub-tree layer node count"
+        );
+        ensure!(
+            self.top_layer_nodes == 0,
+            "Base layer proof must have 0 as top layer node count"
+        );
+        ensure!(self.sub_tree_proof.is_none(), "Sub tree proof must be None");
+
+        if self.policy != OddRowPolicy::Reject {
+            return self.validate_with_policy::<A>();
+        }
+
+        let size = self.lemma.len();
+        if size < 2 {
+            return Ok(false);
+        }
+
+        let mut a = A::default();
+        let mut h = self.item();
+
+        // 'i' is the lemma index of the first sibling of each row.
+        let mut i = 1;
+        for (row, path_index) in self.path.iter().enumerate() {
+            let branches = self.row_nodes(row);
+            if *path_index >= branches || i + branches - 1 > size - 1 {
+                return Ok(false);
+            }
+
+            a.reset();
+            h = {
+                let mut nodes: Vec<T> = Vec::with_capacity(branches);
+                let mut cur_index = 0;
+                for j in 0..branches {
+                    if j == *path_index {
+                        nodes.push(h.clone());
+                    } else {
+                        nodes.push(self.lemma[i + cur_index].clone());
+                        cur_index += 1;
+                    }
+                }
+
+                a.multi_node(&nodes, row)
+            };
+            i += branches - 1;
+        }
+
+        if i != size - 1 {
+            return Ok(false);
+        }
+
+        Ok(h == self.root())
+    }
+
+    /// Verifies a base layer proof whose rows may be short, walking
+    /// the rows of the tree to know where siblings are missing.
+    fn validate_with_policy<A: Algorithm<T>>(&self) -> Result<bool> {
+        if self.lemma.len() < 2 {
+            return Ok(false);
+        }
+
+        // Recover the leaf index from the path, lowest row first.
+        let mut j = match self.path_leaf_index() {
+            Some(j) if j < self.leafs => j,
+            _ => return Ok(false),
+        };
+
+        let mut h = self.item();
+        let mut width = self.leafs;
+        let mut cur_index = 1;
+
+        for (level, path_index) in self.path.iter().enumerate() {
+            let branches = self.row_nodes(level);
+            let hash_index = (j / branches) * branches;
+            let hash_len = std::cmp::min(branches, width - hash_index);
+            if *path_index >= hash_len {
+                return Ok(false);
+            }
+
+            if cur_index + hash_len > self.lemma.len() {
+                return Ok(false);
+            }
+
+            let mut nodes: Vec<T> = Vec::with_capacity(hash_len);
+            for k in 0..hash_len {
+                if k == *path_index {
+                    nodes.push(h.clone());
+                } else {
+                    nodes.push(self.lemma[cur_index].clone());
+                    cur_index += 1;
+                }
+            }
+
+            if self.policy.is_mutated(&nodes, branches) {
+                return Ok(false);
+            }
+            h = self.policy.hash_nodes::<T, A>(&nodes, branches, level);
+
+            width = self.policy.next_row_width(width, branches);
+            j /= branches;
+        }
+
+        if cur_index != self.lemma.len() - 1 {
+            return Ok(false);
+        }
+
+        Ok(h == self.root())
+    }
+
+    /// Verifies MT inclusion proof against a `trusted_root` obtained
+    /// independently of the proof, and that the path of every
+    /// (sub-tree) level encodes `expected_leaf_index` in a tree of
+    /// `trusted_leafs` leafs (see `MerkleTree::leafs`).
+    ///
+    /// `validate` only checks that the lemma hashes up to the root
+    /// stored in the proof itself, which anyone can forge.  The leaf
+    /// count must be trusted too, as the proof of a tree of another
+    /// number of leafs (or with a short path) can prove an interior
+    /// node of the tree as if it were a leaf.
+    ///
+    /// The compound layers of the tree must hold sub-trees of the
+    /// same size, but for the last one which may be smaller (see
+    /// `leaf_index`).  Proofs of compound trees of unequal sub-trees
+    /// are validated with `validate_against_offsets` instead.
+    pub fn validate_against<A: Algorithm<T>>(
+        &self,
+        trusted_root: &T,
+        expected_leaf_index: usize,
+        trusted_leafs: usize,
+    ) -> Result<bool> {
+        if self.check_shape().is_err()
+            || !self.has_leaf_count(trusted_leafs)
+            || self.root() != *trusted_root
+            || self.leaf_index() != Some(expected_leaf_index)
+        {
+            return Ok(false);
+        }
+
+        self.validate::<A>()
+    }
+
+    /// Verifies the proof of a compound tree whose top compound layer
+    /// holds sub-trees of unequal sizes (see `MerkleTree::from_trees`)
+    /// against a `trusted_root`, and that it is for the leaf
+    /// `expected_leaf_index` of a tree whose slots start at the
+    /// `trusted_offsets` (see `MerkleTree::leaf_offsets`).
+    ///
+    /// The proven sub-tree is validated as by `validate_against`, so
+    /// that its own compound layers (if any) must hold sub-trees of
+    /// the same size.
+    pub fn validate_against_offsets<A: Algorithm<T>>(
+        &self,
+        trusted_root: &T,
+        expected_leaf_index: usize,
+        trusted_offsets: &[usize],
+    ) -> Result<bool> {
+        let sub_tree_proof = self
+            .sub_tree_proof
+            .as_ref()
+            .ok_or_else(|| anyhow!("Only compound tree proofs have leaf offsets"))?;
+        ensure!(
+            trusted_offsets.len() > 1 && trusted_offsets.windows(2).all(|w| w[0] <= w[1]),
+            "Leaf offsets must be increasing, and end with the number of leafs"
+        );
+
+        if self.check_shape().is_err()
+            || trusted_offsets.len() != self.layer_arity() + 1
+            || self.root() != *trusted_root
+        {
+            return Ok(false);
+        }
+
+        // The shape was checked, so the path holds the proven slot.
+        let (start, end) = (
+            trusted_offsets[self.path[0]],
+            trusted_offsets[self.path[0] + 1],
+        );
+        if expected_leaf_index < start
+            || expected_leaf_index >= end
+            || !sub_tree_proof.has_leaf_count(end - start)
+            || sub_tree_proof.leaf_index() != Some(expected_leaf_index - start)
+        {
+            return Ok(false);
+        }
+
+        self.validate::<A>()
+    }
+
+    /// Returns the index of the proven leaf as encoded by the path of
+    /// every level of this proof, or `None` if any path is invalid.
+    ///
+    /// The path of a compound layer only holds the slot of the proven
+    /// sub-tree, so the index assumes that the sub-trees before it
+    /// hold as many leafs as the proven one would if it were full.
+    /// For compound trees of unequal sub-trees, the slot must instead
+    /// be checked against the offsets of the tree layer (see
+    /// `validate_against_offsets`).
+    pub fn leaf_index(&self) -> Option<usize> {
+        match &self.sub_tree_proof {
+            Some(sub_tree_proof) => {
+                if self.path.len() != 1 || self.path[0] >= self.layer_arity() {
+                    return None;
+                }
+
+                let index = sub_tree_proof.leaf_index()?;
+                Some(self.path[0] * sub_tree_proof.leaf_count() + index)
+            }
+            None => {
+                let index = self.path_leaf_index()?;
+                if index >= self.leaf_count() {
+                    return None;
+                }
+
+                Some(index)
+            }
         }
+    }
+
+    /// Returns the number of rows of the tree below the root of this
+    /// proof, as encoded by the path of every level.
+    pub fn row_count(&self) -> usize {
+        match &self.sub_tree_proof {
+            Some(sub_tree_proof) => sub_tree_proof.row_count() + 1,
+            None => self.path.len() + 1,
+        }
+    }
+
+    // Returns the arity of row `row` of the base tree.
+    fn row_nodes(&self, row: usize) -> usize {
+        row_arity(&self.base_tree_arities, row)
+    }
+
+    // Returns the number of leafs of a base tree with full rows and
+    // one row per path digit.
+    fn full_leaf_count(&self) -> usize {
+        (0..self.path.len())
+            .map(|row| self.row_nodes(row))
+            .product()
+    }
+
+    // Returns the leaf index encoded by the path of a base layer
+    // proof, or `None` if a digit is out of range for its row.
+    fn path_leaf_index(&self) -> Option<usize> {
+        // The path lists branch indices from the leaf row up.
+        let mut index = 0;
+        let mut stride = 1;
+        for (row, digit) in self.path.iter().enumerate() {
+            let branches = self.row_nodes(row);
+            if *digit >= branches {
+                return None;
+            }
+
+            index += digit * stride;
+            stride *= branches;
+        }
+
+        Some(index)
+    }
+
+    // Returns the number of leafs below the root of this proof, with
+    // full compound layers.
+    fn leaf_count(&self) -> usize {
+        match &self.sub_tree_proof {
+            Some(sub_tree_proof) => self.layer_arity() * sub_tree_proof.leaf_count(),
+            None if self.policy == OddRowPolicy::Reject => self.full_leaf_count(),
+            None => self.leafs,
+        }
+    }
+
+    // Returns true if this proof is of a tree of `leafs` leafs, whose
+    // compound layers hold sub-trees of the same size but for the
+    // last one, which may be smaller.  The shape of the proof must
+    // have been checked.
+    fn has_leaf_count(&self, leafs: usize) -> bool {
+        match &self.sub_tree_proof {
+            Some(sub_tree_proof) => {
+                // The proven sub-tree would hold `slot_leafs` if full.
+                let slot_leafs = sub_tree_proof.leaf_count();
+                let start = self.path[0] * slot_leafs;
+                slot_leafs > 0
+                    && start < leafs
+                    && (leafs + slot_leafs - 1) / slot_leafs <= self.layer_arity()
+                    && sub_tree_proof.has_leaf_count(std::cmp::min(slot_leafs, leafs - start))
+            }
+            None => self.leaf_count() == leafs,
+        }
+    }
+
+    // Returns the number of slots of the compound layer of this
+    // proof (0 for a base layer proof).
+    fn layer_arity(&self) -> usize {
+        if self.top_layer_nodes > 0 {
+            self.top_layer_nodes
+        } else {
+            self.sub_tree_layer_nodes
+        }
+    }
+
+    /// Verifies MT inclusion proof, reporting the layer and the check
+    /// which failed if the proof is rejected.
+    ///
+    /// The shape of every layer (sub-tree proofs, path length, path
+    /// digits and lemma length) is checked before any hashing is done.
+    pub fn validate_strict<A: Algorithm<T>>(&self) -> std::result::Result<(), ProofError> {
+        self.check_shape()?;
+        self.check_roots::<A>().map(|_| ())
+    }
+
+    /// Strict version of `validate_against`.
+    pub fn validate_strict_against<A: Algorithm<T>>(
+        &self,
+        trusted_root: &T,
+        expected_leaf_index: usize,
+        trusted_leafs: usize,
+    ) -> std::result::Result<(), ProofError> {
+        self.check_shape()?;
+
+        if !self.has_leaf_count(trusted_leafs) {
+            return Err(ProofError::LeafCountMismatch {
+                expected: trusted_leafs,
+                actual: self.leaf_count(),
+            });
+        }
+
+        // The shape was checked, so the path encodes a valid index.
+        let index = self.leaf_index().unwrap();
+        if index != expected_leaf_index {
+            return Err(ProofError::LeafIndexMismatch {
+                expected: expected_leaf_index,
+                actual: index,
+            });
+        }
+        if self.root() != *trusted_root {
+            return Err(ProofError::TrustedRootMismatch);
+        }
+
+        self.check_roots::<A>().map(|_| ())
+    }
+
+    // Returns the arities of the sub-tree layers below the first
+    // sub-tree layer of this proof, which only trees of more than
+    // three layers have.
+    fn layer_nodes(&self) -> Vec<usize> {
+        let mut proof = self.sub_tree_proof.as_deref();
+        if self.top_layer_nodes > 0 {
+            proof = proof.and_then(|sub_tree_proof| sub_tree_proof.sub_tree_proof.as_deref());
+        }
+
+        let mut layer_nodes = Vec::new();
+        while let Some(layer) = proof {
+            if layer.sub_tree_proof.is_some() {
+                layer_nodes.push(layer.sub_tree_layer_nodes);
+            }
+            proof = layer.sub_tree_proof.as_deref();
+        }
+
+        layer_nodes
+    }
+
+    // Returns the layer of the tree this proof is rooted at.
+    fn layer(&self) -> ProofLayer {
+        if self.top_layer_nodes > 0 {
+            ProofLayer::Top
+        } else if self.sub_tree_layer_nodes > 0 {
+            ProofLayer::SubTree
+        } else {
+            ProofLayer::Base
+        }
+    }
+
+    // Checks the structure of this proof and of all its sub-tree
+    // proofs, without hashing.
+    fn check_shape(&self) -> std::result::Result<(), ProofError> {
+        let layer = self.layer();
+        if let Some(sub_tree_proof) = &self.sub_tree_proof {
+            let (arity, expected) = match layer {
+                ProofLayer::Top => (self.top_layer_nodes, ProofLayer::SubTree),
+                // Sub-tree layers can be stacked over each other.
+                ProofLayer::SubTree if sub_tree_proof.layer() == ProofLayer::SubTree => {
+                    (self.sub_tree_layer_nodes, ProofLayer::SubTree)
+                }
+                ProofLayer::SubTree => (self.sub_tree_layer_nodes, ProofLayer::Base),
+                ProofLayer::Base => return Err(ProofError::UnexpectedSubTreeProof { layer }),
+            };
+
+            if sub_tree_proof.layer() != expected {
+                return Err(ProofError::InvalidSubTreeLayer {
+                    layer,
+                    found: sub_tree_proof.layer(),
+                });
+            }
+            if self.path.len() != 1 {
+                return Err(ProofError::InvalidPathLength {
+                    layer,
+                    expected: 1,
+                    actual: self.path.len(),
+                });
+            }
+            if self.path[0] >= arity {
+                return Err(ProofError::PathOutOfRange {
+                    layer,
+                    row: 0,
+                    digit: self.path[0],
+                    arity,
+                });
+            }
+            if self.lemma.len() != arity {
+                return Err(ProofError::InvalidLemmaLength {
+                    layer,
+                    expected: arity,
+                    actual: self.lemma.len(),
+                });
+            }
+
+            return sub_tree_proof.check_shape();
+        }
+
+        if layer != ProofLayer::Base {
+            return Err(ProofError::MissingSubTreeProof { layer });
+        }
+
+        let arities = &self.base_tree_arities;
+        let width = if self.policy == OddRowPolicy::Reject {
+            self.full_leaf_count()
+        } else {
+            if self
+                .policy
+                .ensure_valid_schedule(self.leafs, arities)
+                .is_err()
+            {
+                return Err(ProofError::InvalidLeafCount {
+                    layer,
+                    leafs: self.leafs,
+                });
+            }
+
+            let expected =
+                get_merkle_tree_row_count_with_schedule(self.leafs, arities, self.policy) - 1;
+            if self.path.len() != expected {
+                return Err(ProofError::InvalidPathLength {
+                    layer,
+                    expected,
+                    actual: self.path.len(),
+                });
+            }
+
+            self.leafs
+        };
+
+        if let Some((row, digit)) = self
+            .path
+            .iter()
+            .enumerate()
+            .find(|(row, p)| **p >= self.row_nodes(*row))
+        {
+            return Err(ProofError::PathOutOfRange {
+                layer,
+                row,
+                digit: *digit,
+                arity: self.row_nodes(row),
+            });
+        }
+
+        let index = self.path_leaf_index().unwrap();
+        if index >= width {
+            return Err(ProofError::LeafOutOfRange {
+                layer,
+                index,
+                leafs: width,
+            });
+        }
+
+        // Walk the rows to count the siblings of the proven node.
+        let mut expected = 2;
+        let (mut j, mut width) = (index, width);
+        for (row, digit) in self.path.iter().enumerate() {
+            let branches = self.row_nodes(row);
+            let hash_len = std::cmp::min(branches, width - (j / branches) * branches);
+            if *digit >= hash_len {
+                return Err(ProofError::PathOutOfRange {
+                    layer,
+                    row,
+                    digit: *digit,
+                    arity: hash_len,
+                });
+            }
+            if hash_len > 1 {
+                expected += hash_len - 1;
+            }
+
+            width = self.policy.next_row_width(width, branches);
+            j /= branches;
+        }
+
+        if self.lemma.len() != expected {
+            return Err(ProofError::InvalidLemmaLength {
+                layer,
+                expected,
+                actual: self.lemma.len(),
+            });
+        }
+
+        Ok(())
+    }
+
+    // Recomputes the root of every layer of a proof whose shape was
+    // checked, bottom layer first, returning the root of this layer.
+    fn check_roots<A: Algorithm<T>>(&self) -> std::result::Result<T, ProofError> {
+        let mut a = A::default();
+        let layer = self.layer();
+        let root = match &self.sub_tree_proof {
+            Some(sub_tree_proof) => {
+                let sub_tree_root = sub_tree_proof.check_roots::<A>()?;
+
+                let mut siblings = self.lemma.iter();
+                let nodes: Vec<T> = (0..self.lemma.len())
+                    .map(|j| {
+                        if j == self.path[0] {
+                            sub_tree_root.clone()
+                        } else {
+                            siblings.next().unwrap().clone()
+                        }
+                    })
+                    .collect();
+
+                a.multi_node(&nodes, 0)
+            }
+            None => {
+                let mut width = if self.policy == OddRowPolicy::Reject {
+                    self.full_leaf_count()
+                } else {
+                    self.leafs
+                };
+                let mut j = self.path_leaf_index().unwrap();
+
+                let mut h = self.item();
+                let mut cur_index = 1;
+                for (level, digit) in self.path.iter().enumerate() {
+                    let branches = self.row_nodes(level);
+                    let hash_len = std::cmp::min(branches, width - (j / branches) * branches);
+                    let mut nodes: Vec<T> = Vec::with_capacity(hash_len);
+                    for k in 0..hash_len {
+                        if k == *digit {
+                            nodes.push(h.clone());
+                        } else {
+                            nodes.push(self.lemma[cur_index].clone());
+                            cur_index += 1;
+                        }
+                    }
+
+                    if self.policy.is_mutated(&nodes, branches) {
+                        return Err(ProofError::MutatedRow { layer, row: level });
+                    }
+
+                    h = self.policy.hash_nodes::<T, A>(&nodes, branches, level);
+
+                    width = self.policy.next_row_width(width, branches);
+                    j /= branches;
+                }
+
+                h
+            }
+        };
+
+        if root != self.root() {
+            return Err(ProofError::RootMismatch { layer });
+        }
+
+        Ok(root)
+    }
+
+    /// Verifies MT inclusion proof and that leaf_data is the original leaf data for which proof was generated.
+    pub fn validate_with_data<A: Algorithm<T>>(&self, leaf_data: &dyn Hashable<A>) -> Result<bool> {
+        let mut a = A::default();
+        leaf_data.hash(&mut a);
+        let item = a.hash();
+        a.reset();
+        let leaf_hash = a.leaf(item);
+
+        if leaf_hash == self.item() {
+            self.validate::<A>()
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Returns the path of this proof.
+    pub fn path(&self) -> &Vec<usize> {
+        &self.path
+    }
+
+    /// Returns the lemma of this proof.
+    pub fn lemma(&self) -> &Vec<T> {
+        &self.lemma
+    }
+
+    /// Returns the lemma of this proof as mutable.
+    pub fn lemma_mut(&mut self) -> &mut Vec<T> {
+        &mut self.lemma
+    }
+
+    pub fn top_layer_nodes(&self) -> usize {
+        self.top_layer_nodes
+    }
+
+    pub fn sub_layer_nodes(&self) -> usize {
+        self.sub_tree_layer_nodes
+    }
+
+    /// Returns the arity of the base tree this proof was generated
+    /// from, which is the arity of its leaf row if it was built with
+    /// an arity schedule.
+    pub fn base_tree_nodes(&self) -> usize {
+        self.base_tree_arities[0]
+    }
+
+    /// Returns the arity schedule of the base tree this proof was
+    /// generated from (see `merkle::row_arity`).
+    pub fn base_tree_arities(&self) -> &[usize] {
+        &self.base_tree_arities
+    }
+
+    /// Returns the odd row policy of the tree this proof was generated from.
+    pub fn policy(&self) -> OddRowPolicy {
+        self.policy
+    }
+
+    /// Returns the number of base tree leafs, if tracked by this proof.
+    pub fn leafs(&self) -> usize {
+        self.leafs
+    }
+
+    /// Converts the proof to the proof type of another base tree
+    /// arity, which must be U0 or the arity of this proof.  This
+    /// allows proofs of a `DynMerkleTree` to be used where the typed
+    /// arity is expected, and the other way round.
+    pub fn into_arity<B: Unsigned>(self) -> Result<Proof<T, B>> {
+        Proof::try_from(ProofData::from(self))
+    }
+}
+
+/// Version of the `Proof` binary format written by `to_bytes`.
+pub const PROOF_FORMAT_VERSION: u8 = 1;
+
+impl<T: Element, BaseTreeArity: Unsigned> Proof<T, BaseTreeArity> {
+    /// Encodes the proof in a compact binary format.  All integers are
+    /// little endian:
+    ///
+    /// ```text
+    /// u8  version (PROOF_FORMAT_VERSION)
+    /// u32 element size in bytes
+    /// u32 base tree arity
+    /// u32 sub-tree layer arity (0 if none)
+    /// u32 top layer arity (0 if none)
+    /// u8  odd row policy of the base tree(s)
+    /// u64 base tree leafs (only meaningful if the policy is not Reject)
+    /// u32 number of sub-tree layers below the first one (for trees of
+    ///     more than three layers), followed by each arity as u32
+    /// u32 number of row arities of the base tree (1 without an arity
+    ///     schedule), followed by each arity as u32
+    /// then, for every layer from the top one down to the base one:
+    ///   u32 path length, followed by each path entry as u32
+    ///   u32 lemma length, followed by each lemma element
+    /// ```
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut base = self;
+        while let Some(sub_tree_proof) = &base.sub_tree_proof {
+            base = sub_tree_proof;
+        }
+
+        let layer_nodes = self.layer_nodes();
+
+        let mut bytes = vec![PROOF_FORMAT_VERSION];
+        for n in &[
+            T::byte_len(),
+            self.base_tree_nodes(),
+            self.sub_tree_layer_nodes,
+            self.top_layer_nodes,
+        ] {
+            bytes.extend_from_slice(&(*n as u32).to_le_bytes());
+        }
+        bytes.push(encode_policy(base.policy));
+        bytes.extend_from_slice(&(base.leafs as u64).to_le_bytes());
+        bytes.extend_from_slice(&(layer_nodes.len() as u32).to_le_bytes());
+        for n in &layer_nodes {
+            bytes.extend_from_slice(&(*n as u32).to_le_bytes());
+        }
+        bytes.extend_from_slice(&(self.base_tree_arities.len() as u32).to_le_bytes());
+        for n in &self.base_tree_arities {
+            bytes.extend_from_slice(&(*n as u32).to_le_bytes());
+        }
+
+        let mut proof = Some(self);
+        while let Some(layer) = proof {
+            bytes.extend_from_slice(&(layer.path.len() as u32).to_le_bytes());
+            for p in &layer.path {
+                bytes.extend_from_slice(&(*p as u32).to_le_bytes());
+            }
+
+            bytes.extend_from_slice(&(layer.lemma.len() as u32).to_le_bytes());
+            for node in &layer.lemma {
+                bytes.extend_from_slice(node.as_ref());
+            }
+
+            proof = layer.sub_tree_proof.as_deref();
+        }
+
+        bytes
+    }
+
+    /// Decodes a proof written by `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Proof<T, BaseTreeArity>> {
+        let mut reader = ProofReader { bytes, pos: 0 };
+
+        let version = reader.read(1)?[0];
+        ensure!(
+            version == PROOF_FORMAT_VERSION,
+            "Unsupported proof format version {}",
+            version
+        );
+
+        let elem_len = reader.read_u32()?;
+        ensure!(
+            elem_len == T::byte_len(),
+            "Invalid element size {} (expected: {})",
+            elem_len,
+            T::byte_len()
+        );
+
+        let data = ProofData {
+            base_tree_arity: reader.read_u32()?,
+            sub_tree_layer_nodes: reader.read_u32()?,
+            top_layer_nodes: reader.read_u32()?,
+            policy: decode_policy(reader.read(1)?[0])?,
+            leafs: reader.read_u64()?,
+            base_tree_arities: Vec::new(),
+            layer_nodes: Vec::new(),
+            layers: Vec::new(),
+        };
+
+        let mut data = data;
+        let count = reader.read_u32()?;
+        data.layer_nodes = (0..count)
+            .map(|_| reader.read_u32())
+            .collect::<Result<Vec<usize>>>()?;
+        let count = reader.read_u32()?;
+        ensure!(count > 0, "Proofs require a base tree arity");
+        data.base_tree_arities = (0..count)
+            .map(|_| reader.read_u32())
+            .collect::<Result<Vec<usize>>>()?;
+
+        let layer_count = (data.top_layer_nodes > 0) as usize
+            + (data.sub_tree_layer_nodes > 0) as usize
+            + data.layer_nodes.len()
+            + 1;
+        for _ in 0..layer_count {
+            let path_len = reader.read_u32()?;
+            let path = (0..path_len)
+                .map(|_| reader.read_u32())
+                .collect::<Result<Vec<usize>>>()?;
+
+            let lemma_len = reader.read_u32()?;
+            let lemma = reader
+                .read(
+                    lemma_len
+                        .checked_mul(elem_len)
+                        .context("Invalid lemma length")?,
+                )?
+                .chunks(elem_len)
+                .map(T::from_slice)
+                .collect();
+
+            data.layers.push(ProofLayerData { path, lemma });
+        }
+        ensure!(
+            reader.pos == bytes.len(),
+            "{} trailing bytes after proof",
+            bytes.len() - reader.pos
+        );
+
+        Proof::try_from(data)
+    }
+}
+
+// Bounds-checked reads of the binary proof format.
+struct ProofReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ProofReader<'a> {
+    fn read(&mut self, len: usize) -> Result<&'a [u8]> {
+        ensure!(
+            len <= self.bytes.len() - self.pos,
+            "Truncated proof ({} bytes)",
+            self.bytes.len()
+        );
+
+        let bytes = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+
+        Ok(bytes)
+    }
+
+    fn read_u32(&mut self) -> Result<usize> {
+        Ok(u32::from_le_bytes(*array_ref![self.read(4)?, 0, 4]) as usize)
+    }
+
+    fn read_u64(&mut self) -> Result<usize> {
+        let n = u64::from_le_bytes(*array_ref![self.read(8)?, 0, 8]);
+        usize::try_from(n).context("Invalid leaf count")
+    }
+}
+
+fn encode_policy(policy: OddRowPolicy) -> u8 {
+    match policy {
+        OddRowPolicy::Reject => 0,
+        OddRowPolicy::Promote => 1,
+        OddRowPolicy::Duplicate => 2,
+        OddRowPolicy::Pad => 3,
+    }
+}
+
+fn decode_policy(policy: u8) -> Result<OddRowPolicy> {
+    match policy {
+        0 => Ok(OddRowPolicy::Reject),
+        1 => Ok(OddRowPolicy::Promote),
+        2 => Ok(OddRowPolicy::Duplicate),
+        3 => Ok(OddRowPolicy::Pad),
+        _ => bail!("Invalid odd row policy {}", policy),
+    }
+}
+
+// Serialized form of a `Proof`, holding its layers from the top one
+// down to the base one.
+#[derive(Serialize, Deserialize)]
+struct ProofData<T> {
+    base_tree_arity: usize,
+    sub_tree_layer_nodes: usize,
+    top_layer_nodes: usize,
+    policy: OddRowPolicy,
+    leafs: usize,
+    // Arity schedule of the base tree, if it has several arities.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    base_tree_arities: Vec<usize>,
+    // Arities of the sub-tree layers below the first one.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    layer_nodes: Vec<usize>,
+    layers: Vec<ProofLayerData<T>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ProofLayerData<T> {
+    path: Vec<usize>,
+    lemma: Vec<T>,
+}
+
+impl<T: Eq + Clone + AsRef<[u8]>, BaseTreeArity: Unsigned> From<Proof<T, BaseTreeArity>>
+    for ProofData<T>
+{
+    fn from(proof: Proof<T, BaseTreeArity>) -> ProofData<T> {
+        let base_tree_arities = if proof.base_tree_arities.len() > 1 {
+            proof.base_tree_arities.clone()
+        } else {
+            Vec::new()
+        };
+        let mut data = ProofData {
+            base_tree_arity: proof.base_tree_nodes(),
+            sub_tree_layer_nodes: proof.sub_tree_layer_nodes,
+            top_layer_nodes: proof.top_layer_nodes,
+            policy: OddRowPolicy::Reject,
+            leafs: 0,
+            base_tree_arities,
+            layer_nodes: proof.layer_nodes(),
+            layers: Vec::new(),
+        };
+
+        let mut proof = Some(Box::new(proof));
+        while let Some(layer) = proof {
+            let layer = *layer;
+            data.policy = layer.policy;
+            data.leafs = layer.leafs;
+            data.layers.push(ProofLayerData {
+                path: layer.path,
+                lemma: layer.lemma,
+            });
+            proof = layer.sub_tree_proof;
+        }
+
+        data
+    }
+}
+
+impl<T: Eq + Clone + AsRef<[u8]>, BaseTreeArity: Unsigned> TryFrom<ProofData<T>>
+    for Proof<T, BaseTreeArity>
+{
+    type Error = anyhow::Error;
+
+    fn try_from(data: ProofData<T>) -> Result<Proof<T, BaseTreeArity>> {
+        let base_tree_arities = if data.base_tree_arities.is_empty() {
+            vec![data.base_tree_arity]
+        } else {
+            data.base_tree_arities
+        };
+        ensure!(
+            base_tree_arities[0] == data.base_tree_arity,
+            "Inconsistent base tree arity schedule"
+        );
+        for branches in &base_tree_arities {
+            ensure!(
+                *branches > 0
+                    && (BaseTreeArity::to_usize() == 0 || *branches == BaseTreeArity::to_usize()),
+                "Invalid base tree arity {} (expected: {})",
+                branches,
+                BaseTreeArity::to_usize()
+            );
+        }
+        ensure!(
+            data.top_layer_nodes == 0 || data.sub_tree_layer_nodes > 0,
+            "Top layer proofs require a sub-tree layer"
+        );
+
+        // The arities of each layer, from the top one down.
+        let mut arities = Vec::new();
+        if data.top_layer_nodes > 0 {
+            arities.push((data.top_layer_nodes, data.sub_tree_layer_nodes));
+        }
+        if data.sub_tree_layer_nodes > 0 {
+            arities.push((0, data.sub_tree_layer_nodes));
+        }
+        ensure!(
+            data.layer_nodes.is_empty() || data.sub_tree_layer_nodes > 0,
+            "Sub-tree layers must be below the first sub-tree layer"
+        );
+        for nodes in &data.layer_nodes {
+            ensure!(*nodes > 0, "Invalid sub-tree layer arity");
+            arities.push((0, *nodes));
+        }
+        arities.push((0, 0));
+        ensure!(
+            data.layers.len() == arities.len(),
+            "Invalid proof layer count {} (expected: {})",
+            data.layers.len(),
+            arities.len()
+        );
+
+        let mut proof: Option<Box<Proof<T, BaseTreeArity>>> = None;
+        for (layer, (top_layer_nodes, sub_tree_layer_nodes)) in
+            data.layers.into_iter().zip(arities).rev()
+        {
+            let base = proof.is_none();
+            proof = Some(Box::new(Proof {
+                sub_tree_proof: proof,
+                top_layer_nodes,
+                sub_tree_layer_nodes,
+                base_tree_arities: base_tree_arities.clone(),
+                lemma: layer.lemma,
+                path: layer.path,
+
+                policy: if base {
+                    data.policy
+                } else {
+                    OddRowPolicy::Reject
+                },
+                leafs: if base { data.leafs } else { 0 },
+
+                _u: PhantomData,
+            }));
+        }
+
+        let proof = *proof.unwrap();
+        proof.check_shape()?;
+
+        Ok(proof)
+    }
+}
+
+/// Layer of a (possibly compound) tree a proof belongs to.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ProofLayer {
+    /// Base tree, proving a leaf.
+    Base,
+    /// Sub-tree layer, over the roots of base trees or of the trees
+    /// of another sub-tree layer.
+    SubTree,
+    /// Top layer, over the roots of sub-trees.
+    Top,
+}
+
+impl fmt::Display for ProofLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProofLayer::Base => write!(f, "base layer"),
+            ProofLayer::SubTree => write!(f, "sub-tree layer"),
+            ProofLayer::Top => write!(f, "top layer"),
+        }
+    }
+}
+
+/// Reason for which strict proof validation rejected a proof.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ProofError {
+    /// A compound layer has no proof for the layer below it.
+    MissingSubTreeProof { layer: ProofLayer },
+    /// A base layer proof has a proof below it.
+    UnexpectedSubTreeProof { layer: ProofLayer },
+    /// The proof below a compound layer is not for the layer below it.
+    InvalidSubTreeLayer {
+        layer: ProofLayer,
+        found: ProofLayer,
+    },
+    /// The number of base tree leafs is invalid for the odd row policy.
+    InvalidLeafCount { layer: ProofLayer, leafs: usize },
+    /// The path does not have one digit per row.
+    InvalidPathLength {
+        layer: ProofLayer,
+        expected: usize,
+        actual: usize,
+    },
+    /// A path digit is not a valid index in a group of `arity` nodes.
+    PathOutOfRange {
+        layer: ProofLayer,
+        row: usize,
+        digit: usize,
+        arity: usize,
+    },
+    /// The path encodes a leaf past the end of the tree.
+    LeafOutOfRange {
+        layer: ProofLayer,
+        index: usize,
+        leafs: usize,
+    },
+    /// The lemma does not hold exactly the nodes the path requires.
+    InvalidLemmaLength {
+        layer: ProofLayer,
+        expected: usize,
+        actual: usize,
+    },
+    /// A full group of siblings ends with two equal nodes, which
+    /// hashes like a short group of a `Duplicate` tree (CVE-2012-2459).
+    MutatedRow { layer: ProofLayer, row: usize },
+    /// The lemma does not hash up to its own root.
+    RootMismatch { layer: ProofLayer },
+    /// The proof root is not the trusted root.
+    TrustedRootMismatch,
+    /// The proof is for another leaf.
+    LeafIndexMismatch { expected: usize, actual: usize },
+    /// The proof is for a tree of another number of leafs, or for an
+    /// interior node of the tree.
+    LeafCountMismatch { expected: usize, actual: usize },
+}
+
+impl fmt::Display for ProofError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProofError::MissingSubTreeProof { layer } => {
+                write!(f, "{}: missing sub tree proof", layer)
+            }
+            ProofError::UnexpectedSubTreeProof { layer } => {
+                write!(f, "{}: unexpected sub tree proof", layer)
+            }
+            ProofError::InvalidSubTreeLayer { layer, found } => {
+                write!(f, "{}: invalid sub tree proof for the {}", layer, found)
+            }
+            ProofError::InvalidLeafCount { layer, leafs } => {
+                write!(f, "{}: invalid leaf count {}", layer, leafs)
+            }
+            ProofError::InvalidPathLength {
+                layer,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{}: invalid path length {} (expected: {})",
+                layer, actual, expected
+            ),
+            ProofError::PathOutOfRange {
+                layer,
+                row,
+                digit,
+                arity,
+            } => write!(
+                f,
+                "{}: path digit {} of row {} is out of range (arity: {})",
+                layer, digit, row, arity
+            ),
+            ProofError::LeafOutOfRange {
+                layer,
+                index,
+                leafs,
+            } => write!(
+                f,
+                "{}: leaf {} is out of bounds (leafs: {})",
+                layer, index, leafs
+            ),
+            ProofError::InvalidLemmaLength {
+                layer,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{}: invalid lemma length {} (expected: {})",
+                layer, actual, expected
+            ),
+            ProofError::MutatedRow { layer, row } => {
+                write!(f, "{}: row {} has a duplicated sibling", layer, row)
+            }
+            ProofError::RootMismatch { layer } => write!(f, "{}: root mismatch", layer),
+            ProofError::TrustedRootMismatch => write!(f, "root is not the trusted root"),
+            ProofError::LeafIndexMismatch { expected, actual } => {
+                write!(f, "proof is for leaf {} (expected: {})", actual, expected)
+            }
+            ProofError::LeafCountMismatch { expected, actual } => write!(
+                f,
+                "proof is for a tree of {} leafs (expected: {})",
+                actual, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ProofError {}
+
+/// Merkle tree consistency proof between two sizes of an append-only
+/// binary tree, as defined in RFC 6962 section 2.1.2.
+///
+/// Nodes layout is the output of `SUBPROOF(old_size, D[new_size], true)`:
+///
+/// ```text
+/// [ n1 n2 n3 ... ]
+/// ```
+///
+/// Proof validation recomputes both the old and the new root from the nodes.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ConsistencyProof<T: Eq + Clone + AsRef<[u8]>> {
+    old_size: usize,
+    new_size: usize,
+    nodes: Vec<T>,
+}
+
+impl<T: Eq + Clone + AsRef<[u8]>> ConsistencyProof<T> {
+    /// Creates new MT consistency proof
+    pub fn new(old_size: usize, new_size: usize, nodes: Vec<T>) -> ConsistencyProof<T> {
+        ConsistencyProof {
+            old_size,
+            new_size,
+            nodes,
+        }
+    }
+
+    /// Verifies that `old_root` is the root of the first `old_size`
+    /// leafs of the tree with root `new_root`.
+    pub fn validate<A: Algorithm<T>>(&self, old_root: T, new_root: T) -> Result<bool> {
+        ensure!(
+            self.old_size > 0 && self.old_size <= self.new_size,
+            "Invalid consistency proof sizes"
+        );
+
+        if self.old_size == self.new_size {
+            return Ok(self.nodes.is_empty() && old_root == new_root);
+        }
+
+        // If the old tree is a complete sub-tree of the new one, its
+        // root is the implicit first node of the proof.
+        let mut nodes = self.nodes.iter();
+        let first = if self.old_size.is_power_of_two() {
+            old_root.clone()
+        } else {
+            match nodes.next() {
+                Some(node) => node.clone(),
+                None => return Ok(false),
+            }
+        };
+
+        let mut a = A::default();
+        let mut old_index = self.old_size - 1;
+        let mut new_index = self.new_size - 1;
+        let mut height = 0;
+
+        while old_index & 1 == 1 {
+            old_index >>= 1;
+            new_index >>= 1;
+            height += 1;
+        }
+
+        let mut old_hash = first.clone();
+        let mut new_hash = first;
+        for node in nodes {
+            if new_index == 0 {
+                return Ok(false);
+            }
+
+            if old_index & 1 == 1 || old_index == new_index {
+                a.reset();
+                old_hash = a.node(node.clone(), old_hash, height);
+                a.reset();
+                new_hash = a.node(node.clone(), new_hash, height);
+
+                while old_index & 1 == 0 && old_index != 0 {
+                    old_index >>= 1;
+                    new_index >>= 1;
+                    height += 1;
+                }
+            } else {
+                a.reset();
+                new_hash = a.node(new_hash, node.clone(), height);
+            }
+
+            old_index >>= 1;
+            new_index >>= 1;
+            height += 1;
+        }
+
+        Ok(new_index == 0 && old_hash == old_root && new_hash == new_root)
+    }
+
+    /// Returns the number of leafs in the old tree.
+    pub fn old_size(&self) -> usize {
+        self.old_size
+    }
+
+    /// Returns the number of leafs in the new tree.
+    pub fn new_size(&self) -> usize {
+        self.new_size
+    }
+
+    /// Returns the nodes of this proof.
+    pub fn nodes(&self) -> &Vec<T> {
+        &self.nodes
+    }
+}
+
+/// Merkle tree inclusion proof for a set of data elements, in which
+/// every node required for validation is included exactly once.
+///
+/// Nodes layout, bottom row first and left to right within a row:
+///
+/// ```text
+/// [ h0a h0b ... h1a h1b ... ]
+/// ```
+///
+/// Nodes that can be computed from the items (or from other nodes)
+/// are never included.  Proof validation recomputes the root from
+/// the items and the nodes, row by row.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MultiProof<T: Eq + Clone + AsRef<[u8]>, BaseTreeArity: Unsigned = U2> {
+    leafs: usize,                // leafs of the whole tree
+    policy: OddRowPolicy,        // odd row handling of the base tree(s)
+    top_layer_nodes: usize,      // arity of top layer
+    sub_tree_layer_nodes: usize, // arity of sub-tree layer
+    base_tree_nodes: usize,      // arity of base tree(s)
+
+    indices: Vec<usize>, // sorted leaf indices
+    items: Vec<T>,       // leafs at indices
+    nodes: Vec<T>,       // deduplicated siblings
+
+    _u: PhantomData<BaseTreeArity>, // number of branches per node
+}
+
+/// A row of a (possibly compound) tree as seen by multi proofs,
+/// i.e. `count` trees whose row has `width` nodes each.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct MultiProofRow {
+    pub width: usize,
+    pub count: usize,
+    pub arity: usize,
+    pub base: bool,
+}
+
+impl MultiProofRow {
+    /// Returns the groups of nodes of this row hashed together into
+    /// the parents of the `known` (sorted) nodes, with the index of
+    /// each of these parents.
+    pub fn groups(&self, known: &[usize]) -> Vec<(ops::Range<usize>, usize)> {
+        let parent_width = (self.width + self.arity - 1) / self.arity;
+
+        let mut groups: Vec<(ops::Range<usize>, usize)> = Vec::new();
+        for j in known {
+            let (tree, index) = (j / self.width, j % self.width);
+            let parent = tree * parent_width + index / self.arity;
+            if groups.last().map(|(_, p)| *p) == Some(parent) {
+                continue;
+            }
+
+            let start = tree * self.width + (index / self.arity) * self.arity;
+            let end = tree * self.width
+                + std::cmp::min((index / self.arity + 1) * self.arity, self.width);
+            groups.push((start..end, parent));
+        }
+
+        groups
+    }
+}
+
+impl<T: Eq + Clone + AsRef<[u8]>, BaseTreeArity: Unsigned> MultiProof<T, BaseTreeArity> {
+    /// Creates new MT multi inclusion proof
+    pub fn new<TopLayerArity: Unsigned, SubTreeArity: Unsigned>(
+        leafs: usize,
+        policy: OddRowPolicy,
+        indices: Vec<usize>,
+        items: Vec<T>,
+        nodes: Vec<T>,
+    ) -> Result<MultiProof<T, BaseTreeArity>> {
+        Self::new_with_arity::<TopLayerArity, SubTreeArity>(
+            BaseTreeArity::to_usize(),
+            leafs,
+            policy,
+            indices,
+            items,
+            nodes,
+        )
+    }
+
+    /// Creates new MT multi inclusion proof for base trees of arity
+    /// `branches`, which must be `BaseTreeArity` unless it is U0.
+    pub fn new_with_arity<TopLayerArity: Unsigned, SubTreeArity: Unsigned>(
+        branches: usize,
+        leafs: usize,
+        policy: OddRowPolicy,
+        indices: Vec<usize>,
+        items: Vec<T>,
+        nodes: Vec<T>,
+    ) -> Result<MultiProof<T, BaseTreeArity>> {
+        ensure!(
+            branches > 0
+                && (BaseTreeArity::to_usize() == 0 || BaseTreeArity::to_usize() == branches),
+            "Invalid base tree arity {} (expected: {})",
+            branches,
+            BaseTreeArity::to_usize()
+        );
+        ensure!(!indices.is_empty(), "Invalid empty multi proof");
+        ensure!(indices.len() == items.len(), "Inconsistent item count");
+        ensure!(
+            indices.windows(2).all(|w| w[0] < w[1]),
+            "Indices must be sorted and unique"
+        );
+        ensure!(
+            indices[indices.len() - 1] < leafs,
+            "{} is out of bounds (max: {})",
+            indices[indices.len() - 1],
+            leafs
+        );
+
+        let proof = MultiProof {
+            leafs,
+            policy,
+            top_layer_nodes: TopLayerArity::to_usize(),
+            sub_tree_layer_nodes: SubTreeArity::to_usize(),
+            base_tree_nodes: branches,
+            indices,
+            items,
+            nodes,
+            _u: PhantomData,
+        };
+        proof.rows()?;
+
+        Ok(proof)
+    }
+
+    /// Returns the rows (excluding the root) of a tree of `leafs`,
+    /// with the specified arities.
+    pub(crate) fn get_rows(
+        leafs: usize,
+        policy: OddRowPolicy,
+        branches: usize,
+        sub_tree_layer_nodes: usize,
+        top_layer_nodes: usize,
+    ) -> Result<Vec<MultiProofRow>> {
+        let trees = std::cmp::max(sub_tree_layer_nodes, 1) * std::cmp::max(top_layer_nodes, 1);
+        ensure!(
+            leafs % trees == 0,
+            "Leafs must be evenly split across sub-trees"
+        );
+
+        let mut width = leafs / trees;
+        policy.ensure_valid_shape(width, branches)?;
+
+        let mut rows = Vec::new();
+        while width > 1 {
+            rows.push(MultiProofRow {
+                width,
+                count: trees,
+                arity: branches,
+                base: true,
+            });
+            width = policy.next_row_width(width, branches);
+        }
+
+        if sub_tree_layer_nodes > 0 {
+            rows.push(MultiProofRow {
+                width: sub_tree_layer_nodes,
+                count: std::cmp::max(top_layer_nodes, 1),
+                arity: sub_tree_layer_nodes,
+                base: false,
+            });
+        }
+
+        if top_layer_nodes > 0 {
+            rows.push(MultiProofRow {
+                width: top_layer_nodes,
+                count: 1,
+                arity: top_layer_nodes,
+                base: false,
+            });
+        }
+
+        Ok(rows)
+    }
+
+    fn rows(&self) -> Result<Vec<MultiProofRow>> {
+        Self::get_rows(
+            self.leafs,
+            self.policy,
+            self.base_tree_nodes,
+            self.sub_tree_layer_nodes,
+            self.top_layer_nodes,
+        )
+    }
+
+    /// Verifies MT multi inclusion proof against `root`
+    pub fn validate<A: Algorithm<T>>(&self, root: T) -> Result<bool> {
+        let computed = compute_multi_root::<T, A>(
+            &self.rows()?,
+            self.policy,
+            self.indices.clone(),
+            self.items.clone(),
+            &self.nodes,
+        );
+
+        Ok(computed == Some(root))
+    }
 
-        Ok(Proof {
-            sub_tree_proof,
-            top_layer_nodes: TopLayerArity::to_usize(),
-            sub_tree_layer_nodes: SubTreeArity::to_usize(),
-            lemma,
-            path,
+    /// Returns the sorted indices of the proven leafs.
+    pub fn indices(&self) -> &Vec<usize> {
+        &self.indices
+    }
 
-            _u: PhantomData,
-        })
+    /// Returns the proven leafs, in the order of `indices`.
+    pub fn items(&self) -> &Vec<T> {
+        &self.items
     }
 
-    /// Return proof target leaf
-    pub fn item(&self) -> T {
-        self.lemma.first().unwrap().clone()
+    /// Returns the nodes of this proof.
+    pub fn nodes(&self) -> &Vec<T> {
+        &self.nodes
     }
 
-    /// Return sub tree root
-    pub fn sub_tree_root(&self) -> T {
-        assert!(self.sub_tree_layer_nodes > 0 && self.sub_tree_proof.is_some());
-        self.sub_tree_proof.as_ref().unwrap().root()
+    /// Returns the nodes of this proof as mutable.
+    pub fn nodes_mut(&mut self) -> &mut Vec<T> {
+        &mut self.nodes
     }
 
-    /// Return tree root
-    pub fn root(&self) -> T {
-        self.lemma.last().unwrap().clone()
+    /// Returns the number of leafs of the tree this proof was generated from.
+    pub fn leafs(&self) -> usize {
+        self.leafs
     }
 
-    /// Validates sub-tree proofs with the specified arity.
-    fn validate_sub_tree_proof<A: Algorithm<T>>(&self, arity: usize) -> Result<bool> {
-        // Ensure that the sub_tree validates to the root of that
-        // sub_tree.
-        let valid = self.sub_tree_proof.as_ref().unwrap().validate::<A>()?;
-        if !valid {
-            return Ok(valid);
-        }
+    pub fn top_layer_nodes(&self) -> usize {
+        self.top_layer_nodes
+    }
 
-        // Validate top-most/current layer
-        //
-        // Check that the remaining proof matches the tree root (note
-        // that Proof::validate at the base layer cannot handle a
-        // proof this small, so this is a version specific for what we
-        // know we have in this case).
-        let mut a = A::default();
-        a.reset();
-        let node_count = arity;
-        let h = {
-            let mut nodes: Vec<T> = Vec::with_capacity(node_count);
-            let mut cur_index = 0;
-            for j in 0..node_count {
-                if j == self.path()[0] {
-                    nodes.push(self.sub_tree_root().clone());
-                } else {
-                    nodes.push(self.lemma()[cur_index].clone());
+    pub fn sub_layer_nodes(&self) -> usize {
+        self.sub_tree_layer_nodes
+    }
+}
+
+// Recomputes the root of a tree with the given `rows` from the
+// `known` (sorted) leafs and their `hashes`, consuming all of `nodes`
+// in order.  Returns `None` if `nodes` does not match the leafs.
+fn compute_multi_root<T: Eq + Clone + AsRef<[u8]>, A: Algorithm<T>>(
+    rows: &[MultiProofRow],
+    policy: OddRowPolicy,
+    known: Vec<usize>,
+    hashes: Vec<T>,
+    nodes: &[T],
+) -> Option<T> {
+    let mut a = A::default();
+    let mut known = known;
+    let mut hashes = hashes;
+    let mut cur_index = 0;
+
+    for (level, row) in rows.iter().enumerate() {
+        let mut parents = Vec::new();
+        let mut parent_hashes = Vec::new();
+        let mut known_index = 0;
+
+        for (group, parent) in row.groups(&known) {
+            let mut group_nodes: Vec<T> = Vec::with_capacity(group.len());
+            for k in group {
+                if known_index < known.len() && known[known_index] == k {
+                    group_nodes.push(hashes[known_index].clone());
+                    known_index += 1;
+                } else if cur_index < nodes.len() {
+                    group_nodes.push(nodes[cur_index].clone());
                     cur_index += 1;
+                } else {
+                    return None;
                 }
             }
 
-            if cur_index != node_count - 1 {
-                return Ok(false);
-            }
+            // Compound layers are hashed as in `MerkleTree::from_trees`.
+            let h = if !row.base {
+                a.reset();
+                a.multi_node(&group_nodes, 1)
+            } else if policy.is_mutated(&group_nodes, row.arity) {
+                return None;
+            } else {
+                policy.hash_nodes::<T, A>(&group_nodes, row.arity, level)
+            };
 
-            a.multi_node(&nodes, 0)
-        };
+            parents.push(parent);
+            parent_hashes.push(h);
+        }
 
-        Ok(h == self.root())
+        known = parents;
+        hashes = parent_hashes;
     }
 
-    /// Verifies MT inclusion proof
-    pub fn validate<A: Algorithm<T>>(&self) -> Result<bool> {
-        if self.top_layer_nodes > 0 {
-            // Special Top layer handling here.
-            ensure!(
-                self.sub_tree_proof.is_some(),
-                "Sub tree proof must be present for validation"
-            );
+    if cur_index == nodes.len() && hashes.len() == 1 {
+        hashes.pop()
+    } else {
+        None
+    }
+}
 
-            return self.validate_sub_tree_proof::<A>(self.top_layer_nodes);
-        }
+/// Merkle tree inclusion proof for a contiguous range of data
+/// elements.
+///
+/// Only the nodes bordering the range are included, in the same
+/// layout as `MultiProof` nodes: every node inside the range can be
+/// computed from the items, so at most `arity - 1` nodes are needed
+/// on each side of the range per row.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RangeProof<T: Eq + Clone + AsRef<[u8]>, BaseTreeArity: Unsigned = U2> {
+    leafs: usize,                // leafs of the whole tree
+    policy: OddRowPolicy,        // odd row handling of the base tree(s)
+    top_layer_nodes: usize,      // arity of top layer
+    sub_tree_layer_nodes: usize, // arity of sub-tree layer
+    base_tree_nodes: usize,      // arity of base tree(s)
 
-        if self.sub_tree_layer_nodes > 0 {
-            // Sub-tree layer handling here.
-            ensure!(
-                self.sub_tree_proof.is_some(),
-                "Sub tree proof must be present for validation"
-            );
+    start: usize,  // index of the first leaf of the range
+    items: Vec<T>, // leafs in the range
+    nodes: Vec<T>, // boundary nodes
 
-            return self.validate_sub_tree_proof::<A>(self.sub_tree_layer_nodes);
-        }
+    _u: PhantomData<BaseTreeArity>, // number of branches per node
+}
 
-        // Base layer handling here.
+impl<T: Eq + Clone + AsRef<[u8]>, BaseTreeArity: Unsigned> RangeProof<T, BaseTreeArity> {
+    /// Creates new MT range inclusion proof
+    pub fn new<TopLayerArity: Unsigned, SubTreeArity: Unsigned>(
+        leafs: usize,
+        policy: OddRowPolicy,
+        start: usize,
+        items: Vec<T>,
+        nodes: Vec<T>,
+    ) -> Result<RangeProof<T, BaseTreeArity>> {
+        Self::new_with_arity::<TopLayerArity, SubTreeArity>(
+            BaseTreeArity::to_usize(),
+            leafs,
+            policy,
+            start,
+            items,
+            nodes,
+        )
+    }
+
+    /// Creates new MT range inclusion proof for base trees of arity
+    /// `branches`, which must be `BaseTreeArity` unless it is U0.
+    pub fn new_with_arity<TopLayerArity: Unsigned, SubTreeArity: Unsigned>(
+        branches: usize,
+        leafs: usize,
+        policy: OddRowPolicy,
+        start: usize,
+        items: Vec<T>,
+        nodes: Vec<T>,
+    ) -> Result<RangeProof<T, BaseTreeArity>> {
         ensure!(
-            self.sub_tree_layer_nodes == 0,
-            "Base layer proof must have 0 as sub-tree layer node count"
+            branches > 0
+                && (BaseTreeArity::to_usize() == 0 || BaseTreeArity::to_usize() == branches),
+            "Invalid base tree arity {} (expected: {})",
+            branches,
+            BaseTreeArity::to_usize()
         );
+        ensure!(!items.is_empty(), "Invalid empty range proof");
         ensure!(
-            self.top_layer_nodes == 0,
-            "Base layer proof must have 0 as top layer node count"
+            start + items.len() <= leafs,
+            "{} is out of bounds (max: {})",
+            start + items.len(),
+            leafs
         );
-        ensure!(self.sub_tree_proof.is_none(), "Sub tree proof must be None");
 
-        let size = self.lemma.len();
-        if size < 2 {
-            return Ok(false);
-        }
+        let proof = RangeProof {
+            leafs,
+            policy,
+            top_layer_nodes: TopLayerArity::to_usize(),
+            sub_tree_layer_nodes: SubTreeArity::to_usize(),
+            base_tree_nodes: branches,
+            start,
+            items,
+            nodes,
+            _u: PhantomData,
+        };
+        proof.rows()?;
 
-        let branches = BaseTreeArity::to_usize();
-        let mut a = A::default();
-        let mut h = self.item();
-        let mut path_index = 1;
+        Ok(proof)
+    }
 
-        for i in (1..size - 1).step_by(branches - 1) {
-            a.reset();
-            h = {
-                let mut nodes: Vec<T> = Vec::with_capacity(branches);
-                let mut cur_index = 0;
-                for j in 0..branches {
-                    if j == self.path[path_index - 1] {
-                        nodes.push(h.clone());
-                    } else {
-                        nodes.push(self.lemma[i + cur_index].clone());
-                        cur_index += 1;
-                    }
-                }
+    fn rows(&self) -> Result<Vec<MultiProofRow>> {
+        MultiProof::<T, BaseTreeArity>::get_rows(
+            self.leafs,
+            self.policy,
+            self.base_tree_nodes,
+            self.sub_tree_layer_nodes,
+            self.top_layer_nodes,
+        )
+    }
 
-                if cur_index != branches - 1 {
-                    return Ok(false);
-                }
+    /// Verifies MT range inclusion proof against `root`
+    pub fn validate<A: Algorithm<T>>(&self, root: T) -> Result<bool> {
+        let computed = compute_multi_root::<T, A>(
+            &self.rows()?,
+            self.policy,
+            (self.start..self.end()).collect(),
+            self.items.clone(),
+            &self.nodes,
+        );
 
-                path_index += 1;
-                a.multi_node(&nodes, i - 1)
-            };
-        }
+        Ok(computed == Some(root))
+    }
 
-        Ok(h == self.root())
+    /// Returns the index of the first proven leaf.
+    pub fn start(&self) -> usize {
+        self.start
     }
 
-    /// Verifies MT inclusion proof and that leaf_data is the original leaf data for which proof was generated.
-    pub fn validate_with_data<A: Algorithm<T>>(&self, leaf_data: &dyn Hashable<A>) -> Result<bool> {
-        let mut a = A::default();
-        leaf_data.hash(&mut a);
-        let item = a.hash();
-        a.reset();
-        let leaf_hash = a.leaf(item);
+    /// Returns the index past the last proven leaf.
+    pub fn end(&self) -> usize {
+        self.start + self.items.len()
+    }
 
-        if leaf_hash == self.item() {
-            self.validate::<A>()
-        } else {
-            Ok(false)
-        }
+    /// Returns the proven leafs.
+    pub fn items(&self) -> &Vec<T> {
+        &self.items
     }
 
-    /// Returns the path of this proof.
-    pub fn path(&self) -> &Vec<usize> {
-        &self.path
+    /// Returns the nodes of this proof.
+    pub fn nodes(&self) -> &Vec<T> {
+        &self.nodes
     }
 
-    /// Returns the lemma of this proof.
-    pub fn lemma(&self) -> &Vec<T> {
-        &self.lemma
+    /// Returns the nodes of this proof as mutable.
+    pub fn nodes_mut(&mut self) -> &mut Vec<T> {
+        &mut self.nodes
     }
 
-    /// Returns the lemma of this proof as mutable.
-    pub fn lemma_mut(&mut self) -> &mut Vec<T> {
-        &mut self.lemma
+    /// Returns the number of leafs of the tree this proof was generated from.
+    pub fn leafs(&self) -> usize {
+        self.leafs
     }
 
     pub fn top_layer_nodes(&self) -> usize {
@@ -482,3 +2246,612 @@ fn test_ccompound_single_octree_broken_proofs() {
         assert!(!p.validate::<XOR128>().expect("failed to validate"));
     }
 }
+
+#[test]
+fn test_consistency_proofs() {
+    use crate::merkle::OddRowPolicy;
+
+    let trees: Vec<MerkleTree<Item, XOR128, VecStore<_>>> = (1..40)
+        .map(|leafs| {
+            MerkleTree::from_data_with_options(
+                (0..leafs).map(|x| x * 93),
+                &TreeOptions::new().with_policy(OddRowPolicy::Promote),
+            )
+            .expect("failed to create tree")
+        })
+        .collect();
+
+    for new_tree in trees.iter() {
+        for old_tree in trees.iter().take(new_tree.leafs()) {
+            let p = new_tree
+                .gen_consistency_proof(old_tree.leafs())
+                .expect("failed to generate consistency proof");
+            assert!(p
+                .validate::<XOR128>(old_tree.root(), new_tree.root())
+                .expect("failed to validate"));
+
+            // Roots must be in the expected order.
+            if old_tree.root() != new_tree.root() {
+                assert!(!p
+                    .validate::<XOR128>(new_tree.root(), old_tree.root())
+                    .expect("failed to validate"));
+            }
+
+            // Break every node of the proof.
+            for i in 0..p.nodes().len() {
+                let mut nodes = p.nodes().clone();
+                nodes[i][0] ^= 0xff;
+                let broken = ConsistencyProof::new(p.old_size(), p.new_size(), nodes);
+                assert!(!broken
+                    .validate::<XOR128>(old_tree.root(), new_tree.root())
+                    .expect("failed to validate"));
+            }
+        }
+
+        assert!(new_tree.gen_consistency_proof(0).is_err());
+        assert!(new_tree
+            .gen_consistency_proof(new_tree.leafs() + 1)
+            .is_err());
+    }
+}
+
+#[test]
+fn test_consistency_proof_rfc6962_sizes() {
+    use crate::merkle::OddRowPolicy;
+
+    // Proof lengths from the RFC 6962 section 2.1.3 example tree.
+    let tree: MerkleTree<Item, XOR128, VecStore<_>> = MerkleTree::from_data_with_options(
+        (0..7).map(|x| x * 93),
+        &TreeOptions::new().with_policy(OddRowPolicy::Promote),
+    )
+    .expect("failed to create tree");
+    assert_eq!(tree.gen_consistency_proof(3).unwrap().nodes().len(), 4);
+    assert_eq!(tree.gen_consistency_proof(4).unwrap().nodes().len(), 1);
+    assert_eq!(tree.gen_consistency_proof(6).unwrap().nodes().len(), 3);
+    assert!(tree.gen_consistency_proof(7).unwrap().nodes().is_empty());
+
+    // Power of two trees built without a policy are supported as well.
+    let old_tree = get_vec_tree_from_slice::<U2>(16);
+    let new_tree = get_vec_tree_from_slice::<U2>(64);
+    let p = new_tree.gen_consistency_proof(16).unwrap();
+    assert!(p
+        .validate::<XOR128>(old_tree.root(), new_tree.root())
+        .expect("failed to validate"));
+
+    let quad_tree = get_vec_tree_from_slice::<U4>(64);
+    assert!(quad_tree.gen_consistency_proof(16).is_err());
+}
+
+#[cfg(test)]
+fn test_multi_proof<B: Unsigned, N: Unsigned, R: Unsigned>(
+    tree: &MerkleTree<Item, XOR128, VecStore<Item>, B, N, R>,
+) {
+    use rand::prelude::*;
+
+    let mut rng = thread_rng();
+    let leafs = tree.leafs();
+
+    for count in &[1, 2, 7, 50] {
+        let indices: Vec<usize> = (0..*count).map(|_| rng.gen_range(0, leafs)).collect();
+        let mut p = tree.gen_multi_proof(&indices).unwrap();
+        assert!(p
+            .validate::<XOR128>(tree.root())
+            .expect("failed to validate"));
+
+        // Every proven leaf is reported, and no node is repeated.
+        for (i, item) in p.indices().iter().zip(p.items().iter()) {
+            assert!(indices.contains(i));
+            assert_eq!(*item, tree.read_at(*i).unwrap());
+        }
+        let lemma_len: usize = p
+            .indices()
+            .iter()
+            .map(|i| {
+                let mut proof = Some(Box::new(tree.gen_proof(*i).unwrap()));
+                let mut len = 0;
+                while let Some(p) = proof {
+                    len += p.lemma().len();
+                    proof = p.sub_tree_proof;
+                }
+                len
+            })
+            .sum();
+        assert!(p.nodes().len() < lemma_len);
+
+        if leafs > 1 {
+            assert!(!p
+                .validate::<XOR128>(p.items()[0])
+                .expect("failed to validate"));
+        }
+        if !p.nodes().is_empty() {
+            let i = rng.gen_range(0, p.nodes().len());
+            p.nodes_mut()[i][0] ^= 0xff;
+            assert!(!p
+                .validate::<XOR128>(tree.root())
+                .expect("failed to validate"));
+
+            p.nodes_mut().pop();
+            assert!(!p
+                .validate::<XOR128>(tree.root())
+                .expect("failed to validate"));
+        }
+    }
+
+    // Proving all leafs requires no additional nodes.
+    let p = tree
+        .gen_multi_proof(&(0..leafs).collect::<Vec<_>>())
+        .unwrap();
+    assert!(p.nodes().is_empty());
+    assert!(p
+        .validate::<XOR128>(tree.root())
+        .expect("failed to validate"));
+
+    assert!(tree.gen_multi_proof(&[]).is_err());
+    assert!(tree.gen_multi_proof(&[leafs]).is_err());
+}
+
+#[test]
+fn test_multi_proofs() {
+    use crate::merkle::OddRowPolicy;
+
+    test_multi_proof(&get_vec_tree_from_slice::<U2>(1024));
+    test_multi_proof(&get_vec_tree_from_slice::<U4>(1024));
+    test_multi_proof(&get_vec_tree_from_slice::<U8>(512));
+
+    for leafs in &[1, 5, 37, 100] {
+        let tree: MerkleTree<Item, XOR128, VecStore<_>> = MerkleTree::from_data_with_options(
+            (0..*leafs).map(|x| x * 93),
+            &TreeOptions::new().with_policy(OddRowPolicy::Promote),
+        )
+        .unwrap();
+        test_multi_proof(&tree);
+    }
+}
+
+#[test]
+fn test_compound_multi_proofs() {
+    let mt1 = get_vec_tree_from_slice::<U4>(256);
+    let mt2 = get_vec_tree_from_slice::<U4>(256);
+    let mt3 = get_vec_tree_from_slice::<U4>(256);
+    let tree: MerkleTree<Item, XOR128, VecStore<_>, U4, U3> =
+        MerkleTree::from_trees(vec![mt1, mt2, mt3]).expect("Failed to build compound tree");
+    test_multi_proof(&tree);
+
+    let mt1 = get_vec_tree_from_slice::<U8>(512);
+    let tree: MerkleTree<Item, XOR128, VecStore<_>, U8, U1> =
+        MerkleTree::from_trees(vec![mt1]).expect("Failed to build compound tree");
+    test_multi_proof(&tree);
+
+    let sub_trees = (0..3)
+        .map(|_| {
+            let trees = (0..3).map(|_| get_vec_tree_from_slice::<U4>(64)).collect();
+            MerkleTree::from_trees(trees).expect("failed to build compound merkle tree")
+        })
+        .collect();
+    let tree: MerkleTree<Item, XOR128, VecStore<_>, U4, U3, U3> =
+        MerkleTree::from_sub_trees(sub_trees).expect("Failed to build ccompound tree");
+    test_multi_proof(&tree);
+}
+
+#[cfg(test)]
+fn test_range_proof<B: Unsigned, N: Unsigned, R: Unsigned>(
+    tree: &MerkleTree<Item, XOR128, VecStore<Item>, B, N, R>,
+) {
+    let leafs = tree.leafs();
+    let max_arity = *[B::to_usize(), N::to_usize(), R::to_usize()]
+        .iter()
+        .max()
+        .unwrap();
+
+    let ranges = [
+        (0, 1),
+        (0, leafs),
+        (leafs / 3, leafs / 2 + 1),
+        (leafs - 1, leafs),
+    ];
+    for (start, end) in ranges.iter().filter(|(s, e)| s < e) {
+        let mut p = tree.gen_range_proof(*start, *end).unwrap();
+        assert!(p
+            .validate::<XOR128>(tree.root())
+            .expect("failed to validate"));
+
+        assert_eq!(p.start(), *start);
+        assert_eq!(p.end(), *end);
+        for (i, item) in (*start..*end).zip(p.items().iter()) {
+            assert_eq!(*item, tree.read_at(i).unwrap());
+        }
+
+        // Only boundary nodes are included, as in the multi proof of
+        // the same leafs.
+        let indices: Vec<usize> = (*start..*end).collect();
+        assert_eq!(p.nodes(), tree.gen_multi_proof(&indices).unwrap().nodes());
+        assert!(p.nodes().len() <= 2 * (max_arity - 1) * tree.row_count());
+
+        if leafs > 1 {
+            assert!(!p
+                .validate::<XOR128>(p.items()[0])
+                .expect("failed to validate"));
+        }
+        if !p.nodes().is_empty() {
+            p.nodes_mut()[0][0] ^= 0xff;
+            assert!(!p
+                .validate::<XOR128>(tree.root())
+                .expect("failed to validate"));
+        }
+    }
+
+    assert!(tree.gen_range_proof(0, 0).is_err());
+    assert!(tree.gen_range_proof(0, leafs + 1).is_err());
+}
+
+#[test]
+fn test_range_proofs() {
+    use crate::merkle::OddRowPolicy;
+
+    test_range_proof(&get_vec_tree_from_slice::<U2>(1024));
+    test_range_proof(&get_vec_tree_from_slice::<U4>(1024));
+    test_range_proof(&get_vec_tree_from_slice::<U8>(512));
+
+    for leafs in &[1, 5, 37, 100] {
+        let tree: MerkleTree<Item, XOR128, VecStore<_>> = MerkleTree::from_data_with_options(
+            (0..*leafs).map(|x| x * 93),
+            &TreeOptions::new().with_policy(OddRowPolicy::Promote),
+        )
+        .unwrap();
+        test_range_proof(&tree);
+    }
+
+    let trees = (0..3).map(|_| get_vec_tree_from_slice::<U4>(256)).collect();
+    let tree: MerkleTree<Item, XOR128, VecStore<_>, U4, U3> =
+        MerkleTree::from_trees(trees).expect("Failed to build compound tree");
+    test_range_proof(&tree);
+
+    let sub_trees = (0..3)
+        .map(|_| {
+            let trees = (0..3).map(|_| get_vec_tree_from_slice::<U4>(64)).collect();
+            MerkleTree::from_trees(trees).expect("failed to build compound merkle tree")
+        })
+        .collect();
+    let tree: MerkleTree<Item, XOR128, VecStore<_>, U4, U3, U3> =
+        MerkleTree::from_sub_trees(sub_trees).expect("Failed to build ccompound tree");
+    test_range_proof(&tree);
+}
+
+#[cfg(test)]
+fn test_validate_against_tree<B: Unsigned, N: Unsigned, R: Unsigned>(
+    tree: &MerkleTree<Item, XOR128, VecStore<Item>, B, N, R>,
+    other: &MerkleTree<Item, XOR128, VecStore<Item>, B, N, R>,
+) {
+    let root = tree.root();
+    let leafs = tree.leafs();
+
+    for i in 0..leafs {
+        let p = tree.gen_proof(i).unwrap();
+        assert_eq!(p.leaf_index(), Some(i));
+        assert!(p
+            .validate_against::<XOR128>(&root, i, tree.leafs())
+            .expect("failed to validate"));
+        assert!(!p
+            .validate_against::<XOR128>(&root, (i + 1) % leafs, tree.leafs())
+            .expect("failed to validate"));
+
+        // A proof from another tree is self-consistent only.
+        let forged = other.gen_proof(i).unwrap();
+        assert!(forged.validate::<XOR128>().expect("failed to validate"));
+        assert!(!forged
+            .validate_against::<XOR128>(&root, i, tree.leafs())
+            .expect("failed to validate"));
+
+        assert_eq!(p.validate_strict::<XOR128>(), Ok(()));
+        assert_eq!(
+            p.validate_strict_against::<XOR128>(&root, i, tree.leafs()),
+            Ok(())
+        );
+        assert_eq!(
+            p.validate_strict_against::<XOR128>(&root, (i + 1) % leafs, tree.leafs()),
+            Err(ProofError::LeafIndexMismatch {
+                expected: (i + 1) % leafs,
+                actual: i
+            })
+        );
+        assert_eq!(
+            forged.validate_strict_against::<XOR128>(&root, i, tree.leafs()),
+            Err(ProofError::TrustedRootMismatch)
+        );
+    }
+}
+
+#[test]
+fn test_validate_against() {
+    use crate::merkle::OddRowPolicy;
+
+    let get_other = |leafs: usize| -> MerkleTree<Item, XOR128, VecStore<_>> {
+        MerkleTree::from_data_with_options((0..leafs).map(|x| x * 7 + 1), &TreeOptions::new())
+            .unwrap()
+    };
+    test_validate_against_tree(&get_vec_tree_from_slice::<U2>(64), &get_other(64));
+
+    // A proof of an interior node, as if it were a leaf of a smaller
+    // tree with the same root, is only valid against its own number
+    // of leafs.
+    let tree = get_vec_tree_from_slice::<U2>(64);
+    let p = tree.gen_proof(6).unwrap();
+    let mut lemma = tree.read_range(64 + 3, 64 + 4).unwrap();
+    lemma.extend_from_slice(&p.lemma()[2..]);
+    let forged: Proof<Item> = Proof::new::<U0, U0>(None, lemma, p.path()[1..].to_vec()).unwrap();
+    assert!(forged.validate::<XOR128>().expect("failed to validate"));
+    assert_eq!(forged.leaf_index(), Some(3));
+    assert!(!forged
+        .validate_against::<XOR128>(&tree.root(), 3, tree.leafs())
+        .expect("failed to validate"));
+    assert_eq!(
+        forged.validate_strict_against::<XOR128>(&tree.root(), 3, tree.leafs()),
+        Err(ProofError::LeafCountMismatch {
+            expected: tree.leafs(),
+            actual: tree.leafs() / 2,
+        })
+    );
+
+    // The same goes for trees of the same height: H45 of a 6 leafs
+    // RFC 6962 tree is promoted to its root as leaf 4 of a 5 leafs
+    // tree would be.
+    let get_rfc6962 = |leafs: usize| -> MerkleTree<Item, XOR128, VecStore<_>> {
+        MerkleTree::from_data_with_options(
+            (0..leafs).map(|x| x * 93 + 1),
+            &TreeOptions::new().with_policy(OddRowPolicy::Promote),
+        )
+        .unwrap()
+    };
+    let (five, six) = (get_rfc6962(5), get_rfc6962(6));
+    assert_eq!(five.row_count(), six.row_count());
+    let mut forged = five.gen_proof(4).unwrap();
+    let root_index = forged.lemma.len() - 1;
+    forged.lemma[0] = six.read_at(6 + 2).unwrap();
+    forged.lemma[root_index] = six.root();
+    assert!(forged.validate::<XOR128>().expect("failed to validate"));
+    assert_eq!(forged.leaf_index(), Some(4));
+    assert!(!forged
+        .validate_against::<XOR128>(&six.root(), 4, six.leafs())
+        .expect("failed to validate"));
+    assert_eq!(
+        forged.validate_strict_against::<XOR128>(&six.root(), 4, six.leafs()),
+        Err(ProofError::LeafCountMismatch {
+            expected: 6,
+            actual: 5,
+        })
+    );
+
+    let other: MerkleTree<Item, XOR128, VecStore<_>, U4> =
+        MerkleTree::from_data((0..256).map(|x| x * 7 + 1)).unwrap();
+    test_validate_against_tree(&get_vec_tree_from_slice::<U4>(256), &other);
+
+    for leafs in &[5, 37] {
+        let get_tree = |factor: usize| -> MerkleTree<Item, XOR128, VecStore<_>> {
+            MerkleTree::from_data_with_options(
+                (0..*leafs).map(|x| x * factor + 1),
+                &TreeOptions::new().with_policy(OddRowPolicy::Promote),
+            )
+            .unwrap()
+        };
+        test_validate_against_tree(&get_tree(93), &get_tree(7));
+    }
+
+    let get_compound = |factor: usize| -> MerkleTree<Item, XOR128, VecStore<_>, U4, U3> {
+        let trees = (0..3)
+            .map(|i| MerkleTree::from_data((0..64).map(|x| (x + i) * factor)).unwrap())
+            .collect();
+        MerkleTree::from_trees(trees).expect("Failed to build compound tree")
+    };
+    test_validate_against_tree(&get_compound(93), &get_compound(7));
+
+    let get_ccompound = |factor: usize| -> MerkleTree<Item, XOR128, VecStore<_>, U4, U3, U2> {
+        let sub_trees = (0..2)
+            .map(|j| {
+                let trees = (0..3)
+                    .map(|i| {
+                        MerkleTree::from_data((0..16).map(|x| (x + i + j * 3) * factor)).unwrap()
+                    })
+                    .collect();
+                MerkleTree::from_trees(trees).expect("failed to build compound merkle tree")
+            })
+            .collect();
+        MerkleTree::from_sub_trees(sub_trees).expect("Failed to build ccompound tree")
+    };
+    test_validate_against_tree(&get_ccompound(93), &get_ccompound(7));
+}
+
+#[test]
+fn test_validate_strict_errors() {
+    use crate::merkle::OddRowPolicy;
+
+    let tree = get_vec_tree_from_slice::<U4>(256);
+    let p = tree.gen_proof(5).unwrap();
+
+    let mut bad = p.clone();
+    bad.path[1] = 4;
+    assert_eq!(
+        bad.validate_strict::<XOR128>(),
+        Err(ProofError::PathOutOfRange {
+            layer: ProofLayer::Base,
+            row: 1,
+            digit: 4,
+            arity: 4
+        })
+    );
+    assert!(!bad.validate::<XOR128>().expect("failed to validate"));
+
+    let mut bad = p.clone();
+    bad.lemma.remove(1);
+    assert_eq!(
+        bad.validate_strict::<XOR128>(),
+        Err(ProofError::InvalidLemmaLength {
+            layer: ProofLayer::Base,
+            expected: 14,
+            actual: 13
+        })
+    );
+
+    let mut bad = p.clone();
+    bad.lemma[1][0] ^= 0xff;
+    assert_eq!(
+        bad.validate_strict::<XOR128>(),
+        Err(ProofError::RootMismatch {
+            layer: ProofLayer::Base
+        })
+    );
+
+    // Promoted rows have no siblings, and the path must match the rows.
+    let tree: MerkleTree<Item, XOR128, VecStore<_>> = MerkleTree::from_data_with_options(
+        (0..37).map(|x| x * 93),
+        &TreeOptions::new().with_policy(OddRowPolicy::Promote),
+    )
+    .unwrap();
+    let p = tree.gen_proof(36).unwrap();
+    assert_eq!(p.validate_strict::<XOR128>(), Ok(()));
+
+    let mut bad = p.clone();
+    bad.path.push(0);
+    assert_eq!(
+        bad.validate_strict::<XOR128>(),
+        Err(ProofError::InvalidPathLength {
+            layer: ProofLayer::Base,
+            expected: 6,
+            actual: 7
+        })
+    );
+
+    let mut bad = p.clone();
+    bad.path[0] = 1;
+    assert_eq!(
+        bad.validate_strict::<XOR128>(),
+        Err(ProofError::LeafOutOfRange {
+            layer: ProofLayer::Base,
+            index: 37,
+            leafs: 37
+        })
+    );
+
+    let trees = (0..3).map(|_| get_vec_tree_from_slice::<U4>(64)).collect();
+    let tree: MerkleTree<Item, XOR128, VecStore<_>, U4, U3> =
+        MerkleTree::from_trees(trees).expect("Failed to build compound tree");
+    let p = tree.gen_proof(100).unwrap();
+
+    let mut bad = p.clone();
+    bad.lemma[0][0] ^= 0xff;
+    assert_eq!(
+        bad.validate_strict::<XOR128>(),
+        Err(ProofError::RootMismatch {
+            layer: ProofLayer::SubTree
+        })
+    );
+
+    let mut bad = p.clone();
+    bad.path[0] = 3;
+    assert_eq!(
+        bad.validate_strict::<XOR128>(),
+        Err(ProofError::PathOutOfRange {
+            layer: ProofLayer::SubTree,
+            row: 0,
+            digit: 3,
+            arity: 3
+        })
+    );
+
+    let mut bad = p;
+    bad.sub_tree_proof = None;
+    assert_eq!(
+        bad.validate_strict::<XOR128>(),
+        Err(ProofError::MissingSubTreeProof {
+            layer: ProofLayer::SubTree
+        })
+    );
+}
+
+#[cfg(test)]
+fn test_proof_encoding<B: Unsigned + Eq + fmt::Debug, N: Unsigned, R: Unsigned>(
+    tree: &MerkleTree<Item, XOR128, VecStore<Item>, B, N, R>,
+) {
+    for i in &[0, tree.leafs() / 2, tree.leafs() - 1] {
+        let p = tree.gen_proof(*i).unwrap();
+
+        let bytes = p.to_bytes();
+        let decoded = Proof::<Item, B>::from_bytes(&bytes).expect("failed to decode");
+        assert_eq!(decoded, p);
+        assert!(decoded
+            .validate_against::<XOR128>(&tree.root(), *i, tree.leafs())
+            .expect("failed to validate"));
+
+        let json = serde_json::to_string(&p).expect("failed to serialize");
+        let decoded: Proof<Item, B> = serde_json::from_str(&json).expect("failed to deserialize");
+        assert_eq!(decoded, p);
+
+        // Truncated and padded encodings are rejected.
+        assert!(Proof::<Item, B>::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        let mut padded = bytes.clone();
+        padded.push(0);
+        assert!(Proof::<Item, B>::from_bytes(&padded).is_err());
+
+        // Only the version byte differs from a valid encoding.
+        let mut bad_version = bytes.clone();
+        bad_version[0] = PROOF_FORMAT_VERSION + 1;
+        assert!(Proof::<Item, B>::from_bytes(&bad_version).is_err());
+        bad_version[0] = 0;
+        assert!(Proof::<Item, B>::from_bytes(&bad_version).is_err());
+
+        // The base tree arity must match the one of the proof type.
+        if B::to_usize() == 2 {
+            assert!(Proof::<Item, U4>::from_bytes(&bytes).is_err());
+            assert!(serde_json::from_str::<Proof<Item, U4>>(&json).is_err());
+        } else {
+            assert!(Proof::<Item, U2>::from_bytes(&bytes).is_err());
+            assert!(serde_json::from_str::<Proof<Item, U2>>(&json).is_err());
+        }
+    }
+}
+
+#[test]
+fn test_proof_encodings() {
+    use crate::merkle::OddRowPolicy;
+
+    test_proof_encoding(&get_vec_tree_from_slice::<U2>(64));
+    test_proof_encoding(&get_vec_tree_from_slice::<U8>(512));
+
+    let tree: MerkleTree<Item, XOR128, VecStore<_>> = MerkleTree::from_data_with_options(
+        (0..37).map(|x| x * 93),
+        &TreeOptions::new().with_policy(OddRowPolicy::Promote),
+    )
+    .unwrap();
+    test_proof_encoding(&tree);
+    let tree: MerkleTree<Item, XOR128, VecStore<_>, U4> = MerkleTree::from_data_with_options(
+        (0..37).map(|x| x * 93),
+        &TreeOptions::new().with_policy(OddRowPolicy::Pad),
+    )
+    .unwrap();
+    test_proof_encoding(&tree);
+
+    let trees = (0..3).map(|_| get_vec_tree_from_slice::<U4>(64)).collect();
+    let tree: MerkleTree<Item, XOR128, VecStore<_>, U4, U3> =
+        MerkleTree::from_trees(trees).expect("Failed to build compound tree");
+    test_proof_encoding(&tree);
+
+    let sub_trees = (0..2)
+        .map(|_| {
+            let trees = (0..3).map(|_| get_vec_tree_from_slice::<U4>(16)).collect();
+            MerkleTree::from_trees(trees).expect("failed to build compound merkle tree")
+        })
+        .collect();
+    let tree: MerkleTree<Item, XOR128, VecStore<_>, U4, U3, U2> =
+        MerkleTree::from_sub_trees(sub_trees).expect("Failed to build ccompound tree");
+    test_proof_encoding(&tree);
+
+    // The layout is stable: header, layer and row arities, then the
+    // path and lemma.
+    let tree = get_vec_tree_from_slice::<U2>(2);
+    let bytes = tree.gen_proof(1).unwrap().to_bytes();
+    assert_eq!(
+        bytes.len(),
+        1 + 4 * 4 + 1 + 8 + 4 + (4 + 4) + (4 + 4) + (4 + 3 * 16)
+    );
+    assert_eq!(&bytes[..5], &[PROOF_FORMAT_VERSION, 16, 0, 0, 0]);
+    assert_eq!(&bytes[26..38], &[0, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0]);
+}
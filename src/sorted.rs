@@ -0,0 +1,363 @@
+use std::cmp::Ordering;
+
+use anyhow::Result;
+use typenum::marker_traits::Unsigned;
+use typenum::U2;
+
+use crate::hash::Algorithm;
+use crate::merkle::{Element, MerkleTree, OddRowPolicy};
+use crate::options::TreeOptions;
+use crate::proof::Proof;
+use crate::store::Store;
+
+/// Merkle Tree whose elements are sorted (by the `Ord` of `E`) and
+/// unique, so that the absence of an element can be proven.
+///
+/// The leafs of the tree are the `Algorithm::leaf` hashes of the
+/// elements, so that interior nodes can't be passed off as elements,
+/// and the item of an inclusion proof is the leaf hash of the proven
+/// element.  The elements are kept alongside the tree to search them.
+#[derive(Debug, Clone)]
+pub struct SortedMerkleTree<E, A, S, BaseTreeArity = U2>
+where
+    E: Element,
+    A: Algorithm<E>,
+    S: Store<E>,
+    BaseTreeArity: Unsigned,
+{
+    tree: MerkleTree<E, A, S, BaseTreeArity>,
+    keys: Vec<E>,
+}
+
+/// Proof that an element is not a leaf of a `SortedMerkleTree`.
+///
+/// Holds the elements immediately before and after the element with
+/// the inclusion proofs of their leafs, only one of them if the
+/// element sorts before the first leaf or after the last one.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct NonInclusionProof<E: Element, BaseTreeArity: Unsigned = U2> {
+    key: E,
+    left: Option<(E, Proof<E, BaseTreeArity>)>,
+    right: Option<(E, Proof<E, BaseTreeArity>)>,
+}
+
+// Returns the leaf of `key` in a sorted tree.
+fn hash_key<E: Element, A: Algorithm<E>>(key: &E) -> E {
+    let mut a = A::default();
+    a.leaf(key.clone())
+}
+
+impl<E: Element, A: Algorithm<E>, S: Store<E>, BaseTreeArity: Unsigned>
+    SortedMerkleTree<E, A, S, BaseTreeArity>
+{
+    /// Sorts `leafs` and builds the tree over them.  Duplicated leafs
+    /// are rejected.
+    pub fn new<I: IntoIterator<Item = E>>(leafs: I) -> Result<Self> {
+        Self::new_with_policy(leafs, OddRowPolicy::Reject)
+    }
+
+    /// Sorts `leafs` and builds the tree over them, with the odd row
+    /// policy.  Duplicated leafs are rejected.
+    pub fn new_with_policy<I: IntoIterator<Item = E>>(
+        leafs: I,
+        policy: OddRowPolicy,
+    ) -> Result<Self> {
+        let mut keys: Vec<E> = leafs.into_iter().collect();
+        keys.sort_unstable();
+        if let Some(i) = keys.windows(2).position(|w| w[0] == w[1]) {
+            bail!("Duplicated leaf {:?}", keys[i]);
+        }
+
+        let mut bytes = vec![0; keys.len() * E::byte_len()];
+        for (key, chunk) in keys.iter().zip(bytes.chunks_mut(E::byte_len())) {
+            hash_key::<E, A>(key).copy_to_slice(chunk);
+        }
+
+        Ok(SortedMerkleTree {
+            tree: MerkleTree::from_byte_slice_with_options(
+                &bytes,
+                &TreeOptions::new().with_policy(policy),
+            )?,
+            keys,
+        })
+    }
+
+    /// Wraps an existing base tree built over the leaf hashes of
+    /// `keys`, checking that they are sorted and unique.
+    pub fn from_tree(tree: MerkleTree<E, A, S, BaseTreeArity>, keys: Vec<E>) -> Result<Self> {
+        let store = tree
+            .data()
+            .ok_or_else(|| anyhow!("Sorted trees require a base tree"))?;
+
+        ensure!(
+            keys.windows(2).all(|w| w[0] < w[1]),
+            "Leafs must be sorted and unique"
+        );
+        ensure!(keys.len() == tree.leafs(), "Inconsistent number of leafs");
+        let leafs = store.read_range(0..tree.leafs())?;
+        ensure!(
+            keys.iter()
+                .zip(leafs.iter())
+                .all(|(key, leaf)| hash_key::<E, A>(key) == *leaf),
+            "Leafs must be the leaf hashes of the keys"
+        );
+
+        Ok(SortedMerkleTree { tree, keys })
+    }
+
+    /// Returns the underlying tree.
+    pub fn tree(&self) -> &MerkleTree<E, A, S, BaseTreeArity> {
+        &self.tree
+    }
+
+    /// Returns merkle root
+    pub fn root(&self) -> E {
+        self.tree.root()
+    }
+
+    /// Returns number of leafs
+    pub fn leafs(&self) -> usize {
+        self.tree.leafs()
+    }
+
+    /// Returns the sorted elements of the tree.
+    pub fn keys(&self) -> &[E] {
+        &self.keys
+    }
+
+    /// Binary searches the elements for `key`, returning its index if
+    /// found, or else the index where it would be inserted.
+    pub fn find(&self, key: &E) -> std::result::Result<usize, usize> {
+        self.keys.binary_search(key)
+    }
+
+    /// Generate merkle tree inclusion proof for `key`, which must be a
+    /// leaf.
+    pub fn gen_proof(&self, key: &E) -> Result<Proof<E, BaseTreeArity>> {
+        match self.find(key) {
+            Ok(i) => self.tree.gen_proof(i),
+            Err(_) => bail!("{:?} is not a leaf", key),
+        }
+    }
+
+    /// Generate a proof that `key` is not a leaf.
+    pub fn gen_non_inclusion_proof(&self, key: &E) -> Result<NonInclusionProof<E, BaseTreeArity>> {
+        let i = match self.find(key) {
+            Ok(i) => bail!("{:?} is leaf {}", key, i),
+            Err(i) => i,
+        };
+
+        let left = if i > 0 {
+            Some((self.keys[i - 1].clone(), self.tree.gen_proof(i - 1)?))
+        } else {
+            None
+        };
+        let right = if i < self.tree.leafs() {
+            Some((self.keys[i].clone(), self.tree.gen_proof(i)?))
+        } else {
+            None
+        };
+
+        Ok(NonInclusionProof {
+            key: key.clone(),
+            left,
+            right,
+        })
+    }
+}
+
+impl<E: Element, BaseTreeArity: Unsigned> NonInclusionProof<E, BaseTreeArity> {
+    /// Verifies that `key` is not a leaf of the sorted tree of `leafs`
+    /// leafs with root `trusted_root`, both obtained independently of
+    /// the proof: the neighbour proofs must be valid against them, be
+    /// for adjacent leafs (or for the first or last leaf) and prove
+    /// elements surrounding `key`.
+    pub fn validate<A: Algorithm<E>>(&self, trusted_root: &E, leafs: usize) -> Result<bool> {
+        let mut indices = [None, None];
+        let neighbours = [
+            (&self.left, Ordering::Less),
+            (&self.right, Ordering::Greater),
+        ];
+        for (index, (neighbour, ordering)) in indices.iter_mut().zip(neighbours.iter()) {
+            let (key, proof) = match neighbour {
+                Some((key, proof)) => (key, proof),
+                None => continue,
+            };
+            if key.cmp(&self.key) != *ordering || proof.item() != hash_key::<E, A>(key) {
+                return Ok(false);
+            }
+
+            // The proof must be of a tree of `leafs`, so that the
+            // proven node is a leaf.
+            *index = match proof.leaf_index() {
+                Some(i) if proof.validate_against::<A>(trusted_root, i, leafs)? => Some(i),
+                _ => return Ok(false),
+            };
+        }
+
+        Ok(match (indices[0], indices[1]) {
+            (Some(l), Some(r)) => r == l + 1,
+            (Some(l), None) => l + 1 == leafs,
+            (None, Some(r)) => r == 0,
+            (None, None) => false,
+        })
+    }
+
+    /// Returns the element proven not to be a leaf.
+    pub fn key(&self) -> &E {
+        &self.key
+    }
+
+    /// Returns the greatest element smaller than the key, with the
+    /// proof of its leaf.
+    pub fn left(&self) -> Option<(&E, &Proof<E, BaseTreeArity>)> {
+        self.left.as_ref().map(|(key, proof)| (key, proof))
+    }
+
+    /// Returns the smallest element greater than the key, with the
+    /// proof of its leaf.
+    pub fn right(&self) -> Option<(&E, &Proof<E, BaseTreeArity>)> {
+        self.right.as_ref().map(|(key, proof)| (key, proof))
+    }
+}
+
+#[cfg(test)]
+use crate::store::VecStore;
+#[cfg(test)]
+use crate::test_common::{Item, XOR128};
+#[cfg(test)]
+use std::fmt;
+#[cfg(test)]
+use typenum::{U0, U4};
+
+#[cfg(test)]
+fn get_key(i: usize) -> Item {
+    let mut key = [0; 16];
+    key[..8].copy_from_slice(&(i as u64).to_be_bytes());
+    key
+}
+
+#[cfg(test)]
+fn test_sorted<U: Unsigned + Eq + fmt::Debug>(count: usize, policy: OddRowPolicy) {
+    // Keys 2, 4, ..., 2 * count, shuffled.
+    let keys = (0..count).map(|i| get_key((i * 7) % count * 2 + 2));
+    let tree: SortedMerkleTree<Item, XOR128, VecStore<_>, U> =
+        SortedMerkleTree::new_with_policy(keys, policy).expect("failed to build tree");
+    let root = tree.root();
+    assert_eq!(tree.leafs(), count);
+
+    for i in 0..count {
+        let key = get_key(i * 2 + 2);
+        assert_eq!(tree.find(&key), Ok(i));
+        let p = tree.gen_proof(&key).unwrap();
+        assert_eq!(p.item(), hash_key::<Item, XOR128>(&key));
+        assert!(p
+            .validate_against::<XOR128>(&root, i, tree.tree().leafs())
+            .unwrap());
+        assert!(tree.gen_non_inclusion_proof(&key).is_err());
+    }
+
+    for i in 0..=count {
+        let key = get_key(i * 2 + 1);
+        assert_eq!(tree.find(&key), Err(i));
+        assert!(tree.gen_proof(&key).is_err());
+
+        let p = tree.gen_non_inclusion_proof(&key).unwrap();
+        assert_eq!(p.left().is_some(), i > 0);
+        assert_eq!(p.right().is_some(), i < count);
+        assert!(p.validate::<XOR128>(&root, count).unwrap());
+        if count > 1 {
+            assert!(!p.validate::<XOR128>(&root, count - 1).unwrap());
+        }
+
+        // The proof does not hold for the neighbour leafs.
+        let mut forged = p.clone();
+        forged.key = get_key(if i < count { i * 2 + 2 } else { i * 2 });
+        assert!(!forged.validate::<XOR128>(&root, count).unwrap());
+
+        // Missing one side is only valid at the boundaries.
+        if i > 0 && i < count {
+            let mut forged = p.clone();
+            forged.right = None;
+            assert!(!forged.validate::<XOR128>(&root, count).unwrap());
+            let mut forged = p.clone();
+            forged.left = None;
+            assert!(!forged.validate::<XOR128>(&root, count).unwrap());
+        }
+    }
+
+    // Leafs which are not adjacent do not prove anything.
+    if count > 3 {
+        let mut forged = tree.gen_non_inclusion_proof(&get_key(3)).unwrap();
+        forged.right = Some((get_key(6), tree.gen_proof(&get_key(6)).unwrap()));
+        forged.key = get_key(5);
+        assert!(!forged.validate::<XOR128>(&root, count).unwrap());
+    }
+}
+
+#[test]
+fn test_sorted_trees() {
+    test_sorted::<U2>(16, OddRowPolicy::Reject);
+    test_sorted::<U4>(64, OddRowPolicy::Reject);
+    test_sorted::<U2>(1, OddRowPolicy::Promote);
+    test_sorted::<U2>(37, OddRowPolicy::Promote);
+}
+
+#[test]
+fn test_sorted_interior_node() {
+    let keys = (0..16).map(|i| get_key(i * 2 + 2));
+    let tree: SortedMerkleTree<Item, XOR128, VecStore<_>> =
+        SortedMerkleTree::new(keys).expect("failed to build tree");
+    let root = tree.root();
+
+    // A proof of the parent of the first two leafs, with an all zero
+    // path one row short, as the first leaf of a smaller tree.
+    let node = tree.tree().read_range(16, 17).unwrap()[0];
+    let p = tree.tree().gen_proof(0).unwrap();
+    let mut lemma = vec![node];
+    lemma.extend_from_slice(&p.lemma()[2..]);
+    let interior: Proof<Item> = Proof::new::<U0, U0>(None, lemma, vec![0; 3]).unwrap();
+    assert!(interior.validate::<XOR128>().unwrap());
+    assert_eq!(interior.root(), root);
+
+    // The leaf hashes of XOR128 are rotated elements, so the node is
+    // the leaf hash of an element.
+    let mut key = [0; 16];
+    key[..15].copy_from_slice(&node[1..]);
+    key[15] = node[0];
+    assert_eq!(hash_key::<Item, XOR128>(&key), node);
+
+    let forged = NonInclusionProof {
+        key: [0; 16],
+        left: None,
+        right: Some((key, interior)),
+    };
+    assert!(key > forged.key);
+    assert!(!forged.validate::<XOR128>(&root, 16).unwrap());
+}
+
+#[test]
+fn test_sorted_duplicates() {
+    let keys = vec![get_key(1), get_key(2), get_key(1), get_key(3)];
+    assert!(
+        SortedMerkleTree::<Item, XOR128, VecStore<_>>::new_with_policy(keys, OddRowPolicy::Promote)
+            .is_err()
+    );
+
+    let get_tree = |keys: &[Item]| -> MerkleTree<Item, XOR128, VecStore<_>> {
+        let leafs: Vec<Item> = keys.iter().map(hash_key::<Item, XOR128>).collect();
+        MerkleTree::from_byte_slice(&leafs.concat()).unwrap()
+    };
+    let unsorted = vec![get_key(2), get_key(1)];
+    assert!(SortedMerkleTree::from_tree(get_tree(&unsorted), unsorted).is_err());
+
+    // The leafs must be the leaf hashes of the keys.
+    let sorted = vec![get_key(1), get_key(2)];
+    let raw: MerkleTree<Item, XOR128, VecStore<_>> =
+        MerkleTree::from_byte_slice(&sorted.concat()).unwrap();
+    assert!(SortedMerkleTree::from_tree(raw, sorted.clone()).is_err());
+
+    let tree = SortedMerkleTree::from_tree(get_tree(&sorted), sorted).unwrap();
+    let p = tree.gen_non_inclusion_proof(&get_key(3)).unwrap();
+    assert!(p.validate::<XOR128>(&tree.root(), 2).unwrap());
+}
@@ -0,0 +1,442 @@
+use std::collections::{BTreeSet, HashMap};
+use std::marker::PhantomData;
+
+use anyhow::Result;
+
+use crate::hash::Algorithm;
+use crate::merkle::Element;
+
+/// Key of a `SparseMerkleTree` leaf, read as a big endian integer.
+pub type SparseKey = [u8; 32];
+
+/// Number of rows below the root of a `SparseMerkleTree`.
+pub const SPARSE_TREE_DEPTH: usize = 256;
+
+/// Sparse binary Merkle Tree, with one leaf for each of the 2^256
+/// possible keys.
+///
+/// A leaf holds `Leaf(value)` if a value was set for its key and
+/// `E::default()` otherwise.  The hash of a sub-tree whose leafs are
+/// all empty only depends on its height, so these hashes are computed
+/// once per height and only nodes which differ from them are stored:
+///
+/// ```text
+///     empty[0] = E::default()
+///     empty[h + 1] = Node(empty[h], empty[h])
+/// ```
+///
+/// The root splits on the most significant bit of the keys, and the
+/// leafs are ordered by key.  Proofs only include the siblings which
+/// are not empty, along with a bitmap of the rows they belong to.
+#[derive(Debug, Clone)]
+pub struct SparseMerkleTree<E: Element, A: Algorithm<E>> {
+    // Non-empty nodes, by height and key of their first leaf.
+    nodes: HashMap<(usize, SparseKey), E>,
+    // Values of the non-empty leafs.
+    values: HashMap<SparseKey, E>,
+    // Hash of an empty sub-tree, by height.
+    empty: Vec<E>,
+    _a: PhantomData<A>,
+}
+
+/// Membership or non-membership proof for a key of a
+/// `SparseMerkleTree`.
+///
+/// Siblings are ordered from the leaf row up, and bit `h` of `bitmap`
+/// (in the same order as the bits of a key) is set if the sibling at
+/// height `h` is included, i.e. is not the empty sub-tree hash.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SparseProof<E: Element> {
+    key: SparseKey,
+    value: Option<E>,
+    bitmap: SparseKey,
+    siblings: Vec<E>,
+}
+
+impl<E: Element, A: Algorithm<E>> Default for SparseMerkleTree<E, A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<E: Element, A: Algorithm<E>> SparseMerkleTree<E, A> {
+    /// Creates a new tree in which all leafs are empty.
+    pub fn new() -> SparseMerkleTree<E, A> {
+        SparseMerkleTree {
+            nodes: HashMap::new(),
+            values: HashMap::new(),
+            empty: get_empty_hashes::<E, A>(),
+            _a: PhantomData,
+        }
+    }
+
+    /// Returns merkle root
+    pub fn root(&self) -> E {
+        self.node(SPARSE_TREE_DEPTH, &[0; 32])
+    }
+
+    /// Returns the value set for `key`, if any.
+    pub fn get(&self, key: &SparseKey) -> Option<E> {
+        self.values.get(key).cloned()
+    }
+
+    /// Returns the number of non-empty leafs.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Returns `true` if all leafs are empty.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Returns the number of stored (non-empty) nodes, leafs included.
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Sets the value of `key`.
+    pub fn insert(&mut self, key: SparseKey, value: E) -> Result<()> {
+        self.update_batch(vec![(key, Some(value))])
+    }
+
+    /// Clears the value of `key`, making its leaf empty.
+    pub fn remove(&mut self, key: &SparseKey) -> Result<()> {
+        self.update_batch(vec![(*key, None)])
+    }
+
+    /// Sets (or clears, for `None`) the value of every key in
+    /// `updates`, in order, then rehashes each affected node once.
+    pub fn update_batch<I: IntoIterator<Item = (SparseKey, Option<E>)>>(
+        &mut self,
+        updates: I,
+    ) -> Result<()> {
+        let mut a = A::default();
+        let mut dirty = BTreeSet::new();
+        for (key, value) in updates {
+            match value {
+                Some(value) => {
+                    a.reset();
+                    let leaf = a.leaf(value.clone());
+                    ensure!(leaf != self.empty[0], "Leaf hash collides with empty leaf");
+
+                    self.nodes.insert((0, key), leaf);
+                    self.values.insert(key, value);
+                }
+                None => {
+                    self.nodes.remove(&(0, key));
+                    self.values.remove(&key);
+                }
+            }
+            dirty.insert(key);
+        }
+
+        for height in 0..SPARSE_TREE_DEPTH {
+            let mut parents = BTreeSet::new();
+            for key in dirty {
+                let mut left = key;
+                clear_bit(&mut left, height);
+                let mut right = left;
+                set_bit(&mut right, height);
+
+                a.reset();
+                let parent = a.node(self.node(height, &left), self.node(height, &right), height);
+
+                if parent == self.empty[height + 1] {
+                    self.nodes.remove(&(height + 1, left));
+                } else {
+                    self.nodes.insert((height + 1, left), parent);
+                }
+                parents.insert(left);
+            }
+
+            dirty = parents;
+        }
+
+        Ok(())
+    }
+
+    /// Generate a proof that `key` holds its current value, or that
+    /// it is empty if no value is set.
+    pub fn gen_proof(&self, key: &SparseKey) -> SparseProof<E> {
+        let mut bitmap = [0; 32];
+        let mut siblings = Vec::new();
+        for height in 0..SPARSE_TREE_DEPTH {
+            let mut sibling = *key;
+            flip_bit(&mut sibling, height);
+            clear_low_bits(&mut sibling, height);
+
+            if let Some(node) = self.nodes.get(&(height, sibling)) {
+                set_bit(&mut bitmap, height);
+                siblings.push(node.clone());
+            }
+        }
+
+        SparseProof {
+            key: *key,
+            value: self.get(key),
+            bitmap,
+            siblings,
+        }
+    }
+
+    // Returns the node at `height` whose first leaf is `key`.
+    fn node(&self, height: usize, key: &SparseKey) -> E {
+        self.nodes
+            .get(&(height, *key))
+            .cloned()
+            .unwrap_or_else(|| self.empty[height].clone())
+    }
+}
+
+impl<E: Element> SparseProof<E> {
+    /// Returns the proven key.
+    pub fn key(&self) -> &SparseKey {
+        &self.key
+    }
+
+    /// Returns the proven value, or `None` for a non-membership proof.
+    pub fn value(&self) -> Option<&E> {
+        self.value.as_ref()
+    }
+
+    /// Returns `true` if this proof shows that a value is set for the key.
+    pub fn is_membership(&self) -> bool {
+        self.value.is_some()
+    }
+
+    /// Returns the included (non-empty) siblings, from the leaf row up.
+    pub fn siblings(&self) -> &Vec<E> {
+        &self.siblings
+    }
+
+    /// Verifies the proof against `root`.
+    pub fn validate<A: Algorithm<E>>(&self, root: &E) -> bool {
+        let empty = get_empty_hashes::<E, A>();
+
+        let mut a = A::default();
+        let mut h = match &self.value {
+            Some(value) => a.leaf(value.clone()),
+            None => empty[0].clone(),
+        };
+
+        let mut siblings = self.siblings.iter();
+        for (height, empty) in empty.iter().enumerate().take(SPARSE_TREE_DEPTH) {
+            let sibling = if get_bit(&self.bitmap, height) {
+                match siblings.next() {
+                    Some(sibling) => sibling.clone(),
+                    None => return false,
+                }
+            } else {
+                empty.clone()
+            };
+
+            a.reset();
+            h = if get_bit(&self.key, height) {
+                a.node(sibling, h, height)
+            } else {
+                a.node(h, sibling, height)
+            };
+        }
+
+        siblings.next().is_none() && h == *root
+    }
+
+    /// Verifies that the proof shows `key` holds `value` under `root`.
+    pub fn validate_membership<A: Algorithm<E>>(
+        &self,
+        root: &E,
+        key: &SparseKey,
+        value: &E,
+    ) -> bool {
+        self.key == *key && self.value.as_ref() == Some(value) && self.validate::<A>(root)
+    }
+
+    /// Verifies that the proof shows `key` is empty under `root`.
+    pub fn validate_non_membership<A: Algorithm<E>>(&self, root: &E, key: &SparseKey) -> bool {
+        self.key == *key && self.value.is_none() && self.validate::<A>(root)
+    }
+}
+
+// Returns the hash of an empty sub-tree for each height up to the root.
+fn get_empty_hashes<E: Element, A: Algorithm<E>>() -> Vec<E> {
+    let mut a = A::default();
+    let mut empty = Vec::with_capacity(SPARSE_TREE_DEPTH + 1);
+    empty.push(E::default());
+    for height in 0..SPARSE_TREE_DEPTH {
+        a.reset();
+        let h = a.node(empty[height].clone(), empty[height].clone(), height);
+        empty.push(h);
+    }
+
+    empty
+}
+
+// Bit `i` of a key, counting from the least significant bit.
+#[inline]
+fn get_bit(key: &SparseKey, i: usize) -> bool {
+    key[31 - i / 8] >> (i % 8) & 1 == 1
+}
+
+#[inline]
+fn set_bit(key: &mut SparseKey, i: usize) {
+    key[31 - i / 8] |= 1 << (i % 8);
+}
+
+#[inline]
+fn clear_bit(key: &mut SparseKey, i: usize) {
+    key[31 - i / 8] &= !(1 << (i % 8));
+}
+
+#[inline]
+fn flip_bit(key: &mut SparseKey, i: usize) {
+    key[31 - i / 8] ^= 1 << (i % 8);
+}
+
+// Clears the `count` least significant bits of a key.
+#[inline]
+fn clear_low_bits(key: &mut SparseKey, count: usize) {
+    for i in 0..count {
+        clear_bit(key, i);
+    }
+}
+
+#[cfg(test)]
+use crate::hash::Hashable;
+#[cfg(test)]
+use crate::merkle::MerkleTree;
+#[cfg(test)]
+use crate::store::VecStore;
+#[cfg(test)]
+use crate::test_common::{Item, XOR128};
+
+#[cfg(test)]
+fn get_key(i: usize) -> SparseKey {
+    let mut key = [0; 32];
+    key[..8].copy_from_slice(&(i as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15).to_be_bytes());
+    key[31] = i as u8;
+    key
+}
+
+#[cfg(test)]
+fn get_value(i: usize) -> Item {
+    let mut a = XOR128::new();
+    ((i + 1) * 93).hash(&mut a);
+    a.hash()
+}
+
+#[test]
+fn test_sparse_empty() {
+    let tree: SparseMerkleTree<Item, XOR128> = SparseMerkleTree::new();
+    let empty = get_empty_hashes::<Item, XOR128>();
+    assert_eq!(tree.root(), empty[SPARSE_TREE_DEPTH]);
+    assert!(tree.is_empty());
+
+    let p = tree.gen_proof(&get_key(1));
+    assert!(p.siblings().is_empty());
+    assert!(p.validate_non_membership::<XOR128>(&tree.root(), &get_key(1)));
+}
+
+#[test]
+fn test_sparse_proofs() {
+    let mut tree: SparseMerkleTree<Item, XOR128> = SparseMerkleTree::new();
+    for i in 0..50 {
+        tree.insert(get_key(i), get_value(i))
+            .expect("failed to insert");
+    }
+    assert_eq!(tree.len(), 50);
+    assert!(tree.node_count() <= 50 * (SPARSE_TREE_DEPTH + 1));
+
+    let root = tree.root();
+    for i in 0..50 {
+        let p = tree.gen_proof(&get_key(i));
+        assert!(p.is_membership());
+        assert!(p.validate_membership::<XOR128>(&root, &get_key(i), &get_value(i)));
+        assert!(!p.validate_membership::<XOR128>(&root, &get_key(i), &get_value(i + 1)));
+        assert!(!p.validate_non_membership::<XOR128>(&root, &get_key(i)));
+    }
+
+    for i in 50..60 {
+        let p = tree.gen_proof(&get_key(i));
+        assert!(!p.is_membership());
+        assert!(p.validate_non_membership::<XOR128>(&root, &get_key(i)));
+
+        // A forged value for an empty key does not validate.
+        let mut forged = p.clone();
+        forged.value = Some(get_value(i));
+        assert!(!forged.validate::<XOR128>(&root));
+    }
+
+    let mut p = tree.gen_proof(&get_key(3));
+    p.siblings.pop();
+    assert!(!p.validate::<XOR128>(&root));
+}
+
+#[test]
+fn test_sparse_batch_update() {
+    let mut single: SparseMerkleTree<Item, XOR128> = SparseMerkleTree::new();
+    for i in 0..40 {
+        single
+            .insert(get_key(i), get_value(i))
+            .expect("failed to insert");
+    }
+    for i in 0..40 {
+        if i % 3 == 0 {
+            single.remove(&get_key(i)).expect("failed to remove");
+        }
+    }
+
+    let mut batch: SparseMerkleTree<Item, XOR128> = SparseMerkleTree::new();
+    batch
+        .update_batch((0..40).map(|i| (get_key(i), Some(get_value(i)))))
+        .expect("failed to update");
+    batch
+        .update_batch((0..40).filter(|i| i % 3 == 0).map(|i| (get_key(i), None)))
+        .expect("failed to update");
+
+    assert_eq!(single.root(), batch.root());
+    assert_eq!(single.len(), batch.len());
+    assert_eq!(single.node_count(), batch.node_count());
+
+    // Removing everything leaves no stored node.
+    batch
+        .update_batch((0..40).map(|i| (get_key(i), None)))
+        .expect("failed to update");
+    assert_eq!(batch.node_count(), 0);
+    assert_eq!(batch.root(), SparseMerkleTree::<Item, XOR128>::new().root());
+}
+
+#[test]
+fn test_sparse_dense_root() {
+    // Keys 0..n populate the first leafs, so the sub-tree above them
+    // is the dense tree over the same leafs (empty ones included).
+    let leafs = 16;
+    let mut tree: SparseMerkleTree<Item, XOR128> = SparseMerkleTree::new();
+    let mut dense = Vec::with_capacity(leafs);
+    for i in 0..leafs {
+        let mut key = [0; 32];
+        key[31] = i as u8;
+        if i % 4 != 1 {
+            tree.insert(key, get_value(i)).expect("failed to insert");
+            dense.push(get_value(i));
+        } else {
+            dense.push(Item::default());
+        }
+    }
+
+    let mut a = XOR128::new();
+    let leafs_hashes: Vec<Item> = dense
+        .iter()
+        .map(|v| {
+            a.reset();
+            if *v == Item::default() {
+                *v
+            } else {
+                a.leaf(*v)
+            }
+        })
+        .collect();
+    let mt: MerkleTree<Item, XOR128, VecStore<_>> =
+        MerkleTree::from_byte_slice(&leafs_hashes.concat()).expect("failed to create tree");
+    assert_eq!(tree.node(4, &[0; 32]), mt.root());
+}
@@ -4,22 +4,22 @@ use std::iter::FromIterator;
 use std::marker::PhantomData;
 use std::ops;
 use std::path::Path;
-use std::sync::{Arc, RwLock};
 
 use anyhow::{Context, Result};
-use memmap::MmapOptions;
 use positioned_io::{ReadAt, WriteAt};
 use rayon::iter::*;
-use rayon::prelude::*;
 use tempfile::tempfile;
-use typenum::marker_traits::Unsigned;
 
 use crate::hash::Algorithm;
 use crate::merkle::{
-    get_merkle_tree_cache_size, get_merkle_tree_leafs, get_merkle_tree_len, log2_pow2, next_pow2,
-    Element,
+    get_merkle_tree_cache_size_with_schedule, get_merkle_tree_leafs_with_schedule,
+    get_merkle_tree_len_with_schedule, row_arity, Element, OddRowPolicy,
+};
+use crate::options::BuildOptions;
+use crate::store::{
+    block_row_offsets, hash_block, hash_chunk, nodes_as_bytes, populate_block_size, populated_len,
+    Store, StoreConfig, StoreConfigDataVersion,
 };
-use crate::store::{Store, StoreConfig, StoreConfigDataVersion, BUILD_CHUNK_NODES};
 
 /// The Disk-only store is used to reduce memory to the minimum at the
 /// cost of build time performance. Most of its I/O logic is in the
@@ -233,15 +233,25 @@ impl<E: Element> Store<E> for DiskStore<E> {
         branches: usize,
         config: StoreConfig,
         store_version: u32,
+    ) -> Result<bool> {
+        self.compact_with_schedule(&[branches], config, store_version)
+    }
+
+    fn compact_with_schedule(
+        &mut self,
+        arities: &[usize],
+        config: StoreConfig,
+        store_version: u32,
     ) -> Result<bool> {
         // Determine how many base layer leafs there are (and in bytes).
-        let leafs = get_merkle_tree_leafs(self.len, branches)?;
+        let leafs = get_merkle_tree_leafs_with_schedule(self.len, arities)?;
         let data_width = leafs * self.elem_len;
 
         // Calculate how large the cache should be (based on the
         // config.rows_to_discard param).
         let cache_size =
-            get_merkle_tree_cache_size(leafs, branches, config.rows_to_discard)? * self.elem_len;
+            get_merkle_tree_cache_size_with_schedule(leafs, arities, config.rows_to_discard)?
+                * self.elem_len;
 
         // The file cannot be compacted if the specified configuration
         // requires either 1) nothing to be cached, or 2) everything
@@ -333,81 +343,127 @@ impl<E: Element> Store<E> for DiskStore<E> {
         self.file.sync_all().context("failed to sync file")
     }
 
-    #[allow(unsafe_code)]
-    fn process_layer<A: Algorithm<E>, U: Unsigned>(
+    fn process_layer<A: Algorithm<E>>(
         &mut self,
         width: usize,
         level: usize,
         read_start: usize,
         write_start: usize,
+        branches: usize,
+        policy: OddRowPolicy,
+        options: &BuildOptions,
     ) -> Result<()> {
-        // Safety: this operation is safe becase it's a limited
-        // writable region on the backing store managed by this type.
-        let mut mmap = unsafe {
-            let mut mmap_options = MmapOptions::new();
-            mmap_options
-                .offset((write_start * E::byte_len()) as u64)
-                .len(width * E::byte_len())
-                .map_mut(&self.file)
-        }?;
-
-        let data_lock = Arc::new(RwLock::new(self));
-        let branches = U::to_usize();
-        let shift = log2_pow2(branches);
-        let write_chunk_width = (BUILD_CHUNK_NODES >> shift) * E::byte_len();
-
-        ensure!(BUILD_CHUNK_NODES % branches == 0, "Invalid chunk size");
-        Vec::from_iter((read_start..read_start + width).step_by(BUILD_CHUNK_NODES))
+        let write_width = policy.next_row_width(width, branches);
+        ensure!(
+            (write_start + write_width) * E::byte_len() <= self.store_size,
+            "Requested slice too large (max: {})",
+            self.store_size
+        );
+
+        // Each thread reads a chunk of the row and writes its parents
+        // with a positioned write on its own handle of the file: the
+        // written ranges are disjoint, so that no lock is needed.
+        let store = &*self;
+        let read_chunk_width = options.build_chunk_nodes(branches);
+
+        Vec::from_iter((read_start..read_start + width).step_by(read_chunk_width))
             .into_par_iter()
-            .zip(mmap.par_chunks_mut(write_chunk_width))
-            .try_for_each(|(chunk_index, write_mmap)| -> Result<()> {
-                let chunk_size = std::cmp::min(BUILD_CHUNK_NODES, read_start + width - chunk_index);
-
-                let chunk_nodes = {
-                    // Read everything taking the lock once.
-                    data_lock
-                        .read()
-                        .unwrap()
-                        .read_range(chunk_index..chunk_index + chunk_size)?
-                };
-
-                let nodes_size = (chunk_nodes.len() / branches) * E::byte_len();
-                let hashed_nodes_as_bytes = chunk_nodes.chunks(branches).fold(
-                    Vec::with_capacity(nodes_size),
-                    |mut acc, nodes| {
-                        let h = A::default().multi_node(&nodes, level);
-                        acc.extend_from_slice(h.as_ref());
-                        acc
-                    },
-                );
-
-                // Check that we correctly pre-allocated the space.
-                let hashed_nodes_as_bytes_len = hashed_nodes_as_bytes.len();
-                ensure!(
-                    hashed_nodes_as_bytes.len() == chunk_size / branches * E::byte_len(),
-                    "Invalid hashed node length"
-                );
-
-                write_mmap[0..hashed_nodes_as_bytes_len].copy_from_slice(&hashed_nodes_as_bytes);
+            .try_for_each_init(
+                || store.file.try_clone(),
+                |file, chunk_index| -> Result<()> {
+                    let file = file
+                        .as_mut()
+                        .map_err(|e| anyhow!("failed to clone store file: {}", e))?;
+                    let chunk_size =
+                        std::cmp::min(read_chunk_width, read_start + width - chunk_index);
+                    let chunk_nodes = store.read_range(chunk_index..chunk_index + chunk_size)?;
+                    let hashed_nodes_as_bytes =
+                        hash_chunk::<E, A>(&chunk_nodes, branches, level, policy);
+
+                    // Check that we correctly sized the written range.
+                    ensure!(
+                        hashed_nodes_as_bytes.len()
+                            == policy.next_row_width(chunk_size, branches) * E::byte_len(),
+                        "Invalid hashed node length"
+                    );
+
+                    let write_index = write_start + (chunk_index - read_start) / branches;
+                    file.write_all_at(
+                        (write_index * E::byte_len()) as u64,
+                        &hashed_nodes_as_bytes,
+                    )?;
+
+                    Ok(())
+                },
+            )?;
+
+        // The rows are written outside of the store interface.
+        self.len = std::cmp::max(self.len, write_start + write_width);
+
+        Ok(())
+    }
+
+    fn populate_rows<A: Algorithm<E>, I: IndexedParallelIterator<Item = E>>(
+        &mut self,
+        iter: I,
+        rows: usize,
+        starts: &[usize],
+        arities: &[usize],
+        policy: OddRowPolicy,
+        options: &BuildOptions,
+    ) -> Result<()> {
+        let end = populated_len(rows, starts);
+        ensure!(
+            end * E::byte_len() <= self.store_size,
+            "Requested slice too large (max: {})",
+            self.store_size
+        );
+
+        // Each thread hashes a block and writes its rows with
+        // positioned writes on its own handle of the file: the written
+        // ranges are disjoint, so that no lock is needed.
+        let store = &*self;
+        let block_size = populate_block_size(rows, arities, options);
+        iter.chunks(block_size).enumerate().try_for_each_init(
+            || store.file.try_clone(),
+            |file, (index, block)| -> Result<()> {
+                options.check_cancelled()?;
+                let file = file
+                    .as_mut()
+                    .map_err(|e| anyhow!("failed to clone store file: {}", e))?;
+
+                let block_rows = hash_block::<E, A>(block, rows, arities, policy, options);
+                let offsets = block_row_offsets(index, block_size, rows, starts, arities);
+                for (nodes, offset) in block_rows.iter().zip(offsets) {
+                    file.write_all_at((offset * E::byte_len()) as u64, &nodes_as_bytes(nodes))?;
+                }
 
                 Ok(())
-            })
+            },
+        )?;
+
+        // The rows are written outside of the store interface.
+        self.len = std::cmp::max(self.len, end);
+
+        self.sync()
     }
 
     // DiskStore specific merkle-tree build.
-    fn build<A: Algorithm<E>, U: Unsigned>(
+    fn build_with_schedule<A: Algorithm<E>>(
         &mut self,
         leafs: usize,
         row_count: usize,
-        _config: Option<StoreConfig>,
+        config: Option<StoreConfig>,
+        arities: &[usize],
+        policy: OddRowPolicy,
+        options: &BuildOptions,
     ) -> Result<E> {
-        let branches = U::to_usize();
-        ensure!(
-            next_pow2(branches) == branches,
-            "branches MUST be a power of 2"
-        );
+        policy.ensure_valid_schedule(leafs, arities)?;
         ensure!(Store::len(self) == leafs, "Inconsistent data");
-        ensure!(leafs % 2 == 0, "Leafs must be a power of two");
+
+        if let Some(budget) = config.and_then(|config| config.build_memory_budget) {
+            return self.build_depth_first::<A>(leafs, row_count, arities, policy, budget, options);
+        }
 
         // Process one `level` at a time of `width` nodes. Each level has half the nodes
         // as the previous one; the first level, completely stored in `data`, has `leafs`
@@ -417,9 +473,9 @@ impl<E: Element> Store<E> for DiskStore<E> {
         let mut width = leafs;
         let mut level_node_index = 0;
 
-        let shift = log2_pow2(branches);
-
         while width > 1 {
+            options.check_cancelled()?;
+
             // Start reading at the beginning of the current level, and writing the next
             // level immediate after.  `level_node_index` keeps track of the current read
             // starts, and width is updated accordingly at each level so that we know where
@@ -431,21 +487,26 @@ impl<E: Element> Store<E> for DiskStore<E> {
                 (level_node_index, level_node_index + width)
             };
 
-            self.process_layer::<A, U>(width, level, read_start, write_start)?;
+            let branches = row_arity(arities, level);
+            self.process_layer::<A>(
+                width,
+                level,
+                read_start,
+                write_start,
+                branches,
+                policy,
+                options,
+            )?;
 
             level_node_index += width;
             level += 1;
-            width >>= shift; // width /= branches;
-
-            // When the layer is complete, update the store length
-            // since we know the backing file was updated outside of
-            // the store interface.
-            self.set_len(Store::len(self) + width);
+            width = policy.next_row_width(width, branches);
+            options.report_row(level, row_count);
         }
 
         // Ensure every element is accounted for.
         ensure!(
-            Store::len(self) == get_merkle_tree_len(leafs, branches)?,
+            Store::len(self) == get_merkle_tree_len_with_schedule(leafs, arities, policy)?,
             "Invalid merkle tree length"
         );
 
@@ -459,10 +520,6 @@ impl<E: Element> Store<E> for DiskStore<E> {
 }
 
 impl<E: Element> DiskStore<E> {
-    fn set_len(&mut self, len: usize) {
-        self.len = len;
-    }
-
     // 'store_range' must be the total number of elements in the store
     // (e.g. tree.len()).  Arity/branches is ignored since a
     // DiskStore's size is related only to the number of elements in
@@ -5,22 +5,24 @@ use std::iter::FromIterator;
 use std::marker::PhantomData;
 use std::ops;
 use std::path::Path;
-use std::sync::{Arc, RwLock};
 
 use anyhow::{Context, Result};
-use memmap::MmapOptions;
 use positioned_io::{ReadAt, WriteAt};
 use rayon::iter::*;
-use rayon::prelude::*;
 use tempfile::tempfile;
-use typenum::marker_traits::Unsigned;
 
 use crate::hash::Algorithm;
 use crate::merkle::{
-    get_merkle_tree_cache_size, get_merkle_tree_leafs, get_merkle_tree_len, log2_pow2, next_pow2,
-    Element,
+    div_arity, get_merkle_tree_cache_size, get_merkle_tree_cache_size_with_schedule,
+    get_merkle_tree_leafs, get_merkle_tree_leafs_with_schedule, get_merkle_tree_len_with_schedule,
+    is_merkle_tree_size_valid, is_merkle_tree_size_valid_with_schedule, row_arity, Element,
+    OddRowPolicy,
+};
+use crate::options::BuildOptions;
+use crate::store::{
+    block_row_offsets, hash_block, hash_chunk, nodes_as_bytes, populate_block_size, populated_len,
+    row_starts, ExternalReader, LeafFileStore, Store, StoreConfig,
 };
-use crate::store::{ExternalReader, Store, StoreConfig, BUILD_CHUNK_NODES};
 
 /// The LevelCacheStore is used to reduce the on-disk footprint even
 /// further to the minimum at the cost of build time performance.
@@ -77,6 +79,17 @@ impl<E: Element, R: Read + Send + Sync> LevelCacheStore<E, R> {
         branches: usize,
         config: &StoreConfig,
         reader: ExternalReader<R>,
+    ) -> Result<Self> {
+        Self::new_from_disk_with_reader_and_schedule(store_range, &[branches], config, reader)
+    }
+
+    /// Used for opening v2 compacted DiskStores of trees with the
+    /// arity schedule `arities` (see `merkle::row_arity`).
+    pub fn new_from_disk_with_reader_and_schedule(
+        store_range: usize,
+        arities: &[usize],
+        config: &StoreConfig,
+        reader: ExternalReader<R>,
     ) -> Result<Self> {
         let data_path = StoreConfig::data_path(&config.path, &config.id);
 
@@ -85,12 +98,12 @@ impl<E: Element, R: Read + Send + Sync> LevelCacheStore<E, R> {
         let store_size = metadata.len() as usize;
 
         // The LevelCacheStore base data layer must already be a
-        // massaged next pow2 (guaranteed if created with
+        // massaged power of the arity (guaranteed if created with
         // DiskStore::compact, which is the only supported method at
         // the moment).
-        let size = get_merkle_tree_leafs(store_range, branches)?;
+        let size = get_merkle_tree_leafs_with_schedule(store_range, arities)?;
         ensure!(
-            size == next_pow2(size),
+            is_merkle_tree_size_valid_with_schedule(size, arities, OddRowPolicy::Reject),
             "Inconsistent merkle tree row_count detected"
         );
 
@@ -102,7 +115,8 @@ impl<E: Element, R: Read + Send + Sync> LevelCacheStore<E, R> {
         // the file size dictates the cache_size.  Calculate cache
         // start and the updated size with repect to the file size.
         let cache_size =
-            get_merkle_tree_cache_size(size, branches, config.rows_to_discard)? * E::byte_len();
+            get_merkle_tree_cache_size_with_schedule(size, arities, config.rows_to_discard)?
+                * E::byte_len();
         let cache_index_start = store_range - cache_size;
 
         // Sanity checks that the StoreConfig rows_to_discard matches this
@@ -138,13 +152,21 @@ impl<E: Element, R: Read + Send + Sync> LevelCacheStore<E, R> {
 
 impl<E: Element, R: Read + Send + Sync> Store<E> for LevelCacheStore<E, R> {
     fn new_with_config(size: usize, branches: usize, config: StoreConfig) -> Result<Self> {
+        Self::new_with_config_and_schedule(size, &[branches], config)
+    }
+
+    fn new_with_config_and_schedule(
+        size: usize,
+        arities: &[usize],
+        config: StoreConfig,
+    ) -> Result<Self> {
         let data_path = StoreConfig::data_path(&config.path, &config.id);
 
         // If the specified file exists, load it from disk.  This is
         // the only supported usage of this call for this type of
         // Store.
         if Path::new(&data_path).exists() {
-            return Self::new_from_disk(size, branches, &config);
+            return Self::new_from_disk_with_schedule(size, arities, &config);
         }
 
         // Otherwise, create the file and allow it to be the on-disk store.
@@ -155,17 +177,18 @@ impl<E: Element, R: Read + Send + Sync> Store<E> for LevelCacheStore<E, R> {
             .open(data_path)?;
 
         let store_size = E::byte_len() * size;
-        let leafs = get_merkle_tree_leafs(size, branches)?;
+        let leafs = get_merkle_tree_leafs_with_schedule(size, arities)?;
 
         ensure!(
-            leafs == next_pow2(leafs),
+            is_merkle_tree_size_valid_with_schedule(leafs, arities, OddRowPolicy::Reject),
             "Inconsistent merkle tree row_count detected"
         );
 
         // Calculate cache start and the updated size with repect to
         // the data size.
         let cache_size =
-            get_merkle_tree_cache_size(leafs, branches, config.rows_to_discard)? * E::byte_len();
+            get_merkle_tree_cache_size_with_schedule(leafs, arities, config.rows_to_discard)?
+                * E::byte_len();
         let cache_index_start = store_size - cache_size;
 
         file.set_len(store_size as u64)?;
@@ -243,6 +266,16 @@ impl<E: Element, R: Read + Send + Sync> Store<E> for LevelCacheStore<E, R> {
 
     // Used for opening v1 compacted DiskStores.
     fn new_from_disk(store_range: usize, branches: usize, config: &StoreConfig) -> Result<Self> {
+        Self::new_from_disk_with_schedule(store_range, &[branches], config)
+    }
+
+    // Used for opening v1 compacted DiskStores of trees with an arity
+    // schedule.
+    fn new_from_disk_with_schedule(
+        store_range: usize,
+        arities: &[usize],
+        config: &StoreConfig,
+    ) -> Result<Self> {
         let data_path = StoreConfig::data_path(&config.path, &config.id);
 
         let file = OpenOptions::new().write(true).read(true).open(data_path)?;
@@ -250,12 +283,12 @@ impl<E: Element, R: Read + Send + Sync> Store<E> for LevelCacheStore<E, R> {
         let store_size = metadata.len() as usize;
 
         // The LevelCacheStore base data layer must already be a
-        // massaged next pow2 (guaranteed if created with
+        // massaged power of the arity (guaranteed if created with
         // DiskStore::compact, which is the only supported method at
         // the moment).
-        let size = get_merkle_tree_leafs(store_range, branches)?;
+        let size = get_merkle_tree_leafs_with_schedule(store_range, arities)?;
         ensure!(
-            size == next_pow2(size),
+            is_merkle_tree_size_valid_with_schedule(size, arities, OddRowPolicy::Reject),
             "Inconsistent merkle tree row_count detected"
         );
 
@@ -266,7 +299,8 @@ impl<E: Element, R: Read + Send + Sync> Store<E> for LevelCacheStore<E, R> {
         // Calculate cache start and the updated size with repect to
         // the data size.
         let cache_size =
-            get_merkle_tree_cache_size(size, branches, config.rows_to_discard)? * E::byte_len();
+            get_merkle_tree_cache_size_with_schedule(size, arities, config.rows_to_discard)?
+                * E::byte_len();
         let cache_index_start = store_range - cache_size;
 
         // For a true v1 compatible store, this check should remain,
@@ -297,7 +331,8 @@ impl<E: Element, R: Read + Send + Sync> Store<E> for LevelCacheStore<E, R> {
     }
 
     fn write_at(&mut self, el: E, index: usize) -> Result<()> {
-        self.store_copy_from_slice(index * self.elem_len, el.as_ref())?;
+        let start = self.adjusted_write_start(index * self.elem_len, self.elem_len)?;
+        self.store_copy_from_slice(start, el.as_ref())?;
         self.len = std::cmp::max(self.len, index + 1);
 
         Ok(())
@@ -309,7 +344,8 @@ impl<E: Element, R: Read + Send + Sync> Store<E> for LevelCacheStore<E, R> {
             "buf size must be a multiple of {}",
             self.elem_len
         );
-        self.store_copy_from_slice(start * self.elem_len, buf)?;
+        let adjusted_start = self.adjusted_write_start(start * self.elem_len, buf.len())?;
+        self.store_copy_from_slice(adjusted_start, buf)?;
         self.len = std::cmp::max(self.len, start + buf.len() / self.elem_len);
 
         Ok(())
@@ -405,6 +441,9 @@ impl<E: Element, R: Read + Send + Sync> Store<E> for LevelCacheStore<E, R> {
         self.len == 0
     }
 
+    // Pushes go through `write_at`, so past the base layer the element
+    // is written at its position in the cached rows, as it is read,
+    // and pushing into the discarded rows fails.
     fn push(&mut self, el: E) -> Result<()> {
         let len = self.len;
         ensure!(
@@ -422,81 +461,122 @@ impl<E: Element, R: Read + Send + Sync> Store<E> for LevelCacheStore<E, R> {
         self.file.sync_all().context("failed to sync file")
     }
 
-    #[allow(unsafe_code)]
-    fn process_layer<A: Algorithm<E>, U: Unsigned>(
+    fn process_layer<A: Algorithm<E>>(
         &mut self,
         width: usize,
         level: usize,
         read_start: usize,
         write_start: usize,
+        branches: usize,
+        policy: OddRowPolicy,
+        options: &BuildOptions,
     ) -> Result<()> {
-        // Safety: this operation is safe becase it's a limited
-        // writable region on the backing store managed by this type.
-        let mut mmap = unsafe {
-            let mut mmap_options = MmapOptions::new();
-            mmap_options
-                .offset((write_start * E::byte_len()) as u64)
-                .len(width * E::byte_len())
-                .map_mut(&self.file)
-        }?;
-
-        let data_lock = Arc::new(RwLock::new(self));
-        let branches = U::to_usize();
-        let shift = log2_pow2(branches);
-        let write_chunk_width = (BUILD_CHUNK_NODES >> shift) * E::byte_len();
-
-        ensure!(BUILD_CHUNK_NODES % branches == 0, "Invalid chunk size");
-        Vec::from_iter((read_start..read_start + width).step_by(BUILD_CHUNK_NODES))
+        // Each thread reads a chunk of the row and writes its parents
+        // with a positioned write on its own handle of the file: the
+        // written ranges are disjoint, so that no lock is needed.
+        let store = &*self;
+        let read_chunk_width = options.build_chunk_nodes(branches);
+
+        Vec::from_iter((read_start..read_start + width).step_by(read_chunk_width))
             .into_par_iter()
-            .zip(mmap.par_chunks_mut(write_chunk_width))
-            .try_for_each(|(chunk_index, write_mmap)| -> Result<()> {
-                let chunk_size = std::cmp::min(BUILD_CHUNK_NODES, read_start + width - chunk_index);
-
-                let chunk_nodes = {
-                    // Read everything taking the lock once.
-                    data_lock
-                        .read()
-                        .unwrap()
-                        .read_range_internal(chunk_index..chunk_index + chunk_size)?
-                };
-
-                let nodes_size = (chunk_nodes.len() / branches) * E::byte_len();
-                let hashed_nodes_as_bytes = chunk_nodes.chunks(branches).fold(
-                    Vec::with_capacity(nodes_size),
-                    |mut acc, nodes| {
-                        let h = A::default().multi_node(&nodes, level);
-                        acc.extend_from_slice(h.as_ref());
-                        acc
-                    },
-                );
-
-                // Check that we correctly pre-allocated the space.
-                let hashed_nodes_as_bytes_len = hashed_nodes_as_bytes.len();
-                ensure!(
-                    hashed_nodes_as_bytes.len() == chunk_size / branches * E::byte_len(),
-                    "Invalid hashed node length"
-                );
-
-                write_mmap[0..hashed_nodes_as_bytes_len].copy_from_slice(&hashed_nodes_as_bytes);
+            .try_for_each_init(
+                || store.file.try_clone(),
+                |file, chunk_index| -> Result<()> {
+                    let file = file
+                        .as_mut()
+                        .map_err(|e| anyhow!("failed to clone store file: {}", e))?;
+                    let chunk_size =
+                        std::cmp::min(read_chunk_width, read_start + width - chunk_index);
+
+                    // Read the base layer through the external reader, if any.
+                    let chunk_nodes = if level == 0 && store.reader.is_some() {
+                        store.read_range(chunk_index..chunk_index + chunk_size)?
+                    } else {
+                        store.read_range_internal(chunk_index..chunk_index + chunk_size)?
+                    };
+                    let hashed_nodes_as_bytes =
+                        hash_chunk::<E, A>(&chunk_nodes, branches, level, policy);
+
+                    // Check that we correctly sized the written range.
+                    ensure!(
+                        hashed_nodes_as_bytes.len()
+                            == policy.next_row_width(chunk_size, branches) * E::byte_len(),
+                        "Invalid hashed node length"
+                    );
+
+                    let write_index = write_start + (chunk_index - read_start) / branches;
+                    file.write_all_at(
+                        (write_index * E::byte_len()) as u64,
+                        &hashed_nodes_as_bytes,
+                    )?;
+
+                    Ok(())
+                },
+            )
+    }
+
+    fn populate_rows<A: Algorithm<E>, I: IndexedParallelIterator<Item = E>>(
+        &mut self,
+        iter: I,
+        rows: usize,
+        starts: &[usize],
+        arities: &[usize],
+        policy: OddRowPolicy,
+        options: &BuildOptions,
+    ) -> Result<()> {
+        // Each thread hashes a block and writes its rows with
+        // positioned writes on its own handle of the file (shifted as
+        // by `copy_from_slice`): the written ranges are disjoint, so
+        // that no lock is needed.
+        let store = &*self;
+        let block_size = populate_block_size(rows, arities, options);
+        iter.chunks(block_size).enumerate().try_for_each_init(
+            || store.file.try_clone(),
+            |file, (index, block)| -> Result<()> {
+                options.check_cancelled()?;
+                let file = file
+                    .as_mut()
+                    .map_err(|e| anyhow!("failed to clone store file: {}", e))?;
+
+                let block_rows = hash_block::<E, A>(block, rows, arities, policy, options);
+                let offsets = block_row_offsets(index, block_size, rows, starts, arities);
+                for (nodes, offset) in block_rows.iter().zip(offsets) {
+                    let bytes = nodes_as_bytes(nodes);
+                    let start = store.adjusted_write_start(offset * store.elem_len, bytes.len())?;
+                    ensure!(
+                        start + bytes.len() <= store.store_size,
+                        "Requested slice too large (max: {})",
+                        store.store_size
+                    );
+                    file.write_all_at(start as u64, &bytes)?;
+                }
 
                 Ok(())
-            })
+            },
+        )?;
+
+        // The rows are written outside of the store interface.
+        self.len = std::cmp::max(self.len, populated_len(rows, starts));
+
+        self.sync()
     }
 
     // LevelCacheStore specific merkle-tree build.
-    fn build<A: Algorithm<E>, U: Unsigned>(
+    fn build_with_schedule<A: Algorithm<E>>(
         &mut self,
         leafs: usize,
         row_count: usize,
         config: Option<StoreConfig>,
+        arities: &[usize],
+        policy: OddRowPolicy,
+        options: &BuildOptions,
     ) -> Result<E> {
-        let branches = U::to_usize();
         ensure!(
-            next_pow2(branches) == branches,
-            "branches MUST be a power of 2"
+            policy == OddRowPolicy::Reject,
+            "LevelCacheStore only supports full rows (OddRowPolicy::Reject)"
         );
+        policy.ensure_valid_schedule(leafs, arities)?;
         ensure!(Store::len(self) == leafs, "Inconsistent data");
-        ensure!(leafs % 2 == 0, "Leafs must be a power of two");
         ensure!(
             config.is_some(),
             "LevelCacheStore build requires a valid config"
@@ -511,13 +591,25 @@ impl<E: Element, R: Read + Send + Sync> Store<E> for LevelCacheStore<E, R> {
         let mut level_node_index = 0;
 
         let config = config.unwrap();
-        let shift = log2_pow2(branches);
+
+        if let Some(budget) = config.build_memory_budget {
+            let root = self.build_depth_first_cached::<A>(
+                leafs, row_count, arities, policy, budget, options,
+            )?;
+            self.truncate_uncached(&config)?;
+
+            return Ok(root);
+        }
 
         // Both in terms of elements, not bytes.
-        let cache_size = get_merkle_tree_cache_size(leafs, branches, config.rows_to_discard)?;
-        let cache_index_start = (get_merkle_tree_len(leafs, branches)?) - cache_size;
+        let cache_size =
+            get_merkle_tree_cache_size_with_schedule(leafs, arities, config.rows_to_discard)?;
+        let cache_index_start =
+            (get_merkle_tree_len_with_schedule(leafs, arities, policy)?) - cache_size;
 
         while width > 1 {
+            options.check_cancelled()?;
+
             // Start reading at the beginning of the current level, and writing the next
             // level immediate after.  `level_node_index` keeps track of the current read
             // starts, and width is updated accordingly at each level so that we know where
@@ -534,7 +626,16 @@ impl<E: Element, R: Read + Send + Sync> Store<E> for LevelCacheStore<E, R> {
                 )
             };
 
-            self.process_layer::<A, U>(width, level, read_start, write_start)?;
+            let branches = row_arity(arities, level);
+            self.process_layer::<A>(
+                width,
+                level,
+                read_start,
+                write_start,
+                branches,
+                policy,
+                options,
+            )?;
 
             if level_node_index < cache_index_start {
                 self.front_truncate(&config, width)?;
@@ -542,19 +643,20 @@ impl<E: Element, R: Read + Send + Sync> Store<E> for LevelCacheStore<E, R> {
 
             level_node_index += width;
             level += 1;
-            width >>= shift; // width /= branches;
+            width = div_arity(width, branches); // width /= branches;
 
             // When the layer is complete, update the store length
             // since we know the backing file was updated outside of
             // the store interface.
             self.set_len(level_node_index);
+            options.report_row(level, row_count);
         }
 
         // Account for the root element.
         self.set_len(Store::len(self) + 1);
         // Ensure every element is accounted for.
         ensure!(
-            Store::len(self) == get_merkle_tree_len(leafs, branches)?,
+            Store::len(self) == get_merkle_tree_len_with_schedule(leafs, arities, policy)?,
             "Invalid merkle tree length"
         );
 
@@ -568,9 +670,101 @@ impl<E: Element, R: Read + Send + Sync> Store<E> for LevelCacheStore<E, R> {
         // on disk.
         self.read_at_internal(self.len() - cache_index_start - 1)
     }
+
+    // The cached rows can only be built once all the leafs are
+    // written, so only the leafs are pipelined.
+    fn build_pipelined<A: Algorithm<E>, I: IndexedParallelIterator<Item = E>>(
+        &mut self,
+        iter: I,
+        leafs: usize,
+        row_count: usize,
+        config: Option<StoreConfig>,
+        arities: &[usize],
+        policy: OddRowPolicy,
+        options: &BuildOptions,
+    ) -> Result<E> {
+        ensure!(self.is_empty(), "Inconsistent data");
+        let starts = row_starts(leafs, arities, policy);
+        self.populate_rows::<A, I>(iter, 0, &starts, arities, policy, options)?;
+
+        self.build_with_schedule::<A>(leafs, row_count, config, arities, policy, options)
+    }
+}
+
+impl<E: Element> LeafFileStore<E> for LevelCacheStore<E, File> {
+    // The file of leafs is set as the external reader of the base
+    // layer, so that only the cached rows are ever written to disk.
+    fn new_from_leaf_file(
+        size: usize,
+        leafs: usize,
+        arities: &[usize],
+        path: &Path,
+        config: StoreConfig,
+        _options: &BuildOptions,
+    ) -> Result<Self> {
+        let reader = ExternalReader::new_from_path(&path.to_path_buf())?;
+
+        let data_path = StoreConfig::data_path(&config.path, &config.id);
+        if Path::new(&data_path).exists() {
+            let mut store =
+                Self::new_from_disk_with_reader_and_schedule(size, arities, &config, reader)?;
+            store.loaded_from_disk = true;
+
+            return Ok(store);
+        }
+
+        // The base layer is left as a hole at the start of the file,
+        // which the build truncates.
+        let mut store = Self::new_with_config_and_schedule(size, arities, config)?;
+        store.reader = Some(reader);
+        store.len = leafs;
+
+        Ok(store)
+    }
 }
 
 impl<E: Element, R: Read + Send + Sync> LevelCacheStore<E, R> {
+    // Depth-first build (see `Store::build_depth_first`) of the tree.
+    // The rows above the leafs are all laid out after them in the
+    // file while the tree is built (see `truncate_uncached`).
+    fn build_depth_first_cached<A: Algorithm<E>>(
+        &mut self,
+        leafs: usize,
+        row_count: usize,
+        arities: &[usize],
+        policy: OddRowPolicy,
+        budget: usize,
+        options: &BuildOptions,
+    ) -> Result<E> {
+        let cache_index_start = self.cache_index_start;
+
+        self.cache_index_start = self.data_width * self.elem_len;
+        let root = self.build_depth_first::<A>(leafs, row_count, arities, policy, budget, options);
+        self.cache_index_start = cache_index_start;
+
+        root
+    }
+
+    // Truncates the rows which are not cached from the front of the
+    // file after a depth-first build, as the row by row build does.
+    fn truncate_uncached(&mut self, config: &StoreConfig) -> Result<()> {
+        let cache_index_start = self.cache_index_start;
+        let data_end = self.data_width * self.elem_len;
+
+        // Without an external reader, the leafs are at the start of
+        // the file and are discarded as well.
+        let discarded = if self.reader.is_none() {
+            cache_index_start
+        } else {
+            cache_index_start - data_end
+        };
+        self.front_truncate(config, discarded / self.elem_len)?;
+        self.file
+            .set_len((self.len * self.elem_len - cache_index_start) as u64)?;
+
+        Ok(())
+    }
+
     pub fn set_len(&mut self, len: usize) {
         self.len = len;
     }
@@ -623,12 +817,12 @@ impl<E: Element, R: Read + Send + Sync> LevelCacheStore<E, R> {
         let store_size = metadata.len() as usize;
 
         // The LevelCacheStore base data layer must already be a
-        // massaged next pow2 (guaranteed if created with
+        // massaged power of the arity (guaranteed if created with
         // DiskStore::compact, which is the only supported method at
         // the moment).
         let size = get_merkle_tree_leafs(store_range, branches)?;
         ensure!(
-            size == next_pow2(size),
+            is_merkle_tree_size_valid(size, branches),
             "Inconsistent merkle tree row_count detected"
         );
 
@@ -656,12 +850,12 @@ impl<E: Element, R: Read + Send + Sync> LevelCacheStore<E, R> {
         let store_size = metadata.len() as usize;
 
         // The LevelCacheStore base data layer must already be a
-        // massaged next pow2 (guaranteed if created with
+        // massaged power of the arity (guaranteed if created with
         // DiskStore::compact, which is the only supported method at
         // the moment).
         let size = get_merkle_tree_leafs(store_range, branches)?;
         ensure!(
-            size == next_pow2(size),
+            is_merkle_tree_size_valid(size, branches),
             "Inconsistent merkle tree row_count detected"
         );
 
@@ -831,6 +1025,29 @@ impl<E: Element, R: Read + Send + Sync> LevelCacheStore<E, R> {
         Ok(())
     }
 
+    // Returns the offset in the file of a write of `len` bytes at the
+    // byte offset `start` of the tree, shifting the cached rows over
+    // as reads do.  The write must be entirely in the base layer
+    // (without an external reader) or in the cached rows.
+    fn adjusted_write_start(&self, start: usize, len: usize) -> Result<usize> {
+        let data_end = self.data_width * self.elem_len;
+        if start >= self.cache_index_start && start >= data_end {
+            return Ok(if self.reader.is_none() {
+                start - self.cache_index_start + data_end
+            } else {
+                start - self.cache_index_start
+            });
+        }
+
+        ensure!(start + len <= data_end, "out of bounds");
+        ensure!(
+            self.reader.is_none(),
+            "Base layer data must be written through the external reader"
+        );
+
+        Ok(start)
+    }
+
     pub fn store_copy_from_slice(&mut self, start: usize, slice: &[u8]) -> Result<()> {
         ensure!(
             start + slice.len() <= self.store_size,
@@ -1,13 +1,19 @@
-use std::fs::{File, OpenOptions};
+use std::fs::{remove_file, File, OpenOptions};
 use std::marker::PhantomData;
 use std::ops;
 use std::path::{Path, PathBuf};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use memmap::MmapMut;
+use rayon::prelude::*;
 
-use crate::merkle::Element;
-use crate::store::{Store, StoreConfig};
+use crate::hash::Algorithm;
+use crate::merkle::{Element, OddRowPolicy};
+use crate::options::BuildOptions;
+use crate::store::{
+    hash_block, hash_chunk, nodes_as_bytes, populate_block_size, populated_len, split_block_rows,
+    Store, StoreConfig,
+};
 
 /// Store that saves the data on disk, and accesses it using memmap.
 #[derive(Debug)]
@@ -265,8 +271,9 @@ impl<E: Element> Store<E> for MmapStore<E> {
         Ok(())
     }
 
-    fn delete(_config: StoreConfig) -> Result<()> {
-        Ok(())
+    fn delete(config: StoreConfig) -> Result<()> {
+        let path = StoreConfig::data_path(&config.path, &config.id);
+        remove_file(&path).with_context(|| format!("Failed to delete {:?}", &path))
     }
 
     fn is_empty(&self) -> bool {
@@ -287,4 +294,112 @@ impl<E: Element> Store<E> for MmapStore<E> {
 
         self.write_at(el, l)
     }
+
+    fn process_layer<A: Algorithm<E>>(
+        &mut self,
+        width: usize,
+        level: usize,
+        read_start: usize,
+        write_start: usize,
+        branches: usize,
+        policy: OddRowPolicy,
+        options: &BuildOptions,
+    ) -> Result<()> {
+        let write_width = policy.next_row_width(width, branches);
+        ensure!(
+            read_start + width <= write_start && read_start + width <= self.len,
+            "Invalid row range"
+        );
+        ensure!(
+            (write_start + write_width) * E::byte_len() <= self.store_size,
+            "Requested slice too large (max: {})",
+            self.store_size
+        );
+
+        if self.map.is_none() {
+            self.reinit()?;
+        }
+
+        // Split the map between the row read and the row written, so
+        // that each thread writes the parents of its own chunk.
+        let elem_len = E::byte_len();
+        let read_chunk_width = options.build_chunk_nodes(branches);
+        let (read, write) = self
+            .map
+            .as_mut()
+            .unwrap()
+            .split_at_mut(write_start * elem_len);
+        read[read_start * elem_len..(read_start + width) * elem_len]
+            .par_chunks(read_chunk_width * elem_len)
+            .zip(
+                write[..write_width * elem_len]
+                    .par_chunks_mut((read_chunk_width / branches) * elem_len),
+            )
+            .for_each(|(nodes, parents)| {
+                let nodes: Vec<E> = nodes.chunks(elem_len).map(E::from_slice).collect();
+                parents.copy_from_slice(&hash_chunk::<E, A>(&nodes, branches, level, policy));
+            });
+
+        self.len = std::cmp::max(self.len, write_start + write_width);
+
+        Ok(())
+    }
+
+    fn populate_rows<A: Algorithm<E>, I: IndexedParallelIterator<Item = E>>(
+        &mut self,
+        iter: I,
+        rows: usize,
+        starts: &[usize],
+        arities: &[usize],
+        policy: OddRowPolicy,
+        options: &BuildOptions,
+    ) -> Result<()> {
+        let end = populated_len(rows, starts);
+        ensure!(
+            end * E::byte_len() <= self.store_size,
+            "Requested slice too large (max: {})",
+            self.store_size
+        );
+
+        if self.map.is_none() {
+            self.reinit()?;
+        }
+
+        // Split the map between the blocks, so that each thread
+        // writes the rows of its own block.
+        let elem_len = E::byte_len();
+        let block_size = populate_block_size(rows, arities, options);
+        let blocks = split_block_rows(
+            &mut self.map.as_mut().unwrap()[..end * elem_len],
+            elem_len,
+            rows,
+            starts,
+            block_size,
+            arities,
+        );
+        ensure!(
+            blocks.len() == (iter.len() + block_size - 1) / block_size,
+            "Invalid row range"
+        );
+        iter.chunks(block_size).zip(blocks).try_for_each(
+            |(block, mut block_nodes)| -> Result<()> {
+                options.check_cancelled()?;
+
+                let block_rows = hash_block::<E, A>(block, rows, arities, policy, options);
+                for (row_nodes, nodes) in block_nodes.iter_mut().zip(block_rows) {
+                    ensure!(
+                        row_nodes.len() == nodes.len() * elem_len,
+                        "Invalid hashed node length"
+                    );
+                    row_nodes.copy_from_slice(&nodes_as_bytes(&nodes));
+                }
+
+                Ok(())
+            },
+        )?;
+
+        self.len = std::cmp::max(self.len, end);
+
+        Ok(())
+    }
 }
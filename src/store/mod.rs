@@ -1,12 +1,12 @@
 use std::fmt;
-use std::fs::OpenOptions;
+use std::fs::{File, OpenOptions};
 use std::io::Read;
 use std::iter::FromIterator;
 use std::ops;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, RwLock};
+use std::sync::Mutex;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use positioned_io::ReadAt;
 use rayon::iter::plumbing::*;
 use rayon::iter::*;
@@ -15,7 +15,8 @@ use serde::{Deserialize, Serialize};
 use typenum::marker_traits::Unsigned;
 
 use crate::hash::Algorithm;
-use crate::merkle::{get_merkle_tree_row_count, log2_pow2, next_pow2, Element};
+use crate::merkle::{get_merkle_tree_row_count_with_schedule, row_arity, Element, OddRowPolicy};
+use crate::options::BuildOptions;
 
 /// Tree size (number of nodes) used as threshold to decide which build algorithm
 /// to use. Small trees (below this value) use the old build algorithm, optimized
@@ -23,11 +24,22 @@ use crate::merkle::{get_merkle_tree_row_count, log2_pow2, next_pow2, Element};
 /// threads to work concurrently without interrupting each other. Large trees (above)
 /// use the new build algorithm, optimized for memory rather than speed, allocating
 /// as less as possible with multiple threads competing to get the write lock.
+/// Default of `BuildOptions::with_small_tree_build`.
 pub const SMALL_TREE_BUILD: usize = 1024;
 
-// Number of nodes to process in parallel during the `build` stage.
+// Number of nodes to process in parallel during the `build` stage
+// (default of `BuildOptions::with_chunk_nodes`).
 pub const BUILD_CHUNK_NODES: usize = 1024 * 4;
 
+/// Number of nodes to process in parallel during the `build` stage
+/// for a row hashed with `branches` children per node: the largest
+/// multiple of `branches` not above `BUILD_CHUNK_NODES`, so that no
+/// group of siblings is split across two chunks.
+#[inline]
+pub fn build_chunk_nodes(branches: usize) -> usize {
+    std::cmp::max(BUILD_CHUNK_NODES / branches, 1) * branches
+}
+
 mod disk;
 mod level_cache;
 mod mmap;
@@ -132,6 +144,12 @@ pub struct StoreConfig {
 
     /// The number of merkle tree rows_to_discard then cache on disk.
     pub rows_to_discard: usize,
+
+    /// The number of bytes of nodes which may be held in memory to
+    /// build the tree depth-first (see `Store::build_depth_first`).
+    /// If unset, the tree is built one row at a time.
+    #[serde(default)]
+    pub build_memory_budget: Option<usize>,
 }
 
 impl StoreConfig {
@@ -141,6 +159,7 @@ impl StoreConfig {
             id: id.into(),
             size: None,
             rows_to_discard,
+            build_memory_budget: None,
         }
     }
 
@@ -148,7 +167,15 @@ impl StoreConfig {
     // (per-arity), use it.  If it's too small to cache anything
     // (i.e. not enough rows), don't discard any.
     pub fn default_rows_to_discard(leafs: usize, branches: usize) -> usize {
-        let row_count = get_merkle_tree_row_count(leafs, branches);
+        Self::default_rows_to_discard_with_schedule(leafs, &[branches])
+    }
+
+    // Same as `default_rows_to_discard`, for a tree with the arity
+    // schedule `arities` (the default value is the one of the arity
+    // of its leaf row).
+    pub fn default_rows_to_discard_with_schedule(leafs: usize, arities: &[usize]) -> usize {
+        let row_count =
+            get_merkle_tree_row_count_with_schedule(leafs, arities, OddRowPolicy::Reject);
         if row_count <= 2 {
             // If a tree only has a root row and/or base, there is
             // nothing to discard.
@@ -165,7 +192,7 @@ impl StoreConfig {
         // Discard at most 'constant value' rows (coded below,
         // differing by arity) while respecting the max number that
         // the tree can support discarding.
-        match branches {
+        match arities[0] {
             2 => std::cmp::min(max_rows_to_discard, 7),
             4 => std::cmp::min(max_rows_to_discard, 5),
             _ => std::cmp::min(max_rows_to_discard, 2),
@@ -193,6 +220,7 @@ impl StoreConfig {
             id: id.into(),
             size: val,
             rows_to_discard: config.rows_to_discard,
+            build_memory_budget: config.build_memory_budget,
         }
     }
 }
@@ -214,6 +242,28 @@ pub trait Store<E: Element>: std::fmt::Debug + Send + Sync + Sized {
 
     fn new_from_disk(size: usize, branches: usize, config: &StoreConfig) -> Result<Self>;
 
+    // Same as `new_with_config`, for a tree with the arity schedule
+    // `arities` (see `merkle::row_arity`).  Stores whose layout
+    // depends on the arity must override this.
+    fn new_with_config_and_schedule(
+        size: usize,
+        arities: &[usize],
+        config: StoreConfig,
+    ) -> Result<Self> {
+        Self::new_with_config(size, arities[0], config)
+    }
+
+    // Same as `new_from_disk`, for a tree with the arity schedule
+    // `arities`.  Stores whose layout depends on the arity must
+    // override this.
+    fn new_from_disk_with_schedule(
+        size: usize,
+        arities: &[usize],
+        config: &StoreConfig,
+    ) -> Result<Self> {
+        Self::new_from_disk(size, arities[0], config)
+    }
+
     fn write_at(&mut self, el: E, index: usize) -> Result<()>;
 
     // Used to reduce lock contention and do the `E` to `u8`
@@ -226,6 +276,17 @@ pub trait Store<E: Element>: std::fmt::Debug + Send + Sync + Sized {
     fn compact(&mut self, branches: usize, config: StoreConfig, store_version: u32)
         -> Result<bool>;
 
+    // Same as `compact`, for a tree with the arity schedule `arities`.
+    // Stores whose layout depends on the arity must override this.
+    fn compact_with_schedule(
+        &mut self,
+        arities: &[usize],
+        config: StoreConfig,
+        store_version: u32,
+    ) -> Result<bool> {
+        self.compact(arities[0], config, store_version)
+    }
+
     // re-instate resource usage where needed.
     fn reinit(&mut self) -> Result<()> {
         Ok(())
@@ -257,20 +318,27 @@ pub trait Store<E: Element>: std::fmt::Debug + Send + Sync + Sized {
     }
 
     #[inline]
-    fn build_small_tree<A: Algorithm<E>, U: Unsigned>(
+    fn build_small_tree<A: Algorithm<E>>(
         &mut self,
         leafs: usize,
         row_count: usize,
+        arities: &[usize],
+        policy: OddRowPolicy,
+        options: &BuildOptions,
     ) -> Result<E> {
-        ensure!(leafs % 2 == 0, "Leafs must be a power of two");
+        ensure!(
+            policy != OddRowPolicy::Reject || leafs % row_arity(arities, 0) == 0,
+            "Leafs must be a power of the arity"
+        );
 
         let mut level: usize = 0;
         let mut width = leafs;
         let mut level_node_index = 0;
-        let branches = U::to_usize();
-        let shift = log2_pow2(branches);
 
         while width > 1 {
+            options.check_cancelled()?;
+            let branches = row_arity(arities, level);
+
             // Same indexing logic as `build`.
             let (layer, write_start) = {
                 let (read_start, write_start) = if level == 0 {
@@ -280,11 +348,13 @@ pub trait Store<E: Element>: std::fmt::Debug + Send + Sync + Sized {
                     (level_node_index, level_node_index + width)
                 };
 
-                let layer: Vec<_> = self
-                    .read_range(read_start..read_start + width)?
-                    .par_chunks(branches)
-                    .map(|nodes| A::default().multi_node(&nodes, level))
-                    .collect();
+                let layer = hash_row::<E, A>(
+                    &self.read_range(read_start..read_start + width)?,
+                    branches,
+                    level,
+                    policy,
+                    options,
+                );
 
                 (layer, write_start)
             };
@@ -295,7 +365,8 @@ pub trait Store<E: Element>: std::fmt::Debug + Send + Sync + Sized {
 
             level_node_index += width;
             level += 1;
-            width >>= shift; // width /= branches;
+            width = policy.next_row_width(width, branches);
+            options.report_row(level, row_count);
         }
 
         ensure!(row_count == level + 1, "Invalid tree row_count");
@@ -305,63 +376,49 @@ pub trait Store<E: Element>: std::fmt::Debug + Send + Sync + Sized {
         self.last()
     }
 
-    fn process_layer<A: Algorithm<E>, U: Unsigned>(
+    #[allow(clippy::too_many_arguments)]
+    fn process_layer<A: Algorithm<E>>(
         &mut self,
         width: usize,
         level: usize,
         read_start: usize,
         write_start: usize,
+        branches: usize,
+        policy: OddRowPolicy,
+        options: &BuildOptions,
     ) -> Result<()> {
-        let branches = U::to_usize();
-        let data_lock = Arc::new(RwLock::new(self));
-
         // Allocate `width` indexes during operation (which is a negligible memory bloat
         // compared to the 32-bytes size of the nodes stored in the `Store`s) and hash each
-        // pair of nodes to write them to the next level in concurrent threads.
-        // Process `BUILD_CHUNK_NODES` nodes in each thread at a time to reduce contention,
-        // optimized for big sector sizes (small ones will just have one thread doing all
-        // the work).
-        ensure!(BUILD_CHUNK_NODES % branches == 0, "Invalid chunk size");
-        Vec::from_iter((read_start..read_start + width).step_by(BUILD_CHUNK_NODES))
-            .par_iter()
-            .try_for_each(|&chunk_index| -> Result<()> {
-                let chunk_size = std::cmp::min(BUILD_CHUNK_NODES, read_start + width - chunk_index);
-
-                let chunk_nodes = {
-                    // Read everything taking the lock once.
-                    data_lock
-                        .read()
-                        .unwrap()
-                        .read_range(chunk_index..chunk_index + chunk_size)?
-                };
-
-                // We write the hashed nodes to the next level in the
-                // position that would be "in the middle" of the
-                // previous pair (dividing by branches).
-                let write_delta = (chunk_index - read_start) / branches;
-
-                let nodes_size = (chunk_nodes.len() / branches) * E::byte_len();
-                let hashed_nodes_as_bytes = chunk_nodes.chunks(branches).fold(
-                    Vec::with_capacity(nodes_size),
-                    |mut acc, nodes| {
-                        let h = A::default().multi_node(&nodes, level);
-                        acc.extend_from_slice(h.as_ref());
-                        acc
-                    },
-                );
-
-                // Check that we correctly pre-allocated the space.
-                ensure!(
-                    hashed_nodes_as_bytes.len() == chunk_size / branches * E::byte_len(),
-                    "Invalid hashed node length"
-                );
+        // group of nodes to write them to the next level in concurrent threads.
+        // Process `options.build_chunk_nodes(branches)` nodes in each thread
+        // at a time, and write the chunks hashed by all the threads at once: the threads
+        // only share the store for reading, so that no lock is needed.  Stores which can
+        // write disjoint ranges concurrently override this to write each chunk as it is
+        // hashed.
+        let read_chunk_width = options.build_chunk_nodes(branches);
+        let batch_width = read_chunk_width * rayon::current_num_threads();
+        for batch_start in (read_start..read_start + width).step_by(batch_width) {
+            let batch_end = std::cmp::min(batch_start + batch_width, read_start + width);
+
+            let store = &*self;
+            let hashed_chunks = Vec::from_iter((batch_start..batch_end).step_by(read_chunk_width))
+                .into_par_iter()
+                .map(|chunk_index| -> Result<Vec<u8>> {
+                    let chunk_size = std::cmp::min(read_chunk_width, batch_end - chunk_index);
+                    let chunk_nodes = store.read_range(chunk_index..chunk_index + chunk_size)?;
+
+                    Ok(hash_chunk::<E, A>(&chunk_nodes, branches, level, policy))
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            // We write the hashed nodes to the next level in the
+            // position that would be "in the middle" of the previous
+            // group (dividing by branches).
+            let write_delta = (batch_start - read_start) / branches;
+            self.copy_from_slice(&hashed_chunks.concat(), write_start + write_delta)?;
+        }
 
-                // Write the data into the store.
-                data_lock
-                    .write()
-                    .unwrap()
-                    .copy_from_slice(&hashed_nodes_as_bytes, write_start + write_delta)
-            })
+        Ok(())
     }
 
     // Default merkle-tree build, based on store type.
@@ -369,21 +426,41 @@ pub trait Store<E: Element>: std::fmt::Debug + Send + Sync + Sized {
         &mut self,
         leafs: usize,
         row_count: usize,
-        _config: Option<StoreConfig>,
+        config: Option<StoreConfig>,
     ) -> Result<E> {
-        let branches = U::to_usize();
-        ensure!(
-            next_pow2(branches) == branches,
-            "branches MUST be a power of 2"
-        );
+        self.build_with_schedule::<A>(
+            leafs,
+            row_count,
+            config,
+            &[U::to_usize()],
+            OddRowPolicy::Reject,
+            &BuildOptions::default(),
+        )
+    }
+
+    // Default merkle-tree build with the arity schedule `arities` (see
+    // `merkle::row_arity`) honoring the odd row policy, given the
+    // build `options`, based on store type.  The build runs on the
+    // current rayon pool (see `build_with_options`).
+    fn build_with_schedule<A: Algorithm<E>>(
+        &mut self,
+        leafs: usize,
+        row_count: usize,
+        config: Option<StoreConfig>,
+        arities: &[usize],
+        policy: OddRowPolicy,
+        options: &BuildOptions,
+    ) -> Result<E> {
+        policy.ensure_valid_schedule(leafs, arities)?;
         ensure!(Store::len(self) == leafs, "Inconsistent data");
-        ensure!(leafs % 2 == 0, "Leafs must be a power of two");
 
-        if leafs <= SMALL_TREE_BUILD {
-            return self.build_small_tree::<A, U>(leafs, row_count);
+        if leafs <= options.small_tree_build() {
+            return self.build_small_tree::<A>(leafs, row_count, arities, policy, options);
         }
 
-        let shift = log2_pow2(branches);
+        if let Some(budget) = config.and_then(|config| config.build_memory_budget) {
+            return self.build_depth_first::<A>(leafs, row_count, arities, policy, budget, options);
+        }
 
         // Process one `level` at a time of `width` nodes. Each level has half the nodes
         // as the previous one; the first level, completely stored in `data`, has `leafs`
@@ -393,6 +470,8 @@ pub trait Store<E: Element>: std::fmt::Debug + Send + Sync + Sized {
         let mut width = leafs;
         let mut level_node_index = 0;
         while width > 1 {
+            options.check_cancelled()?;
+
             // Start reading at the beginning of the current level, and writing the next
             // level immediate after.  `level_node_index` keeps track of the current read
             // starts, and width is updated accordingly at each level so that we know where
@@ -405,11 +484,21 @@ pub trait Store<E: Element>: std::fmt::Debug + Send + Sync + Sized {
                 (level_node_index, level_node_index + width)
             };
 
-            self.process_layer::<A, U>(width, level, read_start, write_start)?;
+            let branches = row_arity(arities, level);
+            self.process_layer::<A>(
+                width,
+                level,
+                read_start,
+                write_start,
+                branches,
+                policy,
+                options,
+            )?;
 
             level_node_index += width;
             level += 1;
-            width >>= shift; // width /= branches;
+            width = policy.next_row_width(width, branches);
+            options.report_row(level, row_count);
         }
 
         ensure!(row_count == level + 1, "Invalid tree row_count");
@@ -419,8 +508,459 @@ pub trait Store<E: Element>: std::fmt::Debug + Send + Sync + Sized {
         // Return the root
         self.last()
     }
+
+    // Depth-first merkle-tree build with the arity schedule `arities`
+    // honoring the odd row policy.  The rows are built a few at a
+    // time, from subtrees whose nodes fit in `budget` bytes: each
+    // subtree is hashed in memory after reading its base nodes, and
+    // its rows are written once.  The subtree roots are then the base
+    // of the next rows, and the tree is the same as if built one row
+    // at a time.
+    fn build_depth_first<A: Algorithm<E>>(
+        &mut self,
+        leafs: usize,
+        row_count: usize,
+        arities: &[usize],
+        policy: OddRowPolicy,
+        budget: usize,
+        options: &BuildOptions,
+    ) -> Result<E> {
+        policy.ensure_valid_schedule(leafs, arities)?;
+        ensure!(Store::len(self) == leafs, "Inconsistent data");
+
+        let starts = row_starts(leafs, arities, policy);
+        ensure!(row_count == starts.len(), "Invalid tree row_count");
+
+        let mut level = 0;
+        while level < row_count - 1 {
+            options.check_cancelled()?;
+
+            // Grow the subtrees one row at a time while they fit in
+            // the budget (a subtree of a single group always fits).
+            let mut rows = 0;
+            let mut span = 1;
+            let mut nodes: usize = 1;
+            while level + rows < row_count - 1 {
+                let branches = row_arity(arities, level + rows);
+                let grown = nodes.saturating_mul(branches).saturating_add(1);
+                if rows > 0 && grown.saturating_mul(E::byte_len()) > budget {
+                    break;
+                }
+
+                span *= branches;
+                nodes = grown;
+                rows += 1;
+            }
+
+            let width = starts[level + 1] - starts[level];
+            for chunk_start in (0..width).step_by(span) {
+                let chunk_end = std::cmp::min(chunk_start + span, width);
+                let mut row_nodes =
+                    self.read_range(starts[level] + chunk_start..starts[level] + chunk_end)?;
+
+                let mut divisor = 1;
+                for row in level..level + rows {
+                    let branches = row_arity(arities, row);
+                    row_nodes = hash_row::<E, A>(&row_nodes, branches, row, policy, options);
+                    divisor *= branches;
+
+                    let bytes = row_nodes.iter().fold(
+                        Vec::with_capacity(row_nodes.len() * E::byte_len()),
+                        |mut acc, node| {
+                            acc.extend_from_slice(node.as_ref());
+                            acc
+                        },
+                    );
+                    self.copy_from_slice(&bytes, starts[row + 1] + chunk_start / divisor)?;
+                }
+            }
+
+            for row in level + 1..=level + rows {
+                options.report_row(row, row_count);
+            }
+            level += rows;
+        }
+
+        ensure!(
+            Store::len(self) == starts[row_count - 1] + 1,
+            "Invalid merkle tree length"
+        );
+
+        // Return the root
+        self.last()
+    }
+
+    // Pipelined merkle-tree build with the arity schedule `arities`
+    // honoring the odd row policy, from the leafs yielded by `iter`
+    // (hashed with `A::leaf_batch`) into an empty store.  The leafs
+    // are hashed a block at a time, along with the rows of the
+    // subtrees over each block, which are written as soon as the
+    // block is hashed: the blocks are written while the next ones
+    // are hashed.  The rows above are then built from the subtree
+    // roots, and the tree is the same as if built one row at a time.
+    #[allow(clippy::too_many_arguments)]
+    fn build_pipelined<A: Algorithm<E>, I: IndexedParallelIterator<Item = E>>(
+        &mut self,
+        iter: I,
+        leafs: usize,
+        row_count: usize,
+        config: Option<StoreConfig>,
+        arities: &[usize],
+        policy: OddRowPolicy,
+        options: &BuildOptions,
+    ) -> Result<E> {
+        policy.ensure_valid_schedule(leafs, arities)?;
+        ensure!(self.is_empty(), "Inconsistent data");
+
+        let starts = row_starts(leafs, arities, policy);
+        ensure!(row_count == starts.len(), "Invalid tree row_count");
+
+        // Depth-first builds read the rows back in subtrees of their
+        // own, so only the leafs are pipelined.
+        if config
+            .as_ref()
+            .and_then(|config| config.build_memory_budget)
+            .is_some()
+        {
+            self.populate_rows::<A, I>(iter, 0, &starts, arities, policy, options)?;
+            return self
+                .build_with_schedule::<A>(leafs, row_count, config, arities, policy, options);
+        }
+
+        // Hash along with the leafs the rows of subtrees no wider than
+        // a block.
+        let mut rows = 0;
+        let mut span = 1;
+        while rows < row_count - 1 && span * row_arity(arities, rows) <= options.data_block_size() {
+            span *= row_arity(arities, rows);
+            rows += 1;
+        }
+        self.populate_rows::<A, I>(iter, rows, &starts, arities, policy, options)?;
+        for row in 1..=rows {
+            options.report_row(row, row_count);
+        }
+
+        for level in rows..row_count - 1 {
+            options.check_cancelled()?;
+            let width = starts[level + 1] - starts[level];
+            let branches = row_arity(arities, level);
+            self.process_layer::<A>(
+                width,
+                level,
+                starts[level],
+                starts[level + 1],
+                branches,
+                policy,
+                options,
+            )?;
+            options.report_row(level + 1, row_count);
+        }
+
+        ensure!(
+            Store::len(self) == starts[row_count - 1] + 1,
+            "Invalid merkle tree length"
+        );
+
+        self.last()
+    }
+
+    // Hashes the leafs yielded by `iter` a block at a time, along with
+    // the first `rows` rows of the subtrees over each block, and writes
+    // them at the row offsets `starts` as soon as the block is hashed.
+    // The blocks are made of whole subtrees (see `hash_block`).  The
+    // threads hashing the blocks share the store through a lock to
+    // write them: stores which can write disjoint ranges concurrently
+    // override this to write each block without one.
+    fn populate_rows<A: Algorithm<E>, I: IndexedParallelIterator<Item = E>>(
+        &mut self,
+        iter: I,
+        rows: usize,
+        starts: &[usize],
+        arities: &[usize],
+        policy: OddRowPolicy,
+        options: &BuildOptions,
+    ) -> Result<()> {
+        let block_size = populate_block_size(rows, arities, options);
+
+        {
+            let store = Mutex::new(&mut *self);
+            iter.chunks(block_size)
+                .enumerate()
+                .try_for_each(|(index, block)| -> Result<()> {
+                    options.check_cancelled()?;
+
+                    let block_rows = hash_block::<E, A>(block, rows, arities, policy, options);
+                    let offsets = block_row_offsets(index, block_size, rows, starts, arities);
+
+                    let mut store = store.lock().unwrap();
+                    for (nodes, offset) in block_rows.iter().zip(offsets) {
+                        store.copy_from_slice(&nodes_as_bytes(nodes), offset)?;
+                    }
+
+                    Ok(())
+                })?;
+        }
+
+        self.sync()
+    }
+
+    // Merkle-tree build with the arity schedule `arities` honoring the
+    // odd row policy, run on the thread pool of the build `options`
+    // (see `BuildOptions`).  If the build is cancelled, the store
+    // files created from `config` (if any) are removed.
+    fn build_with_options<A: Algorithm<E>>(
+        &mut self,
+        leafs: usize,
+        row_count: usize,
+        config: Option<StoreConfig>,
+        arities: &[usize],
+        policy: OddRowPolicy,
+        options: &BuildOptions,
+    ) -> Result<E> {
+        let result = options.run(|| {
+            self.build_with_schedule::<A>(
+                leafs,
+                row_count,
+                config.clone(),
+                arities,
+                policy,
+                options,
+            )
+        });
+
+        remove_cancelled::<E, Self, _>(result, config.as_ref(), options)
+    }
+}
+
+/// Returns the `result` of the build of a store created from `config`
+/// (if any), after removing the store files if the build was
+/// cancelled (see `BuildOptions`).
+pub fn remove_cancelled<E: Element, S: Store<E>, T>(
+    result: Result<T>,
+    config: Option<&StoreConfig>,
+    options: &BuildOptions,
+) -> Result<T> {
+    if result.is_err() && options.is_cancelled() {
+        if let Some(config) = config {
+            S::delete(config.clone()).context("failed to remove cancelled store")?;
+        }
+    }
+
+    result
+}
+
+// Returns the index of the first node of each row of a tree of
+// `leafs` with the arity schedule `arities`.
+fn row_starts(leafs: usize, arities: &[usize], policy: OddRowPolicy) -> Vec<usize> {
+    let mut starts = Vec::new();
+    let mut start = 0;
+    let mut width = leafs;
+    while width > 1 {
+        starts.push(start);
+        start += width;
+        width = policy.next_row_width(width, row_arity(arities, starts.len() - 1));
+    }
+    starts.push(start);
+
+    starts
+}
+
+// Returns the number of leafs of the blocks of `Store::populate_rows`,
+// made of whole subtrees of `rows` rows.
+fn populate_block_size(rows: usize, arities: &[usize], options: &BuildOptions) -> usize {
+    let span: usize = (0..rows).map(|row| row_arity(arities, row)).product();
+    std::cmp::max(options.data_block_size() / span, 1) * span
+}
+
+// Returns the number of nodes of the first `rows` + 1 rows of a tree
+// whose rows start at `starts`, which `Store::populate_rows` writes.
+fn populated_len(rows: usize, starts: &[usize]) -> usize {
+    match starts.get(rows + 1) {
+        Some(end) => *end,
+        None => starts[rows] + 1,
+    }
+}
+
+// Hashes a `block` of leafs (with `A::leaf_batch`), along with the
+// first `rows` rows of the subtrees over it, and returns each of
+// these rows.
+fn hash_block<E: Element, A: Algorithm<E>>(
+    block: Vec<E>,
+    rows: usize,
+    arities: &[usize],
+    policy: OddRowPolicy,
+    options: &BuildOptions,
+) -> Vec<Vec<E>> {
+    let mut nodes = block
+        .into_par_iter()
+        .chunks(options.build_chunk_nodes(1))
+        .map(|leafs| A::default().leaf_batch(leafs))
+        .collect::<Vec<_>>()
+        .concat();
+
+    let mut block_rows = Vec::with_capacity(rows + 1);
+    for row in 0..rows {
+        let parents = hash_row::<E, A>(&nodes, row_arity(arities, row), row, policy, options);
+        block_rows.push(std::mem::replace(&mut nodes, parents));
+    }
+    block_rows.push(nodes);
+
+    block_rows
+}
+
+// Returns the index in the store of the first node of each row of
+// the block `index` hashed by `hash_block`, which is the position of
+// its subtrees in the row.
+fn block_row_offsets(
+    index: usize,
+    block_size: usize,
+    rows: usize,
+    starts: &[usize],
+    arities: &[usize],
+) -> Vec<usize> {
+    let mut offset = index * block_size;
+    let mut offsets = Vec::with_capacity(rows + 1);
+    for row in 0..=rows {
+        offsets.push(starts[row] + offset);
+        if row < rows {
+            offset /= row_arity(arities, row);
+        }
+    }
+
+    offsets
+}
+
+// Splits the first `rows` + 1 rows of a tree whose rows start at
+// `starts`, and whose nodes are made of `node_len` items of `nodes`,
+// into the ranges written for each block of `block_size` leafs by
+// `Store::populate_rows`, so that each block can be written
+// concurrently without a lock.
+fn split_block_rows<T>(
+    nodes: &mut [T],
+    node_len: usize,
+    rows: usize,
+    starts: &[usize],
+    block_size: usize,
+    arities: &[usize],
+) -> Vec<Vec<&mut [T]>> {
+    let mut blocks: Vec<Vec<&mut [T]>> = Vec::new();
+    let mut rest = nodes;
+    let mut block_width = block_size;
+    for row in 0..=rows {
+        let width = populated_len(row, starts) - starts[row];
+        let (row_nodes, tail) = std::mem::take(&mut rest).split_at_mut(width * node_len);
+        rest = tail;
+
+        for (index, block_nodes) in row_nodes.chunks_mut(block_width * node_len).enumerate() {
+            if row == 0 {
+                blocks.push(Vec::with_capacity(rows + 1));
+            }
+            blocks[index].push(block_nodes);
+        }
+
+        if row < rows {
+            block_width /= row_arity(arities, row);
+        }
+    }
+
+    blocks
 }
 
+// Returns the `nodes` in bytes form.
+fn nodes_as_bytes<E: Element>(nodes: &[E]) -> Vec<u8> {
+    nodes.iter().fold(
+        Vec::with_capacity(nodes.len() * E::byte_len()),
+        |mut acc, node| {
+            acc.extend_from_slice(node.as_ref());
+            acc
+        },
+    )
+}
+
+/// Hashes each group of `branches` siblings of a chunk of `nodes` of
+/// the row at height `level` into their parent, in bytes form.  Only
+/// the last chunk of a row can end with a short group of nodes (see
+/// `OddRowPolicy`).
+pub fn hash_chunk<E: Element, A: Algorithm<E>>(
+    nodes: &[E],
+    branches: usize,
+    level: usize,
+    policy: OddRowPolicy,
+) -> Vec<u8> {
+    let nodes_size = policy.next_row_width(nodes.len(), branches) * E::byte_len();
+    policy
+        .hash_groups::<E, A>(nodes, branches, level)
+        .iter()
+        .fold(Vec::with_capacity(nodes_size), |mut acc, h| {
+            acc.extend_from_slice(h.as_ref());
+            acc
+        })
+}
+
+// Hashes the `nodes` of a row at height `level` into the row above,
+// with a batch of `build_chunk_nodes(branches)` nodes per thread.
+fn hash_row<E: Element, A: Algorithm<E>>(
+    nodes: &[E],
+    branches: usize,
+    level: usize,
+    policy: OddRowPolicy,
+    options: &BuildOptions,
+) -> Vec<E> {
+    nodes
+        .par_chunks(options.build_chunk_nodes(branches))
+        .map(|chunk| policy.hash_groups::<E, A>(chunk, branches, level))
+        .collect::<Vec<_>>()
+        .concat()
+}
+
+/// Stores which can be created over the base layer of a tree kept in
+/// a file of leafs (see `MerkleTree::from_leaf_file`).
+pub trait LeafFileStore<E: Element>: Store<E> {
+    /// Creates a store for a tree of `size` elements with the arity
+    /// schedule `arities`, whose base layer is made of the `leafs`
+    /// elements of the file at `path`.  Unless it was loaded from
+    /// disk, the store is then ready to be built.
+    ///
+    /// By default, the leafs are copied to the store a block of
+    /// `options.data_block_size()` leafs at a time, so that the file
+    /// is never fully read into memory.
+    fn new_from_leaf_file(
+        size: usize,
+        leafs: usize,
+        arities: &[usize],
+        path: &Path,
+        config: StoreConfig,
+        options: &BuildOptions,
+    ) -> Result<Self> {
+        let mut data = Self::new_with_config_and_schedule(size, arities, config)?;
+        if data.loaded_from_disk() {
+            return Ok(data);
+        }
+
+        let mut file =
+            File::open(path).with_context(|| format!("failed to open leaf file {:?}", path))?;
+        let mut buf = vec![0; options.data_block_size() * E::byte_len()];
+
+        let mut start = 0;
+        while start < leafs {
+            options.check_cancelled()?;
+            let count = std::cmp::min(options.data_block_size(), leafs - start);
+            let block = &mut buf[..count * E::byte_len()];
+            file.read_exact(block)
+                .with_context(|| format!("failed to read leafs from {:?}", path))?;
+            data.copy_from_slice(block, start)?;
+            start += count;
+        }
+        data.sync()?;
+
+        Ok(data)
+    }
+}
+
+impl<E: Element> LeafFileStore<E> for VecStore<E> {}
+impl<E: Element> LeafFileStore<E> for DiskStore<E> {}
+impl<E: Element> LeafFileStore<E> for MmapStore<E> {}
+
 // Using a macro as it is not possible to do a generic implementation for all stores.
 
 macro_rules! impl_parallel_iter {
@@ -1,9 +1,14 @@
 use std::ops::{self, Index};
 
 use anyhow::Result;
+use rayon::prelude::*;
 
-use crate::merkle::Element;
-use crate::store::{Store, StoreConfig};
+use crate::hash::Algorithm;
+use crate::merkle::{Element, OddRowPolicy};
+use crate::options::BuildOptions;
+use crate::store::{
+    hash_block, populate_block_size, populated_len, split_block_rows, Store, StoreConfig,
+};
 
 #[derive(Debug, Clone, Default)]
 pub struct VecStore<E: Element>(Vec<E>);
@@ -130,4 +135,74 @@ impl<E: Element> Store<E> for VecStore<E> {
         self.0.push(el);
         Ok(())
     }
+
+    fn process_layer<A: Algorithm<E>>(
+        &mut self,
+        width: usize,
+        level: usize,
+        read_start: usize,
+        write_start: usize,
+        branches: usize,
+        policy: OddRowPolicy,
+        options: &BuildOptions,
+    ) -> Result<()> {
+        let write_width = policy.next_row_width(width, branches);
+        ensure!(
+            read_start + width <= write_start && read_start + width <= self.0.len(),
+            "Invalid row range"
+        );
+        if self.0.len() < write_start + write_width {
+            self.0.resize(write_start + write_width, E::default());
+        }
+
+        // Split the store between the row read and the row written,
+        // so that each thread writes the parents of its own chunk.
+        let read_chunk_width = options.build_chunk_nodes(branches);
+        let (read, write) = self.0.split_at_mut(write_start);
+        read[read_start..read_start + width]
+            .par_chunks(read_chunk_width)
+            .zip(write[..write_width].par_chunks_mut(read_chunk_width / branches))
+            .for_each(|(nodes, parents)| {
+                parents.clone_from_slice(&policy.hash_groups::<E, A>(nodes, branches, level));
+            });
+
+        Ok(())
+    }
+
+    fn populate_rows<A: Algorithm<E>, I: IndexedParallelIterator<Item = E>>(
+        &mut self,
+        iter: I,
+        rows: usize,
+        starts: &[usize],
+        arities: &[usize],
+        policy: OddRowPolicy,
+        options: &BuildOptions,
+    ) -> Result<()> {
+        let end = populated_len(rows, starts);
+        if self.0.len() < end {
+            self.0.resize(end, E::default());
+        }
+
+        // Split the rows between the blocks, so that each thread
+        // writes the rows of its own block.
+        let block_size = populate_block_size(rows, arities, options);
+        let blocks = split_block_rows(&mut self.0[..end], 1, rows, starts, block_size, arities);
+        ensure!(
+            blocks.len() == (iter.len() + block_size - 1) / block_size,
+            "Invalid row range"
+        );
+        iter.chunks(block_size)
+            .zip(blocks)
+            .try_for_each(|(block, mut block_nodes)| -> Result<()> {
+                options.check_cancelled()?;
+
+                let block_rows = hash_block::<E, A>(block, rows, arities, policy, options);
+                for (row_nodes, nodes) in block_nodes.iter_mut().zip(block_rows) {
+                    ensure!(row_nodes.len() == nodes.len(), "Invalid hashed node length");
+                    row_nodes.clone_from_slice(&nodes);
+                }
+
+                Ok(())
+            })
+    }
 }
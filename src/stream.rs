@@ -0,0 +1,491 @@
+use std::io::{self, Read};
+use std::marker::PhantomData;
+
+use anyhow::{Context, Result};
+use typenum::marker_traits::Unsigned;
+use typenum::U2;
+
+use crate::hash::{Algorithm, Hashable};
+use crate::merkle::{row_arity, Element, MerkleTree, OddRowPolicy, BUILD_DATA_BLOCK_SIZE};
+use crate::options::TreeOptions;
+use crate::store::{Store, StoreConfig};
+
+/// Streaming Merkle Tree builder.
+///
+/// Builds a `MerkleTree` over leafs whose number is not known up
+/// front (e.g. read from a socket).  Leafs are pushed one at a time,
+/// in batches or from a reader, and each node is hashed as soon as
+/// the group of its children is complete, so that only the last
+/// group of each row is held in memory:
+///
+/// ```text
+///     [h1 h2 h3 h4 h5]    <- h5 waits for its sibling
+///     [h12 h34]
+///     [h1234]
+/// ```
+///
+/// Rows are written to stores of type `S` as they grow (allocated
+/// with `Store::new`, i.e. temporary files for the disk stores).
+/// `finish` hashes the short groups left at the end of the rows as
+/// the odd row policy requires, and copies the rows into a single
+/// store laid out as `MerkleTree` expects.
+#[derive(Debug)]
+pub struct StreamingBuilder<E, A, S, BaseTreeArity = U2>
+where
+    E: Element,
+    A: Algorithm<E>,
+    S: Store<E>,
+    BaseTreeArity: Unsigned,
+{
+    rows: Vec<Row<E, S>>,
+    arities: Vec<usize>,
+    policy: OddRowPolicy,
+    config: Option<StoreConfig>,
+    _a: PhantomData<A>,
+    _bta: PhantomData<BaseTreeArity>,
+}
+
+impl<E: Element, A: Algorithm<E>, S: Store<E>, BaseTreeArity: Unsigned>
+    StreamingBuilder<E, A, S, BaseTreeArity>
+{
+    /// Creates a new builder of a tree whose number of leafs must be
+    /// a power of the arity.
+    pub fn new() -> Result<StreamingBuilder<E, A, S, BaseTreeArity>> {
+        Self::new_with_options(&TreeOptions::new())
+    }
+
+    /// Creates a new builder of a tree with the arity schedule, odd
+    /// row policy and store config of `options`.
+    pub fn new_with_options(
+        options: &TreeOptions,
+    ) -> Result<StreamingBuilder<E, A, S, BaseTreeArity>> {
+        let arities = MerkleTree::<E, A, S, BaseTreeArity>::options_schedule(options)?;
+        let policy = options.policy();
+
+        // Fail before any leaf is pushed if no number of leafs fits.
+        ensure!(
+            arities.iter().all(|branches| *branches > 1),
+            "branches MUST be greater than 1"
+        );
+        ensure!(
+            policy != OddRowPolicy::Promote || arities.iter().all(|branches| *branches == 2),
+            "RFC 6962 trees MUST be binary"
+        );
+
+        Ok(StreamingBuilder {
+            rows: vec![Row::new()],
+            arities,
+            policy,
+            config: options.config().cloned(),
+            _a: PhantomData,
+            _bta: PhantomData,
+        })
+    }
+
+    /// Pushes a single leaf, hashing it as `MerkleTree::new` does.
+    pub fn push(&mut self, item: E) -> Result<()> {
+        let mut a = A::default();
+        self.push_node(0, a.leaf(item))
+    }
+
+    /// Pushes a hashable object as a single leaf.
+    pub fn push_data<O: Hashable<A>>(&mut self, data: O) -> Result<()> {
+        let mut a = A::default();
+        data.hash(&mut a);
+        self.push(a.hash())
+    }
+
+    /// Pushes all leafs yielded by `items`.
+    pub fn extend<I: IntoIterator<Item = E>>(&mut self, items: I) -> Result<()> {
+        self.try_extend(items.into_iter().map(Ok))
+    }
+
+    /// Pushes all leafs yielded by `items`, returning the first error
+    /// yielded by the iterator, if any.  The leafs pushed before the
+    /// error are kept.
+    pub fn try_extend<I: IntoIterator<Item = Result<E>>>(&mut self, items: I) -> Result<()> {
+        let mut a = A::default();
+        for item in items {
+            let item = item?;
+
+            a.reset();
+            self.push_node(0, a.leaf(item))?;
+        }
+
+        Ok(())
+    }
+
+    /// Pushes the leafs read from `reader` until its end, each made of
+    /// `E::byte_len()` bytes, and returns the number of leafs read.
+    /// The input must not end with a partial leaf.
+    pub fn read_from<R: Read>(&mut self, mut reader: R) -> Result<usize> {
+        let elem_len = E::byte_len();
+        let mut buf = vec![0; BUILD_DATA_BLOCK_SIZE * elem_len];
+        let mut filled = 0;
+        let mut count = 0;
+
+        loop {
+            let read = match reader.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e).context("failed to read leafs"),
+            };
+            filled += read;
+
+            // Keep the bytes of a partial leaf for the next read.
+            let whole = filled - filled % elem_len;
+            self.extend(buf[..whole].chunks_exact(elem_len).map(E::from_slice))?;
+            count += whole / elem_len;
+
+            buf.copy_within(whole..filled, 0);
+            filled -= whole;
+        }
+
+        ensure!(
+            filled == 0,
+            "input ends with a partial leaf of {} bytes (leaf size: {})",
+            filled,
+            elem_len
+        );
+
+        Ok(count)
+    }
+
+    /// Returns the number of leafs pushed so far.
+    pub fn leafs(&self) -> usize {
+        self.rows[0].len
+    }
+
+    /// Hashes the nodes left at the end of the rows and returns the
+    /// tree, which fails if the odd row policy does not allow a tree
+    /// of this number of leafs.
+    pub fn finish(mut self) -> Result<MerkleTree<E, A, S, BaseTreeArity>> {
+        let leafs = self.leafs();
+        self.policy.ensure_valid_schedule(leafs, &self.arities)?;
+
+        // Only the rows of a tree with odd rows end with a short
+        // group, whose parent completes the row above.
+        let mut row = 0;
+        while self.rows[row].len > 1 {
+            if !self.rows[row].group.is_empty() {
+                let branches = row_arity(&self.arities, row);
+                let node = self
+                    .policy
+                    .hash_nodes::<E, A>(&self.rows[row].group, branches, row);
+                self.rows[row].group.clear();
+                self.push_node(row + 1, node)?;
+            }
+
+            row += 1;
+        }
+
+        let size: usize = self.rows.iter().map(|row| row.len).sum();
+        let mut data = match self.config.take() {
+            Some(config) => S::new_with_config_and_schedule(size, &self.arities, config),
+            None => S::new(size),
+        }
+        .context("failed to create data store")?;
+        ensure!(!data.loaded_from_disk(), "data store already exists");
+
+        let mut index = 0;
+        for row in self.rows.iter_mut() {
+            row.copy_into(&mut data, index)?;
+            index += row.len;
+        }
+        data.sync()?;
+
+        MerkleTree::from_data_store_with_options(
+            data,
+            leafs,
+            &TreeOptions::new()
+                .with_arity_schedule(&self.arities)
+                .with_policy(self.policy),
+        )
+    }
+
+    // Appends `node` to `row`, and the parent of its group to the row
+    // above once the group is complete.
+    fn push_node(&mut self, row: usize, node: E) -> Result<()> {
+        let mut row = row;
+        let mut node = node;
+        loop {
+            if row == self.rows.len() {
+                self.rows.push(Row::new());
+            }
+
+            let branches = row_arity(&self.arities, row);
+            let current = &mut self.rows[row];
+            current.push(node)?;
+            if current.group.len() < branches {
+                return Ok(());
+            }
+
+            node = self
+                .policy
+                .hash_nodes::<E, A>(&current.group, branches, row);
+            current.group.clear();
+            row += 1;
+        }
+    }
+}
+
+// A row of a tree being built, stored in segments which double the
+// capacity of the row as it fills up.
+#[derive(Debug)]
+struct Row<E: Element, S: Store<E>> {
+    segments: Vec<S>,
+
+    // Capacity of the last segment.
+    segment_size: usize,
+
+    // Number of nodes in the row, including the buffered ones.
+    len: usize,
+
+    // Nodes not written to the segments yet, in bytes form.
+    buf: Vec<u8>,
+
+    // Nodes of the last group of the row, whose parent is not known
+    // yet.
+    group: Vec<E>,
+}
+
+impl<E: Element, S: Store<E>> Row<E, S> {
+    fn new() -> Row<E, S> {
+        Row {
+            segments: Vec::new(),
+            segment_size: 0,
+            len: 0,
+            buf: Vec::new(),
+            group: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, node: E) -> Result<()> {
+        self.buf.extend_from_slice(node.as_ref());
+        self.group.push(node);
+        self.len += 1;
+
+        if self.buf.len() >= BUILD_DATA_BLOCK_SIZE * E::byte_len() {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    // Writes the buffered nodes to the segments.
+    fn flush(&mut self) -> Result<()> {
+        let elem_len = E::byte_len();
+        let mut start = 0;
+        while start < self.buf.len() {
+            let remaining = (self.buf.len() - start) / elem_len;
+            let mut used = self.segments.last().map_or(0, |segment| segment.len());
+            if used == self.segment_size {
+                // The new segment holds as many nodes as the row so far.
+                let stored = self.len - remaining;
+                self.segment_size = std::cmp::max(remaining, stored);
+                self.segments
+                    .push(S::new(self.segment_size).context("failed to create row store")?);
+                used = 0;
+            }
+
+            let end = start + std::cmp::min(self.segment_size - used, remaining) * elem_len;
+            self.segments
+                .last_mut()
+                .unwrap()
+                .copy_from_slice(&self.buf[start..end], used)?;
+            start = end;
+        }
+        self.buf.clear();
+
+        Ok(())
+    }
+
+    // Copies all the nodes of the row to `data`, starting at `index`.
+    fn copy_into(&mut self, data: &mut S, index: usize) -> Result<()> {
+        self.flush()?;
+
+        let mut index = index;
+        let mut buf = Vec::with_capacity(BUILD_DATA_BLOCK_SIZE * E::byte_len());
+        for segment in &self.segments {
+            let len = segment.len();
+            for start in (0..len).step_by(BUILD_DATA_BLOCK_SIZE) {
+                let end = std::cmp::min(start + BUILD_DATA_BLOCK_SIZE, len);
+
+                buf.clear();
+                for node in segment.read_range(start..end)? {
+                    buf.extend_from_slice(node.as_ref());
+                }
+                data.copy_from_slice(&buf, index)?;
+                index += end - start;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+use crate::store::{DiskStore, VecStore};
+#[cfg(test)]
+use crate::test_common::{Item, XOR128};
+#[cfg(test)]
+use typenum::U4;
+
+#[cfg(test)]
+fn get_item(i: usize) -> Item {
+    let mut a = XOR128::new();
+    (i * 93).hash(&mut a);
+    a.hash()
+}
+
+#[cfg(test)]
+fn test_stream<S: Store<Item>, U: Unsigned + PartialEq + std::fmt::Debug>(
+    count: usize,
+    policy: OddRowPolicy,
+) {
+    let mut builder: StreamingBuilder<Item, XOR128, S, U> =
+        StreamingBuilder::new_with_options(&TreeOptions::new().with_policy(policy))
+            .expect("failed to create builder");
+
+    // Push the leafs in batches of growing size.
+    let mut leafs = 0;
+    let mut batch = 1;
+    while leafs < count {
+        let end = std::cmp::min(leafs + batch, count);
+        builder
+            .extend((leafs..end).map(get_item))
+            .expect("failed to push leafs");
+        leafs = end;
+        batch += 1;
+    }
+    assert_eq!(builder.leafs(), count);
+
+    let tree = builder.finish().expect("failed to finish tree");
+    let mt: MerkleTree<Item, XOR128, VecStore<_>, U> = MerkleTree::try_from_iter_with_options(
+        (0..count).map(|i| Ok(get_item(i))),
+        &TreeOptions::new().with_policy(policy),
+    )
+    .expect("failed to create tree");
+
+    assert_eq!(tree.leafs(), count);
+    assert_eq!(tree.len(), mt.len());
+    assert_eq!(tree.row_count(), mt.row_count());
+    assert_eq!(tree.root(), mt.root());
+    assert_eq!(
+        tree.read_range(0, tree.len()).unwrap(),
+        mt.read_range(0, mt.len()).unwrap()
+    );
+
+    for i in (0..count).step_by(std::cmp::max(1, count / 16)) {
+        let p = tree.gen_proof(i).expect("failed to generate proof");
+        assert_eq!(p, mt.gen_proof(i).unwrap());
+        assert!(p.validate::<XOR128>().expect("failed to validate"));
+    }
+}
+
+#[test]
+fn test_stream_small_trees() {
+    for count in 1..40 {
+        test_stream::<VecStore<_>, U2>(count, OddRowPolicy::Promote);
+        test_stream::<DiskStore<_>, U2>(count, OddRowPolicy::Promote);
+        test_stream::<VecStore<_>, U4>(count, OddRowPolicy::Duplicate);
+        test_stream::<DiskStore<_>, U4>(count, OddRowPolicy::Pad);
+    }
+
+    test_stream::<VecStore<_>, U2>(64, OddRowPolicy::Reject);
+    test_stream::<DiskStore<_>, U4>(256, OddRowPolicy::Reject);
+}
+
+#[test]
+fn test_stream_large_tree() {
+    // Spans several segments of the leaf row.
+    let count = BUILD_DATA_BLOCK_SIZE * 2 + 5;
+    test_stream::<DiskStore<_>, U2>(count, OddRowPolicy::Promote);
+}
+
+#[test]
+fn test_stream_rejects_invalid_shapes() {
+    let mut builder: StreamingBuilder<Item, XOR128, VecStore<_>> =
+        StreamingBuilder::new().expect("failed to create builder");
+    builder
+        .extend((0..5).map(get_item))
+        .expect("failed to push leafs");
+    assert!(builder.finish().is_err());
+
+    let builder: StreamingBuilder<Item, XOR128, VecStore<_>> =
+        StreamingBuilder::new_with_options(&TreeOptions::new().with_policy(OddRowPolicy::Promote))
+            .expect("failed to create builder");
+    assert!(builder.finish().is_err());
+
+    let builder: Result<StreamingBuilder<Item, XOR128, VecStore<_>, U4>> =
+        StreamingBuilder::new_with_options(&TreeOptions::new().with_policy(OddRowPolicy::Promote));
+    assert!(builder.is_err());
+}
+
+#[test]
+fn test_stream_read_from() {
+    let count = 37;
+    let bytes: Vec<u8> = (0..count).map(get_item).collect::<Vec<_>>().concat();
+
+    // Split the input within a leaf, so that reads return partial
+    // leafs.
+    let mut builder: StreamingBuilder<Item, XOR128, VecStore<_>> =
+        StreamingBuilder::new_with_options(&TreeOptions::new().with_policy(OddRowPolicy::Promote))
+            .expect("failed to create builder");
+    let reader = io::Cursor::new(&bytes[..21]).chain(io::Cursor::new(&bytes[21..]));
+    assert_eq!(builder.read_from(reader).expect("failed to read"), count);
+
+    let mt: MerkleTree<Item, XOR128, VecStore<_>> = MerkleTree::try_from_iter_with_options(
+        (0..count).map(|i| Ok(get_item(i))),
+        &TreeOptions::new().with_policy(OddRowPolicy::Promote),
+    )
+    .expect("failed to create tree");
+    assert_eq!(builder.finish().unwrap().root(), mt.root());
+
+    let mut builder: StreamingBuilder<Item, XOR128, VecStore<_>> =
+        StreamingBuilder::new_with_options(&TreeOptions::new().with_policy(OddRowPolicy::Promote))
+            .expect("failed to create builder");
+    assert!(builder.read_from(&bytes[..bytes.len() - 3]).is_err());
+}
+
+#[test]
+fn test_stream_with_config() {
+    let count = 1000;
+    let name = "test_stream_with_config";
+    let temp_dir = tempdir::TempDir::new(name).unwrap();
+    let config = StoreConfig::new(temp_dir.path(), String::from(name), 0);
+
+    let mut builder: StreamingBuilder<Item, XOR128, DiskStore<_>> =
+        StreamingBuilder::new_with_options(
+            &TreeOptions::new()
+                .with_config(config.clone())
+                .with_policy(OddRowPolicy::Promote),
+        )
+        .expect("failed to create builder");
+    for i in 0..count {
+        builder.push_data(i * 93).expect("failed to push leaf");
+    }
+    let tree = builder.finish().expect("failed to finish tree");
+
+    let mt: MerkleTree<Item, XOR128, VecStore<_>> = MerkleTree::from_data_with_options(
+        (0..count).map(|i| i * 93),
+        &TreeOptions::new().with_policy(OddRowPolicy::Promote),
+    )
+    .expect("failed to create tree");
+    assert_eq!(tree.root(), mt.root());
+    assert!(StoreConfig::data_path(&config.path, &config.id).exists());
+
+    // The store is not overwritten.
+    let mut builder: StreamingBuilder<Item, XOR128, DiskStore<_>> =
+        StreamingBuilder::new_with_options(
+            &TreeOptions::new()
+                .with_config(config)
+                .with_policy(OddRowPolicy::Promote),
+        )
+        .expect("failed to create builder");
+    builder
+        .extend((0..count).map(get_item))
+        .expect("failed to push leafs");
+    assert!(builder.finish().is_err());
+}
@@ -1,23 +1,29 @@
 #[cfg(test)]
 use crate::hash::*;
-use crate::merkle::MerkleTree;
+use crate::merkle::{DynMerkleTree, MerkleTree};
 use crate::store::{DiskStore, ReplicaConfig, StoreConfig, VecStore};
 
 use crate::merkle::{
-    get_merkle_tree_len, get_merkle_tree_row_count, is_merkle_tree_size_valid,
-    FromIndexedParallelIterator,
+    get_merkle_proof_lemma_len_with_schedule, get_merkle_tree_cache_size, get_merkle_tree_leafs,
+    get_merkle_tree_len, get_merkle_tree_len_with_policy, get_merkle_tree_len_with_schedule,
+    get_merkle_tree_row_count, get_merkle_tree_row_count_with_policy,
+    get_merkle_tree_row_count_with_schedule, is_merkle_tree_size_valid,
+    FromIndexedParallelIterator, OddRowPolicy,
 };
+use crate::options::TreeOptions;
+use crate::proof::{DynProof, Proof, PROOF_FORMAT_VERSION};
 use crate::store::{
     DiskStoreProducer, ExternalReader, LevelCacheStore, MmapStore, Store, StoreConfigDataVersion,
     SMALL_TREE_BUILD,
 };
 use rayon::iter::{plumbing::*, IntoParallelIterator, ParallelIterator};
 use std::fs::OpenOptions;
+use std::hash::Hasher;
 use std::io::prelude::*;
 use std::os::unix::prelude::FileExt;
 use std::path::PathBuf;
 use typenum::marker_traits::Unsigned;
-use typenum::{U2, U3, U4, U5, U7, U8};
+use typenum::{U0, U11, U2, U3, U4, U5, U7, U8};
 
 use crate::test_common::{get_vec_tree_from_slice, BINARY_ARITY, OCT_ARITY, QUAD_ARITY, XOR128};
 
@@ -440,6 +446,37 @@ fn test_vec_from_slice() {
     }
 }
 
+#[test]
+fn test_tree_from_tree_slice() {
+    // The root is the last element of the tree, not the element at
+    // the byte length of the slice.
+    let leafs = 8;
+    let mt = get_vec_tree_from_slice::<U2>(leafs);
+    let data = mt.read_range(0, mt.len()).unwrap();
+    let bytes: Vec<u8> = data.iter().flat_map(|x| x.to_vec()).collect();
+
+    let slice_mt: MerkleTree<[u8; 16], XOR128, VecStore<_>> =
+        MerkleTree::from_tree_slice(&bytes, leafs).expect("failed to create tree from slice");
+    assert_eq!(slice_mt.root(), mt.root());
+    assert_eq!(slice_mt.len(), mt.len());
+
+    let temp_dir = tempdir::TempDir::new("test_tree_from_tree_slice").unwrap();
+    let config = StoreConfig::new(
+        temp_dir.path(),
+        String::from("test_tree_from_tree_slice"),
+        StoreConfig::default_rows_to_discard(leafs, 2),
+    );
+    let disk_mt: MerkleTree<[u8; 16], XOR128, DiskStore<_>> =
+        MerkleTree::from_tree_slice_with_config(&bytes, leafs, config)
+            .expect("failed to create tree from slice");
+    assert_eq!(disk_mt.root(), mt.root());
+
+    for i in 0..leafs {
+        let p = disk_mt.gen_proof(i).unwrap();
+        assert!(p.validate::<XOR128>().expect("failed to validate"));
+    }
+}
+
 // B: Branching factor of sub-trees
 // N: Branching factor of top-layer
 fn test_compound_tree_from_slices<B: Unsigned, N: Unsigned>(sub_tree_leafs: usize) {
@@ -600,6 +637,26 @@ fn test_compound_levelcache_tree_from_store_configs<B: Unsigned, N: Unsigned>(
     );
     assert_eq!(tree.leafs(), sub_tree_count * sub_tree_leafs);
 
+    // The same sub-trees stacked at runtime give the same proofs.
+    let trees = sub_tree_configs
+        .iter()
+        .enumerate()
+        .map(|(i, config)| {
+            let store = LevelCacheStore::new_from_disk_with_reader(
+                len,
+                branches,
+                config,
+                ExternalReader::new_from_config(&replica_config, i).unwrap(),
+            )
+            .expect("failed to open store");
+            MerkleTree::from_data_store(store, sub_tree_leafs).unwrap()
+        })
+        .collect();
+    let layered: MerkleTree<[u8; 16], XOR128, LevelCacheStore<[u8; 16], std::fs::File>, B> =
+        MerkleTree::from_trees_with_arities(trees, &[sub_tree_count])
+            .expect("Failed to build layered levelcache tree");
+    assert_eq!(layered.root(), tree.root());
+
     for i in 0..tree.leafs() {
         // Make sure all elements are accessible.
         let _ = tree.read_at(i).expect("Failed to read tree element");
@@ -607,6 +664,10 @@ fn test_compound_levelcache_tree_from_store_configs<B: Unsigned, N: Unsigned>(
         // Make sure all proofs validate.
         let p = tree.gen_cached_proof(i, None).unwrap();
         assert!(p.validate::<XOR128>().expect("failed to validate"));
+        assert_eq!(
+            layered.gen_cached_proof(i, None).unwrap().to_bytes(),
+            p.to_bytes()
+        );
     }
 }
 
@@ -728,6 +789,595 @@ fn test_compound_octree_from_slices() {
     }
 }
 
+type QuadTree = MerkleTree<[u8; 16], XOR128, VecStore<[u8; 16]>, U4>;
+
+fn get_layer_trees(count: usize, leafs: usize) -> Vec<QuadTree> {
+    (0..count)
+        .map(|t| {
+            let data: Vec<usize> = (0..leafs).map(|i| (t * leafs + i) * 93).collect();
+            MerkleTree::from_data(&data).expect("failed to create tree from data")
+        })
+        .collect()
+}
+
+#[test]
+fn test_layered_compound_tree() {
+    // 2 * 3 * 2 quad trees with 16 leafs each, joined by three layers
+    // above the base trees.
+    let leafs = 16;
+    let base_trees = get_layer_trees(12, leafs);
+    let tree: MerkleTree<[u8; 16], XOR128, VecStore<_>, U4> =
+        MerkleTree::from_trees_with_arities(base_trees.clone(), &[2, 3, 2])
+            .expect("Failed to build layered tree");
+    assert_eq!(tree.layer_arities(), vec![2, 3, 2]);
+    assert_eq!(tree.leafs(), 12 * leafs);
+    assert_eq!(tree.row_count(), 6);
+    assert_eq!(tree.len(), 12 * 21 + 6 + 2 + 1);
+
+    let root = tree.root();
+    for i in 0..tree.leafs() {
+        assert_eq!(
+            tree.read_at(i).unwrap(),
+            base_trees[i / leafs].read_at(i % leafs).unwrap()
+        );
+
+        let p = tree.gen_proof(i).unwrap();
+        assert!(p.validate::<XOR128>().expect("failed to validate"));
+        assert!(p
+            .validate_against::<XOR128>(&root, i, tree.leafs())
+            .unwrap());
+        assert_eq!(p.validate_strict::<XOR128>(), Ok(()));
+        assert_eq!(p.leaf_index(), Some(i));
+        assert!(!p
+            .validate_against::<XOR128>(&root, (i + leafs) % tree.leafs(), tree.leafs())
+            .unwrap());
+
+        let bytes = p.to_bytes();
+        assert_eq!(bytes[0], PROOF_FORMAT_VERSION);
+        assert_eq!(Proof::<[u8; 16], U4>::from_bytes(&bytes).unwrap(), p);
+        assert!(Proof::<[u8; 16], U4>::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+
+        let json = serde_json::to_string(&p).expect("failed to serialize");
+        let decoded: Proof<[u8; 16], U4> = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, p);
+    }
+
+    // Multi proofs are only supported on typed compound trees.
+    assert!(tree.gen_multi_proof(&[0, 1]).is_err());
+
+    // All trees of a layer must have the same layers.
+    let mut trees = vec![
+        MerkleTree::from_layer_trees(get_layer_trees(2, leafs)).unwrap(),
+        MerkleTree::from_layer_trees(get_layer_trees(3, leafs)).unwrap(),
+    ];
+    assert!(MerkleTree::<_, _, _, U4>::from_layer_trees(trees.clone()).is_err());
+    trees.pop();
+    assert!(MerkleTree::<_, _, _, U4>::from_layer_trees(trees).is_ok());
+    assert!(
+        MerkleTree::<_, _, _, U4>::from_trees_with_arities(get_layer_trees(6, leafs), &[4, 2])
+            .is_err()
+    );
+}
+
+#[test]
+fn test_layered_compound_tree_matches_typed() {
+    let leafs = 16;
+
+    let typed: MerkleTree<[u8; 16], XOR128, VecStore<_>, U4, U3> =
+        MerkleTree::from_trees(get_layer_trees(3, leafs)).unwrap();
+    let layered: MerkleTree<[u8; 16], XOR128, VecStore<_>, U4> =
+        MerkleTree::from_trees_with_arities(get_layer_trees(3, leafs), &[3]).unwrap();
+    assert_eq!(layered.root(), typed.root());
+    assert_eq!(layered.len(), typed.len());
+    assert_eq!(layered.row_count(), typed.row_count());
+    for i in 0..typed.leafs() {
+        let p = typed.gen_proof(i).unwrap();
+        assert_eq!(layered.gen_proof(i).unwrap(), p);
+        assert_eq!(p.to_bytes()[0], PROOF_FORMAT_VERSION);
+    }
+
+    let sub_trees: Vec<MerkleTree<[u8; 16], XOR128, VecStore<_>, U4, U3>> =
+        get_layer_trees(6, leafs)
+            .chunks(3)
+            .map(|trees| MerkleTree::from_trees(trees.to_vec()).unwrap())
+            .collect();
+    let typed: MerkleTree<[u8; 16], XOR128, VecStore<_>, U4, U3, U2> =
+        MerkleTree::from_sub_trees(sub_trees).unwrap();
+    let layered: MerkleTree<[u8; 16], XOR128, VecStore<_>, U4> =
+        MerkleTree::from_trees_with_arities(get_layer_trees(6, leafs), &[2, 3]).unwrap();
+    assert_eq!(layered.root(), typed.root());
+    for i in 0..typed.leafs() {
+        assert_eq!(layered.read_at(i).unwrap(), typed.read_at(i).unwrap());
+        assert!(layered
+            .gen_proof(i)
+            .unwrap()
+            .validate_against::<XOR128>(&typed.root(), i, typed.leafs())
+            .unwrap());
+    }
+}
+
+fn get_quad_tree(leafs: usize, seed: usize) -> QuadTree {
+    let data: Vec<usize> = (0..leafs).map(|i| (seed + i) * 93).collect();
+    MerkleTree::from_data(&data).expect("failed to create tree from data")
+}
+
+#[test]
+fn test_unequal_compound_tree() {
+    // 3 quad trees of different heights in a layer of arity 4.
+    let base_trees = vec![
+        get_quad_tree(16, 0),
+        get_quad_tree(64, 100),
+        get_quad_tree(4, 200),
+    ];
+    let tree: MerkleTree<[u8; 16], XOR128, VecStore<_>, U4, U4> =
+        MerkleTree::from_trees(base_trees.clone()).expect("Failed to build compound tree");
+    assert_eq!(tree.leafs(), 84);
+    assert_eq!(tree.len(), 21 + 85 + 5 + 1);
+    assert_eq!(tree.row_count(), 5);
+    assert_eq!(tree.leaf_offsets(), &[0, 16, 80, 84, 84]);
+    assert_eq!(tree.layer_arities(), vec![4]);
+
+    // The empty slot is hashed as the empty root.
+    let empty = MerkleTree::<[u8; 16], XOR128, VecStore<_>, U4, U4>::empty_root();
+    assert_eq!(empty, [0; 16]);
+    let roots = vec![
+        base_trees[0].root(),
+        base_trees[1].root(),
+        base_trees[2].root(),
+        empty,
+    ];
+    assert_eq!(tree.root(), XOR128::new().multi_node(&roots, 1));
+
+    let root = tree.root();
+    for i in 0..tree.leafs() {
+        let slot = tree.leaf_offsets().iter().rposition(|o| *o <= i).unwrap();
+        let leaf_index = i - tree.leaf_offsets()[slot];
+        assert_eq!(
+            tree.read_at(i).unwrap(),
+            base_trees[slot].read_at(leaf_index).unwrap()
+        );
+
+        let p = tree.gen_proof(i).unwrap();
+        assert!(p.validate::<XOR128>().expect("failed to validate"));
+        assert_eq!(p.validate_strict::<XOR128>(), Ok(()));
+        assert_eq!(p.root(), root);
+        assert_eq!(p.path(), &vec![slot]);
+        let sub_tree_proof = p.sub_tree_proof.as_ref().unwrap();
+        assert_eq!(sub_tree_proof.leaf_index(), Some(leaf_index));
+        assert_eq!(sub_tree_proof.root(), base_trees[slot].root());
+        assert_eq!(Proof::<[u8; 16], U4>::from_bytes(&p.to_bytes()).unwrap(), p);
+
+        // The leaf index is only known from the offsets of the trees.
+        assert!(p
+            .validate_against_offsets::<XOR128>(&root, i, tree.leaf_offsets())
+            .unwrap());
+        assert!(!p
+            .validate_against_offsets::<XOR128>(&root, (i + 1) % tree.leafs(), tree.leaf_offsets())
+            .unwrap());
+        assert!(!p
+            .validate_against::<XOR128>(&root, i, tree.leafs())
+            .unwrap());
+        assert!(sub_tree_proof
+            .validate_against_offsets::<XOR128>(&root, i, tree.leaf_offsets())
+            .is_err());
+    }
+    assert!(tree.read_at(tree.leafs()).is_err());
+    assert!(tree.gen_proof(tree.leafs()).is_err());
+
+    // Runtime layers can be padded the same way.
+    let layered: MerkleTree<[u8; 16], XOR128, VecStore<_>, U4> =
+        MerkleTree::from_layer_trees_with_arity(base_trees.clone(), 4).unwrap();
+    assert_eq!(layered.root(), tree.root());
+    assert_eq!(layered.layer_arities(), vec![4]);
+    for i in (0..tree.leafs()).step_by(7) {
+        assert_eq!(layered.gen_proof(i).unwrap(), tree.gen_proof(i).unwrap());
+    }
+    assert!(MerkleTree::<_, _, _, U4>::from_layer_trees_with_arity(base_trees.clone(), 2).is_err());
+
+    // A top layer over a single, partially filled, sub-tree.
+    let top: MerkleTree<[u8; 16], XOR128, VecStore<_>, U4, U4, U2> =
+        MerkleTree::from_sub_trees(vec![tree.clone()]).unwrap();
+    assert_eq!(top.leafs(), tree.leafs());
+    assert_eq!(top.leaf_offsets(), &[0, 84, 84]);
+    assert_eq!(
+        top.root(),
+        XOR128::new().multi_node(&[tree.root(), empty], 1)
+    );
+    for i in 0..top.leafs() {
+        assert_eq!(top.read_at(i).unwrap(), tree.read_at(i).unwrap());
+        let p = top.gen_proof(i).unwrap();
+        assert!(p.validate::<XOR128>().expect("failed to validate"));
+        assert_eq!(p.root(), top.root());
+    }
+
+    // Trees are grouped by the sub-tree arity, the last group being short.
+    let trees: Vec<QuadTree> = (0..5).map(|t| get_quad_tree(16, t * 16)).collect();
+    let grouped: MerkleTree<[u8; 16], XOR128, VecStore<_>, U4, U2, U3> =
+        MerkleTree::from_sub_trees_as_trees(trees.clone()).unwrap();
+    let sub_trees: Vec<MerkleTree<[u8; 16], XOR128, VecStore<_>, U4, U2>> = trees
+        .chunks(2)
+        .map(|trees| MerkleTree::from_trees(trees.to_vec()).unwrap())
+        .collect();
+    let top: MerkleTree<[u8; 16], XOR128, VecStore<_>, U4, U2, U3> =
+        MerkleTree::from_sub_trees(sub_trees).unwrap();
+    assert_eq!(grouped.root(), top.root());
+    assert_eq!(grouped.leafs(), 80);
+    for i in (0..grouped.leafs()).step_by(3) {
+        let p = grouped.gen_proof(i).unwrap();
+        assert!(p
+            .validate_against::<XOR128>(&grouped.root(), i, grouped.leafs())
+            .unwrap());
+    }
+
+    // Multi proofs need full layers of equal sub-trees.
+    assert!(tree.gen_multi_proof(&[0, 1]).is_err());
+    assert!(grouped.gen_multi_proof(&[0, 1]).is_err());
+
+    // There can't be more trees than the arity, nor none at all.
+    let trees: Vec<QuadTree> = (0..5).map(|t| get_quad_tree(4, t * 4)).collect();
+    assert!(MerkleTree::<_, _, _, U4, U4>::from_trees(trees).is_err());
+    assert!(MerkleTree::<_, _, _, U4, U4>::from_trees(Vec::<QuadTree>::new()).is_err());
+
+    // All trees must still have the same arity.
+    let binary: MerkleTree<[u8; 16], XOR128, VecStore<_>, U0> =
+        MerkleTree::from_data_with_options(0..16, &TreeOptions::new().with_arity(2)).unwrap();
+    let quad: MerkleTree<[u8; 16], XOR128, VecStore<_>, U0> =
+        MerkleTree::from_data_with_options(0..16, &TreeOptions::new().with_arity(4)).unwrap();
+    assert!(DynMerkleTree::<_, _, _, U3>::from_trees(vec![binary, quad]).is_err());
+}
+
+fn test_dyn_tree<U: Unsigned + Eq + std::fmt::Debug>(leafs: usize, policy: OddRowPolicy) {
+    let branches = U::to_usize();
+    let data: Vec<usize> = (0..leafs).map(|i| i * 93).collect();
+
+    let typed: MerkleTree<[u8; 16], XOR128, VecStore<_>, U> =
+        MerkleTree::from_data_with_options(&data, &TreeOptions::new().with_policy(policy))
+            .expect("failed to build typed tree");
+    let tree: DynMerkleTree<[u8; 16], XOR128, VecStore<_>> = DynMerkleTree::from_data_with_options(
+        &data,
+        &TreeOptions::new().with_arity(branches).with_policy(policy),
+    )
+    .expect("failed to build dyn tree");
+
+    assert_eq!(tree.arity(), branches);
+    assert_eq!(tree.root(), typed.root());
+    assert_eq!(tree.len(), typed.len());
+    assert_eq!(tree.row_count(), typed.row_count());
+    assert_eq!(
+        tree.read_range(0, tree.len()).unwrap(),
+        typed.read_range(0, typed.len()).unwrap()
+    );
+
+    for i in 0..leafs {
+        let p = tree.gen_proof(i).unwrap();
+        let typed_p = typed.gen_proof(i).unwrap();
+        assert!(p.validate::<XOR128>().expect("failed to validate"));
+        assert!(p
+            .validate_against::<XOR128>(&typed.root(), i, typed.leafs())
+            .unwrap());
+        assert_eq!(p.base_tree_nodes(), branches);
+
+        // Proofs are interchangeable with the ones of the typed tree.
+        assert_eq!(p.to_bytes(), typed_p.to_bytes());
+        assert_eq!(DynProof::from_bytes(&typed_p.to_bytes()).unwrap(), p);
+        assert_eq!(p.clone().into_arity::<U>().unwrap(), typed_p);
+        assert_eq!(typed_p.into_arity::<U0>().unwrap(), p);
+        if branches == 2 {
+            assert!(p.into_arity::<U4>().is_err());
+        } else {
+            assert!(p.into_arity::<U2>().is_err());
+        }
+    }
+
+    let indices = [0, leafs / 2, leafs - 1];
+    let p = tree.gen_multi_proof(&indices).unwrap();
+    assert!(p.validate::<XOR128>(typed.root()).unwrap());
+    let p = tree.gen_range_proof(1, leafs - 1).unwrap();
+    assert!(p.validate::<XOR128>(typed.root()).unwrap());
+
+    let bytes: Vec<u8> = (0..leafs)
+        .flat_map(|i| typed.read_at(i).unwrap().to_vec())
+        .collect();
+    let tree: DynMerkleTree<[u8; 16], XOR128, VecStore<_>> =
+        DynMerkleTree::from_byte_slice_with_options(
+            &bytes,
+            &TreeOptions::new().with_arity(branches).with_policy(policy),
+        )
+        .unwrap();
+    assert_eq!(tree.root(), typed.root());
+}
+
+#[test]
+fn test_dyn_trees() {
+    test_dyn_tree::<U2>(64, OddRowPolicy::Reject);
+    test_dyn_tree::<U4>(64, OddRowPolicy::Reject);
+    test_dyn_tree::<U8>(512, OddRowPolicy::Reject);
+    test_dyn_tree::<U2>(37, OddRowPolicy::Promote);
+    test_dyn_tree::<U4>(37, OddRowPolicy::Pad);
+
+    // The arity must be given for runtime arity trees, and match the
+    // one of typed trees.
+    let data: Vec<usize> = (0..16).collect();
+    assert!(DynMerkleTree::<[u8; 16], XOR128, VecStore<_>>::from_data(&data).is_err());
+    assert!(
+        MerkleTree::<[u8; 16], XOR128, VecStore<_>, U4>::from_data_with_options(
+            &data,
+            &TreeOptions::new().with_arity(2)
+        )
+        .is_err()
+    );
+}
+
+#[test]
+fn test_dyn_compound_tree() {
+    let leafs = 16;
+    let trees: Vec<DynMerkleTree<[u8; 16], XOR128, VecStore<_>>> = get_layer_trees(3, leafs)
+        .iter()
+        .map(|mt| {
+            let data = mt.read_range(0, mt.len()).unwrap();
+            let bytes: Vec<u8> = data.iter().flat_map(|x| x.to_vec()).collect();
+            DynMerkleTree::from_tree_slice_with_options(
+                &bytes,
+                leafs,
+                &TreeOptions::new().with_arity(4),
+            )
+            .unwrap()
+        })
+        .collect();
+
+    let typed: MerkleTree<[u8; 16], XOR128, VecStore<_>, U4, U3> =
+        MerkleTree::from_trees(get_layer_trees(3, leafs)).unwrap();
+    let tree: DynMerkleTree<[u8; 16], XOR128, VecStore<_>, U3> =
+        MerkleTree::from_trees(trees.clone()).unwrap();
+    assert_eq!(tree.root(), typed.root());
+    assert_eq!(tree.arity(), 4);
+    for i in 0..tree.leafs() {
+        let p = tree.gen_proof(i).unwrap();
+        assert!(p.validate::<XOR128>().expect("failed to validate"));
+        assert_eq!(p.to_bytes(), typed.gen_proof(i).unwrap().to_bytes());
+    }
+
+    // All trees must have the same arity.
+    let mut trees = trees;
+    trees[2] =
+        DynMerkleTree::from_data_with_options(0..64, &TreeOptions::new().with_arity(2)).unwrap();
+    assert!(DynMerkleTree::<_, _, _, U3>::from_trees(trees).is_err());
+}
+
+#[test]
+fn test_dyn_levelcache_tree() {
+    let (leafs, len, row_count) = (1024, 1365, 6);
+    let branches = 4;
+    let temp_dir = tempdir::TempDir::new("test_dyn_levelcache_tree").unwrap();
+    let config = StoreConfig::new(
+        temp_dir.path(),
+        String::from("test-dyn-levelcache-tree"),
+        StoreConfig::default_rows_to_discard(leafs, branches),
+    );
+    build_disk_tree_from_iter::<U4>(leafs, len, row_count, &config);
+    let typed_config = StoreConfig::from_config(&config, "test-dyn-levelcache-typed", None);
+    build_disk_tree_from_iter::<U4>(leafs, len, row_count, &typed_config);
+
+    let store = DiskStore::new_from_disk(len, branches, &config).unwrap();
+    let mut tree: DynMerkleTree<[u8; 16], XOR128, DiskStore<_>> =
+        DynMerkleTree::from_data_store_with_options(
+            store,
+            leafs,
+            &TreeOptions::new().with_arity(branches),
+        )
+        .unwrap();
+    assert_eq!(tree.row_count(), row_count);
+    assert!(tree
+        .compact(config.clone(), StoreConfigDataVersion::One as u32)
+        .unwrap());
+
+    let store = DiskStore::new_from_disk(len, branches, &typed_config).unwrap();
+    let typed: MerkleTree<[u8; 16], XOR128, DiskStore<_>, U4> =
+        MerkleTree::from_data_store(store, leafs).unwrap();
+
+    let level_cache_store: LevelCacheStore<[u8; 16], std::fs::File> =
+        LevelCacheStore::new_from_disk(len, branches, &config).unwrap();
+    let tree: DynMerkleTree<[u8; 16], XOR128, LevelCacheStore<_, _>> =
+        DynMerkleTree::from_data_store_with_options(
+            level_cache_store,
+            leafs,
+            &TreeOptions::new().with_arity(branches),
+        )
+        .unwrap();
+    assert_eq!(tree.root(), typed.root());
+
+    for i in (0..leafs).step_by(7) {
+        let p = tree
+            .gen_cached_proof(i, Some(config.rows_to_discard))
+            .unwrap();
+        assert!(p.validate::<XOR128>().expect("failed to validate"));
+        assert_eq!(p.to_bytes(), typed.gen_proof(i).unwrap().to_bytes());
+    }
+}
+
+#[test]
+fn test_scheduled_tree() {
+    let leafs = 512;
+    let data: Vec<usize> = (0..leafs).map(|i| i * 93).collect();
+
+    // Trailing arities repeating the last one are redundant.
+    let tree: DynMerkleTree<[u8; 16], XOR128, VecStore<_>> = DynMerkleTree::from_data_with_options(
+        &data,
+        &TreeOptions::new().with_arity_schedule(&[8, 2, 2]),
+    )
+    .expect("failed to build scheduled tree");
+    assert_eq!(tree.arity_schedule(), &[8, 2]);
+    assert_eq!(tree.arity(), 8);
+
+    let arities = [8, 2];
+    let (len, row_count) = (639, 8);
+    assert_eq!(
+        get_merkle_tree_len_with_schedule(leafs, &arities, OddRowPolicy::Reject).unwrap(),
+        len
+    );
+    assert_eq!(
+        get_merkle_tree_row_count_with_schedule(leafs, &arities, OddRowPolicy::Reject),
+        row_count
+    );
+    assert_eq!(tree.len(), len);
+    assert_eq!(tree.row_count(), row_count);
+
+    // The rows above the 8-ary leaf row are a binary tree, and every
+    // row hashes at its height in the whole tree.
+    let mut row = tree.read_range(0, leafs).unwrap();
+    for height in 0..row_count - 1 {
+        let branches = if height == 0 { 8 } else { 2 };
+        row = row
+            .chunks(branches)
+            .map(|nodes| XOR128::new().multi_node(nodes, height))
+            .collect();
+    }
+    assert_eq!(row, vec![tree.root()]);
+
+    let lemma_len = get_merkle_proof_lemma_len_with_schedule(row_count, &arities);
+    assert_eq!(lemma_len, 15);
+    for i in (0..leafs).step_by(5) {
+        let p = tree.gen_proof(i).unwrap();
+        assert_eq!(p.lemma().len(), lemma_len);
+        assert_eq!(p.base_tree_arities(), &arities);
+        assert!(p.validate::<XOR128>().expect("failed to validate"));
+        assert!(p
+            .validate_against::<XOR128>(&tree.root(), i, tree.leafs())
+            .unwrap());
+        assert!(!p
+            .validate_against::<XOR128>(&tree.root(), i ^ 8, tree.leafs())
+            .unwrap());
+        assert_eq!(p.validate_strict::<XOR128>(), Ok(()));
+
+        let bytes = p.to_bytes();
+        assert_eq!(bytes[0], PROOF_FORMAT_VERSION);
+        assert_eq!(DynProof::from_bytes(&bytes).unwrap(), p);
+        assert!(Proof::<[u8; 16], U8>::from_bytes(&bytes).is_err());
+        let json = serde_json::to_string(&p).expect("failed to serialize");
+        assert_eq!(
+            serde_json::from_str::<DynProof<[u8; 16]>>(&json).unwrap(),
+            p
+        );
+
+        let mut broken = p.clone();
+        broken.lemma_mut()[lemma_len - 2] = [0; 16];
+        assert!(!broken.validate::<XOR128>().unwrap());
+    }
+
+    // Only the leaf row is 8-ary, so the proofs are shorter than the
+    // ones of a binary tree of the same data.
+    let binary: DynMerkleTree<[u8; 16], XOR128, VecStore<_>> =
+        DynMerkleTree::from_data_with_options(&data, &TreeOptions::new().with_arity(2)).unwrap();
+    assert!(binary.gen_proof(0).unwrap().lemma().len() > lemma_len);
+
+    let mut tree = tree;
+    tree.update_leaf(42, [7; 16]).unwrap();
+    let p = tree.gen_proof(42).unwrap();
+    assert!(p
+        .validate_against::<XOR128>(&tree.root(), 42, tree.leafs())
+        .unwrap());
+
+    // Short rows of the schedule are padded like the rows of a
+    // single arity.
+    let data: Vec<usize> = (0..37).collect();
+    let tree: DynMerkleTree<[u8; 16], XOR128, VecStore<_>> = DynMerkleTree::from_data_with_options(
+        &data,
+        &TreeOptions::new()
+            .with_arity_schedule(&[4, 2])
+            .with_policy(OddRowPolicy::Pad),
+    )
+    .unwrap();
+    assert_eq!(tree.row_count(), 6);
+    for i in 0..data.len() {
+        let p = tree.gen_proof(i).unwrap();
+        assert!(p
+            .validate_against::<XOR128>(&tree.root(), i, tree.leafs())
+            .unwrap());
+        assert_eq!(p.validate_strict::<XOR128>(), Ok(()));
+    }
+
+    // Every row must be made of full groups without odd rows, and all
+    // the arities of typed trees must be the type arity.
+    assert!(
+        DynMerkleTree::<[u8; 16], XOR128, VecStore<_>>::from_data_with_options(
+            &data[..4],
+            &TreeOptions::new().with_arity_schedule(&[2, 8])
+        )
+        .is_err()
+    );
+    assert!(
+        DynMerkleTree::<[u8; 16], XOR128, VecStore<_>>::from_data_with_options(
+            &data[..16],
+            &TreeOptions::new().with_arity_schedule(&[])
+        )
+        .is_err()
+    );
+    assert!(
+        MerkleTree::<[u8; 16], XOR128, VecStore<_>, U8>::from_data_with_options(
+            0..512,
+            &TreeOptions::new().with_arity_schedule(&[8, 2])
+        )
+        .is_err()
+    );
+}
+
+#[test]
+fn test_scheduled_levelcache_tree() {
+    let (leafs, len, row_count) = (4096, 5119, 11);
+    let arities = [8, 2];
+    let temp_dir = tempdir::TempDir::new("test_scheduled_levelcache_tree").unwrap();
+    let config = StoreConfig::new(
+        temp_dir.path(),
+        String::from("test-scheduled-levelcache-tree"),
+        StoreConfig::default_rows_to_discard_with_schedule(leafs, &arities),
+    );
+
+    let leaf = |x: usize| {
+        let mut a = XOR128::new();
+        (x * 3).hash(&mut a);
+        a.hash()
+    };
+    let vec_tree: DynMerkleTree<[u8; 16], XOR128, VecStore<_>> =
+        DynMerkleTree::try_from_iter_with_options(
+            (0..leafs).map(|x| Ok(leaf(x))),
+            &TreeOptions::new().with_arity_schedule(&arities),
+        )
+        .unwrap();
+    let mut tree: DynMerkleTree<[u8; 16], XOR128, DiskStore<_>> =
+        DynMerkleTree::try_from_iter_with_options(
+            (0..leafs).map(|x| Ok(leaf(x))),
+            &TreeOptions::new()
+                .with_config(config.clone())
+                .with_arity_schedule(&arities),
+        )
+        .unwrap();
+    assert_eq!(tree.len(), len);
+    assert_eq!(tree.row_count(), row_count);
+    assert_eq!(tree.root(), vec_tree.root());
+    assert!(tree
+        .compact(config.clone(), StoreConfigDataVersion::One as u32)
+        .unwrap());
+
+    let level_cache_store: LevelCacheStore<[u8; 16], std::fs::File> =
+        LevelCacheStore::new_from_disk_with_schedule(len, &arities, &config).unwrap();
+    let tree: DynMerkleTree<[u8; 16], XOR128, LevelCacheStore<_, _>> =
+        DynMerkleTree::from_data_store_with_options(
+            level_cache_store,
+            leafs,
+            &TreeOptions::new().with_arity_schedule(&arities),
+        )
+        .unwrap();
+    assert_eq!(tree.root(), vec_tree.root());
+
+    for i in (0..leafs).step_by(37) {
+        let p = tree
+            .gen_cached_proof(i, Some(config.rows_to_discard))
+            .unwrap();
+        assert!(p.validate::<XOR128>().expect("failed to validate"));
+        assert_eq!(p, vec_tree.gen_proof(i).unwrap());
+    }
+}
+
 #[test]
 fn test_quad_from_slice() {
     let (leafs, len, row_count, num_challenges) = { (16, 21, 3, 16) };
@@ -740,6 +1390,135 @@ fn test_quad_from_iter() {
     test_vec_tree_from_iter::<U4>(leafs, len, row_count, num_challenges);
 }
 
+#[test]
+fn test_non_power_of_two_arity_sizes() {
+    assert_eq!(get_merkle_tree_len(9, 3).unwrap(), 13);
+    assert_eq!(get_merkle_tree_len(125, 5).unwrap(), 156);
+    assert_eq!(get_merkle_tree_len(121, 11).unwrap(), 133);
+    assert_eq!(get_merkle_tree_row_count(9, 3), 3);
+    assert_eq!(get_merkle_tree_row_count(121, 11), 3);
+    assert_eq!(get_merkle_tree_leafs(13, 3).unwrap(), 9);
+    assert_eq!(get_merkle_tree_leafs(133, 11).unwrap(), 121);
+
+    assert!(is_merkle_tree_size_valid(27, 3));
+    assert!(!is_merkle_tree_size_valid(18, 3));
+    assert!(!is_merkle_tree_size_valid(32, 3));
+    assert!(is_merkle_tree_size_valid(64, 4));
+    assert!(!is_merkle_tree_size_valid(32, 4));
+    assert!(get_merkle_tree_len(10, 3).is_err());
+    assert!(get_merkle_tree_leafs(14, 3).is_err());
+}
+
+#[test]
+fn test_ternary_from_iter() {
+    let (leafs, len, row_count, num_challenges) = { (2187, 3280, 8, 2187) };
+    test_vec_tree_from_iter::<U3>(leafs, len, row_count, num_challenges);
+}
+
+#[test]
+fn test_ternary_with_partial_cache() {
+    let (leafs, len, row_count, num_challenges) = { (6561, 9841, 9, 6561) };
+    for rows_to_discard in 1..row_count - 1 {
+        test_levelcache_v1_tree_from_iter::<U3>(
+            leafs,
+            len,
+            row_count,
+            num_challenges,
+            rows_to_discard,
+        );
+    }
+    test_levelcache_direct_build_from_slice::<U3>(leafs, len, row_count, num_challenges, None);
+}
+
+#[test]
+fn test_eleven_ary_with_disk_store() {
+    // More leafs than a single build chunk, which is not a multiple
+    // of the arity.
+    let (leafs, len, row_count, num_challenges) = { (14641, 16105, 5, 64) };
+    test_disk_tree_from_iter::<U11>(
+        leafs,
+        len,
+        row_count,
+        num_challenges,
+        StoreConfig::default_rows_to_discard(leafs, 11),
+    );
+}
+
+#[test]
+fn test_non_power_of_two_dyn_tree() {
+    let data: Vec<usize> = (0..125).map(|i| i * 7).collect();
+    let tree: DynMerkleTree<[u8; 16], XOR128, VecStore<_>> =
+        DynMerkleTree::from_data_with_options(&data, &TreeOptions::new().with_arity(5)).unwrap();
+    let typed: MerkleTree<[u8; 16], XOR128, VecStore<_>, U5> =
+        MerkleTree::from_data(&data).unwrap();
+    assert_eq!(tree.root(), typed.root());
+    assert_eq!(tree.len(), 156);
+
+    for i in 0..data.len() {
+        let p = tree.gen_proof(i).unwrap();
+        assert_eq!(p.lemma().len(), 14);
+        assert!(p
+            .validate_against::<XOR128>(&tree.root(), i, tree.leafs())
+            .unwrap());
+        assert_eq!(DynProof::from_bytes(&p.to_bytes()).unwrap(), p);
+    }
+    assert!(
+        DynMerkleTree::<[u8; 16], XOR128, VecStore<_>>::from_data_with_options(
+            &data[..100],
+            &TreeOptions::new().with_arity(5)
+        )
+        .is_err()
+    );
+
+    // A ternary leaf row topped by a 5-ary tree.
+    let arities = [3, 5];
+    let data: Vec<usize> = (0..75).map(|i| i * 11).collect();
+    let tree: DynMerkleTree<[u8; 16], XOR128, VecStore<_>> = DynMerkleTree::from_data_with_options(
+        &data,
+        &TreeOptions::new().with_arity_schedule(&arities),
+    )
+    .unwrap();
+    assert_eq!(
+        get_merkle_tree_len_with_schedule(75, &arities, OddRowPolicy::Reject).unwrap(),
+        106
+    );
+    assert_eq!(tree.len(), 106);
+    assert_eq!(tree.row_count(), 4);
+
+    let mut row = tree.read_range(0, 75).unwrap();
+    for height in 0..tree.row_count() - 1 {
+        let branches = if height == 0 { 3 } else { 5 };
+        row = row
+            .chunks(branches)
+            .map(|nodes| XOR128::new().multi_node(nodes, height))
+            .collect();
+    }
+    assert_eq!(row, vec![tree.root()]);
+
+    for i in 0..data.len() {
+        let p = tree.gen_proof(i).unwrap();
+        assert!(p
+            .validate_against::<XOR128>(&tree.root(), i, tree.leafs())
+            .unwrap());
+    }
+
+    // Odd rows of a ternary tree are filled like the binary ones.
+    let data: Vec<usize> = (0..20).collect();
+    let tree: DynMerkleTree<[u8; 16], XOR128, VecStore<_>> = DynMerkleTree::from_data_with_options(
+        &data,
+        &TreeOptions::new()
+            .with_arity(3)
+            .with_policy(OddRowPolicy::Duplicate),
+    )
+    .unwrap();
+    for i in 0..data.len() {
+        let p = tree.gen_proof(i).unwrap();
+        assert!(p
+            .validate_against::<XOR128>(&tree.root(), i, tree.leafs())
+            .unwrap());
+    }
+}
+
 #[test]
 #[ignore]
 fn test_xlarge_quad_with_disk_store() {
@@ -1286,24 +2065,84 @@ fn test_level_cache_tree_v2() {
 }
 
 #[test]
-fn test_various_trees_with_partial_cache_v2_only() {
-    env_logger::init();
-    let mut a = XOR128::new();
+fn test_from_leaf_file() {
+    let count = SMALL_TREE_BUILD * 2;
+    let leafs = get_rfc6962_leafs(count);
+    let rows_to_discard = StoreConfig::default_rows_to_discard(count, BINARY_ARITY);
 
-    // Attempt to allow this test to move along relatively quickly.
-    let min_count = SMALL_TREE_BUILD / 4;
-    let max_count = SMALL_TREE_BUILD * 4;
-    let mut count = min_count;
+    let temp_dir = tempdir::TempDir::new("test_from_leaf_file").unwrap();
+    let leaf_file = temp_dir.path().join("leafs");
+    std::fs::write(&leaf_file, leafs.concat()).expect("Failed to write leaf file");
 
-    // Test a range of tree sizes, given a range of leaf elements.
-    while count <= max_count {
-        let row_count = get_merkle_tree_row_count(count, BINARY_ARITY);
+    let mt: MerkleTree<[u8; 16], XOR128, VecStore<_>> =
+        MerkleTree::from_byte_slice(&leafs.concat()).expect("Failed to create MT");
 
-        // Test a range of row_counts to cache above the base (for
-        // different partial tree sizes).
-        //
-        // compaction correctly fails at 0 and row_count
-        for i in 1..row_count - 1 {
+    let config = StoreConfig::new(temp_dir.path(), String::from("disk"), rows_to_discard);
+    let mt_disk: MerkleTree<[u8; 16], XOR128, DiskStore<_>> =
+        MerkleTree::from_leaf_file(&leaf_file, config).expect("Failed to create MT");
+    assert_eq!(mt_disk.len(), mt.len());
+    assert_eq!(mt_disk.root(), mt.root());
+    assert_eq!(
+        mt_disk.read_range(0, mt_disk.len()).unwrap(),
+        mt.read_range(0, mt.len()).unwrap()
+    );
+
+    // The level cache store only writes the cached rows, and reads
+    // the base layer from the leaf file.
+    let config = StoreConfig::new(temp_dir.path(), String::from("cache"), rows_to_discard);
+    let mt_cache: MerkleTree<[u8; 16], XOR128, LevelCacheStore<_, std::fs::File>> =
+        MerkleTree::from_leaf_file(&leaf_file, config.clone()).expect("Failed to create MT");
+    assert_eq!(mt_cache.len(), mt.len());
+    assert_eq!(mt_cache.root(), mt.root());
+    assert!(LevelCacheStore::<[u8; 16], std::fs::File>::is_consistent(
+        mt.len(),
+        BINARY_ARITY,
+        &config
+    )
+    .unwrap());
+
+    for i in (0..count).step_by(37) {
+        assert_eq!(mt_cache.read_at(i).unwrap(), leafs[i]);
+        let p = mt_cache
+            .gen_cached_proof(i, None)
+            .expect("Failed to generate proof and partial tree");
+        assert_eq!(p.lemma(), mt.gen_proof(i).unwrap().lemma());
+        assert!(p.validate::<XOR128>().expect("failed to validate"));
+    }
+
+    // The compacted store is reopened as is.
+    let mt_cache: MerkleTree<[u8; 16], XOR128, LevelCacheStore<_, std::fs::File>> =
+        MerkleTree::from_leaf_file(&leaf_file, config).expect("Failed to open MT");
+    assert_eq!(mt_cache.root(), mt.root());
+    assert!(mt_cache.gen_cached_proof(7, None).is_ok());
+
+    std::fs::write(&leaf_file, &leafs.concat()[..count * 16 - 3])
+        .expect("Failed to write leaf file");
+    let config = StoreConfig::new(temp_dir.path(), String::from("bad"), rows_to_discard);
+    let mt_bad: Result<MerkleTree<[u8; 16], XOR128, DiskStore<_>>, _> =
+        MerkleTree::from_leaf_file(&leaf_file, config);
+    assert!(mt_bad.is_err());
+}
+
+#[test]
+fn test_various_trees_with_partial_cache_v2_only() {
+    env_logger::init();
+    let mut a = XOR128::new();
+
+    // Attempt to allow this test to move along relatively quickly.
+    let min_count = SMALL_TREE_BUILD / 4;
+    let max_count = SMALL_TREE_BUILD * 4;
+    let mut count = min_count;
+
+    // Test a range of tree sizes, given a range of leaf elements.
+    while count <= max_count {
+        let row_count = get_merkle_tree_row_count(count, BINARY_ARITY);
+
+        // Test a range of row_counts to cache above the base (for
+        // different partial tree sizes).
+        //
+        // compaction correctly fails at 0 and row_count
+        for i in 1..row_count - 1 {
             let temp_dir = tempdir::TempDir::new("test_various_trees_with_partial_cache").unwrap();
             let temp_path = temp_dir.path();
 
@@ -1550,3 +2389,928 @@ fn test_parallel_iter_disk_2() {
         }
     }
 }
+
+// Reference RFC 6962 Merkle Tree Hash over already hashed leafs.
+fn rfc6962_root(a: &mut XOR128, leafs: &[[u8; 16]]) -> [u8; 16] {
+    if leafs.len() == 1 {
+        return leafs[0];
+    }
+
+    let mut k = 1;
+    while k * 2 < leafs.len() {
+        k *= 2;
+    }
+
+    let left = rfc6962_root(a, &leafs[..k]);
+    let right = rfc6962_root(a, &leafs[k..]);
+    a.reset();
+    a.node(left, right, 0)
+}
+
+fn get_rfc6962_leafs(count: usize) -> Vec<[u8; 16]> {
+    let mut a = XOR128::new();
+    (0..count)
+        .map(|x| {
+            a.reset();
+            x.hash(&mut a);
+            93.hash(&mut a);
+            let item = a.hash();
+            a.reset();
+            a.leaf(item)
+        })
+        .collect()
+}
+
+fn test_rfc6962_tree<S: Store<[u8; 16]>>(count: usize, num_challenges: usize) {
+    let mut a = XOR128::new();
+    let leafs = get_rfc6962_leafs(count);
+
+    let mt: MerkleTree<[u8; 16], XOR128, S> = MerkleTree::try_from_iter_with_options(
+        (0..count).map(|x| {
+            a.reset();
+            x.hash(&mut a);
+            93.hash(&mut a);
+            Ok(a.hash())
+        }),
+        &TreeOptions::new().with_policy(OddRowPolicy::Promote),
+    )
+    .expect("failed to create RFC 6962 tree");
+
+    assert_eq!(mt.leafs(), count);
+    assert_eq!(mt.policy(), OddRowPolicy::Promote);
+    assert_eq!(
+        mt.len(),
+        get_merkle_tree_len_with_policy(count, BINARY_ARITY, OddRowPolicy::Promote)
+            .expect("failed to get merkle len")
+    );
+    assert_eq!(
+        mt.row_count(),
+        get_merkle_tree_row_count_with_policy(count, BINARY_ARITY, OddRowPolicy::Promote)
+    );
+    assert_eq!(mt.root(), rfc6962_root(&mut a, &leafs));
+
+    for i in (0..count).step_by(std::cmp::max(1, count / num_challenges)) {
+        let mut p = mt.gen_proof(i).expect("failed to generate proof");
+        assert_eq!(p.item(), leafs[i]);
+        assert!(p.validate::<XOR128>().expect("failed to validate"));
+
+        // Any modification of the lemma must invalidate the proof.
+        for j in 0..p.lemma().len() {
+            let mut broken = p.clone();
+            broken.lemma_mut()[j][0] ^= 0xff;
+            assert!(!broken.validate::<XOR128>().expect("failed to validate"));
+        }
+
+        // As must proving a different leaf position.
+        if count > 1 {
+            let mut moved = p.clone();
+            moved.lemma_mut()[0] = leafs[(i + 1) % count];
+            assert!(!moved.validate::<XOR128>().expect("failed to validate"));
+        }
+
+        p.lemma_mut().pop();
+        assert!(!p.validate::<XOR128>().expect("failed to validate"));
+    }
+}
+
+#[test]
+fn test_rfc6962_tree_len() {
+    let policy = OddRowPolicy::Promote;
+    let lens = [(1, 1), (2, 3), (3, 6), (4, 7), (5, 11), (6, 12), (7, 14)];
+    for (leafs, len) in lens.iter() {
+        assert_eq!(
+            get_merkle_tree_len_with_policy(*leafs, BINARY_ARITY, policy).unwrap(),
+            *len
+        );
+    }
+    assert_eq!(
+        get_merkle_tree_row_count_with_policy(5, BINARY_ARITY, policy),
+        4
+    );
+    assert!(get_merkle_tree_len_with_policy(0, BINARY_ARITY, policy).is_err());
+    assert!(get_merkle_tree_len_with_policy(5, QUAD_ARITY, policy).is_err());
+
+    // Power of two sizes are identical regardless of the policy.
+    assert_eq!(
+        get_merkle_tree_len_with_policy(64, BINARY_ARITY, policy).unwrap(),
+        get_merkle_tree_len(64, BINARY_ARITY).unwrap()
+    );
+    assert!(get_merkle_tree_len_with_policy(5, BINARY_ARITY, OddRowPolicy::Reject).is_err());
+}
+
+#[test]
+fn test_rfc6962_small_trees() {
+    for count in 1..70 {
+        test_rfc6962_tree::<VecStore<_>>(count, count);
+        test_rfc6962_tree::<DiskStore<_>>(count, count);
+    }
+}
+
+#[test]
+fn test_rfc6962_large_tree() {
+    let count = SMALL_TREE_BUILD * 2 + 5;
+    test_rfc6962_tree::<VecStore<_>>(count, 64);
+    test_rfc6962_tree::<DiskStore<_>>(count, 64);
+}
+
+#[test]
+fn test_rfc6962_power_of_two_matches_default() {
+    let leafs = get_rfc6962_leafs(64).concat();
+
+    let mt: MerkleTree<[u8; 16], XOR128, VecStore<_>> =
+        MerkleTree::from_byte_slice(&leafs).expect("failed to create tree");
+    let rfc: MerkleTree<[u8; 16], XOR128, VecStore<_>> = MerkleTree::from_byte_slice_with_options(
+        &leafs,
+        &TreeOptions::new().with_policy(OddRowPolicy::Promote),
+    )
+    .expect("failed to create RFC 6962 tree");
+
+    assert_eq!(mt.root(), rfc.root());
+    assert_eq!(
+        mt.gen_proof(7).unwrap().lemma(),
+        rfc.gen_proof(7).unwrap().lemma()
+    );
+}
+
+#[test]
+fn test_rfc6962_rejects_odd_leafs_by_default() {
+    let leafs = get_rfc6962_leafs(5).concat();
+
+    let mt: Result<MerkleTree<[u8; 16], XOR128, VecStore<_>>, _> =
+        MerkleTree::from_byte_slice(&leafs);
+    assert!(mt.is_err());
+
+    let mt: Result<MerkleTree<[u8; 16], XOR128, VecStore<_>, U4>, _> =
+        MerkleTree::from_byte_slice_with_options(
+            &leafs,
+            &TreeOptions::new().with_policy(OddRowPolicy::Promote),
+        );
+    assert!(mt.is_err());
+}
+
+// Root of the tree over `leafs`, filling the short group of each row
+// as `OddRowPolicy::Duplicate` or `OddRowPolicy::Pad` would.
+fn filled_root(leafs: &[[u8; 16]], branches: usize, policy: OddRowPolicy) -> [u8; 16] {
+    let mut row = leafs.to_vec();
+    let mut height = 0;
+    while row.len() > 1 {
+        row = row
+            .chunks(branches)
+            .map(|nodes| {
+                let filler = match policy {
+                    OddRowPolicy::Duplicate => nodes[nodes.len() - 1],
+                    _ => XOR128::new().pad(),
+                };
+                let mut group = nodes.to_vec();
+                group.resize(branches, filler);
+                XOR128::new().multi_node(&group, height)
+            })
+            .collect();
+        height += 1;
+    }
+
+    row[0]
+}
+
+fn test_filled_tree<S: Store<[u8; 16]>, U: Unsigned>(
+    count: usize,
+    policy: OddRowPolicy,
+    num_challenges: usize,
+) {
+    use rand::prelude::*;
+
+    // Random leafs, as XOR128 hashes distinct leafs into equal nodes
+    // easily, which `Duplicate` trees treat as mutations.
+    let leafs: Vec<[u8; 16]> = (0..count).map(|_| random()).collect();
+    let branches = U::to_usize();
+
+    let mt: MerkleTree<[u8; 16], XOR128, S, U> = MerkleTree::from_byte_slice_with_options(
+        &leafs.concat(),
+        &TreeOptions::new().with_policy(policy),
+    )
+    .expect("failed to create tree");
+
+    assert_eq!(mt.leafs(), count);
+    assert_eq!(
+        mt.len(),
+        get_merkle_tree_len_with_policy(count, branches, policy).expect("failed to get merkle len")
+    );
+    assert_eq!(
+        mt.row_count(),
+        get_merkle_tree_row_count_with_policy(count, branches, policy)
+    );
+    assert_eq!(mt.root(), filled_root(&leafs, branches, policy));
+    assert!(!mt.is_mutated().expect("failed to check tree"));
+
+    for i in (0..count).step_by(std::cmp::max(1, count / num_challenges)) {
+        let p = mt.gen_proof(i).expect("failed to generate proof");
+        assert_eq!(p.item(), leafs[i]);
+        assert!(p.validate::<XOR128>().expect("failed to validate"));
+        assert!(p
+            .validate_against::<XOR128>(&mt.root(), i, mt.leafs())
+            .expect("failed to validate"));
+        assert_eq!(p.validate_strict::<XOR128>(), Ok(()));
+
+        // Any modification of the lemma must invalidate the proof
+        // (XOR128 cancels duplicated nodes out, so only check padding).
+        if policy == OddRowPolicy::Pad {
+            for j in 0..p.lemma().len() {
+                let mut broken = p.clone();
+                broken.lemma_mut()[j][0] ^= 0xff;
+                assert!(!broken.validate::<XOR128>().expect("failed to validate"));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_filled_trees() {
+    for policy in &[OddRowPolicy::Duplicate, OddRowPolicy::Pad] {
+        for count in 1..40 {
+            test_filled_tree::<VecStore<_>, U2>(count, *policy, count);
+            test_filled_tree::<DiskStore<_>, U4>(count, *policy, count);
+        }
+        test_filled_tree::<VecStore<_>, U8>(100, *policy, 100);
+
+        let count = SMALL_TREE_BUILD * 2 + 5;
+        test_filled_tree::<VecStore<_>, U2>(count, *policy, 64);
+        test_filled_tree::<DiskStore<_>, U4>(count, *policy, 64);
+        test_filled_tree::<MmapStore<_>, U3>(count, *policy, 64);
+    }
+
+    // Full trees hash the same regardless of the policy.
+    let leafs = get_rfc6962_leafs(64).concat();
+    let mt: MerkleTree<[u8; 16], XOR128, VecStore<_>, U4> =
+        MerkleTree::from_byte_slice(&leafs).expect("failed to create tree");
+    for policy in &[OddRowPolicy::Duplicate, OddRowPolicy::Pad] {
+        let filled: MerkleTree<[u8; 16], XOR128, VecStore<_>, U4> =
+            MerkleTree::from_byte_slice_with_options(
+                &leafs,
+                &TreeOptions::new().with_policy(*policy),
+            )
+            .expect("failed to create tree");
+        assert_eq!(mt.root(), filled.root());
+    }
+}
+
+fn test_depth_first_tree<S: Store<[u8; 16]>, U: Unsigned>(
+    count: usize,
+    policy: OddRowPolicy,
+    budget: usize,
+) {
+    let name = format!(
+        "test_depth_first_tree-{}-{}-{:?}-{}",
+        U::to_usize(),
+        count,
+        policy,
+        budget
+    );
+    let temp_dir = tempdir::TempDir::new(&name).unwrap();
+    let leafs = get_rfc6962_leafs(count);
+
+    let mut config = StoreConfig::new(temp_dir.path(), String::from("depth-first"), 0);
+    config.build_memory_budget = Some(budget);
+
+    // The reference tree is built one row at a time.
+    let expected: MerkleTree<[u8; 16], XOR128, VecStore<_>, U> =
+        MerkleTree::try_from_iter_with_options(
+            leafs.iter().map(|x| Ok(*x)),
+            &TreeOptions::new().with_policy(policy),
+        )
+        .expect("failed to create tree");
+    let mt: MerkleTree<[u8; 16], XOR128, S, U> = MerkleTree::try_from_iter_with_options(
+        leafs.iter().map(|x| Ok(*x)),
+        &TreeOptions::new().with_config(config).with_policy(policy),
+    )
+    .expect("failed to create tree");
+
+    assert_eq!(mt.root(), expected.root());
+    assert_eq!(
+        mt.data().unwrap().read_range(0..mt.len()).unwrap(),
+        expected
+            .data()
+            .unwrap()
+            .read_range(0..expected.len())
+            .unwrap()
+    );
+}
+
+#[test]
+fn test_depth_first_trees() {
+    let count = SMALL_TREE_BUILD * 4;
+    for budget in &[0, 16 * 64, 16 * 1000, 1 << 20] {
+        test_depth_first_tree::<DiskStore<_>, U2>(count, OddRowPolicy::Reject, *budget);
+        test_depth_first_tree::<MmapStore<_>, U4>(count, OddRowPolicy::Reject, *budget);
+        test_depth_first_tree::<DiskStore<_>, U8>(count, OddRowPolicy::Reject, *budget);
+        test_depth_first_tree::<MmapStore<_>, U2>(count + 5, OddRowPolicy::Promote, *budget);
+        test_depth_first_tree::<DiskStore<_>, U4>(count + 5, OddRowPolicy::Pad, *budget);
+        test_depth_first_tree::<DiskStore<_>, U8>(count * 2 + 3, OddRowPolicy::Duplicate, *budget);
+    }
+}
+
+// Builds a tree from `leafs` with `config`, cancelling the build when
+// its first row is reported.  Only a depth-first build whose budget
+// fits the whole tree writes all the rows before reporting the first
+// one, so that it completes while a row by row build fails.
+fn build_cancelled_at_first_row<S: Store<[u8; 16]>>(
+    leafs: &[[u8; 16]],
+    config: StoreConfig,
+) -> anyhow::Result<MerkleTree<[u8; 16], XOR128, S, U2>> {
+    use crate::options::{BuildOptions, CancelToken};
+
+    let token = CancelToken::new();
+    let cancel = token.clone();
+    let options = BuildOptions::new()
+        .with_cancel_token(token)
+        .with_progress(move |_, _| cancel.cancel());
+
+    MerkleTree::try_from_iter_with_options(
+        leafs.iter().map(|x| Ok(*x)),
+        &TreeOptions::new()
+            .with_config(config)
+            .with_build_options(options),
+    )
+}
+
+#[test]
+fn test_depth_first_build_path() {
+    let count = SMALL_TREE_BUILD * 4;
+    let leafs = get_rfc6962_leafs(count);
+    let temp_dir = tempdir::TempDir::new("test_depth_first_build_path").unwrap();
+    let rows_to_discard = StoreConfig::default_rows_to_discard(count, 2);
+    let config = |id: &str, budget: Option<usize>| {
+        let mut config = StoreConfig::new(temp_dir.path(), String::from(id), rows_to_discard);
+        config.build_memory_budget = budget;
+        config
+    };
+
+    assert!(build_cancelled_at_first_row::<DiskStore<_>>(&leafs, config("disk", None)).is_err());
+    assert!(build_cancelled_at_first_row::<DiskStore<_>>(
+        &leafs,
+        config("disk-depth-first", Some(1 << 20))
+    )
+    .is_ok());
+    assert!(build_cancelled_at_first_row::<MmapStore<_>>(&leafs, config("mmap", None)).is_err());
+    assert!(build_cancelled_at_first_row::<MmapStore<_>>(
+        &leafs,
+        config("mmap-depth-first", Some(1 << 20))
+    )
+    .is_ok());
+    assert!(
+        build_cancelled_at_first_row::<LevelCacheStore<_, std::fs::File>>(
+            &leafs,
+            config("levelcache", None)
+        )
+        .is_err()
+    );
+    let lc_depth_first = build_cancelled_at_first_row::<LevelCacheStore<_, std::fs::File>>(
+        &leafs,
+        config("levelcache-depth-first", Some(1 << 20)),
+    )
+    .expect("failed to create tree");
+
+    // The depth-first build of a LevelCacheStore caches the same rows
+    // as the row by row build.
+    let rows_config = config("levelcache-rows", None);
+    let lc: MerkleTree<[u8; 16], XOR128, LevelCacheStore<_, std::fs::File>, U2> =
+        MerkleTree::try_from_iter_with_config(leafs.iter().map(|x| Ok(*x)), rows_config.clone())
+            .expect("failed to create tree");
+    let depth_first_config = config("levelcache-depth-first", None);
+    assert_eq!(lc_depth_first.root(), lc.root());
+    assert_eq!(
+        std::fs::read(StoreConfig::data_path(
+            &depth_first_config.path,
+            &depth_first_config.id
+        ))
+        .unwrap(),
+        std::fs::read(StoreConfig::data_path(&rows_config.path, &rows_config.id)).unwrap()
+    );
+
+    for budget in &[0, 16 * 64] {
+        let id = format!("levelcache-{}", budget);
+        let lc_budget: MerkleTree<[u8; 16], XOR128, LevelCacheStore<_, std::fs::File>, U2> =
+            MerkleTree::try_from_iter_with_config(
+                leafs.iter().map(|x| Ok(*x)),
+                config(&id, Some(*budget)),
+            )
+            .expect("failed to create tree");
+        assert_eq!(lc_budget.root(), lc.root());
+        assert_eq!(
+            std::fs::read(StoreConfig::data_path(&rows_config.path, &id)).unwrap(),
+            std::fs::read(StoreConfig::data_path(&rows_config.path, &rows_config.id)).unwrap()
+        );
+    }
+}
+
+#[test]
+fn test_duplicate_mutation() {
+    use crate::proof::{Proof, ProofError};
+
+    // CVE-2012-2459: [a, b, c] and [a, b, c, c] share their root.
+    let leafs = get_rfc6962_leafs(3);
+    let mutated_leafs = [&leafs[..], &leafs[2..]].concat();
+    let policy = OddRowPolicy::Duplicate;
+
+    let mt: MerkleTree<[u8; 16], XOR128, VecStore<_>> = MerkleTree::from_byte_slice_with_options(
+        &leafs.concat(),
+        &TreeOptions::new().with_policy(policy),
+    )
+    .expect("failed to create tree");
+    let mutated: MerkleTree<[u8; 16], XOR128, VecStore<_>> =
+        MerkleTree::from_byte_slice_with_options(
+            &mutated_leafs.concat(),
+            &TreeOptions::new().with_policy(policy),
+        )
+        .expect("failed to create tree");
+
+    assert_eq!(mt.root(), mutated.root());
+    assert!(!mt.is_mutated().unwrap());
+    assert!(mutated.is_mutated().unwrap());
+
+    // The padded trees do not collide.
+    let padded: MerkleTree<[u8; 16], XOR128, VecStore<_>> =
+        MerkleTree::from_byte_slice_with_options(
+            &leafs.concat(),
+            &TreeOptions::new().with_policy(OddRowPolicy::Pad),
+        )
+        .expect("failed to create tree");
+    assert_ne!(padded.root(), mt.root());
+
+    // A proof for the duplicated leaf is rejected, even when forged
+    // from a proof of the genuine tree.
+    let p = mutated.gen_proof(3).unwrap();
+    assert!(!p.validate::<XOR128>().unwrap());
+    assert_eq!(
+        p.validate_strict::<XOR128>(),
+        Err(ProofError::MutatedRow {
+            layer: crate::proof::ProofLayer::Base,
+            row: 0,
+        })
+    );
+
+    let genuine = mt.gen_proof(2).unwrap();
+    let lemma = vec![
+        genuine.lemma()[0],
+        genuine.lemma()[0],
+        genuine.lemma()[1],
+        mt.root(),
+    ];
+    let forged: Proof<[u8; 16]> = Proof::new_with_policy(lemma, vec![1, 1], policy, 4).unwrap();
+    assert!(!forged
+        .validate_against::<XOR128>(&mt.root(), 3, mt.leafs())
+        .unwrap());
+}
+
+fn test_update_leaves_with_store<S: Store<[u8; 16]>, U: Unsigned>(
+    leafs: usize,
+    policy: OddRowPolicy,
+) {
+    let mut data = get_rfc6962_leafs(leafs);
+    let mut mt: MerkleTree<[u8; 16], XOR128, S, U> = MerkleTree::from_byte_slice_with_options(
+        &data.concat(),
+        &TreeOptions::new().with_policy(policy),
+    )
+    .expect("failed to create tree");
+
+    // Single leaf updates, including the first and last leafs.
+    for i in &[0, leafs / 3, leafs - 1] {
+        data[*i][1] ^= 0xff;
+        mt.update_leaf(*i, data[*i]).expect("failed to update leaf");
+    }
+
+    // Batch updates, with duplicated indexes and shared ancestors.
+    let updates: Vec<(usize, [u8; 16])> = (0..leafs)
+        .step_by(3)
+        .chain(vec![1, 1])
+        .map(|i| {
+            data[i][2] ^= 0x5a;
+            (i, data[i])
+        })
+        .collect();
+    // The last update of an index wins (restoring leaf 1 here).
+    mt.update_leaves(&updates).expect("failed to update leaves");
+
+    let expected: MerkleTree<[u8; 16], XOR128, VecStore<_>, U> =
+        MerkleTree::from_byte_slice_with_options(
+            &data.concat(),
+            &TreeOptions::new().with_policy(policy),
+        )
+        .expect("failed to create tree");
+
+    assert_eq!(mt.root(), expected.root());
+    assert_eq!(
+        mt.read_range(0, mt.len()).unwrap(),
+        expected.read_range(0, expected.len()).unwrap()
+    );
+    for i in 0..leafs {
+        let p = mt.gen_proof(i).unwrap();
+        assert!(p.validate::<XOR128>().expect("failed to validate"));
+    }
+
+    assert!(mt.update_leaf(leafs, data[0]).is_err());
+}
+
+#[test]
+fn test_update_leaves() {
+    test_update_leaves_with_store::<VecStore<_>, U2>(64, OddRowPolicy::Reject);
+    test_update_leaves_with_store::<DiskStore<_>, U2>(64, OddRowPolicy::Reject);
+    test_update_leaves_with_store::<MmapStore<_>, U2>(64, OddRowPolicy::Reject);
+
+    test_update_leaves_with_store::<VecStore<_>, U4>(256, OddRowPolicy::Reject);
+    test_update_leaves_with_store::<DiskStore<_>, U8>(512, OddRowPolicy::Reject);
+
+    test_update_leaves_with_store::<VecStore<_>, U2>(37, OddRowPolicy::Promote);
+    test_update_leaves_with_store::<DiskStore<_>, U2>(
+        SMALL_TREE_BUILD * 2 + 3,
+        OddRowPolicy::Promote,
+    );
+    test_update_leaves_with_store::<MmapStore<_>, U2>(99, OddRowPolicy::Promote);
+
+    test_update_leaves_with_store::<VecStore<_>, U4>(37, OddRowPolicy::Pad);
+    test_update_leaves_with_store::<DiskStore<_>, U8>(SMALL_TREE_BUILD + 3, OddRowPolicy::Pad);
+}
+
+#[test]
+fn test_levelcache_update_cached_leaves() {
+    let (leafs, len, row_count, rows_to_discard) = { (256, 341, 5, 1) };
+
+    let test_name = "test_levelcache_update_cached_leaves";
+    let temp_dir = tempdir::TempDir::new(test_name).unwrap();
+    let replica = format!("{}-replica", test_name);
+    let config = StoreConfig::new(temp_dir.path(), replica, rows_to_discard);
+    build_disk_tree_from_iter::<U4>(leafs, len, row_count, &config);
+    let replica_path = StoreConfig::data_path(&config.path, &config.id);
+
+    let lc_config = StoreConfig::from_config(&config, String::from(test_name), Some(len));
+    let mut lc_tree =
+        get_levelcache_tree_from_iter::<U4>(leafs, len, row_count, &lc_config, &replica_path);
+
+    // Update the base data in the replica, then the cached rows.
+    let mut data = vec![0u8; leafs * 16];
+    let replica_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&replica_path)
+        .unwrap();
+    replica_file.read_exact_at(&mut data, 0).unwrap();
+
+    let indices = [3, 7, 200];
+    for i in indices.iter() {
+        data[i * 16] ^= 0xff;
+    }
+    replica_file.write_all_at(&data, 0).unwrap();
+
+    lc_tree
+        .update_cached_leaves(&indices, Some(rows_to_discard))
+        .expect("failed to update cached leaves");
+
+    let expected: MerkleTree<[u8; 16], XOR128, VecStore<_>, U4> =
+        MerkleTree::from_byte_slice(&data).expect("failed to create tree");
+    assert_eq!(lc_tree.root(), expected.root());
+
+    for i in indices.iter().chain([0, 100, 255].iter()) {
+        let proof = lc_tree
+            .gen_cached_proof(*i, Some(rows_to_discard))
+            .expect("Failed to generate proof and partial tree");
+        assert_eq!(proof.root(), expected.root());
+        assert!(proof.validate::<XOR128>().expect("failed to validate"));
+    }
+
+    // Base data can only be updated through the external reader.
+    assert!(lc_tree.update_leaf(0, [0; 16]).is_err());
+}
+
+#[test]
+fn test_levelcache_write_paths() {
+    let (leafs, len, row_count, rows_to_discard) = { (256, 341, 5, 1) };
+
+    let test_name = "test_levelcache_write_paths";
+    let temp_dir = tempdir::TempDir::new(test_name).unwrap();
+    let replica = format!("{}-replica", test_name);
+    let config = StoreConfig::new(temp_dir.path(), replica, rows_to_discard);
+    build_disk_tree_from_iter::<U4>(leafs, len, row_count, &config);
+    let replica_path = StoreConfig::data_path(&config.path, &config.id);
+
+    let lc_config = StoreConfig::from_config(&config, String::from(test_name), Some(len));
+    get_levelcache_tree_from_iter::<U4>(leafs, len, row_count, &lc_config, &replica_path);
+    let mut store: LevelCacheStore<[u8; 16], _> = LevelCacheStore::new_from_disk_with_reader(
+        len,
+        QUAD_ARITY,
+        &lc_config,
+        ExternalReader::new_from_path(&replica_path).unwrap(),
+    )
+    .unwrap();
+
+    let cache_start = len - get_merkle_tree_cache_size(leafs, QUAD_ARITY, rows_to_discard).unwrap();
+    assert!(cache_start > leafs);
+
+    // Single elements and slices are written where they are read.
+    let (a, b) = ([1; 16], [2; 16]);
+    store.write_at(a, cache_start).unwrap();
+    assert_eq!(store.read_at(cache_start).unwrap(), a);
+    store.copy_from_slice(&b, cache_start).unwrap();
+    assert_eq!(store.read_at(cache_start).unwrap(), b);
+    store
+        .copy_from_slice(&[a, b].concat(), cache_start + 1)
+        .unwrap();
+    store.write_at(a, len - 1).unwrap();
+    assert_eq!(
+        store.read_range(cache_start..cache_start + 3).unwrap(),
+        vec![b, a, b]
+    );
+    assert_eq!(store.read_at(len - 1).unwrap(), a);
+
+    // Both fail in the discarded rows, and in the base layer read
+    // through the external reader.
+    assert!(store.write_at(a, leafs).is_err());
+    assert!(store.copy_from_slice(&a, leafs).is_err());
+    assert!(store
+        .copy_from_slice(&[a, b].concat(), cache_start - 1)
+        .is_err());
+    assert!(store.write_at(a, 0).is_err());
+    assert!(store.copy_from_slice(&a, 0).is_err());
+}
+
+#[test]
+fn test_levelcache_multi_proof() {
+    let (leafs, len, row_count, rows_to_discard) = { (1024, 1365, 6, 2) };
+
+    let test_name = "test_levelcache_multi_proof";
+    let temp_dir = tempdir::TempDir::new(test_name).unwrap();
+    let replica = format!("{}-replica", test_name);
+    let config = StoreConfig::new(temp_dir.path(), replica, rows_to_discard);
+    build_disk_tree_from_iter::<U4>(leafs, len, row_count, &config);
+    let replica_path = StoreConfig::data_path(&config.path, &config.id);
+
+    let lc_config = StoreConfig::from_config(&config, String::from(test_name), Some(len));
+    let lc_tree =
+        get_levelcache_tree_from_iter::<U4>(leafs, len, row_count, &lc_config, &replica_path);
+
+    let store = DiskStore::new_from_disk(len, QUAD_ARITY, &config).unwrap();
+    let mt: MerkleTree<[u8; 16], XOR128, DiskStore<_>, U4> =
+        MerkleTree::from_data_store(store, leafs).unwrap();
+
+    let indices = [0, 1, 5, 17, 64, 500, 501, 1023];
+    let proof = lc_tree
+        .gen_cached_multi_proof(&indices, Some(rows_to_discard))
+        .expect("failed to generate multi proof");
+    assert!(proof
+        .validate::<XOR128>(lc_tree.root())
+        .expect("failed to validate"));
+    assert_eq!(proof, mt.gen_multi_proof(&indices).unwrap());
+
+    // Discarded rows can't be read without partial trees.
+    assert!(lc_tree.gen_multi_proof(&indices).is_err());
+}
+
+#[test]
+fn test_levelcache_range_proof() {
+    let (leafs, len, row_count, rows_to_discard) = { (1024, 1365, 6, 2) };
+
+    let test_name = "test_levelcache_range_proof";
+    let temp_dir = tempdir::TempDir::new(test_name).unwrap();
+    let replica = format!("{}-replica", test_name);
+    let config = StoreConfig::new(temp_dir.path(), replica, rows_to_discard);
+    build_disk_tree_from_iter::<U4>(leafs, len, row_count, &config);
+    let replica_path = StoreConfig::data_path(&config.path, &config.id);
+
+    let lc_config = StoreConfig::from_config(&config, String::from(test_name), Some(len));
+    let lc_tree =
+        get_levelcache_tree_from_iter::<U4>(leafs, len, row_count, &lc_config, &replica_path);
+
+    let store = DiskStore::new_from_disk(len, QUAD_ARITY, &config).unwrap();
+    let mt: MerkleTree<[u8; 16], XOR128, DiskStore<_>, U4> =
+        MerkleTree::from_data_store(store, leafs).unwrap();
+
+    for (start, end) in &[(0, 1), (5, 70), (250, 260), (1000, 1024), (0, 1024)] {
+        let proof = lc_tree
+            .gen_cached_range_proof(*start, *end, Some(rows_to_discard))
+            .expect("failed to generate range proof");
+        assert!(proof
+            .validate::<XOR128>(lc_tree.root())
+            .expect("failed to validate"));
+        assert_eq!(proof, mt.gen_range_proof(*start, *end).unwrap());
+    }
+
+    // Discarded rows can't be read without partial trees.
+    assert!(lc_tree.gen_range_proof(5, 70).is_err());
+}
+
+static BATCHED_LEAFS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+static BATCHED_NODES: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+/// XOR128 hashing batches lane by lane, counting what it hashes.
+#[derive(Default)]
+struct BatchXOR128(XOR128);
+
+impl Hasher for BatchXOR128 {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.write(bytes)
+    }
+
+    fn finish(&self) -> u64 {
+        self.0.finish()
+    }
+}
+
+impl Algorithm<[u8; 16]> for BatchXOR128 {
+    fn hash(&mut self) -> [u8; 16] {
+        self.0.hash()
+    }
+
+    fn leaf_batch(&mut self, leafs: Vec<[u8; 16]>) -> Vec<[u8; 16]> {
+        use std::sync::atomic::Ordering;
+
+        BATCHED_LEAFS.fetch_add(leafs.len(), Ordering::SeqCst);
+        let mut lanes: Vec<XOR128> = leafs.iter().map(|_| XOR128::new()).collect();
+        for (lane, leaf) in lanes.iter_mut().zip(leafs) {
+            lane.write(&[0x00]);
+            lane.write(&leaf);
+        }
+        lanes.iter_mut().map(|lane| lane.hash()).collect()
+    }
+
+    fn multi_node_batch(&mut self, groups: &[&[[u8; 16]]], _height: usize) -> Vec<[u8; 16]> {
+        use std::sync::atomic::Ordering;
+
+        BATCHED_NODES.fetch_add(groups.len(), Ordering::SeqCst);
+        let mut lanes: Vec<XOR128> = groups.iter().map(|_| XOR128::new()).collect();
+        for (lane, nodes) in lanes.iter_mut().zip(groups) {
+            lane.write(&[0x01]);
+            for node in nodes.iter() {
+                lane.write(node);
+            }
+        }
+        lanes.iter_mut().map(|lane| lane.hash()).collect()
+    }
+}
+
+#[test]
+fn test_batch_algorithm() {
+    use std::sync::atomic::Ordering;
+
+    for &(count, policy) in &[
+        (64, OddRowPolicy::Reject),
+        (SMALL_TREE_BUILD * 4, OddRowPolicy::Reject),
+        (SMALL_TREE_BUILD * 2 + 5, OddRowPolicy::Pad),
+    ] {
+        let leafs: Vec<[u8; 16]> = (0..count).map(|i| [(i % 251) as u8; 16]).collect();
+
+        BATCHED_LEAFS.store(0, Ordering::SeqCst);
+        BATCHED_NODES.store(0, Ordering::SeqCst);
+        let batched: MerkleTree<[u8; 16], BatchXOR128, VecStore<_>, U2> =
+            MerkleTree::try_from_iter_with_options(
+                leafs.iter().cloned().map(Ok),
+                &TreeOptions::new().with_arity(2).with_policy(policy),
+            )
+            .expect("failed to create tree");
+        let mt: MerkleTree<[u8; 16], XOR128, VecStore<_>, U2> =
+            MerkleTree::try_from_iter_with_options(
+                leafs.iter().cloned().map(Ok),
+                &TreeOptions::new().with_arity(2).with_policy(policy),
+            )
+            .expect("failed to create tree");
+
+        // Every leaf and every full group of the tree is hashed in a
+        // batch, to the same tree.
+        assert_eq!(batched.root(), mt.root());
+        assert_eq!(BATCHED_LEAFS.load(Ordering::SeqCst), count);
+        assert!(BATCHED_NODES.load(Ordering::SeqCst) >= count / 2);
+        for i in (0..count).step_by(count / 8) {
+            assert_eq!(
+                batched.gen_proof(i).expect("failed to generate proof"),
+                mt.gen_proof(i).expect("failed to generate proof")
+            );
+        }
+    }
+}
+
+fn test_pipelined_tree<S: Store<[u8; 16]>, U: Unsigned>(count: usize, policy: OddRowPolicy) {
+    let leafs: Vec<[u8; 16]> = (0..count).map(|i| [(i % 251) as u8; 16]).collect();
+    let branches = U::to_usize();
+
+    let pipelined: MerkleTree<[u8; 16], XOR128, S, U> = MerkleTree::from_par_iter_with_options(
+        leafs.clone().into_par_iter(),
+        &TreeOptions::new().with_arity(branches).with_policy(policy),
+    )
+    .expect("failed to create tree");
+    let mt: MerkleTree<[u8; 16], XOR128, VecStore<_>, U> = MerkleTree::try_from_iter_with_options(
+        leafs.iter().cloned().map(Ok),
+        &TreeOptions::new().with_arity(branches).with_policy(policy),
+    )
+    .expect("failed to create tree");
+
+    // Same root, and same nodes in the same places.
+    assert_eq!(pipelined.root(), mt.root());
+    assert_eq!(pipelined.len(), mt.len());
+    assert_eq!(
+        pipelined.read_range(0, pipelined.len()).unwrap(),
+        mt.read_range(0, mt.len()).unwrap()
+    );
+}
+
+#[test]
+fn test_pipelined_trees() {
+    use crate::merkle::BUILD_DATA_BLOCK_SIZE;
+
+    test_pipelined_tree::<VecStore<_>, U2>(1, OddRowPolicy::Promote);
+    test_pipelined_tree::<VecStore<_>, U2>(100, OddRowPolicy::Promote);
+    test_pipelined_tree::<DiskStore<_>, U4>(64, OddRowPolicy::Reject);
+
+    test_pipelined_tree::<VecStore<_>, U2>(BUILD_DATA_BLOCK_SIZE * 2, OddRowPolicy::Reject);
+    test_pipelined_tree::<DiskStore<_>, U2>(BUILD_DATA_BLOCK_SIZE * 2, OddRowPolicy::Reject);
+    test_pipelined_tree::<MmapStore<_>, U3>(BUILD_DATA_BLOCK_SIZE + 5, OddRowPolicy::Pad);
+    test_pipelined_tree::<DiskStore<_>, U8>(BUILD_DATA_BLOCK_SIZE + 9, OddRowPolicy::Duplicate);
+}
+
+#[test]
+fn test_build_options() {
+    use crate::options::{BuildOptions, CancelToken};
+    use std::sync::{Arc, Mutex};
+
+    let count = 4096;
+    let row_count = get_merkle_tree_row_count(count, 2);
+    let leafs: Vec<[u8; 16]> = (0..count).map(|i| [(i % 251) as u8; 16]).collect();
+    let mt: MerkleTree<[u8; 16], XOR128, VecStore<_>, U2> =
+        MerkleTree::try_from_iter(leafs.iter().cloned().map(Ok)).expect("failed to create tree");
+
+    let temp_dir = tempdir::TempDir::new("test_build_options").unwrap();
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(2)
+        .build()
+        .expect("failed to create thread pool");
+    let rows = Arc::new(Mutex::new(Vec::new()));
+    let reported = rows.clone();
+    let options = BuildOptions::new()
+        .with_thread_pool(Arc::new(pool))
+        .with_small_tree_build(0)
+        .with_chunk_nodes(16)
+        .with_data_block_size(64)
+        .with_progress(move |row, row_count| {
+            // The rows are built on the thread pool of the options.
+            assert_eq!(rayon::current_num_threads(), 2);
+            reported.lock().unwrap().push((row, row_count));
+        });
+    let all_rows: Vec<(usize, usize)> = (1..row_count).map(|row| (row, row_count)).collect();
+
+    // Every row is reported once, in order, whatever the build.
+    let tree: MerkleTree<[u8; 16], XOR128, VecStore<_>, U2> =
+        MerkleTree::try_from_iter_with_options(
+            leafs.iter().cloned().map(Ok),
+            &TreeOptions::new().with_build_options(options.clone()),
+        )
+        .expect("failed to create tree");
+    assert_eq!(tree.root(), mt.root());
+    assert_eq!(*rows.lock().unwrap(), all_rows);
+
+    rows.lock().unwrap().clear();
+    let config = StoreConfig::new(temp_dir.path(), String::from("test_build_options"), 0);
+    let tree: MerkleTree<[u8; 16], XOR128, DiskStore<_>, U2> =
+        MerkleTree::from_par_iter_with_options(
+            leafs.clone(),
+            &TreeOptions::new()
+                .with_config(config)
+                .with_build_options(options.clone()),
+        )
+        .expect("failed to create tree");
+    assert_eq!(tree.root(), mt.root());
+    assert_eq!(*rows.lock().unwrap(), all_rows);
+
+    rows.lock().unwrap().clear();
+    let mut store = VecStore::new_from_slice(count, &leafs.concat()).unwrap();
+    let root = store
+        .build_with_options::<XOR128>(count, row_count, None, &[2], OddRowPolicy::Reject, &options)
+        .expect("failed to build store");
+    let raw: MerkleTree<[u8; 16], XOR128, VecStore<_>, U2> =
+        MerkleTree::from_byte_slice(&leafs.concat()).expect("failed to create tree");
+    assert_eq!(root, raw.root());
+    assert_eq!(*rows.lock().unwrap(), all_rows);
+
+    // A cancelled build fails, and removes the store it created.
+    let token = CancelToken::new();
+    let cancel = token.clone();
+    let options = BuildOptions::new()
+        .with_small_tree_build(0)
+        .with_chunk_nodes(16)
+        .with_cancel_token(token)
+        .with_progress(move |row, _| {
+            if row == 2 {
+                cancel.cancel();
+            }
+        });
+    let config = StoreConfig::new(
+        temp_dir.path(),
+        String::from("test_build_options-cancel"),
+        0,
+    );
+    let data_path = StoreConfig::data_path(&config.path, &config.id);
+    let result: anyhow::Result<MerkleTree<[u8; 16], XOR128, DiskStore<_>, U2>> =
+        MerkleTree::try_from_iter_with_options(
+            leafs.iter().cloned().map(Ok),
+            &TreeOptions::new()
+                .with_config(config)
+                .with_build_options(options),
+        );
+    assert!(result.is_err());
+    assert!(!data_path.exists());
+}
@@ -2,6 +2,9 @@
 #![cfg(feature = "bitcoin")]
 
 use merkletree::hash::{Algorithm, Hashable};
+use merkletree::merkle::{MerkleTree, OddRowPolicy};
+use merkletree::options::TreeOptions;
+use merkletree::store::VecStore;
 use ring::digest::{Context, SHA256};
 use std::fmt;
 use std::hash::Hasher;
@@ -80,6 +83,12 @@ impl Algorithm<RingSHA256Hash> for RingBitcoinAlgorithm {
         right.hash(self);
         self.hash()
     }
+
+    // Bitcoin trees are binary, hashing pairs of nodes with `node`.
+    fn multi_node(&mut self, nodes: &[RingSHA256Hash], height: usize) -> RingSHA256Hash {
+        assert_eq!(nodes.len(), 2, "Bitcoin trees are binary");
+        self.node(nodes[0], nodes[1], height)
+    }
 }
 
 struct HexSlice<'a>(&'a [u8]);
@@ -117,8 +126,6 @@ fn test_ring_bitcoin_leaf_hash() {
     );
 }
 
-/*
-/// Pow2 leafs only supported.
 /// [](http://chimera.labs.oreilly.com/books/1234000001802/ch07.html#merkle_trees)
 #[test]
 fn test_ring_bitcoin_node() {
@@ -154,10 +161,31 @@ fn test_ring_bitcoin_node() {
     );
 
     let t: MerkleTree<RingSHA256Hash, RingBitcoinAlgorithm, VecStore<_>> =
-        MerkleTree::try_from_iter(vec![h1, h2, h3].into_iter().map(Ok)).unwrap();
+        MerkleTree::try_from_iter_with_options(
+            vec![h1, h2, h3].into_iter().map(Ok),
+            &TreeOptions::new().with_policy(OddRowPolicy::Duplicate),
+        )
+        .unwrap();
     assert_eq!(
         format!("{}", HexSlice::new(t.root().as_ref())),
         "5ba580c87c9bae263e6186318d77963846ff7a3e92b45f2ed30495ccf52b4731"
     );
+    assert!(!t.is_mutated().unwrap());
+
+    for i in 0..3 {
+        let p = t.gen_proof(i).unwrap();
+        assert!(p
+            .validate_against::<RingBitcoinAlgorithm>(&t.root(), i, t.leafs())
+            .unwrap());
+    }
+
+    // CVE-2012-2459: duplicating the last leaf keeps the same root.
+    let mutated: MerkleTree<RingSHA256Hash, RingBitcoinAlgorithm, VecStore<_>> =
+        MerkleTree::try_from_iter_with_options(
+            vec![h1, h2, h3, h3].into_iter().map(Ok),
+            &TreeOptions::new().with_policy(OddRowPolicy::Duplicate),
+        )
+        .unwrap();
+    assert_eq!(mutated.root(), t.root());
+    assert!(mutated.is_mutated().unwrap());
 }
-*/